@@ -19,9 +19,10 @@ use image:: {
     ImageDecoder,
     ImageResult,
     ImageFormat,
+    ImageOutputFormat,
 };
 
-use image::DecodingResult::{U8};
+use image::DecodingResult::{U8, U16};
 
 /// A Dynamic Image
 pub enum DynamicImage {
@@ -331,35 +332,57 @@ impl DynamicImage {
 
     /// Encode this image and write it to ```w```
     pub fn save<W: Writer>(&self, w: &mut W, format: ImageFormat) -> old_io::IoResult<ImageResult<()>> {
+        let format = match format {
+            image::ImageFormat::PNG  => ImageOutputFormat::PNG,
+            image::ImageFormat::PPM  => ImageOutputFormat::PPM,
+            image::ImageFormat::JPEG => ImageOutputFormat::JPEG(jpeg::DEFAULT_QUALITY),
+            image::ImageFormat::WEBP => ImageOutputFormat::WEBP(
+                webp::WebpCompression::Lossy(webp::DEFAULT_QUALITY)
+            ),
+
+            _ => return Ok(Err(image::ImageError::UnsupportedError(
+                     format!("An encoder for {:?} is not available.", format))
+                 )),
+        };
+
+        self.save_with_format(w, format)
+    }
+
+    /// Encode this image with the encoder options of ```format```
+    /// and write it to ```w```
+    pub fn save_with_format<W: Writer>(&self, w: &mut W, format: ImageOutputFormat) -> old_io::IoResult<ImageResult<()>> {
         let bytes = self.raw_pixels();
         let (width, height) = self.dimensions();
         let color = self.color();
 
         let r = match format {
-            image::ImageFormat::PNG  => {
+            ImageOutputFormat::PNG  => {
                 let mut p = png::PNGEncoder::new(w);
 
                 try!(p.encode(&bytes[], width, height, color));
                 Ok(())
             }
 
-            image::ImageFormat::PPM  => {
+            ImageOutputFormat::PPM  => {
                 let mut p = ppm::PPMEncoder::new(w);
 
                 try!(p.encode(&bytes[], width, height, color));
                 Ok(())
             }
 
-            image::ImageFormat::JPEG => {
-                let mut j = jpeg::JPEGEncoder::new(w);
+            ImageOutputFormat::JPEG(quality) => {
+                let mut j = jpeg::JPEGEncoder::new_with_quality(w, quality);
 
                 try!(j.encode(&bytes[], width, height, color));
                 Ok(())
             }
 
-            _ => Err(image::ImageError::UnsupportedError(
-                     format!("An encoder for {:?} is not available.", format))
-                 ),
+            ImageOutputFormat::WEBP(compression) => {
+                let mut e = webp::WebpEncoder::new_with_compression(w, compression);
+
+                try!(e.encode(&bytes[], width, height, color));
+                Ok(())
+            }
         };
 
         Ok(r)
@@ -431,6 +454,23 @@ pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage>
         (color::ColorType::GrayA(8), U8(buf)) => {
             ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageLumaA8(v))
         }
+        // Images with 16 bits per sample are reduced to 8 bits
+        (color::ColorType::RGB(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, to_8_bit(buf)).map(|v| DynamicImage::ImageRgb8(v))
+        }
+
+        (color::ColorType::RGBA(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, to_8_bit(buf)).map(|v| DynamicImage::ImageRgba8(v))
+        }
+
+        (color::ColorType::Gray(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, to_8_bit(buf)).map(|v| DynamicImage::ImageLuma8(v))
+        }
+
+        (color::ColorType::GrayA(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, to_8_bit(buf)).map(|v| DynamicImage::ImageLumaA8(v))
+        }
+
         (color::ColorType::Gray(bit_depth), U8(ref buf)) if bit_depth == 1 || bit_depth == 2 || bit_depth == 4 => {
             // Note: this conversion assumes that the scanlines begin on byte boundaries
             let mask = (1u8 << bit_depth as usize) - 1;
@@ -463,6 +503,11 @@ pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage>
     }
 }
 
+// Rounds samples with 16 bits to 8 bits
+fn to_8_bit(buf: Vec<u16>) -> Vec<u8> {
+    buf.iter().map(|&s| ((s as u32 * 255 + 32767) / 65535) as u8).collect()
+}
+
 #[allow(deprecated)]
 fn image_to_bytes(image: &DynamicImage) -> Vec<u8> {
     match *image {
@@ -488,11 +533,29 @@ fn image_to_bytes(image: &DynamicImage) -> Vec<u8> {
 /// Open the image located at the path specified.
 /// The image's format is determined from the path's file extension.
 pub fn open(path: &Path) -> ImageResult<DynamicImage> {
+    let format = try!(format_from_path(path));
+    let fin = match old_io::File::open(path) {
+        Ok(f)  => f,
+        Err(err) => return Err(image::ImageError::IoError(err))
+    };
+
+    load(fin, format)
+}
+
+/// Open the image located at the path specified and rotate and flip it
+/// according to the orientation stored in its EXIF metadata.
+/// The image's format is determined from the path's file extension.
+pub fn open_oriented(path: &Path) -> ImageResult<DynamicImage> {
+    let format = try!(format_from_path(path));
     let fin = match old_io::File::open(path) {
         Ok(f)  => f,
         Err(err) => return Err(image::ImageError::IoError(err))
     };
 
+    load_oriented(fin, format)
+}
+
+fn format_from_path(path: &Path) -> ImageResult<ImageFormat> {
     let ext = path.extension_str()
                   .map_or("".to_string(), | s | s.to_string().into_ascii_lowercase());
 
@@ -511,7 +574,7 @@ pub fn open(path: &Path) -> ImageResult<DynamicImage> {
         )))
     };
 
-    load(fin, format)
+    Ok(format)
 }
 
 /// Saves the supplied buffer to a file at the path specified.
@@ -520,7 +583,7 @@ pub fn open(path: &Path) -> ImageResult<DynamicImage> {
 /// the correct format according to the specified color type.
 
 /// This will lead to corrupted files if the buffer contains malformed data. Currently only
-/// jpeg and png files are supported.
+/// jpeg, png, ppm and webp files are supported.
 pub fn save_buffer(path: &Path, buf: &[u8], width: u32, height: u32, color: color::ColorType) ->  old_io::IoResult<()> {
     let ref mut fout = try!(old_io::File::create(path));
     let ext = path.extension_str()
@@ -531,6 +594,7 @@ pub fn save_buffer(path: &Path, buf: &[u8], width: u32, height: u32, color: colo
         "jpeg" => jpeg::JPEGEncoder::new(fout).encode(buf, width, height, color),
         "png"  => png::PNGEncoder::new(fout).encode(buf, width, height, color),
         "ppm"  => ppm::PPMEncoder::new(fout).encode(buf, width, height, color),
+        "webp" => webp::WebpEncoder::new(fout).encode(buf, width, height, color),
         format => Err(old_io::IoError {
             kind: old_io::InvalidInput,
             desc: "Unsupported image format.",
@@ -555,6 +619,40 @@ pub fn load<R: Reader+Seek>(r: R, format: ImageFormat) -> ImageResult<DynamicIma
     }
 }
 
+/// Create a new image from a Reader and rotate and flip it according to
+/// the orientation stored in its EXIF metadata.
+/// Only JPEG images carry EXIF metadata, other formats are loaded unchanged.
+pub fn load_oriented<R: Reader+Seek>(r: R, format: ImageFormat) -> ImageResult<DynamicImage> {
+    match format {
+        image::ImageFormat::JPEG => {
+            let mut decoder = jpeg::JPEGDecoder::new(old_io::BufferedReader::new(r));
+            let orientation = try!(decoder.exif()).and_then(| e | e.orientation());
+            let image = try!(decoder_to_image(decoder));
+
+            Ok(match orientation {
+                Some(orientation) => apply_orientation(image, orientation),
+                None => image
+            })
+        }
+        _ => load(r, format)
+    }
+}
+
+// Transforms an image stored with the EXIF orientation ```orientation```
+// so that it is displayed upright.
+fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image
+    }
+}
+
 static MAGIC_BYTES: [(&'static [u8], ImageFormat); 7] = [
     (b"\x89PNG\r\n\x1a\n", ImageFormat::PNG),
     (&[0xff, 0xd8, 0xff], ImageFormat::JPEG),
@@ -589,6 +687,72 @@ pub fn load_from_memory_with_format(buf: &[u8], format: ImageFormat) -> ImageRes
     load(b, format)
 }
 
+#[cfg(test)]
+mod test {
+    use std::old_io::{File, MemReader};
+
+    use buffer::Pixel;
+    use image::{GenericImage, ImageFormat};
+    use super::{DynamicImage, load, load_oriented, open_oriented};
+
+    // The stored image is 3 by 2 flat gray blocks of 8 by 8 pixels,
+    // the offset of the value of the orientation tag in its EXIF segment
+    fn oriented_test_image() -> (Path, usize) {
+        (Path::new(".").join_many(&["src", "jpeg", "testdata", "exif-orientation.jpg"]), 30)
+    }
+
+    fn block_value(x: u32, y: u32) -> u8 {
+        (20 + 40 * ((y / 8) * 3 + x / 8)) as u8
+    }
+
+    // Checks that ```image``` shows the stored image as described by ```orientation```
+    fn assert_oriented(image: &DynamicImage, orientation: u16) {
+        let (w, h) = (24, 16);
+        let expected = if orientation < 5 { (w, h) } else { (h, w) };
+        assert_eq!(image.dimensions(), expected);
+
+        let pixels = image.to_luma();
+
+        for y in (0..expected.1) {
+            for x in (0..expected.0) {
+                // The stored pixel that is displayed at (x, y)
+                let (sx, sy) = match orientation {
+                    1 => (x, y),
+                    2 => (w - 1 - x, y),
+                    3 => (w - 1 - x, h - 1 - y),
+                    4 => (x, h - 1 - y),
+                    5 => (y, x),
+                    6 => (y, h - 1 - x),
+                    7 => (w - 1 - y, h - 1 - x),
+                    _ => (w - 1 - y, x),
+                };
+
+                assert_eq!(pixels.get_pixel(x, y).channels()[0], block_value(sx, sy));
+            }
+        }
+    }
+
+    #[test]
+    fn test_orientations() {
+        let (path, offset) = oriented_test_image();
+        let data = File::open(&path).read_to_end().unwrap();
+
+        let stored = load(MemReader::new(data.clone()), ImageFormat::JPEG).unwrap();
+        assert_oriented(&stored, 1);
+
+        for orientation in (1u16..9) {
+            let mut data = data.clone();
+            data[offset] = orientation as u8;
+
+            let image = load_oriented(MemReader::new(data), ImageFormat::JPEG).unwrap();
+            assert_oriented(&image, orientation);
+        }
+
+        // The file is stored rotated by 90 degrees
+        assert_oriented(&open_oriented(&path).unwrap(), 6);
+    }
+}
+
 #[cfg(test)]
 mod bench {
     use test;
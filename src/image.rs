@@ -11,6 +11,7 @@ use buffer::{ImageBuffer, Pixel};
 
 use animation::{Frame, Frames};
 use dynimage::decoder_to_image;
+use webp::WebpCompression;
 
 /// An enumeration of Image Errors
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -108,6 +109,22 @@ pub enum ImageFormat {
     TGA
 }
 
+/// An image format to encode to, together with the options of its encoder
+#[derive(Copy, PartialEq, Debug)]
+pub enum ImageOutputFormat {
+    /// An Image in PNG Format
+    PNG,
+
+    /// An Image in JPEG Format with a quality from 1 to 100
+    JPEG(u8),
+
+    /// An Image in PPM Format
+    PPM,
+
+    /// An Image in WEBP Format, lossy or lossless
+    WEBP(WebpCompression),
+}
+
 /// The trait that all decoders implement
 pub trait ImageDecoder: Sized {
     /// Returns a tuple containing the width and height of the image
@@ -7,11 +7,13 @@ use std::num::{ Float };
 use std::iter::repeat;
 
 use color;
+use tiff::Exif;
 use super::transform;
 
 use super::entropy:: {
     HuffTable,
     HuffDecoder,
+    ArithDecoder,
     derive_tables,
 };
 
@@ -57,13 +59,63 @@ pub struct Component {
     pub dc_pred: i32
 }
 
+/// The quantized DCT coefficients of a component
+pub struct ComponentCoefficients {
+    /// The component as described by the frame header
+    pub component: Component,
+
+    /// The number of blocks per row, including the blocks that pad the image to whole MCUs
+    pub blocks_w: usize,
+
+    /// The number of blocks per column, including the blocks that pad the image to whole MCUs
+    pub blocks_h: usize,
+
+    /// The blocks in raster order, with their coefficients in row-major order
+    pub blocks: Vec<[i32; 64]>
+}
+
+/// The quantized DCT coefficients of an image together with
+/// everything that is needed to encode them again without loss
+pub struct Coefficients {
+    /// The width of the image
+    pub width: u16,
+
+    /// The height of the image
+    pub height: u16,
+
+    /// Whether the image was encoded progressively
+    pub progressive: bool,
+
+    /// The number of MCUs between restart markers, 0 if the image has none
+    pub restart_interval: u16,
+
+    /// The quantization tables in row-major order, indexed by the table selector of the components
+    pub qtables: [[u8; 64]; 4],
+
+    /// The components in the order of the frame header
+    pub components: Vec<ComponentCoefficients>,
+
+    /// The application and comment segments of the image as pairs of marker and data
+    pub markers: Vec<(u8, Vec<u8>)>
+}
+
 // Markers
 // Baseline DCT
 const SOF0: u8 = 0xC0;
+// Extended sequential DCT
+const SOF1: u8 = 0xC1;
 // Progressive DCT
 const SOF2: u8 = 0xC2;
+// Lossless (sequential)
+const SOF3: u8 = 0xC3;
 // Huffman Tables
 const DHT: u8 = 0xC4;
+// Extended sequential DCT, arithmetic coding
+const SOF9: u8 = 0xC9;
+// Progressive DCT, arithmetic coding
+const SOF10: u8 = 0xCA;
+// Arithmetic coding conditioning
+const DAC: u8 = 0xCC;
 // Restart Interval start and End (standalone)
 const RST0: u8 = 0xD0;
 const RST7: u8 = 0xD7;
@@ -81,6 +133,9 @@ const DNL: u8 = 0xDC;
 const DRI: u8 = 0xDD;
 // Application segments start and end
 const APP0: u8 = 0xE0;
+const APP1: u8 = 0xE1;
+const APP2: u8 = 0xE2;
+const APP14: u8 = 0xEE;
 const APPF: u8 = 0xEF;
 // Comment
 const COM: u8 = 0xFE;
@@ -93,26 +148,37 @@ enum JPEGState {
     HaveSOI,
     HaveFirstFrame,
     HaveFirstScan,
-    #[allow(dead_code)]
     End
 }
 
 /// The representation of a JPEG decoder
 ///
-/// Does not support decoding progressive JPEG images
+/// Baseline images are decoded one MCU row at a time.
+/// The coefficients of progressive images are accumulated over all scans
+/// and the image is reconstructed once the end of the image is reached.
+/// The same applies to images with more than 8 bits per sample, to lossless images,
+/// whose samples are predicted from their neighbours instead of being transformed,
+/// and to arithmetic coded images.
+///
+/// Samples with more than 8 bits are scaled to 16 bits and returned as ```DecodingResult::U16```,
+/// the samples of lossless images with fewer than 8 bits are scaled to 8 bits.
 pub struct JPEGDecoder<R> {
     r: R,
 
-    qtables: [u8; 64 * 4],
-    dctables: [HuffTable; 2],
-    actables: [HuffTable; 2],
+    qtables: [u16; 64 * 4],
+    dctables: [HuffTable; 4],
+    actables: [HuffTable; 4],
 
     h: HuffDecoder,
+    arith: ArithDecoder,
+    arithmetic: bool,
 
     height: u16,
     width: u16,
+    precision: u8,
 
     num_components: u8,
+    frame_components: Vec<u8>,
     scan_components: Vec<u8>,
     components: VecMap<Component>,
 
@@ -122,9 +188,32 @@ pub struct JPEGDecoder<R> {
     vmax: u8,
 
     interval: u16,
-    mcucount: u16,
+    mcucount: usize,
     expected_rst: u8,
 
+    adobe_transform: Option<u8>,
+    exif: Option<Exif>,
+    markers: Vec<(u8, Vec<u8>)>,
+
+    progressive: bool,
+    lossless: bool,
+    spectral_start: u8,
+    spectral_end: u8,
+    approx_high: u8,
+    approx_low: u8,
+    eobrun: u16,
+    coefficients: VecMap<Vec<i32>>,
+    component_qtables: VecMap<[u16; 64]>,
+    image: Vec<u8>,
+
+    planes: VecMap<Vec<u16>>,
+    first_lines: VecMap<usize>,
+    image16: Vec<u16>,
+
+    idct_size: usize,
+    lenient: bool,
+    warnings: Vec<String>,
+    gray_mcus: usize,
     row_count: u8,
     decoded_rows: u32,
     padded_width: usize,
@@ -139,16 +228,20 @@ impl<R: Reader>JPEGDecoder<R> {
         JPEGDecoder {
             r: r,
 
-            qtables: [0u8; 64 * 4],
-            dctables: [h.clone(), h.clone()],
-            actables: [h.clone(), h.clone()],
+            qtables: [0u16; 64 * 4],
+            dctables: [h.clone(), h.clone(), h.clone(), h.clone()],
+            actables: [h.clone(), h.clone(), h.clone(), h.clone()],
 
             h: HuffDecoder::new(),
+            arith: ArithDecoder::new(),
+            arithmetic: false,
 
             height: 0,
             width: 0,
+            precision: 8,
 
             num_components: 0,
+            frame_components: Vec::new(),
             scan_components: Vec::new(),
             components: VecMap::new(),
 
@@ -161,6 +254,29 @@ impl<R: Reader>JPEGDecoder<R> {
             mcucount: 0,
             expected_rst: RST0,
 
+            adobe_transform: None,
+            exif: None,
+            markers: Vec::new(),
+
+            progressive: false,
+            lossless: false,
+            spectral_start: 0,
+            spectral_end: 63,
+            approx_high: 0,
+            approx_low: 0,
+            eobrun: 0,
+            coefficients: VecMap::new(),
+            component_qtables: VecMap::new(),
+            image: Vec::new(),
+
+            planes: VecMap::new(),
+            first_lines: VecMap::new(),
+            image16: Vec::new(),
+
+            idct_size: 8,
+            lenient: false,
+            warnings: Vec::new(),
+            gray_mcus: 0,
             row_count: 0,
             decoded_rows: 0,
             state: JPEGState::Start,
@@ -170,10 +286,30 @@ impl<R: Reader>JPEGDecoder<R> {
 
     fn decode_mcu_row(&mut self) -> ImageResult<()> {
         let bytesperpixel = self.num_components as usize;
+        let sampling = self.sampling_factors();
 
-        for x0 in range_step(0, self.padded_width * bytesperpixel, bytesperpixel * 8 * self.hmax as usize) {
+        let mcu_width = self.idct_size * self.hmax as usize;
 
-            let _ = try!(self.decode_mcu());
+        for x0 in range_step(0, self.padded_width * bytesperpixel, bytesperpixel * mcu_width) {
+            if self.gray_mcus > 0 {
+                self.skip_mcu();
+            } else {
+                match self.decode_mcu() {
+                    Ok(()) => (),
+                    Err(err) => {
+                        if !self.lenient {
+                            return Err(err)
+                        }
+
+                        let (mcus_per_row, mcus_per_column) = self.mcu_counts();
+                        let unit = self.mcucount;
+
+                        let next = self.resynchronize(unit, mcus_per_row * mcus_per_column, err);
+                        self.gray_mcus = next - unit;
+                        self.skip_mcu();
+                    }
+                }
+            }
 
             upsample_mcu (
                 &mut self.mcu_row[],
@@ -181,8 +317,10 @@ impl<R: Reader>JPEGDecoder<R> {
                 self.padded_width,
                 bytesperpixel,
                 &self.mcu[],
+                &sampling[],
                 self.hmax,
-                self.vmax
+                self.vmax,
+                self.idct_size
             );
         }
 
@@ -205,347 +343,1505 @@ impl<R: Reader>JPEGDecoder<R> {
             self.components.insert(*id as usize, c);
         }
 
-        self.mcucount += 1;
-        self.read_restart()
+        let (mcus_per_row, mcus_per_column) = self.mcu_counts();
+        let count = self.mcucount + 1;
+
+        // The MCUs of intervals whose restart markers were lost are filled with gray
+        self.gray_mcus = try!(self.restart_if_due(count, mcus_per_row * mcus_per_column));
+        self.mcucount = count;
+
+        Ok(())
     }
 
-    fn decode_block(&mut self, i: usize, dc: u8, pred: i32, ac: u8, q: u8) -> ImageResult<i32> {
-        let zz   = &mut self.mcu[i * 64..i * 64 + 64];
-        let mut tmp = [0i32; 64];
+    // Fills the samples of an MCU that could not be decoded with gray
+    fn skip_mcu(&mut self) {
+        for sample in self.mcu.iter_mut() {
+            *sample = 128;
+        }
 
-        let dctable = &self.dctables[dc as usize];
-        let actable = &self.actables[ac as usize];
-        let qtable  = &self.qtables[64 * q as usize..64 * q as usize + 64];
+        self.gray_mcus -= 1;
+        self.mcucount += 1;
+    }
 
-        let t     = try!(self.h.decode_symbol(&mut self.r, dctable));
+    // Skips the corrupt data of the ```unit```th unit of a scan up to the next restart marker.
+    // Returns the unit at which decoding continues, which is ```total``` if the rest of the scan is lost.
+    fn resynchronize(&mut self, unit: usize, total: usize, err: image::ImageError) -> usize {
+        let interval = self.interval as usize;
 
-        let diff  = if t > 0 {
-            try!(self.h.receive(&mut self.r, t))
+        let next = if interval == 0 {
+            total
         } else {
-            0
+            match self.find_restart_marker() {
+                Ok(rst) => self.restart_after(rst, unit, total),
+                Err(_) => total
+            }
         };
 
-        // Section F.2.1.3.1
-        let diff = extend(diff, t);
-        let dc = diff + pred;
-        tmp[0] = dc * qtable[0] as i32;
+        self.warnings.push(format!(
+            "{} in unit {} of a scan, {} units were skipped", err, unit, next - unit
+        ));
+
+        next
+    }
 
-        let mut k = 0us;
-        while k < 63 {
-            let rs = try!(self.h.decode_symbol(&mut self.r, actable));
+    // Continues decoding after the marker ```rst``` that follows the ```unit```th unit of a scan.
+    // Returns the unit at which decoding continues, which is ```total``` if ```rst``` is no restart marker.
+    fn restart_after(&mut self, rst: u8, unit: usize, total: usize) -> usize {
+        match rst {
+            RST0 ... RST7 => {
+                // Restart markers that were lost with the corrupt data skip whole intervals
+                let skipped = ((rst + 8 - self.expected_rst) & 7) as usize;
+                let interval = self.interval as usize;
 
-            let ssss = rs & 0x0F;
-            let rrrr = rs >> 4;
+                self.reset();
+                self.expected_rst = if rst == RST7 { RST0 } else { rst + 1 };
 
-            if ssss == 0 {
-                if rrrr != 15 {
-                    break
-                }
+                cmp::min((unit / interval + 1 + skipped) * interval, total)
+            }
+            _ => total
+        }
+    }
 
-                k += 16;
-            } else {
-                k += rrrr as usize;
+    fn decode_block(&mut self, i: usize, dc: u8, pred: i32, ac: u8, q: u8) -> ImageResult<i32> {
+        let n    = self.idct_size * self.idct_size;
+        let zz   = &mut self.mcu[i * n..i * n + n];
+        let mut coefficients = [0i32; 64];
+        let mut tmp = [0i32; 64];
+
+        let dctable = &self.dctables[dc as usize];
+        let actable = &self.actables[ac as usize];
+        let qtable  = &self.qtables[64 * q as usize..64 * q as usize + 64];
 
-                // Figure F.14
-                let t = try!(self.h.receive(&mut self.r, ssss));
+        let dc = try!(decode_sequential_block(&mut self.h, &mut self.r, dctable, actable, pred, &mut coefficients));
 
-                tmp[UNZIGZAG[k + 1] as usize] = extend(t, ssss) * qtable[k + 1] as i32;
-                k += 1;
-            }
+        for k in (0us..64) {
+            tmp[UNZIGZAG[k] as usize] = coefficients[k] * qtable[k] as i32;
         }
 
-        transform::idct(&tmp, zz);
+        transform::idct_scaled(&tmp, zz, self.idct_size);
 
         Ok(dc)
     }
 
-    fn read_metadata(&mut self) -> ImageResult<()> {
-        while self.state != JPEGState::HaveFirstScan {
-            let byte = try!(self.r.read_u8());
+    // Decodes the coefficients, or the samples of lossless images, of all scans up to the end of the image
+    fn decode_coefficients(&mut self) -> ImageResult<()> {
+        for &id in self.frame_components.iter() {
+            let c = *self.components.get(&(id as usize)).unwrap();
+            let (blocks_w, blocks_h) = self.component_blocks(&c);
 
-            if byte != 0xFF {
-                continue;
+            if self.lossless {
+                let plane = repeat(0u16).take(blocks_w * blocks_h).collect::<Vec<u16>>();
+                self.planes.insert(id as usize, plane);
+            } else {
+                let coefficients = repeat(0i32).take(blocks_w * blocks_h * 64).collect::<Vec<i32>>();
+                self.coefficients.insert(id as usize, coefficients);
             }
+        }
 
-            let marker = try!(self.r.read_u8());
+        // The header of the first scan has been read by read_metadata
+        let _ = try!(self.decode_coefficient_scan());
 
-            match marker {
-                SOI => self.state = JPEGState::HaveSOI,
-                DHT => try!(self.read_huffman_tables()),
-                DQT => try!(self.read_quantization_tables()),
-                SOF0 => {
-                    let _ = try!(self.read_frame_header());
-                    self.state = JPEGState::HaveFirstFrame;
+        loop {
+            let marker = match self.read_marker() {
+                Ok(marker) => marker,
+                Err(err) => {
+                    if !self.lenient {
+                        return Err(err)
+                    }
+
+                    self.warnings.push(format!("The image ends without an EOI marker: {}", err));
+                    break
                 }
+            };
+
+            match marker {
                 SOS => {
                     let _ = try!(self.read_scan_header());
-                    self.state = JPEGState::HaveFirstScan;
+                    let _ = try!(self.decode_coefficient_scan());
                 }
+                DHT => try!(self.read_huffman_tables()),
+                DQT => try!(self.read_quantization_tables()),
+                DAC => try!(self.read_arithmetic_conditioning()),
                 DRI => try!(self.read_restart_interval()),
                 APP0 ... APPF | COM => {
-                    let length = try!(self.r.read_be_u16());
-                    let _ = try!(self.r.read_exact((length - 2) as usize));
+                    let _ = try!(self.read_segment_data());
                 }
-                TEM  => continue,
-                SOF2 => return Err(image::ImageError::UnsupportedError("Marker SOF2 ist not supported.".to_string())),
-                DNL  => return Err(image::ImageError::UnsupportedError("Marker DNL ist not supported.".to_string())),
-                marker => return Err(image::ImageError::FormatError(format!("Unkown marker {} encountered.", marker))),
+                RST0 ... RST7 | TEM => continue,
+                EOI => break,
+                marker => return Err(image::ImageError::FormatError(format!(
+                    "Unexpected marker {} between scans.", marker
+                ))),
             }
         }
 
+        self.state = JPEGState::End;
+
         Ok(())
     }
 
-    fn read_frame_header(&mut self) -> ImageResult<()> {
-        let _frame_length = try!(self.r.read_be_u16());
-        let sample_precision = try!(self.r.read_u8());
+    // Returns the marker that one of the entropy decoders ran into, or 0
+    fn take_marker(&mut self) -> u8 {
+        let m = if self.h.marker != 0 { self.h.marker } else { self.arith.take_marker() };
+        self.h.marker = 0;
 
-        if sample_precision != 8 {
-            return Err(image::ImageError::UnsupportedError(format!(
-                "A sample precision of {} is not supported",
-                sample_precision
-            )))
-        }
+        m
+    }
 
-        self.height 	    = try!(self.r.read_be_u16());
-        self.width  	    = try!(self.r.read_be_u16());
-        self.num_components = try!(self.r.read_u8());
+    // Returns the next marker, which may already have been read by the entropy decoder
+    fn read_marker(&mut self) -> ImageResult<u8> {
+        let m = self.take_marker();
 
-        if self.height == 0 || self.width == 0 {
-            return Err(image::ImageError::DimensionError)
+        if m != 0 {
+            return Ok(m)
         }
 
-        if self.num_components != 1 && self.num_components != 3 {
-            return Err(image::ImageError::UnsupportedError(format!(
-                "Frames with {} components are not supported",
-                self.num_components
-            )))
-        }
+        loop {
+            if try!(self.r.read_u8()) != 0xFF {
+                continue
+            }
 
-        self.padded_width = 8 * ((self.width as usize + 7) / 8);
+            // Markers may be preceded by any number of fill bytes
+            let mut b = try!(self.r.read_u8());
+            while b == 0xFF {
+                b = try!(self.r.read_u8());
+            }
 
-        let num_components = self.num_components;
-        self.read_frame_components(num_components)
+            if b != 0 {
+                return Ok(b)
+            }
+        }
     }
 
-    fn read_frame_components(&mut self, n: u8) -> ImageResult<()> {
-        let mut blocks_per_mcu = 0;
+    // Section G.1.2
+    // Decodes the coefficients of a scan, baseline scans are decoded in one go
+    fn decode_coefficient_scan(&mut self) -> ImageResult<()> {
+        self.reset();
+        self.expected_rst = RST0;
 
-        for _ in (0..n) {
-            let id = try!(self.r.read_u8());
-            let hv = try!(self.r.read_u8());
-            let tq = try!(self.r.read_u8());
+        let ids = self.scan_components.clone();
 
-            let c = Component {
-                id: id,
-                h: hv >> 4,
-                v: hv & 0x0F,
-                tq: tq,
-                dc_table: 0,
-                ac_table: 0,
-                dc_pred: 0
-            };
+        // Like libjpeg, the quantization table of a component is latched by its first scan,
+        // tables that are redefined between scans only apply to components that follow
+        for &id in ids.iter() {
+            if !self.component_qtables.contains_key(&(id as usize)) {
+                let c = *self.components.get(&(id as usize)).unwrap();
+                let qtable = self.component_qtable(&c);
 
-            blocks_per_mcu += (hv >> 4) * (hv & 0x0F);
-            self.components.insert(id as usize, c);
+                self.component_qtables.insert(id as usize, qtable);
+            }
         }
 
-        let (hmax, vmax) = self.components.iter().fold((0, 0), | (h, v), (_, c) | {
-            (cmp::max(h, c.h), cmp::max(v, c.v))
-        });
+        // The units of non-interleaved scans are blocks, or samples of lossless images,
+        // the units of interleaved scans are MCUs
+        let total = if ids.len() == 1 {
+            let c = *self.components.get(&(ids[0] as usize)).unwrap();
+            let (w, h) = self.scan_blocks(&c);
 
-        self.hmax = hmax;
-        self.vmax = vmax;
+            w * h
+        } else {
+            let (mcus_per_row, mcus_per_column) = self.mcu_counts();
 
-        // only 1 component no interleaving
-        if n == 1 {
-        for (_, c) in self.components.iter_mut() {
-                c.h = 1;
-                c.v = 1;
-            }
+            mcus_per_row * mcus_per_column
+        };
 
-            blocks_per_mcu = 1;
-            self.hmax = 1;
-            self.vmax = 1;
-        }
+        let mut unit = 0;
 
-        self.mcu = repeat(0u8).take(blocks_per_mcu as usize * 64).collect::<Vec<u8>>();
+        while unit < total {
+            let result = match self.decode_coefficient_unit(&ids[], unit) {
+                Ok(()) => self.restart_if_due(unit + 1, total),
+                Err(err) => Err(err)
+            };
 
-        let mcus_per_row = (self.width as f32 / (8 * hmax) as f32).ceil() as usize;
-        let mcu_row_len = (hmax as usize * vmax as usize) * self.mcu.len() * mcus_per_row;
+            match result {
+                // The units of intervals whose restart markers were lost keep the coefficients of the previous scans
+                Ok(skipped) => unit += 1 + skipped,
+                Err(err) => {
+                    if !self.lenient {
+                        return Err(err)
+                    }
 
-        self.mcu_row = repeat(0u8).take(mcu_row_len).collect::<Vec<u8>>();
+                    // The skipped units keep the coefficients of the previous scans
+                    unit = self.resynchronize(unit, total, err);
+                }
+            }
+        }
 
         Ok(())
     }
 
-    fn read_scan_header(&mut self) -> ImageResult<()> {
-        let _scan_length = try!(self.r.read_be_u16());
+    // Non-interleaved scans only cover the blocks
+    // that intersect the component's own dimensions
+    fn scan_blocks(&self, c: &Component) -> (usize, usize) {
+        let width  = (self.width as usize * c.h as usize + self.hmax as usize - 1) / self.hmax as usize;
+        let height = (self.height as usize * c.v as usize + self.vmax as usize - 1) / self.vmax as usize;
+        let unit = self.unit_size();
 
-        let num_scan_components = try!(self.r.read_u8());
+        ((width + unit - 1) / unit, (height + unit - 1) / unit)
+    }
 
-        self.scan_components = Vec::new();
+    // Decodes the ```unit```th block of a non-interleaved scan or MCU of an interleaved scan
+    fn decode_coefficient_unit(&mut self, ids: &[u8], unit: usize) -> ImageResult<()> {
+        if ids.len() == 1 {
+            let c = *self.components.get(&(ids[0] as usize)).unwrap();
+            let (blocks_w, _) = self.component_blocks(&c);
+            let (w, _) = self.scan_blocks(&c);
 
-        for _ in (0..num_scan_components as usize) {
-            let id = try!(self.r.read_u8());
-            let tables = try!(self.r.read_u8());
+            return self.decode_coefficient_block(c.id, (unit / w) * blocks_w + unit % w)
+        }
 
-            let c = self.components.get_mut(&(id as usize)).unwrap();
+        let (mcus_per_row, _) = self.mcu_counts();
+        let (my, mx) = (unit / mcus_per_row, unit % mcus_per_row);
 
-            c.dc_table = tables >> 4;
-            c.ac_table = tables & 0x0F;
+        for &id in ids.iter() {
+            let c = *self.components.get(&(id as usize)).unwrap();
+            let (blocks_w, _) = self.component_blocks(&c);
 
-            self.scan_components.push(id);
+            for by in (0..c.v as usize) {
+                for bx in (0..c.h as usize) {
+                    let index = (my * c.v as usize + by) * blocks_w + mx * c.h as usize + bx;
+                    let _ = try!(self.decode_coefficient_block(id, index));
+                }
+            }
         }
 
-        let _spectral_end   = try!(self.r.read_u8());
-        let _spectral_start = try!(self.r.read_u8());
+        Ok(())
+    }
 
-        let approx = try!(self.r.read_u8());
+    fn decode_coefficient_block(&mut self, id: u8, index: usize) -> ImageResult<()> {
+        if self.lossless {
+            return self.decode_lossless_sample(id, index)
+        }
 
-        let _approx_high = approx >> 4;
-        let _approx_low  = approx & 0x0F;
+        if self.arithmetic {
+            return self.decode_arithmetic_block(id, index)
+        }
 
-        Ok(())
-    }
+        let c = self.components.get_mut(&(id as usize)).unwrap();
+        let coefficients = &mut self.coefficients.get_mut(&(id as usize)).unwrap()[index * 64..index * 64 + 64];
+
+        if !self.progressive {
+            c.dc_pred = try!(decode_sequential_block(&mut self.h,
+                                                     &mut self.r,
+                                                     &self.dctables[c.dc_table as usize],
+                                                     &self.actables[c.ac_table as usize],
+                                                     c.dc_pred,
+                                                     coefficients));
+            return Ok(())
+        }
 
-    fn read_quantization_tables(&mut self) -> ImageResult<()> {
-        let mut table_length = try!(self.r.read_be_u16()) as i32;
-        table_length -= 2;
+        let (start, end) = (self.spectral_start as usize, self.spectral_end as usize);
+        let low = self.approx_low as usize;
 
-        while table_length > 0 {
-            let pqtq = try!(self.r.read_u8());
-            let pq = pqtq >> 4;
-            let tq = pqtq & 0x0F;
+        if start == 0 {
+            if self.approx_high == 0 {
+                // Section G.1.2.1
+                let t = try!(self.h.decode_symbol(&mut self.r, &self.dctables[c.dc_table as usize]));
 
-            if pq != 0 || tq > 3 {
-                return Err(image::ImageError::FormatError("Quantization table malformed.".to_string()))
+                let diff = if t > 0 {
+                    extend(try!(self.h.receive(&mut self.r, t)), t)
+                } else {
+                    0
+                };
+
+                c.dc_pred += diff;
+                coefficients[0] = c.dc_pred << low;
+            } else if try!(self.h.read_bit(&mut self.r)) == 1 {
+                coefficients[0] |= 1 << low;
             }
 
-            let slice = &mut self.qtables[64 * tq as usize..64 * tq as usize + 64];
+            return Ok(())
+        }
 
-            for i in (0us..64) {
-                slice[i] = try!(self.r.read_u8());
+        let actable = &self.actables[c.ac_table as usize];
+
+        if self.approx_high == 0 {
+            // Section G.1.2.2
+            if self.eobrun > 0 {
+                self.eobrun -= 1;
+                return Ok(())
             }
 
-            table_length -= 1 + 64;
-        }
+            let mut k = start;
+            while k <= end {
+                let rs = try!(self.h.decode_symbol(&mut self.r, actable));
 
-        Ok(())
-    }
+                let ssss = rs & 0x0F;
+                let rrrr = rs >> 4;
 
-    fn read_huffman_tables(&mut self) -> ImageResult<()> {
-        let mut table_length = try!(self.r.read_be_u16());
-        table_length -= 2;
+                if ssss == 0 {
+                    if rrrr < 15 {
+                        // The remaining blocks of the band are empty
+                        self.eobrun = (1 << rrrr as usize) - 1;
 
-        while table_length > 0 {
-            let tcth = try!(self.r.read_u8());
-            let tc = tcth >> 4;
-            let th = tcth & 0x0F;
+                        if rrrr > 0 {
+                            self.eobrun += try!(self.h.receive(&mut self.r, rrrr)) as u16;
+                        }
 
-            if tc != 0 && tc != 1 {
-                return Err(image::ImageError::UnsupportedError(format!(
-                    "Huffman table class {} is not supported", tc
-                )))
-            }
+                        break
+                    }
 
-            let bits = try!(self.r.read_exact(16));
-            let len = bits.len();
+                    k += 16;
+                } else {
+                    k += rrrr as usize;
 
-            let mt = bits.iter().fold(0, | a, b | a + *b);
-            let huffval = try!(self.r.read_exact(mt as usize));
+                    if k > 63 {
+                        return Err(image::ImageError::FormatError("Coefficient index out of range.".to_string()))
+                    }
 
-            if tc == 0 {
-                self.dctables[th as usize] = derive_tables(bits, huffval);
-            } else {
-                self.actables[th as usize] = derive_tables(bits, huffval);
+                    let t = try!(self.h.receive(&mut self.r, ssss));
+                    coefficients[k] = extend(t, ssss) << low;
+                    k += 1;
+                }
             }
 
-            table_length -= 1 + len as u16 + mt as u16;
+            return Ok(())
         }
 
-        Ok(())
-    }
+        // Section G.1.2.3
+        let p1 =  1 << low;
+        let m1 = -1 << low;
 
+        let mut k = start;
 
-    fn read_restart_interval(&mut self) -> ImageResult<()> {
-        let _length = try!(self.r.read_be_u16());
-        self.interval = try!(self.r.read_be_u16());
+        if self.eobrun == 0 {
+            while k <= end {
+                let rs = try!(self.h.decode_symbol(&mut self.r, actable));
 
-        Ok(())
-    }
+                let ssss = rs & 0x0F;
+                let mut rrrr = (rs >> 4) as i32;
+                let mut value = 0;
 
-    fn read_restart(&mut self) -> ImageResult<()> {
-        let w = (self.width + 7) / (self.hmax * 8) as u16;
-        let h = (self.height + 7) / (self.vmax * 8) as u16;
+                if ssss == 0 {
+                    if rrrr < 15 {
+                        self.eobrun = 1 << rrrr as usize;
 
-        if self.interval != 0  &&
-           self.mcucount % self.interval == 0 &&
-           self.mcucount < w * h {
+                        if rrrr > 0 {
+                            self.eobrun += try!(self.h.receive(&mut self.r, rrrr as u8)) as u16;
+                        }
 
-            let rst = try!(self.find_restart_marker());
+                        break
+                    }
+                } else {
+                    // Newly nonzero coefficients always have a magnitude of one
+                    value = if try!(self.h.read_bit(&mut self.r)) == 1 { p1 } else { m1 };
+                }
 
-            if rst == self.expected_rst {
-                self.reset();
-                self.expected_rst += 1;
+                // Skip rrrr zero coefficients, refining the nonzero ones on the way
+                while k <= end {
+                    if coefficients[k] != 0 {
+                        let _ = try!(refine_coefficient(&mut self.h, &mut self.r, &mut coefficients[k], p1, m1));
+                    } else {
+                        rrrr -= 1;
+
+                        if rrrr < 0 {
+                            break
+                        }
+                    }
 
-                if self.expected_rst > RST7 {
-                    self.expected_rst = RST0;
+                    k += 1;
                 }
-            } else {
-                return Err(image::ImageError::FormatError(format!(
-                    "Unexpected restart maker {} found", rst
-                )))
+
+                if value != 0 && k < 64 {
+                    coefficients[k] = value;
+                }
+
+                k += 1;
+            }
+        }
+
+        if self.eobrun > 0 {
+            // Only the nonzero coefficients of blocks in an EOB run are refined
+            while k <= end {
+                if coefficients[k] != 0 {
+                    let _ = try!(refine_coefficient(&mut self.h, &mut self.r, &mut coefficients[k], p1, m1));
+                }
+
+                k += 1;
             }
+
+            self.eobrun -= 1;
         }
 
         Ok(())
     }
 
-    fn find_restart_marker(&mut self) -> ImageResult<u8> {
-        if self.h.marker != 0 {
-            let m = self.h.marker;
-            self.h.marker = 0;
+    // Sections F.2.4 and G.2
+    // Decodes the coefficients of a block of a sequential or progressive arithmetic coded image
+    fn decode_arithmetic_block(&mut self, id: u8, index: usize) -> ImageResult<()> {
+        let c = self.components.get_mut(&(id as usize)).unwrap();
+        let coefficients = &mut self.coefficients.get_mut(&(id as usize)).unwrap()[index * 64..index * 64 + 64];
 
-            return Ok(m);
-        }
+        let (start, end, low) = if self.progressive {
+            (self.spectral_start as usize, self.spectral_end as usize, self.approx_low as usize)
+        } else {
+            (0, 63, 0)
+        };
 
-        let mut b;
-        loop {
-            b = try!(self.r.read_u8());
+        let refine = self.progressive && self.approx_high != 0;
 
-            if b == 0xFF {
-            b = try!(self.r.read_u8());
-                match b {
-                    RST0 ... RST7 => break,
-                    EOI => return Err(image::ImageError::FormatError("Restart marker not found.".to_string())),
-                    _   => continue
+        if start == 0 {
+            if refine {
+                if try!(self.arith.decode_dc_refinement(&mut self.r)) {
+                    coefficients[0] |= 1 << low;
                 }
+            } else {
+                c.dc_pred += try!(self.arith.decode_dc_diff(&mut self.r, c.dc_table as usize, id as usize));
+                coefficients[0] = c.dc_pred << low;
             }
-        }
 
-        Ok(b)
-    }
+            // Progressive scans either code the DC or AC coefficients
+            if self.progressive {
+                return Ok(())
+            }
+        }
 
-    fn reset(&mut self) {
-        self.h.bits = 0;
-        self.h.num_bits = 0;
-        self.h.end = false;
-        self.h.marker = 0;
+        let start = cmp::max(start, 1);
 
-        for (_, c) in self.components.iter_mut() {
-            c.dc_pred = 0;
+        if refine {
+            self.arith.refine_ac(&mut self.r, c.ac_table as usize, start, end, low, coefficients)
+        } else {
+            self.arith.decode_ac(&mut self.r, c.ac_table as usize, start, end, low, coefficients)
         }
     }
-}
 
-impl<R: Reader> ImageDecoder for JPEGDecoder<R> {
+    // Section H.1.2
+    // Decodes the ```index```th sample of a component of a lossless image
+    fn decode_lossless_sample(&mut self, id: u8, index: usize) -> ImageResult<()> {
+        let c = *self.components.get(&(id as usize)).unwrap();
+        let (w, _) = self.component_blocks(&c);
+        let (x, y) = (index % w, index / w);
+        let pt = self.approx_low as usize;
+
+        let t = try!(self.h.decode_symbol(&mut self.r, &self.dctables[c.dc_table as usize]));
+
+        // Section H.1.2.2
+        // A difference of 32768 is not followed by any additional bits
+        let diff = match t {
+            0 => 0,
+            1 ... 15 => extend(try!(self.h.receive(&mut self.r, t)), t),
+            16 => 32768,
+            _ => return Err(image::ImageError::FormatError("Invalid lossless difference category.".to_string()))
+        };
+
+        let plane = self.planes.get_mut(&(id as usize)).unwrap();
+
+        // The first sample of a scan or restart interval is predicted from the middle of the range
+        let prediction = match self.first_lines.get(&(id as usize)) {
+            Some(&line) => predict(&plane[], w, x, y, line, self.spectral_start, pt),
+            None => 1 << (self.precision as usize - pt - 1)
+        };
+
+        if !self.first_lines.contains_key(&(id as usize)) {
+            self.first_lines.insert(id as usize, y);
+        }
+
+        // The reconstruction is calculated modulo 2^16
+        plane[y * w + x] = (((prediction + diff) & 0xFFFF) << pt) as u16;
+
+        Ok(())
+    }
+
+    // Converts the accumulated coefficients of all components to pixels
+    fn reconstruct_image(&mut self) {
+        let bytesperpixel = self.num_components as usize;
+        let (width, height) = self.output_dimensions();
+        let row_len = width * bytesperpixel;
+        let size = self.idct_size;
+        let mcu_height = size * self.vmax as usize;
+        let (mcus_per_row, mcus_per_column) = self.mcu_counts();
+        let sampling = self.sampling_factors();
+
+        self.image = repeat(0u8).take(row_len * height).collect::<Vec<u8>>();
+
+        for my in (0..mcus_per_column) {
+            for mx in (0..mcus_per_row) {
+                let mut i = 0;
+
+                for &id in self.frame_components.iter() {
+                    let c = *self.components.get(&(id as usize)).unwrap();
+                    let (blocks_w, _) = self.component_blocks(&c);
+
+                    let coefficients = self.coefficients.get(&(id as usize)).unwrap();
+                    let qtable = self.component_qtable(&c);
+
+                    for by in (0..c.v as usize) {
+                        for bx in (0..c.h as usize) {
+                            let index = (my * c.v as usize + by) * blocks_w + mx * c.h as usize + bx;
+                            let block = &coefficients[index * 64..index * 64 + 64];
+
+                            let mut tmp = [0i32; 64];
+                            for k in (0us..64) {
+                                tmp[UNZIGZAG[k] as usize] = block[k] * qtable[k] as i32;
+                            }
+
+                            transform::idct_scaled(&tmp, &mut self.mcu[i * size * size..(i + 1) * size * size], size);
+                            i += 1;
+                        }
+                    }
+                }
+
+                upsample_mcu (
+                    &mut self.mcu_row[],
+                    mx * size * self.hmax as usize * bytesperpixel,
+                    self.padded_width,
+                    bytesperpixel,
+                    &self.mcu[],
+                    &sampling[],
+                    self.hmax,
+                    self.vmax,
+                    size
+                );
+            }
+
+            for y in (0..mcu_height) {
+                let row = my * mcu_height + y;
+
+                if row >= height {
+                    break
+                }
+
+                let start = y * self.padded_width * bytesperpixel;
+                slice::bytes::copy_memory(
+                    &mut self.image[row * row_len..row * row_len + row_len],
+                    &self.mcu_row[start..start + row_len]
+                );
+            }
+        }
+    }
+
+    // Converts the accumulated coefficients of images with more than 8 bits per sample
+    // to a plane of samples per component
+    fn reconstruct_planes(&mut self) {
+        let ids = self.frame_components.clone();
+
+        for &id in ids.iter() {
+            let c = *self.components.get(&(id as usize)).unwrap();
+            let (blocks_w, blocks_h) = self.component_blocks(&c);
+            let plane_w = blocks_w * 8;
+
+            let qtable = self.component_qtable(&c);
+            let coefficients = self.coefficients.get(&(id as usize)).unwrap();
+            let mut plane = repeat(0u16).take(plane_w * blocks_h * 8).collect::<Vec<u16>>();
+
+            for (index, block) in coefficients.chunks(64).enumerate() {
+                let mut tmp = [0i32; 64];
+                for k in (0us..64) {
+                    tmp[UNZIGZAG[k] as usize] = block[k] * qtable[k] as i32;
+                }
+
+                let mut samples = [0u16; 64];
+                transform::idct_precise(&tmp, &mut samples, self.precision);
+
+                let (x0, y0) = (index % blocks_w * 8, index / blocks_w * 8);
+
+                for y in (0us..8) {
+                    for x in (0us..8) {
+                        plane[(y0 + y) * plane_w + x0 + x] = samples[y * 8 + x];
+                    }
+                }
+            }
+
+            self.planes.insert(id as usize, plane);
+        }
+    }
+
+    // Interleaves the samples of the component planes in the order of the frame components.
+    // Components with a lower sampling factor than ```hmax``` and ```vmax``` are replicated.
+    fn interleave_planes(&self) -> Vec<u16> {
+        let n = self.num_components as usize;
+        let (width, height) = (self.width as usize, self.height as usize);
+        let (hmax, vmax) = (self.hmax as usize, self.vmax as usize);
+        let unit = self.unit_size();
+
+        let mut samples = repeat(0u16).take(width * height * n).collect::<Vec<u16>>();
+
+        for (i, &id) in self.frame_components.iter().enumerate() {
+            let c = *self.components.get(&(id as usize)).unwrap();
+            let (blocks_w, _) = self.component_blocks(&c);
+            let (h, v) = (c.h as usize, c.v as usize);
+
+            let plane = self.planes.get(&(id as usize)).unwrap();
+            let plane_w = blocks_w * unit;
+
+            for y in (0..height) {
+                for x in (0..width) {
+                    samples[(y * width + x) * n + i] = plane[(y * v / vmax) * plane_w + x * h / hmax];
+                }
+            }
+        }
+
+        samples
+    }
+
+    // Decodes the whole image for the modes that can not be decoded one MCU row at a time
+    fn decode_image(&mut self) -> ImageResult<()> {
+        if (self.lossless || self.precision > 8) && self.idct_size != 8 {
+            return Err(image::ImageError::UnsupportedError(
+                "Lossless images and images with more than 8 bits per sample can not be scaled".to_string()
+            ))
+        }
+
+        let _ = try!(self.decode_coefficients());
+
+        if !self.lossless && self.precision == 8 {
+            self.reconstruct_image();
+            return Ok(())
+        }
+
+        if !self.lossless {
+            self.reconstruct_planes();
+        }
+
+        let samples = self.interleave_planes();
+
+        if self.precision > 8 {
+            self.image16 = samples;
+        } else {
+            self.image = samples.iter().map(|&s| s as u8).collect();
+        }
+
+        Ok(())
+    }
+
+    fn read_metadata(&mut self) -> ImageResult<()> {
+        while self.state != JPEGState::HaveFirstScan {
+            let byte = try!(self.r.read_u8());
+
+            if byte != 0xFF {
+                continue;
+            }
+
+            let marker = try!(self.r.read_u8());
+
+            match marker {
+                SOI => self.state = JPEGState::HaveSOI,
+                DHT => try!(self.read_huffman_tables()),
+                DQT => try!(self.read_quantization_tables()),
+                SOF0 | SOF1 => {
+                    let _ = try!(self.read_frame_header(marker));
+                    self.state = JPEGState::HaveFirstFrame;
+                }
+                SOF2 => {
+                    self.progressive = true;
+
+                    let _ = try!(self.read_frame_header(marker));
+                    self.state = JPEGState::HaveFirstFrame;
+                }
+                SOF3 => {
+                    self.lossless = true;
+
+                    let _ = try!(self.read_frame_header(marker));
+                    self.state = JPEGState::HaveFirstFrame;
+                }
+                SOF9 | SOF10 => {
+                    self.arithmetic = true;
+                    self.progressive = marker == SOF10;
+
+                    let _ = try!(self.read_frame_header(marker));
+                    self.state = JPEGState::HaveFirstFrame;
+                }
+                DAC => try!(self.read_arithmetic_conditioning()),
+                SOS => {
+                    let _ = try!(self.read_scan_header());
+                    self.state = JPEGState::HaveFirstScan;
+                }
+                DRI => try!(self.read_restart_interval()),
+                APP0 ... APPF | COM => try!(self.read_application_segment(marker)),
+                TEM  => continue,
+                DNL  => return Err(image::ImageError::UnsupportedError("Marker DNL ist not supported.".to_string())),
+                marker => return Err(image::ImageError::FormatError(format!("Unkown marker {} encountered.", marker))),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_frame_header(&mut self, marker: u8) -> ImageResult<()> {
+        let _frame_length = try!(self.r.read_be_u16());
+        let sample_precision = try!(self.r.read_u8());
+
+        // Baseline images have 8 bits per sample, the other DCT modes 8 or 12
+        let supported = match marker {
+            SOF0 => sample_precision == 8,
+            SOF3 => sample_precision >= 2 && sample_precision <= 16,
+            _ => sample_precision == 8 || sample_precision == 12
+        };
+
+        if !supported {
+            return Err(image::ImageError::UnsupportedError(format!(
+                "A sample precision of {} is not supported",
+                sample_precision
+            )))
+        }
+
+        self.height 	    = try!(self.r.read_be_u16());
+        self.width  	    = try!(self.r.read_be_u16());
+        self.num_components = try!(self.r.read_u8());
+
+        if self.height == 0 || self.width == 0 {
+            return Err(image::ImageError::DimensionError)
+        }
+
+        if self.num_components != 1 && self.num_components != 3 && self.num_components != 4 {
+            return Err(image::ImageError::UnsupportedError(format!(
+                "Frames with {} components are not supported",
+                self.num_components
+            )))
+        }
+
+        if self.num_components == 4 && sample_precision != 8 {
+            return Err(image::ImageError::UnsupportedError(format!(
+                "Four component images with a sample precision of {} are not supported",
+                sample_precision
+            )))
+        }
+
+        self.precision = sample_precision;
+
+        let num_components = self.num_components;
+        self.read_frame_components(num_components)
+    }
+
+    fn read_frame_components(&mut self, n: u8) -> ImageResult<()> {
+        for _ in (0..n) {
+            let id = try!(self.r.read_u8());
+            let hv = try!(self.r.read_u8());
+            let tq = try!(self.r.read_u8());
+
+            let c = Component {
+                id: id,
+                h: hv >> 4,
+                v: hv & 0x0F,
+                tq: tq,
+                dc_table: 0,
+                ac_table: 0,
+                dc_pred: 0
+            };
+
+            self.frame_components.push(id);
+            self.components.insert(id as usize, c);
+        }
+
+        let (hmax, vmax) = self.components.iter().fold((0, 0), | (h, v), (_, c) | {
+            (cmp::max(h, c.h), cmp::max(v, c.v))
+        });
+
+        self.hmax = hmax;
+        self.vmax = vmax;
+
+        // only 1 component no interleaving
+        if n == 1 {
+        for (_, c) in self.components.iter_mut() {
+                c.h = 1;
+                c.v = 1;
+            }
+
+            self.hmax = 1;
+            self.vmax = 1;
+        }
+
+        // Lossless images are not decoded in blocks
+        if !self.lossless {
+            self.allocate_buffers();
+        }
+
+        Ok(())
+    }
+
+    // Allocates the buffers of the samples of an MCU and an MCU row,
+    // which depend on the size of the inverse DCT
+    fn allocate_buffers(&mut self) {
+        let size = self.idct_size;
+        let blocks_per_mcu = self.sampling_factors().iter().fold(0, |n, &(h, v)| n + h as usize * v as usize);
+
+        self.mcu = repeat(0u8).take(blocks_per_mcu * size * size).collect::<Vec<u8>>();
+
+        let (mcus_per_row, _) = self.mcu_counts();
+
+        self.padded_width = size * self.hmax as usize * mcus_per_row;
+
+        let mcu_row_len = self.padded_width * self.num_components as usize * size * self.vmax as usize;
+        self.mcu_row = repeat(0u8).take(mcu_row_len).collect::<Vec<u8>>();
+    }
+
+    // The dimensions of the decoded image, which is scaled by ```idct_size``` / 8
+    fn output_dimensions(&self) -> (usize, usize) {
+        let size = self.idct_size;
+
+        ((self.width as usize * size + 7) / 8, (self.height as usize * size + 7) / 8)
+    }
+
+    // The width and height of a data unit, which is a block
+    // of 8 by 8 samples or a single sample of lossless images
+    fn unit_size(&self) -> usize {
+        if self.lossless { 1 } else { 8 }
+    }
+
+    // The number of MCUs per row and per column
+    fn mcu_counts(&self) -> (usize, usize) {
+        let mcu_width  = self.unit_size() * self.hmax as usize;
+        let mcu_height = self.unit_size() * self.vmax as usize;
+
+        ((self.width as usize + mcu_width - 1) / mcu_width,
+         (self.height as usize + mcu_height - 1) / mcu_height)
+    }
+
+    // The sampling factors of the components in the order of the frame header
+    fn sampling_factors(&self) -> Vec<(u8, u8)> {
+        self.frame_components.iter().map(|id| {
+            let c = self.components.get(&(*id as usize)).unwrap();
+            (c.h, c.v)
+        }).collect()
+    }
+
+    // The quantization table of a component in zigzag order,
+    // the current table if the component has not been scanned yet
+    fn component_qtable(&self, c: &Component) -> [u16; 64] {
+        match self.component_qtables.get(&(c.id as usize)) {
+            Some(qtable) => *qtable,
+            None => {
+                let mut qtable = [0u16; 64];
+
+                for k in (0us..64) {
+                    qtable[k] = self.qtables[64 * c.tq as usize + k];
+                }
+
+                qtable
+            }
+        }
+    }
+
+    // The number of data units per row and per column of a component,
+    // including the units that pad the image to whole MCUs
+    fn component_blocks(&self, c: &Component) -> (usize, usize) {
+        let (mcus_per_row, mcus_per_column) = self.mcu_counts();
+
+        (mcus_per_row * c.h as usize, mcus_per_column * c.v as usize)
+    }
+
+    fn read_scan_header(&mut self) -> ImageResult<()> {
+        let _scan_length = try!(self.r.read_be_u16());
+
+        let num_scan_components = try!(self.r.read_u8());
+
+        self.scan_components = Vec::new();
+
+        for _ in (0..num_scan_components as usize) {
+            let id = try!(self.r.read_u8());
+            let tables = try!(self.r.read_u8());
+
+            if tables >> 4 > 3 || tables & 0x0F > 3 {
+                return Err(image::ImageError::FormatError("Invalid Huffman table selector.".to_string()))
+            }
+
+            let c = match self.components.get_mut(&(id as usize)) {
+                Some(c) => c,
+                None => return Err(image::ImageError::FormatError(format!(
+                    "The scan names component {} which is not in the frame.", id
+                )))
+            };
+
+            c.dc_table = tables >> 4;
+            c.ac_table = tables & 0x0F;
+
+            self.scan_components.push(id);
+        }
+
+        self.spectral_start = try!(self.r.read_u8());
+        self.spectral_end   = try!(self.r.read_u8());
+
+        let approx = try!(self.r.read_u8());
+
+        self.approx_high = approx >> 4;
+        self.approx_low  = approx & 0x0F;
+
+        if self.progressive && (self.spectral_end > 63 ||
+                                self.spectral_start > self.spectral_end ||
+                                (self.spectral_start == 0 && self.spectral_end != 0) ||
+                                (self.spectral_start > 0 && num_scan_components != 1)) {
+            return Err(image::ImageError::FormatError("Invalid progressive scan parameters.".to_string()))
+        }
+
+        // The start of the spectral selection selects the predictor of lossless scans,
+        // the successive approximation bit position the point transform
+        if self.lossless && (self.spectral_start == 0 ||
+                             self.spectral_start > 7 ||
+                             self.approx_low >= self.precision) {
+            return Err(image::ImageError::FormatError("Invalid lossless scan parameters.".to_string()))
+        }
+
+        Ok(())
+    }
+
+    fn read_quantization_tables(&mut self) -> ImageResult<()> {
+        let mut table_length = try!(self.r.read_be_u16()) as i32;
+        table_length -= 2;
+
+        while table_length > 0 {
+            let pqtq = try!(self.r.read_u8());
+            let pq = pqtq >> 4;
+            let tq = pqtq & 0x0F;
+
+            if pq > 1 || tq > 3 {
+                return Err(image::ImageError::FormatError("Quantization table malformed.".to_string()))
+            }
+
+            let slice = &mut self.qtables[64 * tq as usize..64 * tq as usize + 64];
+
+            // The entries of tables with a precision of 1 are 16 bits wide
+            for i in (0us..64) {
+                slice[i] = if pq == 0 {
+                    try!(self.r.read_u8()) as u16
+                } else {
+                    try!(self.r.read_be_u16())
+                };
+            }
+
+            table_length -= 1 + 64 * (pq as i32 + 1);
+        }
+
+        Ok(())
+    }
+
+    fn read_huffman_tables(&mut self) -> ImageResult<()> {
+        let mut table_length = try!(self.r.read_be_u16());
+        table_length -= 2;
+
+        while table_length > 0 {
+            let tcth = try!(self.r.read_u8());
+            let tc = tcth >> 4;
+            let th = tcth & 0x0F;
+
+            if tc != 0 && tc != 1 {
+                return Err(image::ImageError::UnsupportedError(format!(
+                    "Huffman table class {} is not supported", tc
+                )))
+            }
+
+            if th > 3 {
+                return Err(image::ImageError::FormatError("Huffman table malformed.".to_string()))
+            }
+
+            let bits = try!(self.r.read_exact(16));
+            let len = bits.len();
+
+            let mt = bits.iter().fold(0, | a, b | a + *b);
+            let huffval = try!(self.r.read_exact(mt as usize));
+
+            if tc == 0 {
+                self.dctables[th as usize] = derive_tables(bits, huffval);
+            } else {
+                self.actables[th as usize] = derive_tables(bits, huffval);
+            }
+
+            table_length -= 1 + len as u16 + mt as u16;
+        }
+
+        Ok(())
+    }
+
+
+    // Application and comment segments are kept to be written again by lossless transformations
+    fn read_application_segment(&mut self, marker: u8) -> ImageResult<()> {
+        let data = try!(self.read_segment_data());
+
+        match marker {
+            APP1 => self.read_exif_segment(&data[]),
+            APP14 => self.read_adobe_segment(&data[]),
+            _ => ()
+        }
+
+        self.markers.push((marker, data));
+
+        Ok(())
+    }
+
+    // The data of a segment, the length includes its own two bytes
+    fn read_segment_data(&mut self) -> ImageResult<Vec<u8>> {
+        let length = try!(self.r.read_be_u16());
+
+        if length < 2 {
+            return Err(image::ImageError::FormatError(format!("Invalid segment length {}.", length)))
+        }
+
+        Ok(try!(self.r.read_exact((length - 2) as usize)))
+    }
+
+    // The Adobe segment tells how the components of the image are encoded
+    fn read_adobe_segment(&mut self, data: &[u8]) {
+        // The identifier is followed by the version, two flag words and the transform
+        if data.len() >= 12 && &data[..5] == b"Adobe" {
+            self.adobe_transform = Some(data[11]);
+        }
+    }
+
+    // EXIF metadata is a TIFF structure following the identifier.
+    // Malformed metadata is ignored as it is not needed to decode the image.
+    fn read_exif_segment(&mut self, data: &[u8]) {
+        if self.exif.is_none() && data.len() > 6 && &data[..6] == b"Exif\0\0" {
+            self.exif = Exif::from_bytes(&data[6..]).ok();
+        }
+    }
+
+    // Section B.2.4.3
+    // The conditioning of the arithmetic decoder
+    fn read_arithmetic_conditioning(&mut self) -> ImageResult<()> {
+        let mut table_length = try!(self.r.read_be_u16()) as i32;
+        table_length -= 2;
+
+        while table_length > 0 {
+            let tctb = try!(self.r.read_u8());
+            let cs = try!(self.r.read_u8());
+
+            let tc = tctb >> 4;
+            let tb = tctb & 0x0F;
+
+            if tc > 1 || tb > 3 {
+                return Err(image::ImageError::FormatError("Arithmetic conditioning table malformed.".to_string()))
+            }
+
+            if tc == 0 {
+                // The lower bound is stored in the low nibble
+                let (lower, upper) = (cs & 0x0F, cs >> 4);
+
+                if lower > upper {
+                    return Err(image::ImageError::FormatError("Invalid DC conditioning bounds.".to_string()))
+                }
+
+                self.arith.dc_bounds[tb as usize] = (lower, upper);
+            } else {
+                if cs == 0 || cs > 63 {
+                    return Err(image::ImageError::FormatError("Invalid AC conditioning.".to_string()))
+                }
+
+                self.arith.ac_k[tb as usize] = cs;
+            }
+
+            table_length -= 2;
+        }
+
+        Ok(())
+    }
+
+    fn read_restart_interval(&mut self) -> ImageResult<()> {
+        let _length = try!(self.r.read_be_u16());
+        self.interval = try!(self.r.read_be_u16());
+
+        Ok(())
+    }
+
+    // Expects a restart marker after every ```interval``` of the
+    // ```total``` units of a scan, ```count``` units have been decoded so far.
+    // In lenient mode an unexpected marker means that whole intervals were lost,
+    // the number of units that follow and were lost is returned.
+    fn restart_if_due(&mut self, count: usize, total: usize) -> ImageResult<usize> {
+        if self.interval == 0 || count % self.interval as usize != 0 || count >= total {
+            return Ok(0)
+        }
+
+        let rst = try!(self.find_restart_marker());
+
+        if rst == self.expected_rst {
+            self.reset();
+            self.expected_rst = if rst == RST7 { RST0 } else { rst + 1 };
+
+            return Ok(0)
+        }
+
+        let err = image::ImageError::FormatError(format!("Unexpected restart marker {} found", rst));
+
+        if !self.lenient {
+            return Err(err)
+        }
+
+        // The units before the marker were decoded
+        let next = self.restart_after(rst, count - 1, total);
+
+        self.warnings.push(format!(
+            "{} after unit {} of a scan, {} units were skipped", err, count - 1, next - count
+        ));
+
+        Ok(next - count)
+    }
+
+    fn find_restart_marker(&mut self) -> ImageResult<u8> {
+        let m = self.take_marker();
+
+        if m != 0 {
+            return Ok(m);
+        }
+
+        let mut b;
+        loop {
+            b = try!(self.r.read_u8());
+
+            if b == 0xFF {
+            b = try!(self.r.read_u8());
+                match b {
+                    RST0 ... RST7 => break,
+                    EOI => return Err(image::ImageError::FormatError("Restart marker not found.".to_string())),
+                    _   => continue
+                }
+            }
+        }
+
+        Ok(b)
+    }
+
+    // Copies the next row of samples in the order of the frame components,
+    // without any color conversion, to ```buf```
+    fn read_raw_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
+        if self.decoded_rows as usize >= self.output_dimensions().1 {
+            return Err(image::ImageError::ImageEnd)
+        }
+
+        if self.progressive || self.lossless || self.arithmetic {
+            if self.image.is_empty() {
+                let _ = try!(self.decode_image());
+            }
+
+            let len   = self.output_dimensions().0 * self.num_components as usize;
+            let start = self.decoded_rows as usize * len;
+
+            slice::bytes::copy_memory(buf, &self.image[start..start + buf.len()]);
+            self.decoded_rows += 1;
+
+            return Ok(self.decoded_rows)
+        }
+
+        if self.row_count == 0 {
+            let _ = try!(self.decode_mcu_row());
+        }
+
+        let len   = self.padded_width * self.num_components as usize;
+        let slice = &self.mcu_row[self.row_count as usize * len..
+        self.row_count as usize * len + buf.len()];
+
+        slice::bytes::copy_memory(buf, slice);
+
+        self.row_count = (self.row_count + 1) % (self.vmax * self.idct_size as u8);
+        self.decoded_rows += 1;
+
+        Ok(self.decoded_rows)
+    }
+
+    // Copies the next row of samples of an image with more than 8 bits per sample to ```buf```
+    fn read_raw_scanline16(&mut self, buf: &mut [u16]) -> ImageResult<u32> {
+        if self.decoded_rows as usize >= self.output_dimensions().1 {
+            return Err(image::ImageError::ImageEnd)
+        }
+
+        if self.image16.is_empty() {
+            let _ = try!(self.decode_image());
+        }
+
+        let len = buf.len();
+        let start = self.decoded_rows as usize * len;
+
+        for (sample, &s) in buf.iter_mut().zip(self.image16[start..start + len].iter()) {
+            *sample = s;
+        }
+
+        self.decoded_rows += 1;
+
+        Ok(self.decoded_rows)
+    }
+
+    // Whether a three component image stores RGB instead of YCbCr samples.
+    // Adobe marks RGB images with a transform of 0, others name the components R, G and B.
+    fn is_rgb(&self) -> bool {
+        self.adobe_transform == Some(0) || self.frame_components == vec![b'R', b'G', b'B']
+    }
+
+    // Converts a row of samples to grayscale or RGB
+    fn convert_row(&self, samples: &[u8], buf: &mut [u8]) {
+        match self.num_components {
+            3 => {
+                let rgb = self.is_rgb();
+
+                for (p, q) in samples.chunks(3).zip(buf.chunks_mut(3)) {
+                    let (r, g, b) = if rgb {
+                        (p[0], p[1], p[2])
+                    } else {
+                        ycbcr_to_rgb(p[0], p[1], p[2])
+                    };
+
+                    q[0] = r;
+                    q[1] = g;
+                    q[2] = b;
+                }
+            }
+
+            4 => {
+                for (p, q) in samples.chunks(4).zip(buf.chunks_mut(3)) {
+                    let (c, m, y, k) = self.to_cmyk(p);
+                    let (r, g, b) = cmyk_to_rgb(c, m, y, k);
+
+                    q[0] = r;
+                    q[1] = g;
+                    q[2] = b;
+                }
+            }
+
+            _ => slice::bytes::copy_memory(buf, samples)
+        }
+    }
+
+    // Converts a row of samples with more or fewer than 8 bits to grayscale or RGB.
+    // The converted samples are scaled to 16 bits, or to 8 bits if they have fewer.
+    fn convert_row16(&self, samples: &[u16], buf: &mut [u16]) {
+        let max = (1u32 << self.precision as usize) - 1;
+        let target = if self.precision > 8 { 0xFFFF } else { 0xFF };
+
+        if self.num_components == 3 && !self.is_rgb() {
+            for (p, q) in samples.chunks(3).zip(buf.chunks_mut(3)) {
+                let (r, g, b) = ycbcr_to_rgb16(p[0], p[1], p[2], self.precision);
+
+                q[0] = scale_sample(r, max, target);
+                q[1] = scale_sample(g, max, target);
+                q[2] = scale_sample(b, max, target);
+            }
+        } else {
+            for (q, &p) in buf.iter_mut().zip(samples.iter()) {
+                *q = scale_sample(p, max, target);
+            }
+        }
+    }
+
+    // Converts the samples of a four component image to CMYK, where 0 means no ink
+    fn to_cmyk(&self, p: &[u8]) -> (u8, u8, u8, u8) {
+        let (c, m, y) = if self.adobe_transform == Some(2) {
+            // YCCK
+            let (r, g, b) = ycbcr_to_rgb(p[0], p[1], p[2]);
+            (255 - r, 255 - g, 255 - b)
+        } else {
+            (p[0], p[1], p[2])
+        };
+
+        // Adobe applications store inverted CMYK samples
+        if self.adobe_transform.is_some() {
+            (255 - c, 255 - m, 255 - y, 255 - p[3])
+        } else {
+            (c, m, y, p[3])
+        }
+    }
+
+    /// Decodes the image at 1/```denominator``` of its size with reduced inverse DCTs,
+    /// which is much faster than decoding the full image and resizing it.
+    /// ```denominator``` has to be 1, 2, 4 or 8, the scaled dimensions are rounded up.
+    /// The scale can only be changed before the first scanline is read.
+    pub fn set_scale(&mut self, denominator: u8) -> ImageResult<()> {
+        if denominator != 1 && denominator != 2 && denominator != 4 && denominator != 8 {
+            return Err(image::ImageError::UnsupportedError(format!(
+                "A scale of 1/{} is not supported", denominator
+            )))
+        }
+
+        if self.decoded_rows > 0 || self.state == JPEGState::End {
+            return Err(image::ImageError::UnsupportedError(
+                "The scale can not be changed after decoding has started".to_string()
+            ))
+        }
+
+        self.idct_size = 8 / denominator as usize;
+
+        if self.state != JPEGState::Start && !self.lossless {
+            self.allocate_buffers();
+        }
+
+        Ok(())
+    }
+
+    /// In lenient mode corrupt entropy coded data does not fail decoding.
+    /// The decoder resynchronizes at the next restart marker and fills the damaged MCUs
+    /// with gray, damaged parts of progressive images keep the coefficients of the previous scans.
+    /// Every resynchronization is recorded in the ```warnings```.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// The warnings about corrupt data that was skipped in lenient mode
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings[]
+    }
+
+    /// Returns the EXIF metadata of the image, if it contains an APP1 segment with valid EXIF data.
+    pub fn exif(&mut self) -> ImageResult<Option<&Exif>> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(self.exif.as_ref())
+    }
+
+    /// Returns the ICC profile of the image, reassembled from its ```ICC_PROFILE``` APP2 segments.
+    /// Profiles with missing or inconsistent segments are ignored.
+    pub fn icc_profile(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        // The identifier is followed by the sequence number of the chunk and the number of chunks
+        let mut chunks = self.markers.iter().filter(|&&(marker, ref data)| {
+            marker == APP2 && data.len() >= 14 && &data[..12] == b"ICC_PROFILE\0"
+        }).map(|&(_, ref data)| {
+            (data[12], data[13], &data[14..])
+        }).collect::<Vec<(u8, u8, &[u8])>>();
+
+        chunks.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let count = chunks.len();
+        let complete = count > 0 && chunks.iter().enumerate().all(|(i, &(seq, n, _))| {
+            seq as usize == i + 1 && n as usize == count
+        });
+
+        if !complete {
+            return Ok(None)
+        }
+
+        let mut profile = Vec::new();
+
+        for &(_, _, data) in chunks.iter() {
+            profile.push_all(data);
+        }
+
+        Ok(Some(profile))
+    }
+
+    /// Decodes the quantized DCT coefficients of the image without transforming them to samples.
+    /// This has to be done before any scanline is read.
+    pub fn read_coefficients(&mut self) -> ImageResult<Coefficients> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        if self.state == JPEGState::End || self.decoded_rows > 0 {
+            return Err(image::ImageError::ImageEnd)
+        }
+
+        if self.lossless || self.precision != 8 || self.qtables.iter().any(|&q| q > 255) {
+            return Err(image::ImageError::UnsupportedError(
+                "Only the coefficients of 8 bit DCT images with 8 bit quantization tables can be read".to_string()
+            ))
+        }
+
+        let _ = try!(self.decode_coefficients());
+
+        let mut qtables = [[0u8; 64]; 4];
+
+        for (i, table) in qtables.iter_mut().enumerate() {
+            for k in (0us..64) {
+                table[UNZIGZAG[k] as usize] = self.qtables[64 * i + k] as u8;
+            }
+        }
+
+        let mut components = Vec::new();
+
+        for &id in self.frame_components.iter() {
+            let c = *self.components.get(&(id as usize)).unwrap();
+
+            // The table the component was quantized with may have been redefined by a later scan
+            let qtable = self.component_qtable(&c);
+
+            if qtable.iter().any(|&q| q > 255) {
+                return Err(image::ImageError::UnsupportedError(
+                    "Only the coefficients of 8 bit DCT images with 8 bit quantization tables can be read".to_string()
+                ))
+            }
+
+            for k in (0us..64) {
+                qtables[c.tq as usize][UNZIGZAG[k] as usize] = qtable[k] as u8;
+            }
+            let (blocks_w, blocks_h) = self.component_blocks(&c);
+
+            let blocks = self.coefficients.get(&(id as usize)).unwrap().chunks(64).map(|zz| {
+                let mut block = [0i32; 64];
+
+                for k in (0us..64) {
+                    block[UNZIGZAG[k] as usize] = zz[k];
+                }
+
+                block
+            }).collect::<Vec<[i32; 64]>>();
+
+            components.push(ComponentCoefficients {
+                component: c,
+                blocks_w: blocks_w,
+                blocks_h: blocks_h,
+                blocks: blocks
+            });
+        }
+
+        Ok(Coefficients {
+            width: self.width,
+            height: self.height,
+            progressive: self.progressive,
+            restart_interval: self.interval,
+            qtables: qtables,
+            components: components,
+            markers: self.markers.clone()
+        })
+    }
+
+    /// Decodes a four component image and returns its
+    /// C, M, Y and K samples, where 0 means no ink
+    pub fn read_cmyk_image(&mut self) -> ImageResult<Vec<u8>> {
+        if self.state == JPEGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        if self.num_components != 4 || self.precision > 8 {
+            return Err(image::ImageError::UnsupportedError(format!(
+                "An image with {} components has no 8 bit CMYK samples",
+                self.num_components
+            )))
+        }
+
+        let (width, height) = self.output_dimensions();
+
+        let row = width * 4;
+        let mut buf = repeat(0u8).take(row * height).collect::<Vec<u8>>();
+
+        for chunk in buf[].chunks_mut(row) {
+            let _len = try!(self.read_raw_scanline(chunk));
+
+            for p in chunk.chunks_mut(4) {
+                let (c, m, y, k) = self.to_cmyk(p);
+
+                p[0] = c;
+                p[1] = m;
+                p[2] = y;
+                p[3] = k;
+            }
+        }
+
+        Ok(buf)
+    }
+
+    fn reset(&mut self) {
+        self.h.bits = 0;
+        self.h.num_bits = 0;
+        self.h.end = false;
+        self.h.marker = 0;
+        self.eobrun = 0;
+        self.first_lines.clear();
+        self.arith.reset();
+
+        for (_, c) in self.components.iter_mut() {
+            c.dc_pred = 0;
+        }
+    }
+}
+
+impl<R: Reader> ImageDecoder for JPEGDecoder<R> {
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         if self.state == JPEGState::Start {
             let _ = try!(self.read_metadata());
         }
 
-        Ok((self.width as u32, self.height as u32))
+        let (width, height) = self.output_dimensions();
+
+        Ok((width as u32, height as u32))
     }
 
     fn colortype(&mut self) -> ImageResult<color::ColorType> {
@@ -553,10 +1849,13 @@ impl<R: Reader> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
+        // Samples with more than 8 bits are returned with 16 bits
+        let bits = if self.precision > 8 { 16 } else { 8 };
+
         let ctype = if self.num_components == 1 {
-            color::ColorType::Gray(8)
+            color::ColorType::Gray(bits)
         } else {
-            color::ColorType::RGB(8)
+            color::ColorType::RGB(bits)
         };
 
         Ok(ctype)
@@ -567,7 +1866,11 @@ impl<R: Reader> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
-        let len = self.width as usize * self.num_components as usize;
+        // Four component images are converted to RGB,
+        // samples with more than 8 bits take two bytes
+        let channels = if self.num_components == 1 { 1 } else { 3 };
+        let bytes = if self.precision > 8 { 2 } else { 1 };
+        let len = self.output_dimensions().0 * channels * bytes;
 
         Ok(len)
     }
@@ -577,20 +1880,41 @@ impl<R: Reader> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
-        if self.row_count == 0 {
-            let _ = try!(self.decode_mcu_row());
+        let len = self.output_dimensions().0 * self.num_components as usize;
+
+        if self.precision > 8 {
+            let mut samples = repeat(0u16).take(len).collect::<Vec<u16>>();
+            let mut converted = samples.clone();
+
+            let row = try!(self.read_raw_scanline16(&mut samples[]));
+            self.convert_row16(&samples[], &mut converted[]);
+
+            // The samples are written in big endian byte order
+            for (&s, b) in converted.iter().zip(buf.chunks_mut(2)) {
+                b[0] = (s >> 8) as u8;
+                b[1] = s as u8;
+            }
+
+            return Ok(row)
         }
 
-        let len   = self.padded_width * self.num_components as usize;
-        let slice = &self.mcu_row[self.row_count as usize * len..
-        self.row_count as usize * len + buf.len()];
+        let mut samples = repeat(0u8).take(len).collect::<Vec<u8>>();
+        let row = try!(self.read_raw_scanline(&mut samples[]));
 
-        slice::bytes::copy_memory(buf, slice);
+        if self.precision < 8 {
+            // Lossless samples with fewer than 8 bits are converted before they are scaled
+            let samples = samples.iter().map(|&s| s as u16).collect::<Vec<u16>>();
+            let mut converted = samples.clone();
+            self.convert_row16(&samples[], &mut converted[]);
 
-        self.row_count = (self.row_count + 1) % (self.vmax * 8);
-        self.decoded_rows += 1;
+            for (b, &s) in buf.iter_mut().zip(converted.iter()) {
+                *b = s as u8;
+            }
+        } else {
+            self.convert_row(&samples[], buf);
+        }
 
-        Ok(self.decoded_rows)
+        Ok(row)
     }
 
     fn read_image(&mut self) -> ImageResult<image::DecodingResult> {
@@ -598,8 +1922,25 @@ impl<R: Reader> ImageDecoder for JPEGDecoder<R> {
             let _ = try!(self.read_metadata());
         }
 
+        let (width, height) = self.output_dimensions();
+
+        if self.precision > 8 {
+            let channels = if self.num_components == 1 { 1 } else { 3 };
+            let row = width * channels;
+
+            let mut buf = repeat(0u16).take(row * height).collect::<Vec<u16>>();
+            let mut samples = repeat(0u16).take(width * self.num_components as usize).collect::<Vec<u16>>();
+
+            for chunk in buf[].chunks_mut(row) {
+                let _len = try!(self.read_raw_scanline16(&mut samples[]));
+                self.convert_row16(&samples[], chunk);
+            }
+
+            return Ok(image::DecodingResult::U16(buf))
+        }
+
         let row = try!(self.row_len());
-        let mut buf = repeat(0u8).take(row * self.height as usize).collect::<Vec<u8>>();
+        let mut buf = repeat(0u8).take(row * height).collect::<Vec<u8>>();
 
         for chunk in buf[].chunks_mut(row) {
             let _len = try!(self.read_scanline(chunk));
@@ -609,43 +1950,30 @@ impl<R: Reader> ImageDecoder for JPEGDecoder<R> {
     }
 }
 
-fn upsample_mcu(out: &mut [u8], xoffset: usize, width: usize, bpp: usize, mcu: &[u8], h: u8, v: u8) {
-    if mcu.len() == 64 {
-        for y in (0us..8) {
-            for x in (0us..8) {
-                out[xoffset + x + (y * width)] = mcu[x + y * 8]
+// Writes the samples of an MCU to ```out```, with the samples of each pixel interleaved.
+// Components with a lower sampling factor than ```hmax``` and ```vmax``` are replicated.
+// The blocks of the MCU consist of ```size``` by ```size``` samples.
+fn upsample_mcu(out: &mut [u8], xoffset: usize, width: usize, bpp: usize, mcu: &[u8],
+                sampling: &[(u8, u8)], hmax: u8, vmax: u8, size: usize) {
+    let (hmax, vmax) = (hmax as usize, vmax as usize);
+    let mut first_block = 0;
+
+    for (i, &(h, v)) in sampling.iter().enumerate() {
+        let (h, v) = (h as usize, v as usize);
+        let n = size * size;
+        let blocks = &mcu[first_block * n..(first_block + h * v) * n];
+
+        for y in (0..size * vmax) {
+            for x in (0..size * hmax) {
+                let cx = x * h / hmax;
+                let cy = y * v / vmax;
+
+                let sample = blocks[((cy / size) * h + cx / size) * n + (cy % size) * size + cx % size];
+                out[y * width * bpp + xoffset + x * bpp + i] = sample;
             }
         }
-    } else {
-        let y_blocks = h * v;
-
-        let y_blocks = &mcu[..y_blocks as usize * 64];
-        let cb = &mcu[y_blocks.len()..y_blocks.len() + 64];
-        let cr = &mcu[y_blocks.len() + cb.len()..];
-
-        let mut k = 0;
-
-        for by in (0..v as usize) {
-            let y0 = by * 8;
-
-            for bx in (0..h as usize) {
-                let x0 = xoffset + bx * 8 * bpp;
-
-                for y in (0us..8) {
-                    for x in (0us..8) {
-                        let (a, b, c) = (y_blocks[k * 64 + x + y * 8], cb[x + y * 8], cr[x + y * 8]);
-                        let (r, g, b) = ycbcr_to_rgb(a , b , c );
-
-                        let offset = (y0 + y) * (width * bpp) + x0 + x * bpp;
-                        out[offset + 0] = r;
-                        out[offset + 1] = g;
-                        out[offset + 2] = b;
-                    }
-                }
 
-                k += 1;
-            }
-        }
+        first_block += h * v;
     }
 }
 
@@ -665,8 +1993,135 @@ fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
     (r, g, b)
 }
 
+fn ycbcr_to_rgb16(y: u16, cb: u16, cr: u16, precision: u8) -> (u16, u16, u16) {
+    let center = (1i32 << (precision - 1) as usize) as f32;
+    let max = (1i32 << precision as usize) - 1;
+
+    let y = y as f32;
+    let cr = cr as f32;
+    let cb = cb as f32;
+
+    let r1 = y + 1.402f32 * (cr - center);
+    let g1 = y - 0.34414f32 * (cb - center) - 0.71414f32 * (cr - center);
+    let b1 = y + 1.772f32 * (cb - center);
+
+    let r = clamp(r1 as i32, 0, max) as u16;
+    let g = clamp(g1 as i32, 0, max) as u16;
+    let b = clamp(b1 as i32, 0, max) as u16;
+
+    (r, g, b)
+}
+
+// Scales the sample ```s``` from the range [0, ```max```] to [0, ```target```]
+fn scale_sample(s: u16, max: u32, target: u32) -> u16 {
+    ((s as u32 * target + max / 2) / max) as u16
+}
+
+fn cmyk_to_rgb(c: u8, m: u8, y: u8, k: u8) -> (u8, u8, u8) {
+    let k = 255 - k as u32;
+
+    let r = (255 - c as u32) * k / 255;
+    let g = (255 - m as u32) * k / 255;
+    let b = (255 - y as u32) * k / 255;
+
+    (r as u8, g as u8, b as u8)
+}
+
+// Section H.1.2.1
+// Predicts the sample at ```x```, ```y``` of a lossless image from its reconstructed neighbours
+// a (left), b (above) and c (above left) with the ```predictor``` of the scan
+fn predict(plane: &[u16], w: usize, x: usize, y: usize, first_line: usize, predictor: u8, pt: usize) -> i32 {
+    let sample = |x: usize, y: usize| (plane[y * w + x] >> pt) as i32;
+
+    // The first sample of a line is predicted from the sample above it
+    if x == 0 {
+        return if y > first_line { sample(x, y - 1) } else { sample(x, y) }
+    }
+
+    let ra = sample(x - 1, y);
+
+    // The first line of a scan or restart interval is predicted from the left
+    if y <= first_line {
+        return ra
+    }
+
+    let rb = sample(x, y - 1);
+    let rc = sample(x - 1, y - 1);
+
+    match predictor {
+        1 => ra,
+        2 => rb,
+        3 => rc,
+        4 => ra + rb - rc,
+        5 => ra + ((rb - rc) >> 1),
+        6 => rb + ((ra - rc) >> 1),
+        _ => (ra + rb) >> 1
+    }
+}
+
+// Section G.1.2.3
+// Refines a nonzero coefficient by one bit of precision
+fn refine_coefficient<R: Reader>(h: &mut HuffDecoder, r: &mut R, coefficient: &mut i32, p1: i32, m1: i32) -> ImageResult<()> {
+    if try!(h.read_bit(r)) == 1 && *coefficient & p1 == 0 {
+        *coefficient += if *coefficient >= 0 { p1 } else { m1 };
+    }
+
+    Ok(())
+}
+
 // Section F.2.2.1
 // Figure F.12
+// Section F.2.2
+// Decodes the coefficients of a sequential block in zigzag order and returns the DC coefficient
+fn decode_sequential_block<R: Reader>(h: &mut HuffDecoder,
+                                      r: &mut R,
+                                      dctable: &HuffTable,
+                                      actable: &HuffTable,
+                                      pred: i32,
+                                      coefficients: &mut [i32]) -> ImageResult<i32> {
+    let t = try!(h.decode_symbol(r, dctable));
+
+    // Section F.2.1.3.1
+    let diff = if t > 0 {
+        extend(try!(h.receive(r, t)), t)
+    } else {
+        0
+    };
+
+    let dc = diff + pred;
+    coefficients[0] = dc;
+
+    let mut k = 0us;
+    while k < 63 {
+        let rs = try!(h.decode_symbol(r, actable));
+
+        let ssss = rs & 0x0F;
+        let rrrr = rs >> 4;
+
+        if ssss == 0 {
+            if rrrr != 15 {
+                break
+            }
+
+            k += 16;
+        } else {
+            k += rrrr as usize;
+
+            if k >= 63 {
+                return Err(image::ImageError::FormatError("Coefficient index out of range.".to_string()))
+            }
+
+            // Figure F.14
+            let t = try!(h.receive(r, ssss));
+
+            coefficients[k + 1] = extend(t, ssss);
+            k += 1;
+        }
+    }
+
+    Ok(dc)
+}
+
 fn extend(v: i32, t: u8) -> i32 {
 let vt:
     i32 = 1 << t as usize - 1;
@@ -678,3 +2133,371 @@ let vt:
         v
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::num::SignedInt;
+    use std::old_io::{File, MemReader, MemWriter};
+
+    use std::iter::repeat;
+
+    use color;
+    use image;
+    use dynimage::load_from_memory;
+    use image::{ImageDecoder, DecodingResult};
+    use super::{JPEGDecoder, Coefficients, RST0, RST7};
+    use super::super::encoder::JPEGEncoder;
+
+    // A lossless 2x2 grayscale image with the first predictor
+    fn lossless_image(precision: u8) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+
+        // SOF3
+        data.push_all(&[0xFF, 0xC3, 0, 11, precision, 0, 2, 0, 2, 1, 1, 0x11, 0]);
+        // The differences are of the categories 0 to 3
+        data.push_all(&[0xFF, 0xC4, 0, 23, 0x00, 0, 3, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]);
+        // SOS
+        data.push_all(&[0xFF, 0xDA, 0, 8, 1, 1, 0x00, 1, 0, 0]);
+        // The differences 0, 2, -1 and 4
+        data.push_all(&[0x29, 0x69]);
+        data.push_all(&[0xFF, 0xD9]);
+
+        data
+    }
+
+    #[test]
+    fn test_lossless() {
+        let mut decoder = JPEGDecoder::new(MemReader::new(lossless_image(8)));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::Gray(8));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(samples) => assert_eq!(samples, vec![128, 130, 127, 131]),
+            _ => panic!("expected 8 bit samples")
+        }
+
+        // The samples 2048, 2050, 2047 and 2051 are scaled to 16 bits
+        let mut decoder = JPEGDecoder::new(MemReader::new(lossless_image(12)));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::Gray(16));
+        assert_eq!(decoder.row_len().unwrap(), 4);
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(samples) => assert_eq!(samples, vec![32776, 32808, 32759, 32824]),
+            _ => panic!("expected 16 bit samples")
+        }
+
+        // The samples 8, 10, 7 and 11 are scaled to 8 bits
+        let mut decoder = JPEGDecoder::new(MemReader::new(lossless_image(4)));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::Gray(8));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(samples) => assert_eq!(samples, vec![136, 170, 119, 187]),
+            _ => panic!("expected 8 bit samples")
+        }
+
+        // Rows of 16 bit samples are read one by one until the end of the image
+        let mut decoder = JPEGDecoder::new(MemReader::new(lossless_image(12)));
+        let mut row = [0u8; 4];
+
+        for &(y, expected) in [(1, [128, 8, 128, 40]), (2, [127, 247, 128, 56])].iter() {
+            assert_eq!(decoder.read_scanline(&mut row).unwrap(), y);
+            assert_eq!(row, expected);
+        }
+
+        match decoder.read_scanline(&mut row) {
+            Err(image::ImageError::ImageEnd) => (),
+            _ => panic!("expected ImageEnd")
+        }
+
+        // Images with 16 bits per sample are loaded with 8 bits
+        let image = load_from_memory(&lossless_image(16)[]).unwrap();
+        assert_eq!(image.to_luma().into_raw(), vec![128, 128, 127, 128]);
+    }
+
+    fn read_coefficients(name: &str) -> Coefficients {
+        let path = Path::new(".").join_many(&["src", "jpeg", "testdata", name]);
+
+        JPEGDecoder::new(File::open(&path)).read_coefficients().unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic_coding() {
+        // The images of each pair only differ in their entropy coding
+        let pairs = [("gray-arithmetic-progressive.jpg", "gray-huffman.jpg"),
+                     ("rgb-arithmetic-restart.jpg", "rgb-huffman-restart.jpg")];
+
+        for &(arithmetic, huffman) in pairs.iter() {
+            let a = read_coefficients(arithmetic);
+            let b = read_coefficients(huffman);
+
+            assert_eq!(a.components.len(), b.components.len());
+
+            for (x, y) in a.components.iter().zip(b.components.iter()) {
+                assert_eq!(x.blocks.len(), y.blocks.len());
+
+                for (p, q) in x.blocks.iter().zip(y.blocks.iter()) {
+                    assert_eq!(&p[], &q[]);
+                }
+            }
+        }
+    }
+
+    fn read_pixels(name: &str) -> Vec<u8> {
+        let path = Path::new(".").join_many(&["src", "jpeg", "testdata", name]);
+
+        match JPEGDecoder::new(File::open(&path)).read_image().unwrap() {
+            DecodingResult::U8(pixels) => pixels,
+            _ => panic!("expected 8 bit samples")
+        }
+    }
+
+    #[test]
+    fn test_progressive() {
+        // The progressive image has the coefficients of the baseline image.
+        // The chroma quantization table is only defined before the first chroma scan,
+        // and the luma table is redefined after the first luma scan, which must be ignored.
+        let baseline = read_pixels("rgb-baseline.jpg");
+        assert_eq!(baseline.len(), 35 * 27 * 3);
+        assert!(read_pixels("rgb-progressive-dqt.jpg") == baseline);
+
+        let a = read_coefficients("rgb-baseline.jpg");
+        let b = read_coefficients("rgb-progressive-dqt.jpg");
+        assert!(b.progressive);
+
+        for (p, q) in a.qtables.iter().zip(b.qtables.iter()) {
+            assert_eq!(&p[], &q[]);
+        }
+    }
+
+    #[test]
+    fn test_adobe_cmyk() {
+        // Both images consist of four flat 16x16 blocks of these inks, stored inverted
+        // with an Adobe segment. The YCCK image is converted through YCbCr, which rounds.
+        let inks = [[0, 0, 0, 0], [255, 0, 0, 0], [0, 128, 255, 0], [20, 40, 60, 100]];
+        let rgb = [[255, 255, 255], [0, 255, 255], [255, 127, 0], [142, 130, 118]];
+
+        for &(name, tolerance) in [("cmyk-adobe.jpg", 0), ("ycck-adobe.jpg", 1)].iter() {
+            let path = Path::new(".").join_many(&["src", "jpeg", "testdata", name]);
+
+            let mut decoder = JPEGDecoder::new(File::open(&path));
+            assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGB(8));
+            let cmyk = decoder.read_cmyk_image().unwrap();
+
+            let mut decoder = JPEGDecoder::new(File::open(&path));
+            let pixels = match decoder.read_image().unwrap() {
+                DecodingResult::U8(pixels) => pixels,
+                _ => panic!("expected 8 bit samples")
+            };
+
+            // The centers of the blocks are not affected by the upsampling of the YCCK chroma
+            for i in (0us..4) {
+                let x = i * 16 + 8;
+
+                for k in (0us..4) {
+                    let d = cmyk[4 * (8 * 64 + x) + k] as i32 - inks[i][k];
+                    assert!(d.abs() <= tolerance, "{} ink {} of block {}", name, k, i);
+                }
+
+                for k in (0us..3) {
+                    let d = pixels[3 * (8 * 64 + x) + k] as i32 - rgb[i][k];
+                    assert!(d.abs() <= 2 * tolerance, "{} channel {} of block {}", name, k, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        // The profile does not fit into a single segment
+        let profile = (0..100000us).map(|i| (i % 253) as u8).collect::<Vec<u8>>();
+
+        let mut encoded = MemWriter::new();
+        {
+            let mut encoder = JPEGEncoder::new(&mut encoded);
+            encoder.set_icc_profile(profile.clone());
+            encoder.encode(&[128; 64], 8, 8, color::ColorType::Gray(8)).unwrap();
+        }
+
+        let mut decoder = JPEGDecoder::new(MemReader::new(encoded.into_inner()));
+        assert_eq!(decoder.icc_profile().unwrap(), Some(profile));
+    }
+
+    #[test]
+    fn test_invalid_segment_length() {
+        let path = Path::new(".").join_many(&["src", "jpeg", "testdata", "exif-orientation.jpg"]);
+        let data = File::open(&path).read_to_end().unwrap();
+
+        // The length of the EXIF segment follows the start of image and the APP1 marker
+        for &length in [0u8, 1].iter() {
+            let mut data = data.clone();
+            data[4] = 0;
+            data[5] = length;
+
+            assert!(JPEGDecoder::new(MemReader::new(data)).read_image().is_err());
+        }
+    }
+
+    fn decode_scaled(data: Vec<u8>, denominator: u8) -> (u32, u32, Vec<u8>) {
+        let mut decoder = JPEGDecoder::new(MemReader::new(data));
+        decoder.set_scale(denominator).unwrap();
+        let (width, height) = decoder.dimensions().unwrap();
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => (width, height, pixels),
+            _ => panic!("expected 8 bit samples")
+        }
+    }
+
+    #[test]
+    fn test_scale() {
+        // The scaled dimensions of the 35x27 images are rounded up
+        for &name in ["rgb-baseline.jpg", "rgb-progressive-dqt.jpg"].iter() {
+            let path = Path::new(".").join_many(&["src", "jpeg", "testdata", name]);
+            let data = File::open(&path).read_to_end().unwrap();
+
+            for &(denominator, width, height) in [(1, 35, 27), (2, 18, 14), (4, 9, 7), (8, 5, 4)].iter() {
+                let (w, h, pixels) = decode_scaled(data.clone(), denominator);
+                assert_eq!((w, h), (width, height));
+                assert_eq!(pixels.len(), (3 * width * height) as usize);
+            }
+        }
+
+        // Gray 8x8 blocks, flat or with a horizontal gradient, at a quality that does not quantize
+        let (width, height) = (32, 16);
+        let mut image = Vec::new();
+
+        for y in (0..height) {
+            for x in (0..width) {
+                let block = y / 8 * 4 + x / 8;
+                let gradient = if block % 3 == 1 { 12 * (x % 8) } else { 0 };
+                image.push((20 + 25 * block + gradient) as u8);
+            }
+        }
+
+        let mut m = MemWriter::new();
+        JPEGEncoder::new_with_quality(&mut m, 100).encode(&image[], width as u32, height as u32, color::ColorType::Gray(8)).unwrap();
+        let data = m.into_inner();
+
+        let (_, _, full) = decode_scaled(data.clone(), 1);
+
+        for &denominator in [2, 4, 8].iter() {
+            let size = 8 / denominator as usize;
+            let (w, h, pixels) = decode_scaled(data.clone(), denominator);
+            assert_eq!((w as usize, h as usize), (width / denominator as usize, height / denominator as usize));
+
+            for y in (0..h as usize) {
+                for x in (0..w as usize) {
+                    let block = y / size * 4 + x / size;
+                    let p = pixels[y * w as usize + x] as i32;
+
+                    if block % 3 != 1 {
+                        assert_eq!(p, (20 + 25 * block) as i32);
+                    } else if denominator == 8 {
+                        // Only the DC coefficient is used, the average of the full block
+                        let (bx, by) = (8 * x, 8 * y);
+                        let sum = (0..64).fold(0, |s, i| s + full[(by + i / 8) * width + bx + i % 8] as i32);
+                        assert!((p - (sum + 32) / 64).abs() <= 1);
+                    }
+                }
+            }
+        }
+
+        let mut decoder = JPEGDecoder::new(MemReader::new(data));
+        assert!(decoder.set_scale(3).is_err());
+        let _ = decoder.read_image().unwrap();
+        assert!(decoder.set_scale(2).is_err());
+    }
+
+    fn decode_lenient(data: Vec<u8>) -> (Vec<u8>, usize) {
+        let mut decoder = JPEGDecoder::new(MemReader::new(data));
+        decoder.set_lenient(true);
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => (pixels, decoder.warnings().len()),
+            _ => panic!("expected 8 bit samples")
+        }
+    }
+
+    #[test]
+    fn test_lenient() {
+        // A gray image of 8 by 2 MCUs with a restart marker after every second MCU
+        let (width, height) = (64, 16);
+        let image = (0..width * height).map(|i| (i % width * 3 + i / width * 5) as u8).collect::<Vec<u8>>();
+
+        let mut m = MemWriter::new();
+        {
+            let mut encoder = JPEGEncoder::new(&mut m);
+            encoder.set_restart_interval(2);
+            encoder.encode(&image[], width as u32, height as u32, color::ColorType::Gray(8)).unwrap();
+        }
+        let data = m.into_inner();
+
+        let (expected, warnings) = decode_lenient(data.clone());
+        assert_eq!(warnings, 0);
+
+        // The positions of the restart markers RST0 to RST6
+        let markers = (0..data.len() - 1).filter(|&i| {
+            data[i] == 0xFF && data[i + 1] >= RST0 && data[i + 1] <= RST7
+        }).collect::<Vec<usize>>();
+        assert_eq!(markers.len(), 7);
+
+        // The data of the fourth interval is replaced by invalid Huffman codes,
+        // or lost together with the restart marker that precedes it
+        let mut garbled = data[..markers[2] + 2].to_vec();
+        garbled.push_all(&[0xFF, 0x00, 0xFF, 0x00]);
+        garbled.push_all(&data[markers[3]..]);
+
+        let mut lost = data[..markers[2]].to_vec();
+        lost.push_all(&data[markers[3]..]);
+
+        for corrupt in [garbled, lost].iter() {
+            assert!(JPEGDecoder::new(MemReader::new(corrupt.clone())).read_image().is_err());
+
+            let (pixels, warnings) = decode_lenient(corrupt.clone());
+            assert_eq!(warnings, 1);
+
+            for (i, (&p, &q)) in pixels.iter().zip(expected.iter()).enumerate() {
+                let mcu = i / width / 8 * 8 + i % width / 8;
+
+                if mcu / 2 == 3 {
+                    assert_eq!(p, 128);
+                } else {
+                    assert_eq!(p, q);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_past_end() {
+        // A baseline image decoded row by row and a progressive image decoded as a whole
+        for name in ["gray-huffman.jpg", "rgb-progressive-dqt.jpg"].iter() {
+            let path = Path::new(".").join_many(&["src", "jpeg", "testdata", *name]);
+            let mut decoder = JPEGDecoder::new(File::open(&path));
+
+            let (_, height) = decoder.dimensions().unwrap();
+            let mut row = repeat(0u8).take(decoder.row_len().unwrap()).collect::<Vec<u8>>();
+
+            for y in (0..height) {
+                assert_eq!(decoder.read_scanline(&mut row[]).unwrap(), y + 1);
+            }
+
+            match decoder.read_scanline(&mut row[]) {
+                Err(image::ImageError::ImageEnd) => (),
+                _ => panic!("expected ImageEnd")
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_scan_component() {
+        // The scan names component 2 of a frame with only component 1
+        let mut data = lossless_image(8);
+        let sos = (0..data.len() - 1).find(|&i| data[i] == 0xFF && data[i + 1] == 0xDA).unwrap();
+        data[sos + 5] = 2;
+
+        match JPEGDecoder::new(MemReader::new(data)).read_image() {
+            Err(image::ImageError::FormatError(_)) => (),
+            _ => panic!("expected a FormatError")
+        }
+    }
+}
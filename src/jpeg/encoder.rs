@@ -1,3 +1,5 @@
+use std::cmp;
+use std::mem;
 use std::old_io::MemWriter;
 use std::old_io;
 use std::old_io::IoResult;
@@ -7,37 +9,53 @@ use std::num::{ Float, SignedInt };
 use color;
 
 use super::transform;
-use super::decoder::Component;
+use super::decoder::{Component, Coefficients};
 use super::decoder::UNZIGZAG;
 use super::entropy::build_huff_lut;
 
 // Markers
 // Baseline DCT
 static SOF0: u8 = 0xC0;
+// Progressive DCT
+static SOF2: u8 = 0xC2;
 // Huffman Tables
 static DHT: u8 = 0xC4;
 // Start of Image (standalone)
 static SOI: u8 = 0xD8;
+// Restart Interval start (standalone)
+static RST0: u8 = 0xD0;
 // End of image (standalone)
 static EOI: u8 = 0xD9;
 // Start of Scan
 static SOS: u8 = 0xDA;
 // Quantization Tables
 static DQT: u8 = 0xDB;
+// Define Restart Interval
+static DRI: u8 = 0xDD;
 // Application segments start and end
 static APP0: u8 = 0xE0;
 
+// Application segment for the ICC profile
+static APP2: u8 = 0xE2;
+
+// The identifier of the APP2 segments holding an ICC profile
+static ICC_SIGNATURE: &'static [u8] = b"ICC_PROFILE\0";
+
+// The largest chunk of an ICC profile that fits into a segment
+// next to the length field, the identifier and the sequence numbers
+static MAX_ICC_CHUNK: usize = 65535 - 2 - 14;
+
 // section K.1
 // table K.1
 static STD_LUMA_QTABLE: [u8; 64] = [
-    16, 11, 10, 16, 124, 140, 151, 161,
-    12, 12, 14, 19, 126, 158, 160, 155,
-    14, 13, 16, 24, 140, 157, 169, 156,
-    14, 17, 22, 29, 151, 187, 180, 162,
-    18, 22, 37, 56, 168, 109, 103, 177,
-    24, 35, 55, 64, 181, 104, 113, 192,
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
     49, 64, 78, 87, 103, 121, 120, 101,
-    72, 92, 95, 98, 112, 100, 103, 199
+    72, 92, 95, 98, 112, 100, 103,  99
  ];
 
 // table K.2
@@ -119,17 +137,123 @@ static ACCLASS: u8 = 1;
 
 static LUMADESTINATION: u8 = 0;
 static CHROMADESTINATION: u8 = 1;
+static CHROMAREDDESTINATION: u8 = 2;
+
+// The longest end of band run
+static MAX_EOBRUN: u16 = 0x7FFF;
+// The most correction bits of refinement scans that are buffered during an end of band run
+static MAX_CORRECTION_BITS: usize = 1000 - 64 + 1;
 
 static LUMAID: u8 = 1;
 static CHROMABLUEID: u8 = 2;
 static CHROMAREDID: u8 = 3;
 
+/// The quality used by ```JPEGEncoder::new```.
+/// It quantizes with the unscaled tables of the JPEG specification.
+pub const DEFAULT_QUALITY: u8 = 50;
+
+/// The resolution of the chroma components relative to the luma component
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Full chroma resolution
+    Ratio444,
+
+    /// Half the horizontal chroma resolution
+    Ratio422,
+
+    /// Half the horizontal and vertical chroma resolution
+    Ratio420,
+}
+
+/// A scan of a progressive JPEG image
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scan {
+    /// The indices of the components of the scan, 0 is Y, 1 is Cb and 2 is Cr.
+    /// Only scans of the DC coefficients may have more than one component.
+    pub components: Vec<usize>,
+
+    /// The index of the first coefficient of the spectral band in zigzag order
+    pub spectral_start: u8,
+
+    /// The index of the last coefficient of the spectral band in zigzag order
+    pub spectral_end: u8,
+
+    /// The point transform of the previous scan of the band, 0 for its first scan
+    pub approx_high: u8,
+
+    /// The point transform, the number of low bits that are not encoded by this scan
+    pub approx_low: u8,
+}
+
+/// The scan script of the progressive mode of the IJG libjpeg
+/// for images with ```num_components``` components
+pub fn default_scan_script(num_components: usize) -> Vec<Scan> {
+    fn scan(components: &[usize], spectral_start: u8, spectral_end: u8, approx_high: u8, approx_low: u8) -> Scan {
+        Scan {
+            components: components.to_vec(),
+            spectral_start: spectral_start,
+            spectral_end: spectral_end,
+            approx_high: approx_high,
+            approx_low: approx_low,
+        }
+    }
+
+    if num_components == 3 {
+        vec![
+            scan(&[0, 1, 2], 0, 0, 0, 1),
+            scan(&[0], 1, 5, 0, 2),
+            scan(&[2], 1, 63, 0, 1),
+            scan(&[1], 1, 63, 0, 1),
+            scan(&[0], 6, 63, 0, 2),
+            scan(&[0], 1, 63, 2, 1),
+            scan(&[0, 1, 2], 0, 0, 1, 0),
+            scan(&[2], 1, 63, 1, 0),
+            scan(&[1], 1, 63, 1, 0),
+            scan(&[0], 1, 63, 1, 0),
+        ]
+    } else {
+        let all = (0..num_components).collect::<Vec<usize>>();
+        let mut script = vec![scan(&all[], 0, 0, 0, 1)];
+
+        // The AC coefficients of each component are sent in separate scans
+        for &(start, end, high, low) in [(1, 5, 0, 2), (6, 63, 0, 2), (1, 63, 2, 1)].iter() {
+            for i in (0..num_components) {
+                script.push(scan(&[i], start, end, high, low));
+            }
+        }
+
+        script.push(scan(&all[], 0, 0, 1, 0));
+
+        for i in (0..num_components) {
+            script.push(scan(&[i], 1, 63, 1, 0));
+        }
+
+        script
+    }
+}
+
 /// The representation of a JPEG encoder
 pub struct JPEGEncoder<'a, W: 'a> {
     w: &'a mut W,
 
+    // The components of the image that is encoded
     components: Vec<Component>,
     tables: Vec<u8>,
+    tq: [u8; 3],
+    subsampling: ChromaSubsampling,
+    optimize_huffman: bool,
+
+    progressive: bool,
+    scan_script: Option<Vec<Scan>>,
+    restart_interval: u16,
+    icc_profile: Option<Vec<u8>>,
+
+    // The state of the scan that is encoded progressively
+    counting: bool,
+    frequencies: Vec<[u32; 256]>,
+    scan_tables: Vec<Vec<(u8, u16)>>,
+    eobrun: u16,
+    correction_bits: Vec<u8>,
 
     accumulator: u32,
     nbits: u8,
@@ -143,27 +267,60 @@ pub struct JPEGEncoder<'a, W: 'a> {
 impl<'a, W: Writer> JPEGEncoder<'a, W> {
     /// Create a new encoder that writes its output to ```w```
     pub fn new(w: &mut W) -> JPEGEncoder<W> {
+        JPEGEncoder::new_with_quality(w, DEFAULT_QUALITY)
+    }
+
+    /// Create a new encoder that writes its output to ```w```.
+    /// ```quality``` ranges from 1 (smallest file) to 100 (best quality),
+    /// the quantization tables are scaled like the IJG libjpeg does.
+    pub fn new_with_quality(w: &mut W, quality: u8) -> JPEGEncoder<W> {
+        let mut tables = Vec::new();
+        tables.extend(scale_quantization_table(&STD_LUMA_QTABLE, quality).into_iter());
+        tables.extend(scale_quantization_table(&STD_CHROMA_QTABLE, quality).into_iter());
+
+        JPEGEncoder::new_with_tables(w, tables, [LUMADESTINATION, CHROMADESTINATION, CHROMADESTINATION])
+    }
+
+    /// Create a new encoder that writes its output to ```w```
+    /// and quantizes the Y, Cb and Cr components with their own table of ```tables```.
+    /// The tables are given in row-major order, grayscale images only use the first one.
+    pub fn new_with_quantization_tables(w: &'a mut W, tables: &[[u8; 64]; 3]) -> JPEGEncoder<'a, W> {
+        let mut t = Vec::new();
+
+        for table in tables.iter() {
+            t.push_all(&table[]);
+        }
+
+        JPEGEncoder::new_with_tables(w, t, [LUMADESTINATION, CHROMADESTINATION, CHROMAREDDESTINATION])
+    }
+
+    // ```tq``` selects the table of each component
+    fn new_with_tables(w: &mut W, tables: Vec<u8>, tq: [u8; 3]) -> JPEGEncoder<W> {
         let ld = build_huff_lut(&STD_LUMA_DC_CODE_LENGTHS, &STD_LUMA_DC_VALUES);
         let la = build_huff_lut(&STD_LUMA_AC_CODE_LENGTHS, &STD_LUMA_AC_VALUES);
 
         let cd = build_huff_lut(&STD_CHROMA_DC_CODE_LENGTHS, &STD_CHROMA_DC_VALUES);
         let ca = build_huff_lut(&STD_CHROMA_AC_CODE_LENGTHS, &STD_CHROMA_AC_VALUES);
 
-        let components = vec![
-            Component {id: LUMAID, h: 1, v: 1, tq: LUMADESTINATION, dc_table: LUMADESTINATION, ac_table: LUMADESTINATION, dc_pred: 0},
-            Component {id: CHROMABLUEID, h: 1, v: 1, tq: CHROMADESTINATION, dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0},
-            Component {id: CHROMAREDID, h: 1, v: 1, tq: CHROMADESTINATION, dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0}
-        ];
-
-        let mut tables = Vec::new();
-        tables.extend(STD_LUMA_QTABLE.iter().map(|&v| v));
-        tables.extend(STD_CHROMA_QTABLE.iter().map(|&v| v));
-
         JPEGEncoder {
             w: w,
 
-            components: components,
+            components: build_components(tq, 1, 1),
             tables: tables,
+            tq: tq,
+            subsampling: ChromaSubsampling::Ratio444,
+            optimize_huffman: false,
+
+            progressive: false,
+            scan_script: None,
+            restart_interval: 0,
+            icc_profile: None,
+
+            counting: false,
+            frequencies: Vec::new(),
+            scan_tables: Vec::new(),
+            eobrun: 0,
+            correction_bits: Vec::new(),
 
             luma_dctable: ld,
             luma_actable: la,
@@ -175,10 +332,47 @@ impl<'a, W: Writer> JPEGEncoder<'a, W> {
         }
     }
 
+    /// Sets the chroma subsampling of color images, the default is 4:4:4
+    pub fn set_subsampling(&mut self, subsampling: ChromaSubsampling) {
+        self.subsampling = subsampling;
+    }
+
+    /// Encodes the image in two passes, the first pass gathers the
+    /// statistics of the symbols to build optimal Huffman tables.
+    /// By default the tables of Annex K of the JPEG specification are used.
+    pub fn set_optimize_huffman(&mut self, optimize: bool) {
+        self.optimize_huffman = optimize;
+    }
+
+    /// Encodes the image progressively with the scans of ```default_scan_script```
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.progressive = progressive;
+    }
+
+    /// Encodes the image progressively with the scans of ```script```.
+    /// Encoding fails unless the scans send all bits of all coefficients,
+    /// the DC coefficient of a component before its AC coefficients.
+    pub fn set_scan_script(&mut self, script: Vec<Scan>) {
+        self.progressive = true;
+        self.scan_script = Some(script);
+    }
+
+    /// Inserts a restart marker after every ```interval``` MCUs, which lets decoders
+    /// resynchronize after corrupt data. An interval of 0 disables restart markers.
+    pub fn set_restart_interval(&mut self, interval: u16) {
+        self.restart_interval = interval;
+    }
+
+    /// Embeds the ICC profile ```profile``` in the image.
+    /// Profiles larger than a segment are split across several APP2 segments.
+    pub fn set_icc_profile(&mut self, profile: Vec<u8>) {
+        self.icc_profile = Some(profile);
+    }
+
     /// Encodes the image ```image```
     /// that has dimensions ```width``` and ```height```
     /// and ```ColorType``` ```c```
-    /// The Image in encoded with subsampling ratio 4:2:2
+    /// Color images are encoded with the chroma subsampling set by ```set_subsampling```
     pub fn encode(&mut self,
                   image: &[u8],
                   width: u32,
@@ -189,17 +383,69 @@ impl<'a, W: Writer> JPEGEncoder<'a, W> {
         let num_components = if n == 1 || n == 2 {1}
                              else {3};
 
+        if self.tables.iter().any(|&v| v == 0) {
+            return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Invalid quantization table.",
+                detail: Some("Quantization table entries must not be zero.".to_string())
+            })
+        }
+
+        // The chroma components are sampled once per MCU
+        let (h, v) = if num_components == 3 {
+            match self.subsampling {
+                ChromaSubsampling::Ratio444 => (1, 1),
+                ChromaSubsampling::Ratio422 => (2, 1),
+                ChromaSubsampling::Ratio420 => (2, 2),
+            }
+        } else {
+            (1, 1)
+        };
+
+        // The components of a previous image are replaced, all settings are kept
+        self.components = build_components(self.tq, h, v);
+
+        let blocks = match c {
+            color::ColorType::RGB(8)   => self.rgb_blocks(image, width as usize, height as usize, 3),
+            color::ColorType::RGBA(8)  => self.rgb_blocks(image, width as usize, height as usize, 4),
+            color::ColorType::Gray(8)  => self.gray_blocks(image, width as usize, height as usize, 1),
+            color::ColorType::GrayA(8) => self.gray_blocks(image, width as usize, height as usize, 2),
+            _  => return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Unsupported color type. Use 8 bit per channel RGB(A) or Gray(A) instead.",
+                detail: Some(format!(
+                    "Color type {:?} is not suppored by this JPEG encoder.",
+                    c
+                ))
+            })
+        };
+
+        let script = if self.progressive {
+            let script = match self.scan_script {
+                Some(ref script) => script.clone(),
+                None => default_scan_script(num_components)
+            };
+
+            let _ = try!(check_scan_script(&script[], num_components));
+            Some(script)
+        } else {
+            None
+        };
+
         let _ = try!(self.write_segment(SOI, None));
 
         let buf = build_jfif_header();
         let _   = try!(self.write_segment(APP0, Some(buf)));
 
+        let _ = try!(self.write_icc_profile());
+
+        let sof = if self.progressive { SOF2 } else { SOF0 };
+
         let buf = build_frame_header(8, width as u16, height as u16, &self.components[..num_components]);
-        let _   = try!(self.write_segment(SOF0, Some(buf)));
+        let _   = try!(self.write_segment(sof, Some(buf)));
 
-        assert!(self.tables.len() / 64 == 2);
         let numtables = if num_components == 1 {1}
-                        else {2};
+                        else {self.tables.len() / 64};
 
         let t = self.tables.clone();
 
@@ -208,52 +454,601 @@ impl<'a, W: Writer> JPEGEncoder<'a, W> {
             let _   = try!(self.write_segment(DQT, Some(buf)));
         }
 
-        let numcodes = STD_LUMA_DC_CODE_LENGTHS;
-        let values   = STD_LUMA_DC_VALUES;
+        let _ = try!(self.write_restart_interval());
 
-        let buf = build_huffman_segment(DCCLASS, LUMADESTINATION, &numcodes, &values);
-        let _   = try!(self.write_segment(DHT, Some(buf)));
+        match script {
+            Some(script) => {
+                let grids = self.component_grids(&blocks[], width as usize, height as usize, num_components);
+                try!(self.encode_progressive(&grids[], &script[], width as usize, height as usize))
+            }
+            None => try!(self.encode_baseline(&blocks[], num_components))
+        }
 
-        let numcodes = STD_LUMA_AC_CODE_LENGTHS;
-        let values   = STD_LUMA_AC_VALUES;
+        self.write_segment(EOI, None)
+    }
 
-        let buf = build_huffman_segment(ACCLASS, LUMADESTINATION, &numcodes, &values);
-        let _   = try!(self.write_segment(DHT, Some(buf)));
+    /// Encodes the quantized DCT coefficients ```coefficients```, as read by
+    /// ```JPEGDecoder::read_coefficients```, without any further loss.
+    /// The quantization tables and the application segments of ```coefficients```
+    /// are written instead of the ones of the encoder.
+    /// An ICC profile set by ```set_icc_profile``` replaces the one of ```coefficients```.
+    pub fn encode_coefficients(&mut self, coefficients: &Coefficients) -> IoResult<()> {
+        let num_components = coefficients.components.len();
+        let (width, height) = (coefficients.width as usize, coefficients.height as usize);
+
+        if num_components != 1 && num_components != 3 && num_components != 4 {
+            return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Unsupported number of components.",
+                detail: Some(format!("Images with {} components can not be encoded.", num_components))
+            })
+        }
 
-        if num_components == 3 {
-            let numcodes = STD_CHROMA_DC_CODE_LENGTHS;
-            let values   = STD_CHROMA_DC_VALUES;
+        // The first component is encoded with the luma Huffman tables, the others with the chroma tables
+        self.components = coefficients.components.iter().enumerate().map(|(i, c)| {
+            let table = if i == 0 { LUMADESTINATION } else { CHROMADESTINATION };
 
-            let buf = build_huffman_segment(DCCLASS, CHROMADESTINATION, &numcodes, &values);
-            let _   = try!(self.write_segment(DHT, Some(buf)));
+            Component { dc_table: table, ac_table: table, dc_pred: 0, .. c.component }
+        }).collect();
 
-            let numcodes = STD_CHROMA_AC_CODE_LENGTHS;
-            let values   = STD_CHROMA_AC_VALUES;
+        let (mcus_per_row, mcus_per_column) = self.mcu_counts(width, height);
 
-            let buf = build_huffman_segment(ACCLASS, CHROMADESTINATION, &numcodes, &values);
+        for c in coefficients.components.iter() {
+            let (h, v) = (c.component.h as usize, c.component.v as usize);
+
+            if c.component.tq > 3 ||
+               c.blocks_w != mcus_per_row * h ||
+               c.blocks_h != mcus_per_column * v ||
+               c.blocks.len() != c.blocks_w * c.blocks_h {
+                return Err(old_io::IoError {
+                    kind: old_io::InvalidInput,
+                    desc: "Invalid coefficients.",
+                    detail: Some(format!("The blocks of component {} do not cover the image.", c.component.id))
+                })
+            }
+        }
+
+        let grids = coefficients.components.iter().map(|c| {
+            c.blocks.iter().map(|&block| block).collect::<Vec<[i32; 64]>>()
+        }).collect::<Vec<Vec<[i32; 64]>>>();
+
+        let script = if self.progressive {
+            let script = match self.scan_script {
+                Some(ref script) => script.clone(),
+                None => default_scan_script(num_components)
+            };
+
+            let _ = try!(check_scan_script(&script[], num_components));
+            Some(script)
+        } else {
+            None
+        };
+
+        let _ = try!(self.write_segment(SOI, None));
+
+        let replace_icc = self.icc_profile.is_some();
+
+        for &(marker, ref data) in coefficients.markers.iter() {
+            if replace_icc && marker == APP2 && data.starts_with(ICC_SIGNATURE) {
+                continue
+            }
+
+            let _ = try!(self.write_segment(marker, Some(data.clone())));
+        }
+
+        let _ = try!(self.write_icc_profile());
+
+        let sof = if self.progressive { SOF2 } else { SOF0 };
+
+        let buf = build_frame_header(8, width as u16, height as u16, &self.components[]);
+        let _   = try!(self.write_segment(sof, Some(buf)));
+
+        let mut tq = self.components.iter().map(|c| c.tq).collect::<Vec<u8>>();
+        tq.sort();
+        tq.dedup();
+
+        for &i in tq.iter() {
+            let buf = build_quantization_segment(8, i, &coefficients.qtables[i as usize][]);
+            let _   = try!(self.write_segment(DQT, Some(buf)));
+        }
+
+        let _ = try!(self.write_restart_interval());
+
+        match script {
+            Some(script) => try!(self.encode_progressive(&grids[], &script[], width, height)),
+            None => {
+                let order = self.scan_order(&(0..num_components).collect::<Vec<usize>>()[], width, height);
+                let blocks = order.iter().map(|&(i, index)| (i, grids[i][index])).collect::<Vec<(usize, [i32; 64])>>();
+
+                try!(self.encode_baseline(&blocks[], num_components))
+            }
+        }
+
+        self.write_segment(EOI, None)
+    }
+
+    fn encode_baseline(&mut self, blocks: &[(usize, [i32; 64])], num_components: usize) -> IoResult<()> {
+        // The code lengths and values of the luma and chroma DC and AC tables
+        let mut huffman_tables = vec![
+            (STD_LUMA_DC_CODE_LENGTHS.to_vec(), STD_LUMA_DC_VALUES.to_vec()),
+            (STD_LUMA_AC_CODE_LENGTHS.to_vec(), STD_LUMA_AC_VALUES.to_vec()),
+            (STD_CHROMA_DC_CODE_LENGTHS.to_vec(), STD_CHROMA_DC_VALUES.to_vec()),
+            (STD_CHROMA_AC_CODE_LENGTHS.to_vec(), STD_CHROMA_AC_VALUES.to_vec()),
+        ];
+
+        let blocks_per_mcu = self.components[..num_components].iter().fold(0, |n, c| n + c.h as usize * c.v as usize);
+        let interval = self.restart_interval as usize;
+
+        if self.optimize_huffman {
+            let frequencies = count_symbols(blocks, blocks_per_mcu, interval);
+
+            // Grayscale images do not use the chroma tables
+            for (table, freq) in huffman_tables.iter_mut().zip(frequencies.iter()) {
+                if freq.iter().any(|&f| f > 0) {
+                    *table = build_optimal_huffman_table(freq);
+                }
+            }
+        }
+
+        self.luma_dctable   = build_huff_lut(&huffman_tables[0].0[], &huffman_tables[0].1[]);
+        self.luma_actable   = build_huff_lut(&huffman_tables[1].0[], &huffman_tables[1].1[]);
+        self.chroma_dctable = build_huff_lut(&huffman_tables[2].0[], &huffman_tables[2].1[]);
+        self.chroma_actable = build_huff_lut(&huffman_tables[3].0[], &huffman_tables[3].1[]);
+
+        let destinations = [
+            (DCCLASS, LUMADESTINATION),
+            (ACCLASS, LUMADESTINATION),
+            (DCCLASS, CHROMADESTINATION),
+            (ACCLASS, CHROMADESTINATION),
+        ];
+
+        let numtables = if num_components == 1 {2}
+                        else {4};
+
+        for (&(class, destination), &(ref numcodes, ref values)) in destinations.iter()
+                                                                               .zip(huffman_tables.iter())
+                                                                               .take(numtables) {
+            let buf = build_huffman_segment(class, destination, &numcodes[], &values[]);
             let _   = try!(self.write_segment(DHT, Some(buf)));
         }
 
-        let buf = build_scan_header(&self.components[..num_components]);
-        let _   = try!(self.write_segment(SOS, Some(buf)));
+        let buf = build_scan_header(&self.components[..num_components], 0, 63, 0, 0);
+        let _   = try!(self.write_segment(SOS, Some(buf)));
+
+        let la = self.luma_actable.clone();
+        let ld = self.luma_dctable.clone();
+        let cd = self.chroma_dctable.clone();
+        let ca = self.chroma_actable.clone();
+
+        let mut dcprev = [0i32; 4];
+
+        for (n, &(i, ref block)) in blocks.iter().enumerate() {
+            let (dctable, actable) = if i == 0 { (&ld, &la) } else { (&cd, &ca) };
+            dcprev[i] = try!(self.write_block(&block[], dcprev[i], &dctable[], &actable[]));
+
+            if let Some(marker) = restart_marker(n, blocks.len(), blocks_per_mcu, interval) {
+                let _ = try!(self.pad_byte());
+                let _ = try!(self.write_segment(RST0 + marker, None));
+
+                dcprev = [0i32; 4];
+            }
+        }
+
+        self.pad_byte()
+    }
+
+    fn encode_progressive(&mut self,
+                          grids: &[Vec<[i32; 64]>],
+                          script: &[Scan],
+                          width: usize,
+                          height: usize) -> IoResult<()> {
+
+        for scan in script.iter() {
+            let order = self.scan_order(&scan.components[], width, height);
+
+            // DC refinement scans consist of raw bits only
+            if scan.spectral_start > 0 || scan.approx_high == 0 {
+                // The first pass gathers the statistics for the Huffman tables of the scan
+                self.frequencies = (0..2).map(|_| [0u32; 256]).collect();
+                self.counting = true;
+
+                let _ = try!(self.encode_scan(grids, &order[], scan));
+                self.counting = false;
+
+                let class = if scan.spectral_start == 0 { DCCLASS } else { ACCLASS };
+                let frequencies = mem::replace(&mut self.frequencies, Vec::new());
+
+                self.scan_tables = Vec::new();
+
+                for (destination, freq) in frequencies.iter().enumerate() {
+                    if freq.iter().all(|&f| f == 0) {
+                        self.scan_tables.push(Vec::new());
+                        continue
+                    }
+
+                    let (numcodes, values) = build_optimal_huffman_table(freq);
+
+                    let buf = build_huffman_segment(class, destination as u8, &numcodes[], &values[]);
+                    let _   = try!(self.write_segment(DHT, Some(buf)));
+
+                    self.scan_tables.push(build_huff_lut(&numcodes[], &values[]));
+                }
+            }
+
+            let components = scan.components.iter().map(|&i| self.components[i]).collect::<Vec<Component>>();
+
+            let buf = build_scan_header(&components[],
+                                        scan.spectral_start,
+                                        scan.spectral_end,
+                                        scan.approx_high,
+                                        scan.approx_low);
+            let _   = try!(self.write_segment(SOS, Some(buf)));
+
+            let _ = try!(self.encode_scan(grids, &order[], scan));
+            let _ = try!(self.pad_byte());
+        }
+
+        Ok(())
+    }
+
+    // Rearranges the blocks of the interleaved MCUs into one grid of blocks per component
+    fn component_grids(&self,
+                       blocks: &[(usize, [i32; 64])],
+                       width: usize,
+                       height: usize,
+                       num_components: usize) -> Vec<Vec<[i32; 64]>> {
+
+        let (mcus_per_row, mcus_per_column) = self.mcu_counts(width, height);
+        let mut blocks = blocks.iter();
+
+        let mut grids = self.components[..num_components].iter().map(|c| {
+            let len = mcus_per_row * c.h as usize * mcus_per_column * c.v as usize;
+            (0..len).map(|_| [0i32; 64]).collect::<Vec<[i32; 64]>>()
+        }).collect::<Vec<Vec<[i32; 64]>>>();
+
+        for my in (0..mcus_per_column) {
+            for mx in (0..mcus_per_row) {
+                for (i, c) in self.components[..num_components].iter().enumerate() {
+                    let (h, v) = (c.h as usize, c.v as usize);
+
+                    for by in (0..v) {
+                        for bx in (0..h) {
+                            let &(_, block) = blocks.next().unwrap();
+                            grids[i][(my * v + by) * mcus_per_row * h + mx * h + bx] = block;
+                        }
+                    }
+                }
+            }
+        }
+
+        grids
+    }
+
+    // The largest horizontal and vertical sampling factors of the components
+    fn max_sampling(&self) -> (usize, usize) {
+        self.components.iter().fold((1, 1), |(h, v), c| {
+            (cmp::max(h, c.h as usize), cmp::max(v, c.v as usize))
+        })
+    }
+
+    // The number of MCUs per row and per column
+    fn mcu_counts(&self, width: usize, height: usize) -> (usize, usize) {
+        let (hmax, vmax) = self.max_sampling();
+
+        ((width + 8 * hmax - 1) / (8 * hmax), (height + 8 * vmax - 1) / (8 * vmax))
+    }
+
+    // The component and the index in its grid of the blocks
+    // of a scan of ```components``` in the order they are written
+    fn scan_order(&self, components: &[usize], width: usize, height: usize) -> Vec<(usize, usize)> {
+        let (hmax, vmax) = self.max_sampling();
+        let (mcus_per_row, mcus_per_column) = self.mcu_counts(width, height);
+
+        let mut order = Vec::new();
+
+        if components.len() == 1 {
+            // Non-interleaved scans only cover the blocks
+            // that intersect the component's own dimensions
+            let i = components[0];
+            let (h, v) = (self.components[i].h as usize, self.components[i].v as usize);
+
+            let blocks_w = ((width * h + hmax - 1) / hmax + 7) / 8;
+            let blocks_h = ((height * v + vmax - 1) / vmax + 7) / 8;
+
+            for by in (0..blocks_h) {
+                for bx in (0..blocks_w) {
+                    order.push((i, by * mcus_per_row * h + bx));
+                }
+            }
+        } else {
+            for my in (0..mcus_per_column) {
+                for mx in (0..mcus_per_row) {
+                    for &i in components.iter() {
+                        let (h, v) = (self.components[i].h as usize, self.components[i].v as usize);
+
+                        for by in (0..v) {
+                            for bx in (0..h) {
+                                order.push((i, (my * v + by) * mcus_per_row * h + mx * h + bx));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        order
+    }
+
+    // Section G.1.2
+    // Encodes, or only counts the symbols of, the blocks of a progressive scan
+    fn encode_scan(&mut self, grids: &[Vec<[i32; 64]>], order: &[(usize, usize)], scan: &Scan) -> IoResult<()> {
+        let mut dcprev = [0i32; 4];
+
+        self.eobrun = 0;
+        self.correction_bits = Vec::new();
+
+        // The MCUs of non-interleaved scans consist of a single block
+        let blocks_per_mcu = if scan.components.len() == 1 {
+            1
+        } else {
+            scan.components.iter().fold(0, |n, &i| n + self.components[i].h as usize * self.components[i].v as usize)
+        };
+        let interval = self.restart_interval as usize;
+
+        // AC scans have a single component
+        let table = if scan.components[0] == 0 { 0 } else { 1 };
+
+        for (n, &(i, index)) in order.iter().enumerate() {
+            let block = &grids[i][index];
+            let table = if i == 0 { 0 } else { 1 };
+
+            if scan.spectral_start == 0 {
+                let dc = block[0] >> scan.approx_low as usize;
+
+                if scan.approx_high == 0 {
+                    let (size, value) = encode_coefficient(dc - dcprev[i]);
+                    dcprev[i] = dc;
+
+                    let _ = try!(self.emit_symbol(table, size));
+                    let _ = try!(self.emit_bits(value, size));
+                } else {
+                    let _ = try!(self.emit_bits((dc & 1) as u16, 1));
+                }
+            } else if scan.approx_high == 0 {
+                let _ = try!(self.encode_ac_first(block, table, scan));
+            } else {
+                let _ = try!(self.encode_ac_refine(block, table, scan));
+            }
+
+            if let Some(marker) = restart_marker(n, order.len(), blocks_per_mcu, interval) {
+                let _ = try!(self.emit_eobrun(table));
+                let _ = try!(self.emit_restart(marker));
+
+                dcprev = [0i32; 4];
+            }
+        }
+
+        self.emit_eobrun(table)
+    }
+
+    // Section G.1.2.2
+    fn encode_ac_first(&mut self, block: &[i32; 64], table: usize, scan: &Scan) -> IoResult<()> {
+        let al = scan.approx_low as usize;
+        let mut zero_run = 0u8;
+
+        for k in (scan.spectral_start as usize..scan.spectral_end as usize + 1) {
+            let coefficient = block[UNZIGZAG[k] as usize];
+
+            // The point transform rounds towards zero
+            let value = if coefficient < 0 {
+                -((-coefficient) >> al)
+            } else {
+                coefficient >> al
+            };
+
+            if value == 0 {
+                zero_run += 1;
+                continue
+            }
+
+            let _ = try!(self.emit_eobrun(table));
+
+            while zero_run > 15 {
+                let _ = try!(self.emit_symbol(table, 0xF0));
+                zero_run -= 16;
+            }
+
+            let (size, bits) = encode_coefficient(value);
+
+            let _ = try!(self.emit_symbol(table, (zero_run << 4) | size));
+            let _ = try!(self.emit_bits(bits, size));
+
+            zero_run = 0;
+        }
+
+        if zero_run > 0 {
+            self.eobrun += 1;
+
+            if self.eobrun == MAX_EOBRUN {
+                let _ = try!(self.emit_eobrun(table));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Section G.1.2.3
+    fn encode_ac_refine(&mut self, block: &[i32; 64], table: usize, scan: &Scan) -> IoResult<()> {
+        let (start, end) = (scan.spectral_start as usize, scan.spectral_end as usize);
+        let al = scan.approx_low as usize;
+
+        // The magnitudes after the point transform, coefficients
+        // with a magnitude of 1 become nonzero in this scan
+        let mut magnitudes = [0i32; 64];
+        let mut last_new = 0;
+
+        for k in (start..end + 1) {
+            magnitudes[k] = block[UNZIGZAG[k] as usize].abs() >> al;
+
+            if magnitudes[k] == 1 {
+                last_new = k;
+            }
+        }
+
+        let mut zero_run = 0u8;
+        let mut block_bits = Vec::new();
+
+        for k in (start..end + 1) {
+            let magnitude = magnitudes[k];
+
+            if magnitude == 0 {
+                zero_run += 1;
+                continue
+            }
+
+            // Zero runs after the last new coefficient are part of the end of block
+            while zero_run > 15 && k <= last_new {
+                let _ = try!(self.emit_eobrun(table));
+                let _ = try!(self.emit_symbol(table, 0xF0));
+                zero_run -= 16;
+
+                let _ = try!(self.emit_correction_bits(&block_bits[]));
+                block_bits.clear();
+            }
+
+            // Coefficients that were already nonzero only get a correction bit
+            if magnitude > 1 {
+                block_bits.push((magnitude & 1) as u8);
+                continue
+            }
+
+            let _ = try!(self.emit_eobrun(table));
+            let _ = try!(self.emit_symbol(table, (zero_run << 4) | 1));
+
+            let sign = if block[UNZIGZAG[k] as usize] < 0 { 0 } else { 1 };
+            let _ = try!(self.emit_bits(sign, 1));
+
+            let _ = try!(self.emit_correction_bits(&block_bits[]));
+            block_bits.clear();
+
+            zero_run = 0;
+        }
+
+        if zero_run > 0 || !block_bits.is_empty() {
+            self.eobrun += 1;
+            self.correction_bits.push_all(&block_bits[]);
+
+            if self.eobrun == MAX_EOBRUN || self.correction_bits.len() > MAX_CORRECTION_BITS {
+                let _ = try!(self.emit_eobrun(table));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the pending end of band run, followed by the correction bits of its blocks
+    fn emit_eobrun(&mut self, table: usize) -> IoResult<()> {
+        if self.eobrun > 0 {
+            let mut nbits = 0u8;
+            while self.eobrun >> (nbits + 1) as usize > 0 {
+                nbits += 1;
+            }
+
+            let _ = try!(self.emit_symbol(table, nbits << 4));
+
+            if nbits > 0 {
+                let bits = self.eobrun & ((1 << nbits as usize) - 1);
+                let _ = try!(self.emit_bits(bits, nbits));
+            }
+
+            self.eobrun = 0;
+
+            let bits = mem::replace(&mut self.correction_bits, Vec::new());
+            let _ = try!(self.emit_correction_bits(&bits[]));
+        }
+
+        Ok(())
+    }
+
+    fn emit_restart(&mut self, marker: u8) -> IoResult<()> {
+        if self.counting {
+            return Ok(())
+        }
+
+        let _ = try!(self.pad_byte());
+        self.write_segment(RST0 + marker, None)
+    }
+
+    fn emit_correction_bits(&mut self, bits: &[u8]) -> IoResult<()> {
+        for &bit in bits.iter() {
+            let _ = try!(self.emit_bits(bit as u16, 1));
+        }
+
+        Ok(())
+    }
+
+    fn emit_symbol(&mut self, table: usize, symbol: u8) -> IoResult<()> {
+        if self.counting {
+            self.frequencies[table][symbol as usize] += 1;
+            return Ok(())
+        }
+
+        let (size, code) = self.scan_tables[table][symbol as usize];
+        self.write_bits(code, size)
+    }
+
+    fn emit_bits(&mut self, bits: u16, size: u8) -> IoResult<()> {
+        if self.counting {
+            return Ok(())
+        }
+
+        self.write_bits(bits, size)
+    }
+
+    // The quantization table of the ```i```th component
+    fn qtable(&self, i: usize) -> &[u8] {
+        let tq = self.components[i].tq as usize;
+        &self.tables[64 * tq..64 * tq + 64]
+    }
+
+    fn write_restart_interval(&mut self) -> IoResult<()> {
+        if self.restart_interval == 0 {
+            return Ok(())
+        }
+
+        let interval = self.restart_interval;
+        let buf = vec![(interval >> 8) as u8, interval as u8];
+
+        self.write_segment(DRI, Some(buf))
+    }
+
+    // Writes the ICC profile in chunks that are numbered from 1
+    fn write_icc_profile(&mut self) -> IoResult<()> {
+        let profile = match self.icc_profile {
+            Some(ref profile) => profile.clone(),
+            None => return Ok(())
+        };
+
+        let count = (profile.len() + MAX_ICC_CHUNK - 1) / MAX_ICC_CHUNK;
 
-        match c {
-            color::ColorType::RGB(8)   => try!(self.encode_rgb(image, width as usize, height as usize, 3)),
-            color::ColorType::RGBA(8)  => try!(self.encode_rgb(image, width as usize, height as usize, 4)),
-            color::ColorType::Gray(8)  => try!(self.encode_gray(image, width as usize, height as usize, 1)),
-            color::ColorType::GrayA(8) => try!(self.encode_gray(image, width as usize, height as usize, 2)),
-            _  => return Err(old_io::IoError {
+        if count > 255 {
+            return Err(old_io::IoError {
                 kind: old_io::InvalidInput,
-                desc: "Unsupported color type. Use 8 bit per channel RGB(A) or Gray(A) instead.",
-                detail: Some(format!(
-                    "Color type {:?} is not suppored by this JPEG encoder.",
-                    c
-                ))
+                desc: "ICC profile too large.",
+                detail: Some(format!("An ICC profile of {} bytes does not fit into 255 segments.", profile.len()))
             })
-        };
+        }
 
-        let _ = try!(self.pad_byte());
-        self.write_segment(EOI, None)
+        for (i, chunk) in profile[].chunks(MAX_ICC_CHUNK).enumerate() {
+            let mut buf = ICC_SIGNATURE.to_vec();
+            buf.push(i as u8 + 1);
+            buf.push(count as u8);
+            buf.push_all(chunk);
+
+            let _ = try!(self.write_segment(APP2, Some(buf)));
+        }
+
+        Ok(())
     }
 
     fn write_segment(&mut self, marker: u8, data: Option<Vec<u8>>) -> IoResult<()> {
@@ -289,7 +1084,13 @@ impl<'a, W: Writer> JPEGEncoder<'a, W> {
     }
 
     fn pad_byte(&mut self) -> IoResult<()> {
-        self.write_bits(0x7F, 7)
+        let _ = try!(self.write_bits(0x7F, 7));
+
+        // The remaining padding bits are discarded
+        self.accumulator = 0;
+        self.nbits = 0;
+
+        Ok(())
     }
 
     fn huffman_encode(&mut self, val: u8, table: &[(u8, u16)]) -> IoResult<()> {
@@ -355,78 +1156,64 @@ impl<'a, W: Writer> JPEGEncoder<'a, W> {
         Ok(dcval)
     }
 
-    fn encode_gray(&mut self, image: &[u8], width: usize, height: usize, bpp: usize) -> IoResult<()> {
-        let mut yblock     = [0u8; 64];
-        let mut y_dcprev   = 0;
-        let mut dct_yblock = [0i32; 64];
+    // The quantized blocks of a grayscale image in the order they are written,
+    // together with the index of their component
+    fn gray_blocks(&self, image: &[u8], width: usize, height: usize, bpp: usize) -> Vec<(usize, [i32; 64])> {
+        let mut blocks = Vec::new();
+        let mut yblock = [0u8; 64];
 
         for y in range_step(0, height, 8) {
             for x in range_step(0, width, 8) {
-                // RGB -> YCbCr
-                copy_blocks_gray(image, x, y, width, bpp, &mut yblock);
-
-                // Level shift and fdct
-                // Coeffs are scaled by 8
-                transform::fdct(&yblock[], &mut dct_yblock);
+                copy_blocks_gray(image, x, y, width, height, bpp, &mut yblock);
 
-                // Quantization
-                for i in (0us..64) {
-                    dct_yblock[i]   = ((dct_yblock[i] / 8)   as f32 / self.tables[i] as f32).round() as i32;
-                }
-
-                let la = self.luma_actable.clone();
-                let ld = self.luma_dctable.clone();
-
-                y_dcprev  = try!(self.write_block(&dct_yblock, y_dcprev, &ld[], &la[]));
+                blocks.push((0, self.transform_block(&yblock, 0)));
             }
         }
 
-        Ok(())
+        blocks
     }
 
-    fn encode_rgb(&mut self, image: &[u8], width: usize, height: usize, bpp: usize) -> IoResult<()> {
-        let mut y_dcprev = 0;
-        let mut cb_dcprev = 0;
-        let mut cr_dcprev = 0;
+    // The quantized blocks of a color image in the order they are written,
+    // together with the index of their component
+    fn rgb_blocks(&self, image: &[u8], width: usize, height: usize, bpp: usize) -> Vec<(usize, [i32; 64])> {
+        let (h, v) = (self.components[0].h as usize, self.components[0].v as usize);
 
-        let mut dct_yblock   = [0i32; 64];
-        let mut dct_cb_block = [0i32; 64];
-        let mut dct_cr_block = [0i32; 64];
+        let mut blocks = Vec::new();
 
-        let mut yblock   = [0u8; 64];
+        let mut yblocks  = [[0u8; 64]; 4];
         let mut cb_block = [0u8; 64];
         let mut cr_block = [0u8; 64];
 
-        for y in range_step(0, height, 8) {
-            for x in range_step(0, width, 8) {
+        for y in range_step(0, height, 8 * v) {
+            for x in range_step(0, width, 8 * h) {
                 // RGB -> YCbCr
-                copy_blocks_ycbcr(image, x, y, width, bpp, &mut yblock, &mut cb_block, &mut cr_block);
-
-                // Level shift and fdct
-                // Coeffs are scaled by 8
-                transform::fdct(&yblock[], &mut dct_yblock);
-                transform::fdct(&cb_block[], &mut dct_cb_block);
-                transform::fdct(&cr_block[], &mut dct_cr_block);
-
-                // Quantization
-                for i in (0us..64) {
-                    dct_yblock[i]   = ((dct_yblock[i] / 8)   as f32 / self.tables[i] as f32).round() as i32;
-                    dct_cb_block[i] = ((dct_cb_block[i] / 8) as f32 / self.tables[64..][i] as f32).round() as i32;
-                    dct_cr_block[i] = ((dct_cr_block[i] / 8) as f32 / self.tables[64..][i] as f32).round() as i32;
-                }
+                copy_mcu_ycbcr(image, x, y, width, height, bpp, h, v, &mut yblocks, &mut cb_block, &mut cr_block);
 
-                let la = self.luma_actable.clone();
-                let ld = self.luma_dctable.clone();
-                let cd = self.chroma_dctable.clone();
-                let ca = self.chroma_actable.clone();
+                // The luma blocks of an MCU are interleaved in raster order
+                for yblock in yblocks[..h * v].iter() {
+                    blocks.push((0, self.transform_block(yblock, 0)));
+                }
 
-                y_dcprev  = try!(self.write_block(&dct_yblock, y_dcprev, &ld[], &la[]));
-                cb_dcprev = try!(self.write_block(&dct_cb_block, cb_dcprev, &cd[], &ca[]));
-                cr_dcprev = try!(self.write_block(&dct_cr_block, cr_dcprev, &cd[], &ca[]));
+                blocks.push((1, self.transform_block(&cb_block, 1)));
+                blocks.push((2, self.transform_block(&cr_block, 2)));
             }
         }
 
-        Ok(())
+        blocks
+    }
+
+    // Transforms and quantizes the samples of the ```i```th component
+    fn transform_block(&self, samples: &[u8; 64], i: usize) -> [i32; 64] {
+        let mut dct_block = [0i32; 64];
+
+        // Level shift and fdct
+        // Coeffs are scaled by 8
+        transform::fdct(&samples[], &mut dct_block);
+
+        // Quantization
+        quantize(&mut dct_block, self.qtable(i));
+
+        dct_block
     }
 }
 
@@ -468,7 +1255,11 @@ fn build_frame_header(precision: u8,
     m.into_inner()
 }
 
-fn build_scan_header(components: &[Component]) -> Vec<u8> {
+fn build_scan_header(components: &[Component],
+                     spectral_start: u8,
+                     spectral_end: u8,
+                     approx_high: u8,
+                     approx_low: u8) -> Vec<u8> {
     let mut m = MemWriter::new();
 
     let _ = m.write_u8(components.len() as u8);
@@ -480,9 +1271,9 @@ fn build_scan_header(components: &[Component]) -> Vec<u8> {
     }
 
     // spectral start and end, approx. high and low
-    let _ = m.write_u8(0);
-    let _ = m.write_u8(63);
-    let _ = m.write_u8(0);
+    let _ = m.write_u8(spectral_start);
+    let _ = m.write_u8(spectral_end);
+    let _ = m.write_u8((approx_high << 4) | approx_low);
 
     m.into_inner()
 }
@@ -534,6 +1325,270 @@ fn build_quantization_segment(precision: u8,
     m.into_inner()
 }
 
+// The Y, Cb and Cr components of an image whose luma component has the sampling
+// factors ```h``` and ```v```, quantized with the tables selected by ```tq```
+fn build_components(tq: [u8; 3], h: u8, v: u8) -> Vec<Component> {
+    vec![
+        Component {id: LUMAID, h: h, v: v, tq: tq[0], dc_table: LUMADESTINATION, ac_table: LUMADESTINATION, dc_pred: 0},
+        Component {id: CHROMABLUEID, h: 1, v: 1, tq: tq[1], dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0},
+        Component {id: CHROMAREDID, h: 1, v: 1, tq: tq[2], dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0}
+    ]
+}
+
+// The quality scaling of the IJG libjpeg.
+// Qualities below 50 scale the tables up, qualities above 50 scale them down.
+fn scale_quantization_table(table: &[u8; 64], quality: u8) -> Vec<u8> {
+    let quality = cmp::min(cmp::max(quality, 1), 100) as u32;
+
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+
+    table.iter().map(|&v| {
+        cmp::min(cmp::max((v as u32 * scale + 50) / 100, 1), 255) as u8
+    }).collect()
+}
+
+// Quantizes the coefficients, which are scaled by 8, with ```qtable```
+fn quantize(coefficients: &mut [i32; 64], qtable: &[u8]) {
+    for i in (0us..64) {
+        coefficients[i] = ((coefficients[i] / 8) as f32 / qtable[i] as f32).round() as i32;
+    }
+}
+
+// Checks that the scans are valid on their own and that, like in a script of the IJG libjpeg,
+// the AC coefficients of a component follow its DC coefficient, every band is refined
+// one bit at a time after it was first sent, and all bits of all coefficients are sent.
+fn check_scan_script(script: &[Scan], num_components: usize) -> IoResult<()> {
+    // The point transform of the last scan of each coefficient of each component
+    let mut sent = (0..num_components).map(|_| [None; 64]).collect::<Vec<[Option<u8>; 64]>>();
+
+    for scan in script.iter() {
+        let (start, end) = (scan.spectral_start, scan.spectral_end);
+
+        // Interleaved scans list their components in the order of the frame
+        let valid = !scan.components.is_empty() &&
+                    scan.components.iter().all(|&i| i < num_components) &&
+                    scan.components.windows(2).all(|w| w[0] < w[1]) &&
+                    start <= end && end < 64 &&
+                    (start > 0 || end == 0) &&
+                    (start == 0 || scan.components.len() == 1) &&
+                    scan.approx_low < 14 &&
+                    (scan.approx_high == 0 || scan.approx_high == scan.approx_low + 1);
+
+        if !valid {
+            return Err(invalid_scan_script(format!("The scan {:?} can not be encoded.", scan)))
+        }
+
+        let previous = if scan.approx_high == 0 { None } else { Some(scan.approx_high) };
+
+        for &i in scan.components.iter() {
+            if start > 0 && sent[i][0].is_none() {
+                return Err(invalid_scan_script(format!(
+                    "The scan {:?} sends AC coefficients before the DC coefficient.", scan
+                )))
+            }
+
+            for k in (start as usize..end as usize + 1) {
+                if sent[i][k] != previous {
+                    return Err(invalid_scan_script(format!(
+                        "The scan {:?} does not continue the previous scans of its coefficients.", scan
+                    )))
+                }
+
+                sent[i][k] = Some(scan.approx_low);
+            }
+        }
+    }
+
+    for (i, coefficients) in sent.iter().enumerate() {
+        if coefficients.iter().any(|&bit| bit != Some(0)) {
+            return Err(invalid_scan_script(format!(
+                "The scans do not send all bits of the coefficients of component {}.", i
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+fn invalid_scan_script(detail: String) -> old_io::IoError {
+    old_io::IoError {
+        kind: old_io::InvalidInput,
+        desc: "Invalid scan script.",
+        detail: Some(detail)
+    }
+}
+
+// The frequencies of the symbols of the luma and chroma DC and AC tables
+// that are needed to encode ```blocks``` with a restart interval of ```interval``` MCUs
+fn count_symbols(blocks: &[(usize, [i32; 64])], blocks_per_mcu: usize, interval: usize) -> Vec<[u32; 256]> {
+    let mut frequencies = (0..4).map(|_| [0u32; 256]).collect::<Vec<[u32; 256]>>();
+    let mut dcprev = [0i32; 4];
+
+    for (n, &(i, ref block)) in blocks.iter().enumerate() {
+        let t = if i == 0 { 0 } else { 2 };
+
+        // Mirrors write_block
+        let (size, _) = encode_coefficient(block[0] - dcprev[i]);
+        frequencies[t][size as usize] += 1;
+        dcprev[i] = block[0];
+
+        let mut zero_run = 0;
+
+        for k in (1us..64) {
+            if block[UNZIGZAG[k] as usize] == 0 {
+                if k == 63 {
+                    frequencies[t + 1][0x00] += 1;
+                }
+
+                zero_run += 1;
+            } else {
+                while zero_run > 15 {
+                    frequencies[t + 1][0xF0] += 1;
+                    zero_run -= 16;
+                }
+
+                let (size, _) = encode_coefficient(block[UNZIGZAG[k] as usize]);
+                frequencies[t + 1][((zero_run << 4) | size) as usize] += 1;
+
+                zero_run = 0;
+            }
+        }
+
+        if restart_marker(n, blocks.len(), blocks_per_mcu, interval).is_some() {
+            dcprev = [0i32; 4];
+        }
+    }
+
+    frequencies
+}
+
+// The number of the restart marker that follows the ```n```th of ```total``` blocks,
+// if it ends a restart interval of ```interval``` MCUs that are ```blocks_per_mcu``` blocks long.
+// No marker follows the last block.
+fn restart_marker(n: usize, total: usize, blocks_per_mcu: usize, interval: usize) -> Option<u8> {
+    let end = n + 1;
+
+    if interval == 0 || end % blocks_per_mcu != 0 || end == total {
+        return None
+    }
+
+    let mcus = end / blocks_per_mcu;
+
+    if mcus % interval == 0 {
+        Some(((mcus / interval - 1) % 8) as u8)
+    } else {
+        None
+    }
+}
+
+// Section K.2
+// Builds the code lengths, limited to 16 bits, and the values of
+// the Huffman table that encodes symbols with the frequencies ```freq```
+fn build_optimal_huffman_table(freq: &[u32; 256]) -> (Vec<u8>, Vec<u8>) {
+    // Symbol 256 is reserved so that no code consists of only 1 bits
+    let mut freq: Vec<u32> = freq.iter().map(|&f| f).collect();
+    freq.push(1);
+
+    let mut codesize = [0usize; 257];
+    let mut others   = [-1is; 257];
+
+    // Figure K.1
+    loop {
+        // The two least frequent symbols, the larger symbol wins ties
+        let mut v1 = None;
+        let mut v2 = None;
+
+        for i in (0us..257) {
+            if freq[i] == 0 {
+                continue
+            }
+
+            match v1 {
+                Some(v) if freq[i] > freq[v] => {
+                    match v2 {
+                        Some(w) if freq[i] > freq[w] => (),
+                        _ => v2 = Some(i)
+                    }
+                }
+                _ => {
+                    v2 = v1;
+                    v1 = Some(i);
+                }
+            }
+        }
+
+        let (mut v1, mut v2) = match (v1, v2) {
+            (Some(v1), Some(v2)) => (v1, v2),
+            _ => break
+        };
+
+        freq[v1] += freq[v2];
+        freq[v2] = 0;
+
+        codesize[v1] += 1;
+        while others[v1] >= 0 {
+            v1 = others[v1] as usize;
+            codesize[v1] += 1;
+        }
+
+        others[v1] = v2 as isize;
+
+        codesize[v2] += 1;
+        while others[v2] >= 0 {
+            v2 = others[v2] as usize;
+            codesize[v2] += 1;
+        }
+    }
+
+    // Figure K.2
+    let mut bits = [0u32; 258];
+    for &size in codesize.iter() {
+        if size > 0 {
+            bits[size] += 1;
+        }
+    }
+
+    // Figure K.3
+    let mut i = 257;
+    while i > 16 {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+
+        i -= 1;
+    }
+
+    // Remove the code of the reserved symbol, which is one of the longest
+    while bits[i] == 0 {
+        i -= 1;
+    }
+    bits[i] -= 1;
+
+    // Figure K.4
+    let mut values = Vec::new();
+    for size in (1us..258) {
+        for symbol in (0us..256) {
+            if codesize[symbol] == size {
+                values.push(symbol as u8);
+            }
+        }
+    }
+
+    (bits[1..17].iter().map(|&b| b as u8).collect(), values)
+}
+
 fn encode_coefficient(coefficient: i32) -> (u8, u16) {
     let mut magnitude = coefficient.abs() as u16;
     let mut num_bits  = 0u8;
@@ -566,55 +1621,478 @@ fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
     (y as u8, cb as u8, cr as u8)
 }
 
-fn value_at(s: &[u8], index: usize) -> u8 {
-    if index < s.len() {
-        s[index]
-    } else {
-        s[s.len() - 1]
-    }
-}
-
-fn copy_blocks_ycbcr(source: &[u8],
-                     x0: usize,
-                     y0: usize,
-                     width: usize,
-                     bpp: usize,
-                     yb: &mut [u8; 64],
-                     cbb: &mut [u8; 64],
-                     crb: &mut [u8; 64]) {
+// Converts the pixels of an MCU of h x v luma blocks to YCbCr.
+// The chroma samples are the average of h x v pixels, pixels
+// outside of the image repeat the last column or row.
+fn copy_mcu_ycbcr(source: &[u8],
+                  x0: usize,
+                  y0: usize,
+                  width: usize,
+                  height: usize,
+                  bpp: usize,
+                  h: usize,
+                  v: usize,
+                  yb: &mut [[u8; 64]; 4],
+                  cbb: &mut [u8; 64],
+                  crb: &mut [u8; 64]) {
+
+    let mut cb_sum = [0u32; 64];
+    let mut cr_sum = [0u32; 64];
+
+    for y in (0..8 * v) {
+        let ystride = cmp::min(y0 + y, height - 1) * bpp * width;
+
+        for x in (0..8 * h) {
+            let xstride = cmp::min(x0 + x, width - 1) * bpp;
+
+            let r = source[ystride + xstride + 0];
+            let g = source[ystride + xstride + 1];
+            let b = source[ystride + xstride + 2];
 
-    for y in (0us..8) {
-        let ystride = (y0 + y) * bpp * width;
+            let (yc, cb, cr) = rgb_to_ycbcr(r, g, b);
 
-        for x in (0us..8) {
-            let xstride = x0 * bpp + x * bpp;
+            yb[(y / 8) * h + x / 8][(y % 8) * 8 + x % 8] = yc;
 
-            let r = value_at(source, ystride + xstride + 0);
-            let g = value_at(source, ystride + xstride + 1);
-            let b = value_at(source, ystride + xstride + 2);
+            let i = (y / v) * 8 + x / h;
+            cb_sum[i] += cb as u32;
+            cr_sum[i] += cr as u32;
+        }
+    }
 
-            let (yc, cb, cr) = rgb_to_ycbcr(r, g, b);
+    let n = (h * v) as u32;
 
-            yb[y * 8 + x]  = yc;
-            cbb[y * 8 + x] = cb;
-            crb[y * 8 + x] = cr;
-        }
+    for i in (0us..64) {
+        cbb[i] = ((cb_sum[i] + n / 2) / n) as u8;
+        crb[i] = ((cr_sum[i] + n / 2) / n) as u8;
     }
 }
 
+// Copies a block of gray samples, pixels outside of the image repeat the last column or row
 fn copy_blocks_gray(source: &[u8],
                     x0: usize,
                     y0: usize,
                     width: usize,
+                    height: usize,
                     bpp: usize,
                     gb: &mut [u8; 64]) {
 
     for y in (0us..8) {
-        let ystride = (y0 + y) * bpp * width;
+        let ystride = cmp::min(y0 + y, height - 1) * bpp * width;
 
         for x in (0us..8) {
-            let xstride = x0 * bpp + x * bpp;
-            gb[y * 8 + x] = value_at(source, ystride + xstride + 1);
+            let xstride = cmp::min(x0 + x, width - 1) * bpp;
+            gb[y * 8 + x] = source[ystride + xstride];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::repeat;
+    use std::num::SignedInt;
+    use std::old_io::{MemReader, MemWriter};
+
+    use color;
+    use image::{ImageDecoder, DecodingResult};
+    use super::super::decoder::{JPEGDecoder, Coefficients};
+    use super::{JPEGEncoder, ChromaSubsampling, Scan, STD_LUMA_QTABLE, STD_CHROMA_QTABLE};
+    use super::build_optimal_huffman_table;
+
+    // An RGB image with smooth gradients and some edges
+    fn test_image(width: usize, height: usize) -> Vec<u8> {
+        let mut image = Vec::new();
+
+        for y in (0..height) {
+            for x in (0..width) {
+                let edge = if (x / 5 + y / 7) % 2 == 0 { 0 } else { 40 };
+                image.push_all(&[(x * 6 + edge) as u8, (y * 9 + edge) as u8, (100 + x * y) as u8]);
+            }
+        }
+
+        image
+    }
+
+    fn read_coefficients(data: Vec<u8>) -> Coefficients {
+        JPEGDecoder::new(MemReader::new(data)).read_coefficients().unwrap()
+    }
+
+    fn decode(data: Vec<u8>) -> Vec<u8> {
+        match JPEGDecoder::new(MemReader::new(data)).read_image().unwrap() {
+            DecodingResult::U8(pixels) => pixels,
+            _ => panic!("expected 8 bit samples")
+        }
+    }
+
+    fn distance(a: &[u8], b: &[u8]) -> i32 {
+        a.iter().zip(b.iter()).fold(0, |d, (&p, &q)| d + (p as i32 - q as i32).abs())
+    }
+
+    fn encode_quality(image: &[u8], quality: u8) -> Vec<u8> {
+        let mut m = MemWriter::new();
+        JPEGEncoder::new_with_quality(&mut m, quality).encode(image, 32, 24, color::ColorType::RGB(8)).unwrap();
+
+        m.into_inner()
+    }
+
+    #[test]
+    fn test_quality() {
+        let image = test_image(32, 24);
+
+        let low = encode_quality(&image[], 1);
+        let default = encode_quality(&image[], 50);
+        let high = encode_quality(&image[], 100);
+
+        // Quality 50 uses the tables of the specification, 100 does not quantize
+        // and 1 quantizes with the largest entry of 8 bit tables
+        for &(data, luma, chroma) in [(&low, [255u8; 64], [255u8; 64]),
+                                         (&default, STD_LUMA_QTABLE, STD_CHROMA_QTABLE),
+                                         (&high, [1u8; 64], [1u8; 64])].iter() {
+            let coefficients = read_coefficients(data.clone());
+
+            assert_eq!(&coefficients.qtables[0][], &luma[]);
+            assert_eq!(&coefficients.qtables[1][], &chroma[]);
+        }
+
+        assert!(low.len() < default.len() && default.len() < high.len());
+
+        let errors = [&low, &default, &high].iter().map(|data| {
+            distance(&decode((*data).clone())[], &image[])
+        }).collect::<Vec<i32>>();
+
+        assert!(errors[0] > errors[1] && errors[1] > errors[2]);
+    }
+
+    #[test]
+    fn test_quantization_tables() {
+        let mut tables = [[0u8; 64]; 3];
+
+        for i in (0us..64) {
+            tables[0][i] = 2;
+            tables[1][i] = 1 + i as u8;
+            tables[2][i] = 200 - i as u8;
+        }
+
+        let image = test_image(32, 24);
+
+        let mut m = MemWriter::new();
+        JPEGEncoder::new_with_quantization_tables(&mut m, &tables).encode(&image[], 32, 24, color::ColorType::RGB(8)).unwrap();
+        let coefficients = read_coefficients(m.into_inner());
+
+        for (i, c) in coefficients.components.iter().enumerate() {
+            assert_eq!(c.component.tq as usize, i);
+            assert_eq!(&coefficients.qtables[i][], &tables[i][]);
+        }
+
+        // Grayscale images only use the first table
+        let mut m = MemWriter::new();
+        JPEGEncoder::new_with_quantization_tables(&mut m, &tables).encode(&[128; 64], 8, 8, color::ColorType::Gray(8)).unwrap();
+        assert_eq!(&read_coefficients(m.into_inner()).qtables[0][], &tables[0][]);
+
+        tables[1][5] = 0;
+        let mut m = MemWriter::new();
+        assert!(JPEGEncoder::new_with_quantization_tables(&mut m, &tables).encode(&image[], 32, 24, color::ColorType::RGB(8)).is_err());
+    }
+
+    #[test]
+    fn test_encoder_reuse() {
+        let image = test_image(32, 24);
+        let gray = image[..32 * 24].to_vec();
+
+        let mut m = MemWriter::new();
+        let _ = JPEGEncoder::new_with_quality(&mut m, 90).encode(&gray[], 32, 24, color::ColorType::Gray(8)).unwrap();
+        let coefficients = read_coefficients(m.into_inner());
+
+        let mut expected = MemWriter::new();
+        {
+            let mut encoder = JPEGEncoder::new(&mut expected);
+            encoder.set_subsampling(ChromaSubsampling::Ratio420);
+            encoder.encode(&image[], 32, 24, color::ColorType::RGB(8)).unwrap();
+        }
+        let expected = expected.into_inner();
+
+        // Neither a subsampled color image nor a lossless transcode
+        // changes how the next image is encoded
+        let mut m = MemWriter::new();
+        {
+            let mut encoder = JPEGEncoder::new(&mut m);
+            encoder.set_subsampling(ChromaSubsampling::Ratio420);
+            encoder.encode(&image[], 32, 24, color::ColorType::RGB(8)).unwrap();
+            encoder.encode_coefficients(&coefficients).unwrap();
+            encoder.encode(&image[], 32, 24, color::ColorType::RGB(8)).unwrap();
+        }
+        let m = m.into_inner();
+
+        assert!(&m[..expected.len()] == &expected[]);
+        assert!(&m[m.len() - expected.len()..] == &expected[]);
+    }
+
+    #[test]
+    fn test_gray() {
+        // The last column and row are repeated to fill the partial blocks
+        let (width, height) = (13, 11);
+        let image = (0..width * height).map(|i| (i % width * 15 + i / width * 4) as u8).collect::<Vec<u8>>();
+
+        let mut m = MemWriter::new();
+        JPEGEncoder::new_with_quality(&mut m, 100).encode(&image[], width as u32, height as u32, color::ColorType::Gray(8)).unwrap();
+
+        let pixels = decode(m.into_inner());
+        assert_eq!(pixels.len(), image.len());
+        assert!(pixels.iter().zip(image.iter()).all(|(&p, &q)| (p as i32 - q as i32).abs() <= 1));
+    }
+
+    #[test]
+    fn test_subsampling() {
+        // The MCUs extend past the image, the last column and row are repeated.
+        // The chroma of the image is smooth so that subsampling loses little.
+        let (width, height) = (17, 13);
+        let mut image = Vec::new();
+
+        for y in (0..height) {
+            for x in (0..width) {
+                image.push_all(&[(40 + 8 * x) as u8, (60 + 10 * y) as u8, (200 - 4 * x - 3 * y) as u8]);
+            }
+        }
+
+        for &(subsampling, h, v) in [(ChromaSubsampling::Ratio422, 2, 1),
+                                     (ChromaSubsampling::Ratio420, 2, 2)].iter() {
+            let mut m = MemWriter::new();
+            {
+                let mut encoder = JPEGEncoder::new_with_quality(&mut m, 90);
+                encoder.set_subsampling(subsampling);
+                encoder.encode(&image[], width as u32, height as u32, color::ColorType::RGB(8)).unwrap();
+            }
+            let data = m.into_inner();
+
+            let coefficients = read_coefficients(data.clone());
+            let sampling = coefficients.components.iter().map(|c| {
+                (c.component.h, c.component.v, c.blocks_w, c.blocks_h)
+            }).collect::<Vec<(u8, u8, usize, usize)>>();
+
+            let (mcus_per_row, mcus_per_column) = ((width + 8 * h - 1) / (8 * h), (height + 8 * v - 1) / (8 * v));
+            assert_eq!(sampling, vec![(h as u8, v as u8, mcus_per_row * h, mcus_per_column * v),
+                                      (1, 1, mcus_per_row, mcus_per_column),
+                                      (1, 1, mcus_per_row, mcus_per_column)]);
+
+            let pixels = decode(data);
+            assert_eq!(pixels.len(), image.len());
+
+            let error = distance(&pixels[], &image[]);
+            assert!(error < 4 * image.len() as i32);
+
+            // The pixels next to the replicated edge
+            let last_column = (0..height).map(|y| 3 * (y * width + width - 1)).collect::<Vec<usize>>();
+            let last_row = (0..width).map(|x| 3 * ((height - 1) * width + x)).collect::<Vec<usize>>();
+
+            for indices in [last_column, last_row].iter() {
+                let edge = indices.iter().fold(0, |d, &i| d + distance(&pixels[i..i + 3], &image[i..i + 3]));
+                assert!(edge < 4 * 3 * indices.len() as i32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_optimal_huffman_table() {
+        let mut single = [0u32; 256];
+        single[0x11] = 1;
+
+        // Unused symbols between rare ones
+        let mut sparse = [0u32; 256];
+        for i in (0us..40) {
+            sparse[i] = (i % 3) as u32 * 1000;
+        }
+        sparse[100] = 1;
+
+        // The code lengths of Fibonacci frequencies exceed 16 bits unless they are limited
+        let mut skewed = [0u32; 256];
+        let (mut a, mut b) = (1u32, 1u32);
+        for i in (0us..30) {
+            skewed[i] = a;
+            let next = a + b;
+            a = b;
+            b = next;
+        }
+
+        for freq in [single, sparse, skewed].iter() {
+            let (bits, values) = build_optimal_huffman_table(freq);
+            assert_eq!(bits.len(), 16);
+
+            let mut used = (0us..256).filter(|&i| freq[i] > 0).map(|i| i as u8).collect::<Vec<u8>>();
+            let mut coded = values.clone();
+            used.sort();
+            coded.sort();
+            assert_eq!(coded, used);
+            assert_eq!(bits.iter().fold(0, |n, &b| n + b as usize), values.len());
+
+            // The code consisting of only 1 bits stays unused
+            let kraft = bits.iter().enumerate().fold(0, |k, (i, &b)| k + ((b as u32) << (15 - i)));
+            assert!(kraft < 1 << 16);
+        }
+    }
+
+    // Encodes ```image``` with the standard and with optimized Huffman tables
+    fn encode_optimized(image: &[u8], width: u32, height: u32, c: color::ColorType, progressive: bool) -> (Vec<u8>, Vec<u8>) {
+        let mut results = Vec::new();
+
+        for &optimize in [false, true].iter() {
+            let mut m = MemWriter::new();
+            {
+                let mut encoder = JPEGEncoder::new(&mut m);
+                encoder.set_optimize_huffman(optimize);
+                encoder.set_progressive(progressive);
+                encoder.set_restart_interval(3);
+                encoder.encode(image, width, height, c).unwrap();
+            }
+            results.push(m.into_inner());
+        }
+
+        let optimized = results.pop().unwrap();
+        (results.pop().unwrap(), optimized)
+    }
+
+    #[test]
+    fn test_optimize_huffman() {
+        let image = test_image(32, 24);
+        let gray = image[..32 * 24].to_vec();
+
+        // A single flat block codes one DC and one AC symbol, both with a frequency of 1
+        let cases = [(repeat(200u8).take(64).collect(), 8, 8, color::ColorType::Gray(8)),
+                     (gray, 32, 24, color::ColorType::Gray(8)),
+                     (image, 32, 24, color::ColorType::RGB(8))];
+
+        for &(ref image, width, height, c) in cases.iter() {
+            for &progressive in [false, true].iter() {
+                let (standard, optimized) = encode_optimized(&image[], width, height, c, progressive);
+
+                assert!(optimized.len() <= standard.len());
+                assert!(decode(optimized) == decode(standard));
+            }
+        }
+    }
+
+    fn scan(components: &[usize], spectral_start: u8, spectral_end: u8, approx_high: u8, approx_low: u8) -> Scan {
+        Scan {
+            components: components.to_vec(),
+            spectral_start: spectral_start,
+            spectral_end: spectral_end,
+            approx_high: approx_high,
+            approx_low: approx_low,
+        }
+    }
+
+    fn encode_script(image: &[u8], width: u32, height: u32, c: color::ColorType,
+                     progressive: bool, script: Option<Vec<Scan>>) -> Vec<u8> {
+        let mut m = MemWriter::new();
+        {
+            let mut encoder = JPEGEncoder::new_with_quality(&mut m, 90);
+            encoder.set_subsampling(ChromaSubsampling::Ratio420);
+            encoder.set_restart_interval(2);
+            encoder.set_progressive(progressive);
+
+            if let Some(script) = script {
+                encoder.set_scan_script(script);
+            }
+
+            encoder.encode(image, width, height, c).unwrap();
+        }
+
+        m.into_inner()
+    }
+
+    // The blocks of each component that cover the image, without the blocks that pad it to whole MCUs
+    fn image_blocks(coefficients: &Coefficients) -> Vec<Vec<[i32; 64]>> {
+        let hmax = coefficients.components.iter().fold(1, |m, c| if c.component.h > m { c.component.h } else { m });
+        let vmax = coefficients.components.iter().fold(1, |m, c| if c.component.v > m { c.component.v } else { m });
+
+        coefficients.components.iter().map(|c| {
+            let w = (coefficients.width as usize * c.component.h as usize + hmax as usize - 1) / hmax as usize;
+            let h = (coefficients.height as usize * c.component.v as usize + vmax as usize - 1) / vmax as usize;
+
+            (0..(h + 7) / 8).flat_map(|y| {
+                (0..(w + 7) / 8).map(move |x| c.blocks[y * c.blocks_w + x])
+            }).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_progressive() {
+        // The blocks past the right and bottom edge of the image are not part
+        // of the scans of a single component. The flat area has long runs of empty blocks.
+        let (width, height) = (45, 27);
+        let mut image = test_image(width, height);
+
+        for i in (0..image.len() / 2) {
+            image[i] = 90;
+        }
+
+        let gray = (0..width * height).map(|i| image[3 * i + 1]).collect::<Vec<u8>>();
+
+        // Spectral selection without successive approximation, and successive
+        // approximation in several steps with differently sized bands
+        let custom = vec![
+            scan(&[0, 1, 2], 0, 0, 0, 2),
+            scan(&[1], 1, 63, 0, 0),
+            scan(&[0], 1, 2, 0, 3),
+            scan(&[0, 1, 2], 0, 0, 2, 1),
+            scan(&[0], 3, 63, 0, 1),
+            scan(&[0], 1, 2, 3, 2),
+            scan(&[0], 1, 2, 2, 1),
+            scan(&[2], 1, 9, 0, 0),
+            scan(&[0], 1, 63, 1, 0),
+            scan(&[2], 10, 63, 0, 0),
+            scan(&[0, 1, 2], 0, 0, 1, 0),
+        ];
+        let custom_gray = vec![
+            scan(&[0], 0, 0, 0, 0),
+            scan(&[0], 1, 2, 0, 0),
+            scan(&[0], 3, 63, 0, 3),
+            scan(&[0], 3, 63, 3, 2),
+            scan(&[0], 3, 63, 2, 1),
+            scan(&[0], 3, 63, 1, 0),
+        ];
+
+        for &(image, c, ref script) in [(&image, color::ColorType::RGB(8), custom),
+                                        (&gray, color::ColorType::Gray(8), custom_gray)].iter() {
+            let baseline = read_coefficients(encode_script(&image[], width as u32, height as u32, c, false, None));
+            assert!(!baseline.progressive);
+
+            for script in [None, Some(script.clone())].iter() {
+                let progressive = read_coefficients(encode_script(&image[], width as u32, height as u32, c,
+                                                                  true, script.clone()));
+                assert!(progressive.progressive);
+                assert!(image_blocks(&progressive) == image_blocks(&baseline));
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_scan_script() {
+        let image = test_image(16, 16);
+        let dc = scan(&[0, 1, 2], 0, 0, 0, 0);
+        let ac = [scan(&[0], 1, 63, 0, 0), scan(&[1], 1, 63, 0, 0), scan(&[2], 1, 63, 0, 0)];
+
+        let scripts = [
+            // The AC coefficients of a component are missing
+            vec![dc.clone(), ac[0].clone(), ac[1].clone()],
+            // The last bit of the AC coefficients is missing
+            vec![dc.clone(), scan(&[0], 1, 63, 0, 1), ac[1].clone(), ac[2].clone()],
+            // AC coefficients before the DC coefficient
+            vec![ac[0].clone(), dc.clone(), ac[1].clone(), ac[2].clone()],
+            // A refinement of bits that were never sent
+            vec![dc.clone(), scan(&[0], 1, 63, 1, 0), ac[1].clone(), ac[2].clone()],
+            // A band that is sent twice
+            vec![dc.clone(), ac[0].clone(), ac[1].clone(), ac[2].clone(), scan(&[0], 5, 10, 0, 0)],
+        ];
+
+        for script in scripts.iter() {
+            let mut m = MemWriter::new();
+            let mut encoder = JPEGEncoder::new(&mut m);
+            encoder.set_scan_script(script.clone());
+            assert!(encoder.encode(&image[], 16, 16, color::ColorType::RGB(8)).is_err());
         }
+
+        let mut m = MemWriter::new();
+        let mut encoder = JPEGEncoder::new(&mut m);
+        encoder.set_scan_script(vec![dc, ac[0].clone(), ac[1].clone(), ac[2].clone()]);
+        assert!(encoder.encode(&image[], 16, 16, color::ColorType::RGB(8)).is_ok());
     }
 }
@@ -1,4 +1,5 @@
 use std::iter::repeat;
+use std::collections::vec_map::VecMap;
 
 use image;
 use image::ImageResult;
@@ -208,3 +209,410 @@ pub fn derive_tables(bits: Vec<u8>, huffval: Vec<u8>) -> HuffTable {
         valptr: valptr
     }
 }
+
+// Table D.2
+// The states of the probability estimation of the QM coder as tuples of the estimate Qe,
+// the next state after an MPS, the next state after an LPS and whether an LPS switches the MPS.
+// The last state is not part of the table, its estimate stays fixed at one half.
+static QM_STATES: [(u16, u8, u8, bool); 114] = [
+    (0x5A1D,   1,   1,  true), (0x2586,   2,  14, false), (0x1114,   3,  16, false),
+    (0x080B,   4,  18, false), (0x03D8,   5,  20, false), (0x01DA,   6,  23, false),
+    (0x00E5,   7,  25, false), (0x006F,   8,  28, false), (0x0036,   9,  30, false),
+    (0x001A,  10,  33, false), (0x000D,  11,  35, false), (0x0006,  12,   9, false),
+    (0x0003,  13,  10, false), (0x0001,  13,  12, false), (0x5A7F,  15,  15,  true),
+    (0x3F25,  16,  36, false), (0x2CF2,  17,  38, false), (0x207C,  18,  39, false),
+    (0x17B9,  19,  40, false), (0x1182,  20,  42, false), (0x0CEF,  21,  43, false),
+    (0x09A1,  22,  45, false), (0x072F,  23,  46, false), (0x055C,  24,  48, false),
+    (0x0406,  25,  49, false), (0x0303,  26,  51, false), (0x0240,  27,  52, false),
+    (0x01B1,  28,  54, false), (0x0144,  29,  56, false), (0x00F5,  30,  57, false),
+    (0x00B7,  31,  59, false), (0x008A,  32,  60, false), (0x0068,  33,  62, false),
+    (0x004E,  34,  63, false), (0x003B,  35,  32, false), (0x002C,   9,  33, false),
+    (0x5AE1,  37,  37,  true), (0x484C,  38,  64, false), (0x3A0D,  39,  65, false),
+    (0x2EF1,  40,  67, false), (0x261F,  41,  68, false), (0x1F33,  42,  69, false),
+    (0x19A8,  43,  70, false), (0x1518,  44,  72, false), (0x1177,  45,  73, false),
+    (0x0E74,  46,  74, false), (0x0BFB,  47,  75, false), (0x09F8,  48,  77, false),
+    (0x0861,  49,  78, false), (0x0706,  50,  79, false), (0x05CD,  51,  48, false),
+    (0x04DE,  52,  50, false), (0x040F,  53,  50, false), (0x0363,  54,  51, false),
+    (0x02D4,  55,  52, false), (0x025C,  56,  53, false), (0x01F8,  57,  54, false),
+    (0x01A4,  58,  55, false), (0x0160,  59,  56, false), (0x0125,  60,  57, false),
+    (0x00F6,  61,  58, false), (0x00CB,  62,  59, false), (0x00AB,  63,  61, false),
+    (0x008F,  32,  61, false), (0x5B12,  65,  65,  true), (0x4D04,  66,  80, false),
+    (0x412C,  67,  81, false), (0x37D8,  68,  82, false), (0x2FE8,  69,  83, false),
+    (0x293C,  70,  84, false), (0x2379,  71,  86, false), (0x1EDF,  72,  87, false),
+    (0x1AA9,  73,  87, false), (0x174E,  74,  72, false), (0x1424,  75,  72, false),
+    (0x119C,  76,  74, false), (0x0F6B,  77,  74, false), (0x0D51,  78,  75, false),
+    (0x0BB6,  79,  77, false), (0x0A40,  48,  77, false), (0x5832,  81,  80,  true),
+    (0x4D1C,  82,  88, false), (0x438E,  83,  89, false), (0x3BDD,  84,  90, false),
+    (0x34EE,  85,  91, false), (0x2EAE,  86,  92, false), (0x299A,  87,  93, false),
+    (0x2516,  71,  86, false), (0x5570,  89,  88,  true), (0x4CA9,  90,  95, false),
+    (0x44D9,  91,  96, false), (0x3E22,  92,  97, false), (0x3824,  93,  99, false),
+    (0x32B4,  94,  99, false), (0x2E17,  86,  93, false), (0x56A8,  96,  95,  true),
+    (0x4F46,  97, 101, false), (0x47E5,  98, 102, false), (0x41CF,  99, 103, false),
+    (0x3C3D, 100, 104, false), (0x375E,  93,  99, false), (0x5231, 102, 105, false),
+    (0x4C0F, 103, 106, false), (0x4639, 104, 107, false), (0x415E,  99, 103, false),
+    (0x5627, 106, 105,  true), (0x50E7, 107, 108, false), (0x4B85, 103, 109, false),
+    (0x5597, 109, 110, false), (0x504F, 107, 111, false), (0x5A10, 111, 110,  true),
+    (0x5522, 109, 112, false), (0x59EB, 111, 112,  true), (0x5A1D, 113, 113, false),
+];
+
+// The register state of the QM coder
+struct QMDecoder {
+    c: u32,
+    a: u32,
+    ct: i32,
+    marker: u8,
+}
+
+impl QMDecoder {
+    fn new() -> QMDecoder {
+        // The first two bytes are read by the first renormalization
+        QMDecoder {
+            c: 0,
+            a: 0,
+            ct: -16,
+            marker: 0
+        }
+    }
+
+    // Section D.2.6
+    // Once a marker is reached the decoder is fed with zeros
+    fn read_byte<R: Reader>(&mut self, r: &mut R) -> ImageResult<u32> {
+        if self.marker != 0 {
+            return Ok(0)
+        }
+
+        let byte = try!(r.read_u8());
+
+        if byte != 0xFF {
+            return Ok(byte as u32)
+        }
+
+        let mut byte2 = try!(r.read_u8());
+        while byte2 == 0xFF {
+            byte2 = try!(r.read_u8());
+        }
+
+        if byte2 == 0 {
+            Ok(0xFF)
+        } else {
+            self.marker = byte2;
+            Ok(0)
+        }
+    }
+
+    // Sections D.2.4 to D.2.6
+    // Decodes a decision with the probability estimate of the statistics bin ```st```
+    fn decode<R: Reader>(&mut self, r: &mut R, st: &mut u8) -> ImageResult<bool> {
+        // Renormalization
+        while self.a < 0x8000 {
+            self.ct -= 1;
+
+            if self.ct < 0 {
+                let byte = try!(self.read_byte(r));
+                self.c = (self.c << 8) | byte;
+                self.ct += 8;
+
+                if self.ct < 0 {
+                    self.ct += 1;
+
+                    // The initial bytes have been read
+                    if self.ct == 0 {
+                        self.a = 0x8000;
+                    }
+                }
+            }
+
+            self.a <<= 1;
+        }
+
+        let sv = *st;
+        let (qe, next_mps, next_lps, switch) = QM_STATES[(sv & 0x7F) as usize];
+        let qe = qe as u32;
+
+        let mps = sv & 0x80;
+        let mps_state = mps | next_mps;
+        let lps_state = if switch { (mps ^ 0x80) | next_lps } else { mps | next_lps };
+
+        let mut bit = sv >> 7;
+
+        self.a -= qe;
+        let temp = self.a << self.ct as usize;
+
+        if self.c >= temp {
+            self.c -= temp;
+
+            // Conditional exchange of the LPS and MPS intervals
+            if self.a < qe {
+                *st = mps_state;
+            } else {
+                *st = lps_state;
+                bit ^= 1;
+            }
+
+            self.a = qe;
+        } else if self.a < 0x8000 {
+            if self.a < qe {
+                *st = lps_state;
+                bit ^= 1;
+            } else {
+                *st = mps_state;
+            }
+        }
+
+        Ok(bit == 1)
+    }
+}
+
+/// The arithmetic entropy decoder of Annex F.2.4 and G.2
+pub struct ArithDecoder {
+    qm: QMDecoder,
+
+    dc_stats: [[u8; 64]; 4],
+    ac_stats: [[u8; 256]; 4],
+    fixed: u8,
+    dc_context: VecMap<usize>,
+
+    /// The lower and upper bound of the DC conditioning of each table
+    pub dc_bounds: [(u8, u8); 4],
+
+    /// The AC conditioning of each table
+    pub ac_k: [u8; 4],
+}
+
+impl ArithDecoder {
+    pub fn new() -> ArithDecoder {
+        ArithDecoder {
+            qm: QMDecoder::new(),
+
+            dc_stats: [[0u8; 64]; 4],
+            ac_stats: [[0u8; 256]; 4],
+            fixed: 113,
+            dc_context: VecMap::new(),
+
+            // The defaults of section F.1.4.4
+            dc_bounds: [(0, 1); 4],
+            ac_k: [5; 4],
+        }
+    }
+
+    /// Resets the statistics and the decoder at the start of a scan or restart interval
+    pub fn reset(&mut self) {
+        self.qm = QMDecoder::new();
+        self.dc_stats = [[0u8; 64]; 4];
+        self.ac_stats = [[0u8; 256]; 4];
+        self.dc_context.clear();
+    }
+
+    /// Returns the marker that ended the entropy coded data, or 0
+    pub fn take_marker(&mut self) -> u8 {
+        let m = self.qm.marker;
+        self.qm.marker = 0;
+
+        m
+    }
+
+    // Section F.1.4.4.1
+    /// Decodes the difference of a DC coefficient to its prediction.
+    /// The decoding is conditioned on the previous difference of the component ```id```.
+    pub fn decode_dc_diff<R: Reader>(&mut self, r: &mut R, table: usize, id: usize) -> ImageResult<i32> {
+        let stats = &mut self.dc_stats[table][];
+
+        // Table F.4
+        let mut st = *self.dc_context.get(&id).unwrap_or(&0);
+
+        // Figure F.19
+        if !try!(self.qm.decode(r, &mut stats[st])) {
+            self.dc_context.insert(id, 0);
+            return Ok(0)
+        }
+
+        // Figure F.22
+        let sign = try!(self.qm.decode(r, &mut stats[st + 1]));
+        st += if sign { 3 } else { 2 };
+
+        // Figure F.23
+        let mut m = 0;
+
+        if try!(self.qm.decode(r, &mut stats[st])) {
+            m = 1;
+            st = 20;
+
+            while try!(self.qm.decode(r, &mut stats[st])) {
+                m <<= 1;
+
+                if m == 0x8000 {
+                    return Err(image::ImageError::FormatError("Magnitude out of range.".to_string()))
+                }
+
+                st += 1;
+            }
+        }
+
+        // Section F.1.4.4.1.2
+        // The magnitude category conditions the decoding of the next difference
+        let (lower, upper) = self.dc_bounds[table];
+
+        let context = if m < (1 << lower as usize) >> 1 {
+            0
+        } else if m > (1 << upper as usize) >> 1 {
+            if sign { 16 } else { 12 }
+        } else {
+            if sign { 8 } else { 4 }
+        };
+
+        self.dc_context.insert(id, context);
+
+        let v = try!(decode_magnitude(&mut self.qm, r, stats, st + 14, m));
+
+        Ok(if sign { -v } else { v })
+    }
+
+    // Section F.1.4.4.2
+    /// Decodes the AC coefficients ```start``` to ```end``` of a block in zigzag order,
+    /// shifted by the successive approximation bit position ```low```
+    pub fn decode_ac<R: Reader>(&mut self,
+                                r: &mut R,
+                                table: usize,
+                                start: usize,
+                                end: usize,
+                                low: usize,
+                                coefficients: &mut [i32]) -> ImageResult<()> {
+        let stats = &mut self.ac_stats[table][];
+        let mut k = start;
+
+        // Figure F.20
+        while k <= end {
+            let mut st = 3 * (k - 1);
+
+            // The end of the block
+            if try!(self.qm.decode(r, &mut stats[st])) {
+                break
+            }
+
+            while !try!(self.qm.decode(r, &mut stats[st + 1])) {
+                st += 3;
+                k += 1;
+
+                if k > end {
+                    return Err(image::ImageError::FormatError("Coefficient index out of range.".to_string()))
+                }
+            }
+
+            // The sign is decoded with a fixed probability
+            let sign = try!(self.qm.decode(r, &mut self.fixed));
+            st += 2;
+
+            // Figure F.23
+            let mut m = 0;
+
+            if try!(self.qm.decode(r, &mut stats[st])) {
+                m = 1;
+
+                if try!(self.qm.decode(r, &mut stats[st])) {
+                    m = 2;
+                    st = if k <= self.ac_k[table] as usize { 189 } else { 217 };
+
+                    while try!(self.qm.decode(r, &mut stats[st])) {
+                        m <<= 1;
+
+                        if m == 0x8000 {
+                            return Err(image::ImageError::FormatError("Magnitude out of range.".to_string()))
+                        }
+
+                        st += 1;
+                    }
+                }
+            }
+
+            let v = try!(decode_magnitude(&mut self.qm, r, stats, st + 14, m));
+            coefficients[k] = (if sign { -v } else { v }) << low;
+
+            k += 1;
+        }
+
+        Ok(())
+    }
+
+    // Section G.2
+    /// Decodes the next bit of a DC coefficient
+    pub fn decode_dc_refinement<R: Reader>(&mut self, r: &mut R) -> ImageResult<bool> {
+        self.qm.decode(r, &mut self.fixed)
+    }
+
+    // Section G.2
+    /// Refines the AC coefficients ```start``` to ```end``` of a block in zigzag order
+    /// by the bit at position ```low```
+    pub fn refine_ac<R: Reader>(&mut self,
+                                r: &mut R,
+                                table: usize,
+                                start: usize,
+                                end: usize,
+                                low: usize,
+                                coefficients: &mut [i32]) -> ImageResult<()> {
+        let stats = &mut self.ac_stats[table][];
+
+        let p1 =  1 << low;
+        let m1 = -1 << low;
+
+        // The end of the block in the previous scans
+        let mut kex = end;
+        while kex > 0 && coefficients[kex] == 0 {
+            kex -= 1;
+        }
+
+        let mut k = start;
+
+        while k <= end {
+            let mut st = 3 * (k - 1);
+
+            // An end of block can only follow the end of block of the previous scans
+            if k > kex && try!(self.qm.decode(r, &mut stats[st])) {
+                break
+            }
+
+            loop {
+                if coefficients[k] != 0 {
+                    if try!(self.qm.decode(r, &mut stats[st + 2])) {
+                        coefficients[k] += if coefficients[k] < 0 { m1 } else { p1 };
+                    }
+
+                    break
+                }
+
+                // Newly nonzero coefficients always have a magnitude of one
+                if try!(self.qm.decode(r, &mut stats[st + 1])) {
+                    coefficients[k] = if try!(self.qm.decode(r, &mut self.fixed)) { m1 } else { p1 };
+                    break
+                }
+
+                st += 3;
+                k += 1;
+
+                if k > end {
+                    return Err(image::ImageError::FormatError("Coefficient index out of range.".to_string()))
+                }
+            }
+
+            k += 1;
+        }
+
+        Ok(())
+    }
+}
+
+// Figure F.24
+// Decodes the bits below the most significant bit ```m``` of a magnitude
+fn decode_magnitude<R: Reader>(qm: &mut QMDecoder, r: &mut R, stats: &mut [u8], st: usize, m: i32) -> ImageResult<i32> {
+    let mut v = m;
+    let mut bit = m >> 1;
+
+    while bit > 0 {
+        if try!(qm.decode(r, &mut stats[st])) {
+            v |= bit;
+        }
+
+        bit >>= 1;
+    }
+
+    Ok(v + 1)
+}
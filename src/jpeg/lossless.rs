@@ -0,0 +1,560 @@
+//! Lossless transformations of JPEG images
+//!
+//! Like jpegtran of the IJG libjpeg, the quantized DCT coefficients are rearranged
+//! instead of decoding and encoding the samples again.
+
+use std::cmp;
+
+use image;
+use image::ImageResult;
+
+use super::decoder::{
+    JPEGDecoder,
+    Component,
+    Coefficients,
+    ComponentCoefficients
+};
+use super::encoder::JPEGEncoder;
+
+// The marker of the segment holding EXIF metadata
+const APP1: u8 = 0xE1;
+
+/// A lossless transformation of a JPEG image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transform {
+    /// Mirror the image horizontally
+    FlipHorizontal,
+
+    /// Mirror the image vertically
+    FlipVertical,
+
+    /// Mirror the image along the diagonal from the top left to the bottom right corner
+    Transpose,
+
+    /// Mirror the image along the diagonal from the top right to the bottom left corner
+    Transverse,
+
+    /// Rotate the image by 90 degrees clockwise
+    Rotate90,
+
+    /// Rotate the image by 180 degrees
+    Rotate180,
+
+    /// Rotate the image by 270 degrees clockwise
+    Rotate270,
+
+    /// Crop the image to the rectangle with the top left corner ```x```, ```y```
+    /// and the dimensions ```width``` and ```height```, in this order.
+    /// The corner is moved up and left to the nearest MCU boundary.
+    Crop(u32, u32, u32, u32),
+}
+
+/// Applies ```transform``` to the JPEG image read from ```r``` and writes the result to ```w```.
+///
+/// The quantization tables, the restart interval and the application segments of the image
+/// are kept, progressive images stay progressive. The EXIF orientation of rotated
+/// and flipped images is reset to 1, as they are stored the way they are displayed.
+/// Partial MCUs at the right or bottom edge that would move to the left or top edge are trimmed.
+pub fn transform_lossless<R: Reader, W: Writer>(r: R, w: &mut W, transform: Transform) -> ImageResult<()> {
+    let mut decoder = JPEGDecoder::new(r);
+
+    let coefficients = try!(decoder.read_coefficients());
+    let transformed = try!(transform_coefficients(&coefficients, transform));
+
+    let mut encoder = JPEGEncoder::new(w);
+    encoder.set_optimize_huffman(true);
+    encoder.set_progressive(transformed.progressive);
+    encoder.set_restart_interval(transformed.restart_interval);
+
+    let _ = try!(encoder.encode_coefficients(&transformed));
+
+    Ok(())
+}
+
+/// Applies ```transform``` to the quantized DCT coefficients of an image
+pub fn transform_coefficients(coefficients: &Coefficients, transform: Transform) -> ImageResult<Coefficients> {
+    let (transpose, flip_h, flip_v) = match transform {
+        Transform::FlipHorizontal => (false, true, false),
+        Transform::FlipVertical => (false, false, true),
+        Transform::Transpose => (true, false, false),
+        Transform::Transverse => (true, true, true),
+        Transform::Rotate90 => (true, true, false),
+        Transform::Rotate180 => (false, true, true),
+        Transform::Rotate270 => (true, false, true),
+        Transform::Crop(x, y, width, height) => return crop(coefficients, x, y, width, height)
+    };
+
+    let (mcu_w, mcu_h) = mcu_size(coefficients);
+
+    // The flips are applied after transposing, so a flip of the new
+    // horizontal axis flips the vertical axis of the original image
+    let (trim_w, trim_h) = if transpose { (flip_v, flip_h) } else { (flip_h, flip_v) };
+
+    let width = if trim_w {
+        coefficients.width as usize / mcu_w * mcu_w
+    } else {
+        coefficients.width as usize
+    };
+
+    let height = if trim_h {
+        coefficients.height as usize / mcu_h * mcu_h
+    } else {
+        coefficients.height as usize
+    };
+
+    if width == 0 || height == 0 {
+        return Err(image::ImageError::DimensionError)
+    }
+
+    let (mcus_per_row, mcus_per_column) = ((width + mcu_w - 1) / mcu_w, (height + mcu_h - 1) / mcu_h);
+    let mut components = Vec::new();
+
+    for c in coefficients.components.iter() {
+        let (h, v) = (c.component.h as usize, c.component.v as usize);
+        let (blocks_w, blocks_h) = if transpose {
+            (mcus_per_column * v, mcus_per_row * h)
+        } else {
+            (mcus_per_row * h, mcus_per_column * v)
+        };
+
+        let mut blocks = Vec::with_capacity(blocks_w * blocks_h);
+
+        for y in (0..blocks_h) {
+            for x in (0..blocks_w) {
+                // The position of the block before flipping and transposing
+                let x0 = if flip_h { blocks_w - 1 - x } else { x };
+                let y0 = if flip_v { blocks_h - 1 - y } else { y };
+                let (x0, y0) = if transpose { (y0, x0) } else { (x0, y0) };
+
+                let block = &c.blocks[y0 * c.blocks_w + x0];
+                blocks.push(transform_block(block, transpose, flip_h, flip_v));
+            }
+        }
+
+        let component = if transpose {
+            Component { h: c.component.v, v: c.component.h, .. c.component }
+        } else {
+            c.component
+        };
+
+        components.push(ComponentCoefficients {
+            component: component,
+            blocks_w: blocks_w,
+            blocks_h: blocks_h,
+            blocks: blocks
+        });
+    }
+
+    let mut qtables = coefficients.qtables;
+
+    if transpose {
+        for table in qtables.iter_mut() {
+            *table = transpose_table(table);
+        }
+    }
+
+    let (width, height) = if transpose { (height, width) } else { (width, height) };
+
+    let mut markers = coefficients.markers.clone();
+
+    for m in markers.iter_mut() {
+        if m.0 == APP1 {
+            reset_orientation(&mut m.1[]);
+        }
+    }
+
+    Ok(Coefficients {
+        width: width as u16,
+        height: height as u16,
+        progressive: coefficients.progressive,
+        restart_interval: coefficients.restart_interval,
+        qtables: qtables,
+        components: components,
+        markers: markers
+    })
+}
+
+fn crop(coefficients: &Coefficients, x: u32, y: u32, width: u32, height: u32) -> ImageResult<Coefficients> {
+    let (x, y) = (x as usize, y as usize);
+
+    if x >= coefficients.width as usize || y >= coefficients.height as usize || width == 0 || height == 0 {
+        return Err(image::ImageError::DimensionError)
+    }
+
+    let (mcu_w, mcu_h) = mcu_size(coefficients);
+
+    // The cropped image starts at an MCU boundary
+    let (x0, y0) = (x / mcu_w * mcu_w, y / mcu_h * mcu_h);
+    let x1 = cmp::min(x + width as usize, coefficients.width as usize);
+    let y1 = cmp::min(y + height as usize, coefficients.height as usize);
+
+    let (width, height) = (x1 - x0, y1 - y0);
+    let (mcus_per_row, mcus_per_column) = ((width + mcu_w - 1) / mcu_w, (height + mcu_h - 1) / mcu_h);
+
+    let components = coefficients.components.iter().map(|c| {
+        let (h, v) = (c.component.h as usize, c.component.v as usize);
+        let (blocks_w, blocks_h) = (mcus_per_row * h, mcus_per_column * v);
+
+        // The first block of the cropped component
+        let (bx, by) = (x0 / mcu_w * h, y0 / mcu_h * v);
+
+        let mut blocks = Vec::with_capacity(blocks_w * blocks_h);
+
+        for y in (by..by + blocks_h) {
+            let start = y * c.blocks_w + bx;
+            blocks.extend(c.blocks[start..start + blocks_w].iter().map(|&block| block));
+        }
+
+        ComponentCoefficients {
+            component: c.component,
+            blocks_w: blocks_w,
+            blocks_h: blocks_h,
+            blocks: blocks
+        }
+    }).collect::<Vec<ComponentCoefficients>>();
+
+    Ok(Coefficients {
+        width: width as u16,
+        height: height as u16,
+        progressive: coefficients.progressive,
+        restart_interval: coefficients.restart_interval,
+        qtables: coefficients.qtables,
+        components: components,
+        markers: coefficients.markers.clone()
+    })
+}
+
+// The width and height of an MCU in pixels
+fn mcu_size(coefficients: &Coefficients) -> (usize, usize) {
+    let (hmax, vmax) = coefficients.components.iter().fold((1, 1), |(h, v), c| {
+        (cmp::max(h, c.component.h as usize), cmp::max(v, c.component.v as usize))
+    });
+
+    (8 * hmax, 8 * vmax)
+}
+
+// Transposes and flips the samples of a block in the frequency domain.
+// Mirroring a block negates the coefficients of its odd frequencies.
+fn transform_block(block: &[i32; 64], transpose: bool, flip_h: bool, flip_v: bool) -> [i32; 64] {
+    let mut out = [0i32; 64];
+
+    for v in (0us..8) {
+        for u in (0us..8) {
+            let mut value = if transpose { block[u * 8 + v] } else { block[v * 8 + u] };
+
+            if flip_h && u % 2 == 1 {
+                value = -value;
+            }
+
+            if flip_v && v % 2 == 1 {
+                value = -value;
+            }
+
+            out[v * 8 + u] = value;
+        }
+    }
+
+    out
+}
+
+// Sets the orientation tag of the EXIF segment ```data``` to 1.
+// Segments that are not EXIF or have no orientation are left unchanged.
+fn reset_orientation(data: &mut [u8]) {
+    // Reads the unsigned integer of ```size``` bytes at ```offset``` of the TIFF structure
+    fn read(tiff: &[u8], offset: usize, size: usize, little_endian: bool) -> Option<usize> {
+        if offset + size > tiff.len() {
+            return None
+        }
+
+        let bytes = &tiff[offset..offset + size];
+
+        Some(if little_endian {
+            bytes.iter().rev().fold(0, |v, &b| v << 8 | b as usize)
+        } else {
+            bytes.iter().fold(0, |v, &b| v << 8 | b as usize)
+        })
+    }
+
+    if data.len() < 14 || &data[..6] != b"Exif\0\0" {
+        return
+    }
+
+    let tiff = &mut data[6..];
+
+    let little_endian = match (tiff[0], tiff[1]) {
+        (b'I', b'I') => true,
+        (b'M', b'M') => false,
+        _ => return
+    };
+
+    // The orientation is a tag of the first IFD, its single SHORT value is stored in the entry
+    let ifd = match read(tiff, 4, 4, little_endian) {
+        Some(offset) => offset,
+        None => return
+    };
+
+    let count = match read(tiff, ifd, 2, little_endian) {
+        Some(count) => count,
+        None => return
+    };
+
+    for i in (0..count) {
+        let entry = ifd + 2 + 12 * i;
+
+        if read(tiff, entry, 2, little_endian) == Some(0x0112) &&
+           read(tiff, entry + 2, 2, little_endian) == Some(3) &&
+           read(tiff, entry + 4, 4, little_endian) == Some(1) &&
+           entry + 10 <= tiff.len() {
+            let (first, second) = if little_endian { (1, 0) } else { (0, 1) };
+            tiff[entry + 8] = first;
+            tiff[entry + 9] = second;
+        }
+    }
+}
+
+fn transpose_table(table: &[u8; 64]) -> [u8; 64] {
+    let mut out = [0u8; 64];
+
+    for v in (0us..8) {
+        for u in (0us..8) {
+            out[v * 8 + u] = table[u * 8 + v];
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::SignedInt;
+    use std::old_io::{MemReader, MemWriter};
+
+    use color;
+    use image::{ImageDecoder, DecodingResult};
+    use super::{Transform, transform_lossless, transform_coefficients};
+    use super::super::decoder::{JPEGDecoder, Coefficients};
+    use super::super::encoder::{JPEGEncoder, ChromaSubsampling};
+
+    // Encodes an RGB image with gradients and edges that differ along both axes
+    fn encode(width: usize, height: usize, subsampling: ChromaSubsampling) -> Vec<u8> {
+        let mut image = Vec::new();
+
+        for y in (0..height) {
+            for x in (0..width) {
+                let edge = if x * 3 > width + y { 60 } else { 0 };
+                image.push_all(&[(20 + 5 * x + edge) as u8, (30 + 7 * y) as u8, (200 - 3 * x - 2 * y + edge) as u8]);
+            }
+        }
+
+        let mut m = MemWriter::new();
+        {
+            let mut encoder = JPEGEncoder::new_with_quality(&mut m, 90);
+            encoder.set_subsampling(subsampling);
+            encoder.encode(&image[], width as u32, height as u32, color::ColorType::RGB(8)).unwrap();
+        }
+
+        m.into_inner()
+    }
+
+    fn transform(data: &[u8], t: Transform) -> Vec<u8> {
+        let mut m = MemWriter::new();
+        transform_lossless(MemReader::new(data.to_vec()), &mut m, t).unwrap();
+
+        m.into_inner()
+    }
+
+    fn read_coefficients(data: &[u8]) -> Coefficients {
+        JPEGDecoder::new(MemReader::new(data.to_vec())).read_coefficients().unwrap()
+    }
+
+    fn decode(data: &[u8]) -> (usize, usize, Vec<u8>) {
+        let mut decoder = JPEGDecoder::new(MemReader::new(data.to_vec()));
+        let (width, height) = decoder.dimensions().unwrap();
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => (width as usize, height as usize, pixels),
+            _ => panic!("expected 8 bit samples")
+        }
+    }
+
+    fn assert_same_blocks(a: &Coefficients, b: &Coefficients) {
+        assert_eq!((a.width, a.height), (b.width, b.height));
+
+        for (x, y) in a.components.iter().zip(b.components.iter()) {
+            assert_eq!((x.blocks_w, x.blocks_h), (y.blocks_w, y.blocks_h));
+
+            for (p, q) in x.blocks.iter().zip(y.blocks.iter()) {
+                assert_eq!(&p[], &q[]);
+            }
+        }
+    }
+
+    // The position in a ```width``` by ```height``` image of the pixel
+    // that ```t``` moves to ```x```, ```y```
+    fn source_position(t: Transform, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        match t {
+            Transform::FlipHorizontal => (width - 1 - x, y),
+            Transform::FlipVertical => (x, height - 1 - y),
+            Transform::Transpose => (y, x),
+            Transform::Transverse => (width - 1 - y, height - 1 - x),
+            Transform::Rotate90 => (y, height - 1 - x),
+            Transform::Rotate180 => (width - 1 - x, height - 1 - y),
+            Transform::Rotate270 => (width - 1 - y, x),
+            Transform::Crop(x0, y0, _, _) => (x0 as usize + x, y0 as usize + y)
+        }
+    }
+
+    // Checks that the image ```data``` shows ```t``` applied to the top left ```width``` by ```height```
+    // pixels of the image ```original```, with the largest error ```tolerance```
+    fn assert_transformed(original: &[u8], t: Transform, width: usize, height: usize, data: &[u8], tolerance: i32) {
+        let (ow, _, original) = decode(original);
+        let (tw, th, pixels) = decode(data);
+
+        let expected = match t {
+            Transform::Transpose | Transform::Transverse |
+            Transform::Rotate90 | Transform::Rotate270 => (height, width),
+            _ => (width, height)
+        };
+        assert_eq!((tw, th), expected);
+
+        for y in (0..th) {
+            for x in (0..tw) {
+                let (sx, sy) = source_position(t, x, y, width, height);
+
+                for c in (0us..3) {
+                    let error = pixels[3 * (y * tw + x) + c] as i32 - original[3 * (sy * ow + sx) + c] as i32;
+                    assert!(error.abs() <= tolerance);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotations_are_lossless() {
+        let (width, height) = (32, 16);
+        let image = (0..width * height * 3).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+
+        let mut encoded = MemWriter::new();
+        JPEGEncoder::new(&mut encoded).encode(&image[], width, height, color::ColorType::RGB(8)).unwrap();
+        let encoded = encoded.into_inner();
+
+        let original = JPEGDecoder::new(MemReader::new(encoded.clone())).read_coefficients().unwrap();
+
+        let mut rotated = MemWriter::new();
+        transform_lossless(MemReader::new(encoded), &mut rotated, Transform::Rotate90).unwrap();
+
+        let rotated = JPEGDecoder::new(MemReader::new(rotated.into_inner())).read_coefficients().unwrap();
+        assert_eq!((rotated.width, rotated.height), (16, 32));
+
+        let restored = transform_coefficients(&rotated, Transform::Rotate270).unwrap();
+        assert_eq!((restored.width, restored.height), (32, 16));
+
+        for (a, b) in original.components.iter().zip(restored.components.iter()) {
+            assert_eq!(a.blocks.len(), b.blocks.len());
+
+            for (x, y) in a.blocks.iter().zip(b.blocks.iter()) {
+                assert_eq!(&x[], &y[]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flips_and_transpositions() {
+        let encoded = encode(32, 16, ChromaSubsampling::Ratio444);
+        let original = read_coefficients(&encoded[]);
+
+        for &t in [Transform::FlipHorizontal, Transform::FlipVertical,
+                   Transform::Transpose, Transform::Transverse, Transform::Rotate180].iter() {
+            // The inverse DCT of the decoder rounds differently along rows and columns
+            let transformed = transform(&encoded[], t);
+            assert_transformed(&encoded[], t, 32, 16, &transformed[], 2);
+
+            // Each of the transformations undoes itself
+            let restored = transform_coefficients(&read_coefficients(&transformed[]), t).unwrap();
+            assert_same_blocks(&restored, &original);
+        }
+    }
+
+    #[test]
+    fn test_subsampled_edges() {
+        // The partial MCUs that would move to the left or top edge are trimmed
+        let encoded = encode(37, 21, ChromaSubsampling::Ratio420);
+
+        let cases = [(Transform::FlipHorizontal, 32, 21),
+                     (Transform::FlipVertical, 37, 16),
+                     (Transform::Transpose, 37, 21),
+                     (Transform::Transverse, 32, 16),
+                     (Transform::Rotate90, 37, 16),
+                     (Transform::Rotate180, 32, 16),
+                     (Transform::Rotate270, 32, 21)];
+
+        for &(t, width, height) in cases.iter() {
+            let transformed = transform(&encoded[], t);
+            assert_transformed(&encoded[], t, width, height, &transformed[], 2);
+
+            let coefficients = read_coefficients(&transformed[]);
+            let sampling = coefficients.components.iter().map(|c| (c.component.h, c.component.v)).collect::<Vec<(u8, u8)>>();
+            assert_eq!(sampling, vec![(2, 2), (1, 1), (1, 1)]);
+        }
+    }
+
+    #[test]
+    fn test_crop() {
+        // The corner moves to the MCU boundary at 16, 0, the far edges are kept
+        let encoded = encode(48, 32, ChromaSubsampling::Ratio420);
+        let cropped = transform(&encoded[], Transform::Crop(21, 9, 20, 15));
+
+        let (width, height, _) = decode(&cropped[]);
+        assert_eq!((width, height), (25, 24));
+
+        // Only the blocks are copied, the pixels next to the cut differ by the upsampled chroma
+        let original = read_coefficients(&encoded[]);
+        let coefficients = read_coefficients(&cropped[]);
+
+        for (a, b) in original.components.iter().zip(coefficients.components.iter()) {
+            let (h, v) = (b.component.h as usize, b.component.v as usize);
+            assert_eq!((b.blocks_w, b.blocks_h), (2 * h, 2 * v));
+
+            for y in (0..b.blocks_h) {
+                for x in (0..b.blocks_w) {
+                    assert_eq!(&b.blocks[y * b.blocks_w + x][], &a.blocks[y * a.blocks_w + x + h][]);
+                }
+            }
+        }
+
+        assert_transformed(&encoded[], Transform::Crop(16, 0, 25, 24), 25, 24, &cropped[], 2);
+
+        assert!(transform_coefficients(&original, Transform::Crop(48, 0, 8, 8)).is_err());
+    }
+
+    #[test]
+    fn test_progressive_restart_exif() {
+        // Little endian EXIF metadata with the orientation 6 as its only tag
+        let exif = vec![b'E', b'x', b'i', b'f', 0, 0, b'I', b'I', 42, 0, 8, 0, 0, 0,
+                        1, 0, 0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut original = read_coefficients(&encode(32, 32, ChromaSubsampling::Ratio420)[]);
+        original.markers.push((0xE1, exif));
+
+        let mut m = MemWriter::new();
+        {
+            let mut encoder = JPEGEncoder::new(&mut m);
+            encoder.set_progressive(true);
+            encoder.set_restart_interval(2);
+            encoder.encode_coefficients(&original).unwrap();
+        }
+        let encoded = m.into_inner();
+
+        for &(t, orientation) in [(Transform::Rotate90, 1), (Transform::Crop(0, 0, 16, 16), 6)].iter() {
+            let transformed = transform(&encoded[], t);
+
+            let mut decoder = JPEGDecoder::new(MemReader::new(transformed.clone()));
+            assert_eq!(decoder.exif().unwrap().and_then(|e| e.orientation()), Some(orientation));
+
+            let coefficients = read_coefficients(&transformed[]);
+            assert!(coefficients.progressive);
+            assert_eq!(coefficients.restart_interval, 2);
+            assert_same_blocks(&coefficients, &transform_coefficients(&original, t).unwrap());
+        }
+    }
+}
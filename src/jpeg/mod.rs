@@ -1,7 +1,7 @@
 //! Decoding and Encoding of JPEG Images
 //!
 //! JPEG (Joint Photographic Experts Group) is an image format that supports lossy compression.
-//! This module implements the Baseline JPEG standard.
+//! This module implements the Baseline and Progressive JPEG standard.
 //!
 //! #Related Links
 //! * http://www.w3.org/Graphics/JPEG/itu-t81.pdf - The JPEG specification
@@ -9,9 +9,12 @@
 
 pub use self::decoder::JPEGDecoder;
 pub use self::encoder::JPEGEncoder;
-pub use self::decoder::Component;
+pub use self::encoder::{DEFAULT_QUALITY, ChromaSubsampling, Scan, default_scan_script};
+pub use self::decoder::{Component, Coefficients, ComponentCoefficients};
+pub use self::lossless::{Transform, transform_lossless, transform_coefficients};
 
 mod encoder;
 mod decoder;
 mod entropy;
 mod transform;
+mod lossless;
@@ -1,6 +1,8 @@
 // The forward dct's output coefficients are scaled by 8
 // The inverse dct's output samples are clamped to the range [0, 255]
 
+use std::num::Float;
+
 fn level_shift_up(a: i32) -> u8 {
     if a < -128 {0u8}
     else if a > 127 {255u8}
@@ -356,3 +358,143 @@ pub fn idct(coeffs: &[i32], samples: &mut [u8]) {
         samples[y0 + 4] = level_shift_up(a);
     }
 }
+
+// Transforms the coefficients to a block of ```size``` by ```size``` samples.
+// Blocks smaller than 8 by 8 only use the lowest frequencies, which scales them down.
+pub fn idct_scaled(coeffs: &[i32], samples: &mut [u8], size: usize) {
+    match size {
+        8 => idct(coeffs, samples),
+        4 => idct_4x4(coeffs, samples),
+        2 => idct_2x2(coeffs, samples),
+        _ => idct_1x1(coeffs, samples),
+    }
+}
+
+// The reduced inverse dcts are translations of jpeg_idct_4x4, jpeg_idct_2x2
+// and jpeg_idct_1x1 of jidctint.c of the IJG's libjpeg version 9a
+fn idct_4x4(coeffs: &[i32], samples: &mut [u8]) {
+    let mut tmp = [0i32; 16];
+
+    // Pass 1: process the columns
+    for x in (0us..4) {
+        // Even part
+        let t0 = coeffs[x + 8 * 0];
+        let t2 = coeffs[x + 8 * 2];
+
+        let t10 = (t0 + t2) << PASS1_BITS as usize;
+        let t12 = (t0 - t2) << PASS1_BITS as usize;
+
+        // Odd part
+        let z2 = coeffs[x + 8 * 1];
+        let z3 = coeffs[x + 8 * 3];
+
+        let z1 = (z2 + z3) * FIX_0_541196100 + (1 << (CONST_BITS - PASS1_BITS - 1) as usize);
+        let t0 = (z1 + z2 * FIX_0_765366865) >> (CONST_BITS - PASS1_BITS) as usize;
+        let t2 = (z1 - z3 * FIX_1_847759065) >> (CONST_BITS - PASS1_BITS) as usize;
+
+        tmp[x + 4 * 0] = t10 + t0;
+        tmp[x + 4 * 3] = t10 - t0;
+        tmp[x + 4 * 1] = t12 + t2;
+        tmp[x + 4 * 2] = t12 - t2;
+    }
+
+    // Pass 2: process the rows
+    for y in (0us..4) {
+        let y0 = y * 4;
+
+        // Even part
+        let t0 = tmp[y0 + 0] + (1 << (PASS1_BITS + 2) as usize);
+        let t2 = tmp[y0 + 2];
+
+        let t10 = (t0 + t2) << CONST_BITS as usize;
+        let t12 = (t0 - t2) << CONST_BITS as usize;
+
+        // Odd part
+        let z2 = tmp[y0 + 1];
+        let z3 = tmp[y0 + 3];
+
+        let z1 = (z2 + z3) * FIX_0_541196100;
+        let t0 = z1 + z2 * FIX_0_765366865;
+        let t2 = z1 - z3 * FIX_1_847759065;
+
+        samples[y0 + 0] = level_shift_up((t10 + t0) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+        samples[y0 + 3] = level_shift_up((t10 - t0) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+        samples[y0 + 1] = level_shift_up((t12 + t2) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+        samples[y0 + 2] = level_shift_up((t12 - t2) >> (CONST_BITS + PASS1_BITS + 3) as usize);
+    }
+}
+
+fn idct_2x2(coeffs: &[i32], samples: &mut [u8]) {
+    // Pass 1: process the columns
+    let t4 = coeffs[8 * 0] + (1 << 2);
+    let t5 = coeffs[8 * 1];
+
+    let t0 = t4 + t5;
+    let t2 = t4 - t5;
+
+    let t4 = coeffs[8 * 0 + 1];
+    let t5 = coeffs[8 * 1 + 1];
+
+    let t1 = t4 + t5;
+    let t3 = t4 - t5;
+
+    // Pass 2: process the rows
+    samples[0] = level_shift_up((t0 + t1) >> 3);
+    samples[1] = level_shift_up((t0 - t1) >> 3);
+    samples[2] = level_shift_up((t2 + t3) >> 3);
+    samples[3] = level_shift_up((t2 - t3) >> 3);
+}
+
+fn idct_1x1(coeffs: &[i32], samples: &mut [u8]) {
+    // Only the dc coefficient contributes to the sample
+    samples[0] = level_shift_up((coeffs[0] + (1 << 2)) >> 3);
+}
+
+// The basis functions of the inverse dct, indexed by the sample and then the frequency.
+// The value for sample x and frequency u is C(u) / 2 * cos((2x + 1) * u * pi / 16).
+static IDCT_BASIS: [f32; 64] = [
+    0.353553391,  0.490392640,  0.461939766,  0.415734806,  0.353553391,  0.277785117,  0.191341716,  0.097545161,
+    0.353553391,  0.415734806,  0.191341716, -0.097545161, -0.353553391, -0.490392640, -0.461939766, -0.277785117,
+    0.353553391,  0.277785117, -0.191341716, -0.490392640, -0.353553391,  0.097545161,  0.461939766,  0.415734806,
+    0.353553391,  0.097545161, -0.461939766, -0.277785117,  0.353553391,  0.415734806, -0.191341716, -0.490392640,
+    0.353553391, -0.097545161, -0.461939766,  0.277785117,  0.353553391, -0.415734806, -0.191341716,  0.490392640,
+    0.353553391, -0.277785117, -0.191341716,  0.490392640, -0.353553391, -0.097545161,  0.461939766, -0.415734806,
+    0.353553391, -0.415734806,  0.191341716,  0.097545161, -0.353553391,  0.490392640, -0.461939766,  0.277785117,
+    0.353553391, -0.490392640,  0.461939766, -0.415734806,  0.353553391, -0.277785117,  0.191341716, -0.097545161,
+];
+
+// A floating point inverse dct for samples with a precision of more than 8 bits,
+// whose coefficients would overflow the integer transform.
+// The output samples are clamped to the range [0, 2^precision - 1].
+pub fn idct_precise(coeffs: &[i32], samples: &mut [u16], precision: u8) {
+    let mut tmp = [0f32; 64];
+
+    // Pass 1: process the rows
+    for v in (0us..8) {
+        for x in (0us..8) {
+            let mut sum = 0f32;
+
+            for u in (0us..8) {
+                sum += IDCT_BASIS[x * 8 + u] * coeffs[v * 8 + u] as f32;
+            }
+
+            tmp[v * 8 + x] = sum;
+        }
+    }
+
+    let center = (1i32 << (precision - 1) as usize) as f32;
+    let max = ((1i32 << precision as usize) - 1) as f32;
+
+    // Pass 2: process the columns
+    for y in (0us..8) {
+        for x in (0us..8) {
+            let mut sum = center;
+
+            for v in (0us..8) {
+                sum += IDCT_BASIS[y * 8 + v] * tmp[v * 8 + x];
+            }
+
+            samples[y * 8 + x] = sum.round().max(0f32).min(max) as u16;
+        }
+    }
+}
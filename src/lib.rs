@@ -46,6 +46,7 @@ pub use image::ImageDecoder as ImageDecoder;
 pub use image::ImageError as ImageError;
 pub use image::ImageResult as ImageResult;
 pub use image::ImageFormat as ImageFormat;
+pub use image::ImageOutputFormat as ImageOutputFormat;
 pub use imageops::FilterType as FilterType;
 
 pub use imageops:: {
@@ -87,7 +88,9 @@ pub use image::MutPixels as MutPixels;
 /// Opening and loading images
 pub use dynimage:: {
     open,
+    open_oriented,
     load,
+    load_oriented,
     load_from_memory,
     load_from_memory_with_format,
     save_buffer,
@@ -0,0 +1,259 @@
+//! Animated PNG images
+//!
+//! The ```acTL``` chunk marks an image as animated. Every frame is described by an ```fcTL```
+//! chunk and stored in ```fdAT``` chunks after the image data, which is the first frame
+//! if an ```fcTL``` chunk precedes it. All ```fcTL``` and ```fdAT``` chunks share one
+//! sequence of numbers.
+//!
+//! # Related Links
+//! * https://wiki.mozilla.org/APNG_Specification - The APNG specification
+
+use std::cmp;
+use std::num::FromPrimitive;
+
+use num::rational::Ratio;
+
+use image::{ImageError, ImageResult};
+
+/// The animation control of an animated image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AnimationControl {
+    /// The number of frames
+    pub num_frames: u32,
+
+    /// The number of times the animation is played, 0 repeats it infinitely
+    pub num_plays: u32,
+}
+
+/// How the area of a frame is disposed before the next frame is rendered
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+pub enum DisposeOp {
+    /// The area is left unchanged
+    None = 0,
+
+    /// The area is cleared to transparent black
+    Background = 1,
+
+    /// The area is restored to its content before the frame was rendered
+    Previous = 2,
+}
+
+/// How a frame is combined with the area it covers
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+pub enum BlendOp {
+    /// The frame replaces the area
+    Source = 0,
+
+    /// The frame is composited over the area
+    Over = 1,
+}
+
+/// The frame control of an animation frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameControl {
+    /// The number of the ```fcTL``` chunk in the sequence of ```fcTL``` and ```fdAT``` chunks
+    pub sequence_number: u32,
+    /// The width of the frame
+    pub width: u32,
+    /// The height of the frame
+    pub height: u32,
+    /// The x offset of the frame on the canvas
+    pub x_offset: u32,
+    /// The y offset of the frame on the canvas
+    pub y_offset: u32,
+    /// The numerator of the delay in seconds
+    pub delay_num: u16,
+    /// The denominator of the delay in seconds, 0 stands for 100
+    pub delay_den: u16,
+    /// How the frame is disposed
+    pub dispose_op: DisposeOp,
+    /// How the frame is blended
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    /// The delay of the frame in seconds
+    pub fn delay(&self) -> Ratio<u16> {
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den };
+
+        Ratio::new(self.delay_num, den)
+    }
+
+    /// Checks that the frame lies within a canvas of ```width``` and ```height```
+    pub fn check_bounds(&self, width: u32, height: u32) -> ImageResult<()> {
+        if self.width == 0 || self.height == 0 ||
+           self.x_offset as u64 + self.width as u64 > width as u64 ||
+           self.y_offset as u64 + self.height as u64 > height as u64 {
+            return Err(ImageError::FormatError("APNG frame exceeds the image.".to_string()))
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a big endian u32 from the start of ```data```
+pub fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+/// Decodes an ```acTL``` chunk
+pub fn decode_actl(data: &[u8]) -> ImageResult<AnimationControl> {
+    if data.len() != 8 || read_u32(data) == 0 {
+        return Err(ImageError::FormatError("Animation control chunk malformed.".to_string()))
+    }
+
+    Ok(AnimationControl {
+        num_frames: read_u32(data),
+        num_plays: read_u32(&data[4..]),
+    })
+}
+
+/// Decodes an ```fcTL``` chunk
+pub fn decode_fctl(data: &[u8]) -> ImageResult<FrameControl> {
+    if data.len() != 26 {
+        return Err(ImageError::FormatError("Frame control chunk malformed.".to_string()))
+    }
+
+    let dispose_op = match FromPrimitive::from_u8(data[24]) {
+        Some(op) => op,
+        None => return Err(ImageError::FormatError(format!("Unknown dispose operation {}.", data[24])))
+    };
+
+    let blend_op = match FromPrimitive::from_u8(data[25]) {
+        Some(op) => op,
+        None => return Err(ImageError::FormatError(format!("Unknown blend operation {}.", data[25])))
+    };
+
+    Ok(FrameControl {
+        sequence_number: read_u32(data),
+        width: read_u32(&data[4..]),
+        height: read_u32(&data[8..]),
+        x_offset: read_u32(&data[12..]),
+        y_offset: read_u32(&data[16..]),
+        delay_num: read_u16(&data[20..]),
+        delay_den: read_u16(&data[22..]),
+        dispose_op: dispose_op,
+        blend_op: blend_op,
+    })
+}
+
+/// Encodes an ```fcTL``` chunk
+pub fn encode_fctl(control: &FrameControl) -> Vec<u8> {
+    let mut data = Vec::with_capacity(26);
+
+    for &v in [control.sequence_number, control.width, control.height,
+               control.x_offset, control.y_offset].iter() {
+        data.push_all(&write_u32(v));
+    }
+
+    for &v in [control.delay_num, control.delay_den].iter() {
+        data.push_all(&[(v >> 8) as u8, v as u8]);
+    }
+
+    data.push(control.dispose_op as u8);
+    data.push(control.blend_op as u8);
+
+    data
+}
+
+/// Stores ```v``` in big endian byte order
+pub fn write_u32(v: u32) -> [u8; 4] {
+    [(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+/// Returns the smallest rectangle ```(x, y, width, height)``` that contains every
+/// pixel that differs between the RGBA canvases ```previous``` and ```current```.
+/// Unchanged canvases yield the top left pixel, as frames can not be empty.
+pub fn changed_region(previous: &[u8], current: &[u8], width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let (mut x0, mut y0, mut x1, mut y1) = (width, height, 0, 0);
+
+    for y in (0..height) {
+        for x in (0..width) {
+            let i = ((y * width + x) * 4) as usize;
+
+            if &previous[i..i + 4] != &current[i..i + 4] {
+                x0 = cmp::min(x0, x);
+                y0 = cmp::min(y0, y);
+                x1 = cmp::max(x1, x + 1);
+                y1 = cmp::max(y1, y + 1);
+            }
+        }
+    }
+
+    if x1 == 0 {
+        (0, 0, 1, 1)
+    } else {
+        (x0, y0, x1 - x0, y1 - y0)
+    }
+}
+
+/// Copies the rectangle of the RGBA canvas at ```x```, ```y``` with ```width``` and ```height```
+pub fn crop_canvas(canvas: &[u8], canvas_width: u32, x: u32, y: u32, width: u32, height: u32) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((4 * width * height) as usize);
+
+    for row in (y..y + height) {
+        let start = ((row * canvas_width + x) * 4) as usize;
+        pixels.push_all(&canvas[start..start + 4 * width as usize]);
+    }
+
+    pixels
+}
+
+/// Renders the RGBA pixels of a frame onto the RGBA canvas
+pub fn composite_frame(canvas: &mut [u8], canvas_width: u32, frame: &[u8], control: &FrameControl) {
+    for y in (0..control.height) {
+        for x in (0..control.width) {
+            let src = ((y * control.width + x) * 4) as usize;
+            let dst = (((control.y_offset + y) * canvas_width + control.x_offset + x) * 4) as usize;
+
+            let pixel = match control.blend_op {
+                BlendOp::Source => [frame[src], frame[src + 1], frame[src + 2], frame[src + 3]],
+                BlendOp::Over => blend_pixel(&canvas[dst..dst + 4], &frame[src..src + 4])
+            };
+
+            for c in (0us..4) {
+                canvas[dst + c] = pixel[c];
+            }
+        }
+    }
+}
+
+/// Clears the area covered by the frame to transparent black
+pub fn clear_frame(canvas: &mut [u8], canvas_width: u32, control: &FrameControl) {
+    for y in (control.y_offset..control.y_offset + control.height) {
+        let start = ((y * canvas_width + control.x_offset) * 4) as usize;
+
+        for v in canvas[start..start + 4 * control.width as usize].iter_mut() {
+            *v = 0;
+        }
+    }
+}
+
+// Non-premultiplied src-over compositing
+fn blend_pixel(dst: &[u8], src: &[u8]) -> [u8; 4] {
+    let src_a = src[3] as u32;
+    let dst_a = dst[3] as u32;
+
+    // dst.A * (1 - src.A / 255), scaled by 255
+    let dst_factor = dst_a * (255 - src_a);
+    let blend_a = src_a * 255 + dst_factor;
+
+    if blend_a == 0 {
+        return [0, 0, 0, 0]
+    }
+
+    let mut pixel = [0u8; 4];
+
+    for c in (0us..3) {
+        let value = (src[c] as u32 * src_a * 255 + dst[c] as u32 * dst_factor) / blend_a;
+        pixel[c] = value as u8;
+    }
+
+    pixel[3] = ((blend_a + 127) / 255) as u8;
+
+    pixel
+}
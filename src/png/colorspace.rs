@@ -0,0 +1,129 @@
+//! Color space information of PNG images
+//!
+//! ```gAMA``` and ```cHRM``` chunks describe the transfer function and the primaries
+//! of an image, ```sRGB``` and ```iCCP``` chunks replace them with the sRGB color space
+//! or an ICC profile.
+//!
+//! # Related Links
+//! * http://www.w3.org/TR/PNG/#11addnlcolinfo - Colour space information
+
+use std::num::Float;
+
+// gAMA and cHRM values are stored as multiples of 1/100000
+const SCALE: f32 = 100000.0;
+
+/// The rendering intent of images in the sRGB color space
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+pub enum RenderingIntent {
+    /// For images preferring good adaptation to the output device gamut at the expense of colorimetric accuracy, such as photographs
+    Perceptual = 0,
+
+    /// For images requiring colour appearance matching relative to the output device white point, such as logos
+    RelativeColorimetric = 1,
+
+    /// For images preferring preservation of saturation at the expense of hue and lightness, such as charts and graphs
+    Saturation = 2,
+
+    /// For images requiring preservation of absolute colorimetry, such as previews of images destined for a different output device
+    AbsoluteColorimetric = 3,
+}
+
+/// The CIE 1931 x and y coordinates of the white point and the primaries
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Chromaticities {
+    /// The white point
+    pub white: (f32, f32),
+    /// The red primary
+    pub red: (f32, f32),
+    /// The green primary
+    pub green: (f32, f32),
+    /// The blue primary
+    pub blue: (f32, f32),
+}
+
+/// The color space chunks of an image
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorInfo {
+    /// The exponent of the ```gAMA``` chunk that relates the samples to the
+    /// light intensity, e.g. ```0.45455``` for images encoded for a display gamma of 2.2
+    pub gamma: Option<f32>,
+
+    /// The chromaticities of the ```cHRM``` chunk
+    pub chromaticities: Option<Chromaticities>,
+
+    /// The rendering intent of the ```sRGB``` chunk
+    pub srgb: Option<RenderingIntent>,
+
+    /// The decompressed profile of the ```iCCP``` chunk
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl ColorInfo {
+    /// Create color information without any color space chunk
+    pub fn new() -> ColorInfo {
+        ColorInfo {
+            gamma: None,
+            chromaticities: None,
+            srgb: None,
+            icc_profile: None,
+        }
+    }
+}
+
+fn read_value(data: &[u8]) -> f32 {
+    let v = (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32;
+
+    v as f32 / SCALE
+}
+
+/// Stores ```value``` as a multiple of 1/100000
+pub fn write_value(buf: &mut Vec<u8>, value: f32) {
+    let v = (value * SCALE).round() as u32;
+
+    buf.push_all(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+/// Decodes a ```gAMA``` chunk, a gamma of zero is invalid
+pub fn decode_gamma(data: &[u8]) -> Option<f32> {
+    if data.len() != 4 {
+        return None
+    }
+
+    let gamma = read_value(data);
+
+    if gamma > 0.0 { Some(gamma) } else { None }
+}
+
+/// Decodes a ```cHRM``` chunk
+pub fn decode_chromaticities(data: &[u8]) -> Option<Chromaticities> {
+    if data.len() != 32 {
+        return None
+    }
+
+    let v = data.chunks(4).map(|c| read_value(c)).collect::<Vec<f32>>();
+
+    Some(Chromaticities {
+        white: (v[0], v[1]),
+        red: (v[2], v[3]),
+        green: (v[4], v[5]),
+        blue: (v[6], v[7]),
+    })
+}
+
+/// Builds a table that converts samples of ```depth``` bits encoded with
+/// the exponent ```gamma``` to the sRGB transfer function
+pub fn build_srgb_table(gamma: f32, depth: usize) -> Vec<u16> {
+    let max = ((1u32 << depth) - 1) as f32;
+
+    (0..1u32 << depth).map(|v| {
+        let linear = (v as f32 / max).powf(1.0 / gamma);
+
+        let srgb = if linear <= 0.0031308 {
+            12.92 * linear
+        } else {
+            1.055 * linear.powf(1.0 / 2.4) - 0.055
+        };
+
+        (srgb * max).round() as u16
+    }).collect()
+}
@@ -1,7 +1,5 @@
 use std::old_io;
 use std::cmp;
-use std::mem;
-use std::iter;
 use std::iter::repeat;
 use std::str;
 use std::slice;
@@ -16,10 +14,16 @@ use image::{
     ImageError
 };
 use color;
+use buffer::ImageBuffer;
+use animation::{Frame, Frames};
+use dynimage::decoder_to_image;
 
 use super::filter::unfilter;
 use super::hash::Crc32;
 use super::zlib::ZlibDecoder;
+use super::text::{self, TextEntry};
+use super::colorspace::{self, ColorInfo};
+use super::apng::{self, AnimationControl, DisposeOp};
 
 use std::num::Float;
 
@@ -137,6 +141,13 @@ pub struct PNGDecoder<R> {
     pixel_type: color::ColorType,
 
     palette: Option<Vec<(u8, u8, u8)>>,
+    trns: Option<Vec<u8>>,
+    color_info: ColorInfo,
+    gamma_table: Option<Vec<u16>>,
+    text: Vec<TextEntry>,
+
+    animation: Option<AnimationControl>,
+    first_frame: Option<Vec<u8>>,
 
     interlace_method: InterlaceMethod,
     pass_iterator: Option<Adam7Iterator>,
@@ -157,6 +168,13 @@ impl<R: Reader> PNGDecoder<R> {
         PNGDecoder {
             pixel_type: color::ColorType::Gray(1),
             palette: None,
+            trns: None,
+            color_info: ColorInfo::new(),
+            gamma_table: None,
+            text: Vec::new(),
+
+            animation: None,
+            first_frame: None,
 
             previous: Vec::new(),
             state: PNGState::Start,
@@ -189,6 +207,188 @@ impl<R: Reader> PNGDecoder<R> {
         }
     }
 
+    /// Returns the ICC profile of the image, if it contains a valid ```iCCP``` chunk.
+    pub fn icc_profile(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(self.color_info.icc_profile.clone())
+    }
+
+    /// Returns the information of the ```gAMA```, ```cHRM```, ```sRGB``` and ```iCCP``` chunks.
+    /// Malformed chunks are ignored.
+    pub fn color_info(&mut self) -> ImageResult<&ColorInfo> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(&self.color_info)
+    }
+
+    /// Converts the samples of images with a ```gAMA``` chunk to the sRGB transfer function
+    /// while decoding. Images whose color space is given by an ```sRGB``` or ```iCCP``` chunk
+    /// are left unchanged, as are alpha samples.
+    pub fn set_convert_to_srgb(&mut self, convert: bool) -> ImageResult<()> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        let info = &self.color_info;
+
+        self.gamma_table = match info.gamma {
+            Some(gamma) if convert && info.srgb.is_none() && info.icc_profile.is_none() => {
+                let depth = color::bits_per_pixel(self.pixel_type) / color::num_components(self.pixel_type);
+
+                Some(colorspace::build_srgb_table(gamma, depth))
+            }
+            _ => None
+        };
+
+        Ok(())
+    }
+
+    /// Returns the entries of the ```tEXt```, ```zTXt``` and ```iTXt``` chunks
+    /// that precede the image data. Malformed chunks are skipped.
+    pub fn text(&mut self) -> ImageResult<&[TextEntry]> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(&self.text[])
+    }
+
+    /// Returns the ```acTL``` chunk of animated images
+    pub fn animation_control(&mut self) -> ImageResult<Option<AnimationControl>> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(self.animation)
+    }
+
+    // A decoder for the image data of an animation frame, which is stored like
+    // the image data of the image itself. The data is wrapped in an IDAT chunk
+    // that is followed by the header of an IEND chunk.
+    fn frame_decoder(&self, width: u32, height: u32, data: Vec<u8>) -> PNGDecoder<MemReader> {
+        let mut crc = Crc32::new();
+        crc.update("IDAT");
+        crc.update(&data[]);
+
+        let length = data.len() as u32;
+        let checksum = crc.checksum();
+
+        let mut stream = data;
+        stream.push_all(&[(checksum >> 24) as u8, (checksum >> 16) as u8, (checksum >> 8) as u8, checksum as u8]);
+        stream.push_all(&[0, 0, 0, 0]);
+        stream.push_all(b"IEND");
+
+        let mut decoder = PNGDecoder::new(MemReader::new(stream));
+
+        decoder.state = PNGState::HaveFirstIDat;
+        decoder.width = width;
+        decoder.height = height;
+        decoder.bit_depth = self.bit_depth;
+        decoder.colour_type = self.colour_type;
+        decoder.pixel_type = self.pixel_type;
+        decoder.palette = self.palette.clone();
+        decoder.trns = self.trns.clone();
+        decoder.gamma_table = self.gamma_table.clone();
+        decoder.interlace_method = self.interlace_method;
+        decoder.bits_per_pixel = self.bits_per_pixel;
+        decoder.bpp = self.bpp;
+
+        if self.interlace_method == InterlaceMethod::Adam7 {
+            decoder.pass_iterator = Some(Adam7Iterator::new(width, height));
+        }
+
+        decoder.previous = repeat(0u8).take(decoder.raw_row_length(width) as usize).collect();
+
+        decoder.z.inner().set_inital_length(length);
+        decoder.z.inner().crc.update("IDAT");
+
+        decoder
+    }
+
+    // Reads the frame controls and the image data of the frames that follow the image data
+    // of the image itself. Returns every frame with its data, the image data is the first
+    // frame if an fcTL chunk precedes it.
+    fn read_frames(&mut self) -> ImageResult<Vec<(apng::FrameControl, Vec<u8>)>> {
+        let idat = try!(self.z.inner().read_to_end());
+        let mut frames = Vec::new();
+
+        if let Some(ref data) = self.first_frame {
+            let control = try!(apng::decode_fctl(&data[]));
+
+            if control.sequence_number != 0 ||
+               control.width != self.width || control.height != self.height ||
+               control.x_offset != 0 || control.y_offset != 0 {
+                return Err(ImageError::FormatError("The first APNG frame has to cover the image.".to_string()))
+            }
+
+            frames.push((control, idat));
+        }
+
+        // The image data of the image itself is not continued by fdAT chunks
+        let mut sequence = frames.len() as u32;
+        let mut accepts_fdat = false;
+
+        let (mut length, mut chunk) = self.z.inner().next_chunk();
+
+        loop {
+            let data = try!(self.z.inner().r.read_exact(length as usize));
+            let chunk_crc = try!(self.z.inner().r.read_be_u32());
+
+            self.crc.reset();
+            self.crc.update(&chunk[]);
+            self.crc.update(&data[]);
+
+            if self.crc.checksum() != chunk_crc {
+                return Err(ImageError::FormatError("CRC checksum invalid.".to_string()))
+            }
+
+            match &chunk[] {
+                b"fcTL" | b"fdAT" => {
+                    if data.len() < 4 || apng::read_u32(&data[]) != sequence {
+                        return Err(ImageError::FormatError("APNG sequence number out of order.".to_string()))
+                    }
+
+                    sequence += 1;
+                }
+
+                b"IEND" => break,
+                _ => ()
+            }
+
+            match &chunk[] {
+                b"fcTL" => {
+                    let control = try!(apng::decode_fctl(&data[]));
+                    let _ = try!(control.check_bounds(self.width, self.height));
+
+                    frames.push((control, Vec::new()));
+                    accepts_fdat = true;
+                }
+
+                b"fdAT" => {
+                    if !accepts_fdat {
+                        return Err(ImageError::FormatError("fdAT chunk without fcTL chunk.".to_string()))
+                    }
+
+                    if let Some(&mut (_, ref mut frame)) = frames.last_mut() {
+                        frame.push_all(&data[4..]);
+                    }
+                }
+
+                _ => ()
+            }
+
+            length = try!(self.z.inner().r.read_be_u32());
+            chunk = try!(self.z.inner().r.read_exact(4));
+        }
+
+        Ok(frames)
+    }
+
     fn read_signature(&mut self) -> ImageResult<bool> {
         let png = try!(self.z.inner().r.read_exact(8));
 
@@ -298,6 +498,35 @@ impl<R: Reader> PNGDecoder<R> {
         Ok(())
     }
 
+    // Palette images store an alpha value for each palette entry,
+    // gray and RGB images the 16 bit sample values of a transparent color.
+    // Pixels are decoded with an alpha channel if the chunk is present.
+    fn parse_trns(&mut self, buf: Vec<u8>) -> ImageResult<()> {
+        self.crc.update(&buf[]);
+
+        let valid = match self.colour_type {
+            0 => buf.len() == 2,
+            2 => buf.len() == 6,
+            3 => self.palette.is_some() && buf.len() <= 256,
+            _ => false
+        };
+
+        if !valid {
+            return Err(ImageError::FormatError("Transparency chunk malformed.".to_string()))
+        }
+
+        self.pixel_type = match self.pixel_type {
+            color::ColorType::Gray(16) => color::ColorType::GrayA(16),
+            color::ColorType::Gray(_)  => color::ColorType::GrayA(8),
+            color::ColorType::RGB(n)   => color::ColorType::RGBA(n),
+            c => c
+        };
+
+        self.trns = Some(buf);
+
+        Ok(())
+    }
+
     fn read_metadata(&mut self) -> ImageResult<()> {
         if !try!(self.read_signature()) {
             return Err(ImageError::FormatError("Could not read PNG signature.".to_string()))
@@ -332,9 +561,57 @@ impl<R: Reader> PNGDecoder<R> {
                     self.state = PNGState::HavePLTE;
                 }
 
-                // (b"tRNS", HavePLTE) => {
-                //     TODO #199: handle transparency
-                // }
+                (b"iCCP", PNGState::HaveIHDR) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    self.crc.update(&d[]);
+                    self.color_info.icc_profile = decompress_icc_profile(&d[]);
+                }
+
+                (b"gAMA", PNGState::HaveIHDR) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    self.crc.update(&d[]);
+                    self.color_info.gamma = colorspace::decode_gamma(&d[]);
+                }
+
+                (b"cHRM", PNGState::HaveIHDR) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    self.crc.update(&d[]);
+                    self.color_info.chromaticities = colorspace::decode_chromaticities(&d[]);
+                }
+
+                (b"sRGB", PNGState::HaveIHDR) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    self.crc.update(&d[]);
+                    self.color_info.srgb = if d.len() == 1 { FromPrimitive::from_u8(d[0]) } else { None };
+                }
+
+                (b"tEXt", _) | (b"zTXt", _) | (b"iTXt", _) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    self.crc.update(&d[]);
+
+                    if let Some(entry) = text::decode_text(&self.chunk_type[], &d[]) {
+                        self.text.push(entry);
+                    }
+                }
+
+                (b"acTL", PNGState::HaveIHDR) | (b"acTL", PNGState::HavePLTE) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    self.crc.update(&d[]);
+
+                    // A malformed animation leaves the image itself intact
+                    self.animation = apng::decode_actl(&d[]).ok();
+                }
+
+                (b"fcTL", PNGState::HaveIHDR) | (b"fcTL", PNGState::HavePLTE) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    self.crc.update(&d[]);
+                    self.first_frame = Some(d);
+                }
+
+                (b"tRNS", PNGState::HaveIHDR) | (b"tRNS", PNGState::HavePLTE) => {
+                    let d = try!(self.z.inner().r.read_exact(length as usize));
+                    try!(self.parse_trns(d));
+                }
 
                 (b"IDAT", PNGState::HaveIHDR) if self.colour_type != 3 => {
                     self.state = PNGState::HaveFirstIDat;
@@ -390,7 +667,14 @@ impl<R: Reader> PNGDecoder<R> {
         slice::bytes::copy_memory(&mut self.previous[], &buf[..rlength as usize]);
 
         if let Some(ref palette) = self.palette {
-            expand_palette(buf, &palette[], rlength as usize, self.bit_depth);
+            expand_palette(buf, &palette[], self.trns.as_ref().map(|t| &t[]), self.bit_depth);
+        } else if let Some(ref trns) = self.trns {
+            let channels = if self.colour_type == 2 { 3 } else { 1 };
+            expand_trns(buf, &trns[], channels, self.bit_depth);
+        }
+
+        if let Some(ref table) = self.gamma_table {
+            correct_gamma(buf, &table[], self.pixel_type);
         }
 
         self.decoded_rows += 1;
@@ -399,6 +683,61 @@ impl<R: Reader> PNGDecoder<R> {
     }
 }
 
+// The profile name is terminated by a NUL and followed by the compression method
+// and the zlib stream of the profile.
+// Malformed profiles are ignored as they are not needed to decode the image.
+fn decompress_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let end = match data.iter().position(|&b| b == 0) {
+        Some(end) => end,
+        None => return None
+    };
+
+    let compressed = &data[end + 1..];
+
+    if compressed.len() < 1 || compressed[0] != 0 {
+        return None
+    }
+
+    ZlibDecoder::new(MemReader::new(compressed[1..].to_vec())).read_to_end().ok()
+}
+
+// Converts the color samples of a row with ```table```, alpha samples are left unchanged.
+// Samples of less than 8 bits are only found in gray images.
+fn correct_gamma(buf: &mut [u8], table: &[u16], pixel_type: color::ColorType) {
+    let channels = color::num_components(pixel_type);
+    let depth = color::bits_per_pixel(pixel_type) / channels;
+
+    let alpha = match pixel_type {
+        color::ColorType::GrayA(_) | color::ColorType::RGBA(_) => true,
+        _ => false
+    };
+
+    for i in (0..buf.len() * 8 / depth) {
+        if alpha && i % channels == channels - 1 {
+            continue
+        }
+
+        match depth {
+            16 => {
+                let v = table[(buf[2 * i] as usize) << 8 | buf[2 * i + 1] as usize];
+                buf[2 * i] = (v >> 8) as u8;
+                buf[2 * i + 1] = v as u8;
+            }
+
+            8 => buf[i] = table[buf[i] as usize] as u8,
+
+            _ => {
+                let bit = i * depth;
+                let shift = 8 - depth - bit % 8;
+                let mask = ((1u16 << depth) - 1) as u8;
+                let v = table[((buf[bit / 8] >> shift) & mask) as usize] as u8;
+
+                buf[bit / 8] = buf[bit / 8] & !(mask << shift) | v << shift;
+            }
+        }
+    }
+}
+
 impl<R: Reader> ImageDecoder for PNGDecoder<R> {
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         if self.state == PNGState::Start {
@@ -416,6 +755,57 @@ impl<R: Reader> ImageDecoder for PNGDecoder<R> {
         Ok(self.pixel_type)
     }
 
+    fn is_animated(&mut self) -> ImageResult<bool> {
+        if self.state == PNGState::Start {
+            let _ = try!(self.read_metadata());
+        }
+
+        Ok(self.animation.is_some())
+    }
+
+    fn into_frames(self) -> ImageResult<Frames> {
+        let mut decoder = self;
+
+        if !try!(decoder.is_animated()) {
+            return Ok(Frames::new(vec![
+                Frame::new(try!(decoder_to_image(decoder)).to_rgba())
+            ]))
+        }
+
+        let (width, height) = (decoder.width, decoder.height);
+        let mut canvas: Vec<u8> = repeat(0u8).take(4 * width as usize
+                                                    * height as usize).collect();
+
+        let controls = try!(decoder.read_frames());
+        let mut frames = Vec::with_capacity(controls.len());
+
+        for (i, (control, data)) in controls.into_iter().enumerate() {
+            let frame_decoder = decoder.frame_decoder(control.width, control.height, data);
+            let frame = try!(decoder_to_image(frame_decoder)).to_rgba().into_raw();
+
+            // The previous content is only kept if it is restored afterwards,
+            // the first frame is disposed to the background instead
+            let previous = if control.dispose_op == DisposeOp::Previous && i > 0 {
+                Some(canvas.clone())
+            } else {
+                None
+            };
+
+            apng::composite_frame(&mut canvas[], width, &frame[], &control);
+
+            let buffer = ImageBuffer::from_raw(width, height, canvas.clone()).unwrap();
+            frames.push(Frame::from_parts(buffer, 0, 0, control.delay()));
+
+            match (control.dispose_op, previous) {
+                (DisposeOp::None, _) => (),
+                (_, Some(previous)) => canvas = previous,
+                (_, None) => apng::clear_frame(&mut canvas[], width, &control)
+            }
+        }
+
+        Ok(Frames::new(frames))
+    }
+
     fn row_len(&mut self) -> ImageResult<usize> {
         if self.state == PNGState::Start {
             let _ = try!(self.read_metadata());
@@ -505,35 +895,73 @@ fn expand_pass(
     }
 }
 
+// The pixels are expanded from the end of the row,
+// so the packed indices are read before they are overwritten.
+// Entries without an alpha value in ```trns``` are opaque.
 fn expand_palette(buf: &mut[u8], palette: &[(u8, u8, u8)],
-                  entries: usize, bit_depth: u8) {
-    let bpp = 8 / bit_depth as usize;
-    assert_eq!(buf.len(), 3 * (entries * bpp - buf.len() % bpp));
-    let mask = (1u8 << bit_depth as usize) - 1;
-    // Unsafe copy create two views into the vector
-    // This is unproblematic since it is only locally to this function and a &[u8]
-    let data = unsafe {
-        let view: &mut [u8] = mem::transmute_copy(&buf);
-        &view[..entries]
-    };
-    let pixels = data
-        .iter()
-        .rev() // Reverse iterator
-        .flat_map(|&v|
-            // This has to be reversed to
-            iter::range_step(0, 8, bit_depth)
-            .zip(iter::iterate(
-                v, |v| v
-            )
-        ))
-        //.skip(buf.len() % bpp) // not necessary, why!?
-        .map(|(shift, pixel)| (pixel & mask << shift as usize) >> shift as usize);
-    for (chunk, (r, g, b)) in buf.chunks_mut(3).rev().zip(pixels.map(|i|
-        palette[i as usize]
-    )) {
-        chunk[0] = r;
-        chunk[1] = g;
-        chunk[2] = b;
+                  trns: Option<&[u8]>, bit_depth: u8) {
+    let channels = if trns.is_some() { 4 } else { 3 };
+    let depth = bit_depth as usize;
+    let mask = ((1u16 << depth) - 1) as u8;
+
+    for i in (0..buf.len() / channels).rev() {
+        let bit = i * depth;
+        let index = ((buf[bit / 8] >> (8 - depth - bit % 8)) & mask) as usize;
+        let (r, g, b) = palette[index];
+
+        let pixel = &mut buf[i * channels..(i + 1) * channels];
+        pixel[0] = r;
+        pixel[1] = g;
+        pixel[2] = b;
+
+        if let Some(trns) = trns {
+            pixel[3] = if index < trns.len() { trns[index] } else { 255 };
+        }
+    }
+}
+
+// Adds an alpha channel to gray and RGB pixels, pixels that match the
+// key of the tRNS chunk are transparent. Gray samples of less than
+// 8 bits are scaled to 8 bits. Like palette indices the pixels are
+// expanded from the end of the row.
+fn expand_trns(buf: &mut[u8], key: &[u8], channels: usize, bit_depth: u8) {
+    let depth = bit_depth as usize;
+    let bytes = if depth == 16 { 2 } else { 1 };
+    let max = (1u32 << depth) - 1;
+    let pixel_len = (channels + 1) * bytes;
+
+    let key = (0..channels).map(|c| {
+        (key[2 * c] as u16) << 8 | key[2 * c + 1] as u16
+    }).collect::<Vec<u16>>();
+
+    for i in (0..buf.len() / pixel_len).rev() {
+        let mut samples = [0u16; 3];
+
+        for c in (0..channels) {
+            let bit = (i * channels + c) * depth;
+
+            samples[c] = if depth == 16 {
+                (buf[bit / 8] as u16) << 8 | buf[bit / 8 + 1] as u16
+            } else {
+                (buf[bit / 8] >> (8 - depth - bit % 8)) as u16 & max as u16
+            };
+        }
+
+        let alpha = if &samples[..channels] == &key[] { 0 } else { 0xFF };
+        let pixel = &mut buf[i * pixel_len..(i + 1) * pixel_len];
+
+        for c in (0..channels) {
+            if depth == 16 {
+                pixel[2 * c] = (samples[c] >> 8) as u8;
+                pixel[2 * c + 1] = samples[c] as u8;
+            } else {
+                pixel[c] = (samples[c] as u32 * 255 / max) as u8;
+            }
+        }
+
+        for v in pixel[channels * bytes..].iter_mut() {
+            *v = alpha;
+        }
     }
 }
 
@@ -543,6 +971,7 @@ pub struct IDATReader<R> {
 
     eof: bool,
     chunk_length: u32,
+    chunk_type: Vec<u8>,
 }
 
 impl<R:Reader> IDATReader<R> {
@@ -552,12 +981,19 @@ impl<R:Reader> IDATReader<R> {
             crc: Crc32::new(),
             eof: false,
             chunk_length: 0,
+            chunk_type: Vec::new(),
         }
     }
 
     pub fn set_inital_length(&mut self, len: u32) {
         self.chunk_length = len;
     }
+
+    /// The length and type of the chunk that follows the image data,
+    /// once all of the image data has been read
+    pub fn next_chunk(&self) -> (u32, Vec<u8>) {
+        (self.chunk_length, self.chunk_type.clone())
+    }
 }
 
 impl<R: Reader> Reader for IDATReader<R> {
@@ -593,6 +1029,7 @@ impl<R: Reader> Reader for IDATReader<R> {
 
                 let v = try!(self.r.read_exact(4));
                 self.crc.update(&v[]);
+                self.chunk_type = v.clone();
 
                 match str::from_utf8(&v[]) {
                     Ok("IDAT") => (),
@@ -614,9 +1051,11 @@ mod tests {
 
     use std::old_io;
     use std::result::Result;
-    use std::old_io::{File, MemReader};
+    use std::old_io::{File, MemReader, MemWriter};
     use test;
 
+    use color;
+
     use image::{
         ImageDecoder,
         ImageResult,
@@ -624,6 +1063,13 @@ mod tests {
     };
 
     use super::PNGDecoder;
+    use super::super::text::TextEntry;
+    use super::super::colorspace::{Chromaticities, RenderingIntent};
+    use super::super::apng::AnimationControl;
+    use animation::Frame;
+    use buffer::{Pixel, RgbaImage};
+    use num::rational::Ratio;
+    use super::super::encoder::PNGEncoder;
 
     /// Filters the testsuite images for certain features
     fn get_testimages(feature: &str, color_type: &str, test_interlaced: bool) -> Vec<Path> {
@@ -690,6 +1136,239 @@ mod tests {
         }
     }
 
+    #[test]
+    /// Transparency
+    fn test_transparency() {
+        let images = get_testimages("t", "", false);
+
+        for path in images.iter() {
+            let mut decoder = PNGDecoder::new(File::open(path));
+
+            // Only the tp0 images have no tRNS chunk
+            let alpha = match decoder.colortype().unwrap() {
+                color::ColorType::GrayA(_) | color::ColorType::RGBA(_) => true,
+                _ => false
+            };
+            assert_eq!(alpha, !path.filename_str().unwrap().starts_with("tp0"));
+
+            assert!(decoder.read_image().is_ok());
+        }
+
+        // The white pixels of this 4 bit gray image are transparent
+        let path = Path::new(".").join_many(&["src", "png", "testdata", "pngsuite", "tbbn0g04.png"]);
+        let mut decoder = PNGDecoder::new(File::open(&path));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::GrayA(8));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => {
+                let transparent = pixels.chunks(2).filter(|p| p[1] == 0).collect::<Vec<&[u8]>>();
+
+                assert_eq!(transparent.len(), 464);
+                assert!(transparent.iter().all(|p| p[0] == 255));
+            }
+            _ => panic!("expected 8 bit pixels")
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        let path = Path::new(".").join_many(&["src", "png", "testdata", "pngsuite", "ctzn0g04.png"]);
+        let mut decoder = PNGDecoder::new(File::open(&path));
+        {
+            let text = decoder.text().unwrap();
+            assert_eq!(text.len(), 6);
+            assert_eq!((&text[0].keyword[], &text[0].text[]), ("Title", "PngSuite"));
+            assert_eq!((&text[5].keyword[], &text[5].text[], text[5].compressed), ("Disclaimer", "Freeware.", true));
+        }
+        assert!(decoder.read_image().is_ok());
+
+        let mut international = TextEntry::new("Title", "Otsikko \u{3a4}");
+        international.language = "fi".to_string();
+        international.compressed = true;
+
+        let entries = vec![TextEntry::new("Author", "Caf\u{e9}"), international];
+
+        let mut encoded = MemWriter::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            for entry in entries.iter() {
+                encoder.add_text(entry.clone());
+            }
+            encoder.encode(&[0, 255, 255, 0], 2, 2, color::ColorType::Gray(8)).unwrap();
+        }
+
+        let mut decoder = PNGDecoder::new(MemReader::new(encoded.into_inner()));
+        assert_eq!(decoder.text().unwrap(), &entries[]);
+    }
+
+    #[test]
+    fn test_color_info() {
+        let path = Path::new(".").join_many(&["src", "png", "testdata", "pngsuite", "ccwn2c08.png"]);
+        let mut decoder = PNGDecoder::new(File::open(&path));
+        {
+            let info = decoder.color_info().unwrap();
+            assert_eq!(info.gamma, Some(1.0));
+            assert_eq!(info.chromaticities, Some(Chromaticities {
+                white: (0.3127, 0.329),
+                red: (0.64, 0.33),
+                green: (0.3, 0.6),
+                blue: (0.15, 0.06),
+            }));
+            assert_eq!(info.srgb, None);
+        }
+        assert!(decoder.read_image().is_ok());
+    }
+
+    fn encode_gray(gamma: Option<f32>, srgb: Option<RenderingIntent>) -> Vec<u8> {
+        let mut encoded = MemWriter::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            if let Some(gamma) = gamma {
+                encoder.set_gamma(gamma);
+            }
+            if let Some(intent) = srgb {
+                encoder.set_srgb(intent);
+            }
+            encoder.encode(&[0, 128, 255, 255], 2, 2, color::ColorType::Gray(8)).unwrap();
+        }
+        encoded.into_inner()
+    }
+
+    #[test]
+    fn test_convert_to_srgb() {
+        // Linear samples are converted
+        let mut decoder = PNGDecoder::new(MemReader::new(encode_gray(Some(1.0), None)));
+        decoder.set_convert_to_srgb(true).unwrap();
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, vec![0, 188, 255, 255]),
+            _ => panic!("expected 8 bit pixels")
+        }
+
+        // sRGB images are left unchanged
+        let mut decoder = PNGDecoder::new(MemReader::new(encode_gray(None, Some(RenderingIntent::Saturation))));
+        assert_eq!(decoder.color_info().unwrap().gamma, Some(0.45455));
+        assert_eq!(decoder.color_info().unwrap().srgb, Some(RenderingIntent::Saturation));
+        decoder.set_convert_to_srgb(true).unwrap();
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, vec![0, 128, 255, 255]),
+            _ => panic!("expected 8 bit pixels")
+        }
+    }
+
+    #[test]
+    fn test_animation() {
+        let path = Path::new(".").join_many(&["src", "png", "testdata", "animated.png"]);
+
+        // The image itself is the first frame
+        match PNGDecoder::new(File::open(&path)).read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert!(pixels.chunks(4).all(|p| p == &[255, 0, 0, 255][])),
+            _ => panic!("expected 8 bit pixels")
+        }
+
+        let mut decoder = PNGDecoder::new(File::open(&path));
+        assert!(decoder.is_animated().unwrap());
+        assert_eq!(decoder.animation_control().unwrap(), Some(AnimationControl { num_frames: 3, num_plays: 0 }));
+
+        let frames = decoder.into_frames().unwrap().collect::<Vec<Frame>>();
+        assert_eq!(frames.len(), 3);
+
+        let delays = frames.iter().map(|f| f.delay()).collect::<Vec<Ratio<u16>>>();
+        assert_eq!(delays, vec![Ratio::new(1, 10), Ratio::new(1, 5), Ratio::new(3, 10)]);
+
+        // The second frame is half transparent blue blended over the center,
+        // it is disposed to the previous content before the third frame
+        // replaces the top left pixel with green
+        assert_eq!(frames[1].buffer().get_pixel(1, 1).channels(), &[127, 0, 128, 255][]);
+        assert_eq!(frames[1].buffer().get_pixel(0, 0).channels(), &[255, 0, 0, 255][]);
+        assert_eq!(frames[2].buffer().get_pixel(0, 0).channels(), &[0, 255, 0, 255][]);
+        assert_eq!(frames[2].buffer().get_pixel(1, 1).channels(), &[255, 0, 0, 255][]);
+    }
+
+    #[test]
+    fn test_encode_animation() {
+        let red = RgbaImage::from_pixel(4, 4, color::Rgba([255, 0, 0, 255]));
+        let blue = RgbaImage::from_pixel(2, 2, color::Rgba([0, 0, 255, 128]));
+
+        // Only the top right pixel of the last frame differs from the second frame
+        let mut last = red.clone();
+        for &(x, y) in [(1, 1), (2, 1), (1, 2), (2, 2)].iter() {
+            last.put_pixel(x, y, color::Rgba([0, 0, 255, 128]));
+        }
+        last.put_pixel(3, 0, color::Rgba([0, 255, 0, 255]));
+
+        let input = vec![
+            Frame::from_parts(red.clone(), 0, 0, Ratio::new(1, 10)),
+            Frame::from_parts(blue, 1, 1, Ratio::new(1, 5)),
+            Frame::from_parts(last.clone(), 0, 0, Ratio::new(3, 10)),
+        ];
+
+        let mut encoded = MemWriter::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            encoder.set_crop_frames(true);
+            encoder.encode_animation(&input[], 2).unwrap();
+        }
+        let encoded = encoded.into_inner();
+
+        let mut decoder = PNGDecoder::new(MemReader::new(encoded.clone()));
+        assert_eq!(decoder.animation_control().unwrap(), Some(AnimationControl { num_frames: 3, num_plays: 2 }));
+
+        let regions = decoder.read_frames().unwrap().iter().map(|&(ref c, _)| {
+            (c.x_offset, c.y_offset, c.width, c.height)
+        }).collect::<Vec<(u32, u32, u32, u32)>>();
+        assert_eq!(regions, vec![(0, 0, 4, 4), (1, 1, 2, 2), (3, 0, 1, 1)]);
+
+        let frames = PNGDecoder::new(MemReader::new(encoded)).into_frames().unwrap().collect::<Vec<Frame>>();
+        assert_eq!(frames.len(), 3);
+
+        let delays = frames.iter().map(|f| f.delay()).collect::<Vec<Ratio<u16>>>();
+        assert_eq!(delays, vec![Ratio::new(1, 10), Ratio::new(1, 5), Ratio::new(3, 10)]);
+
+        assert_eq!(frames[0].buffer().as_slice(), red.as_slice());
+        assert_eq!(frames[1].buffer().get_pixel(1, 1).channels(), &[0, 0, 255, 128][]);
+        assert_eq!(frames[1].buffer().get_pixel(3, 0).channels(), &[255, 0, 0, 255][]);
+        assert_eq!(frames[2].buffer().as_slice(), last.as_slice());
+    }
+
+    #[test]
+    fn test_palette_transparency() {
+        let mut encoded = MemWriter::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            encoder.set_palette(vec![(255, 0, 0), (0, 255, 0), (0, 0, 255)]);
+            encoder.set_transparency(vec![0, 128]);
+
+            // The 2 bit indices 0, 1 and 2 packed into a byte
+            encoder.encode(&[0x18], 3, 1, color::ColorType::Palette(2)).unwrap();
+        }
+
+        let mut decoder = PNGDecoder::new(MemReader::new(encoded.into_inner()));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, vec![255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255]),
+            _ => panic!("expected 8 bit pixels")
+        }
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        let profile = (0..1000us).map(|i| (i % 7) as u8).collect::<Vec<u8>>();
+
+        let mut encoded = MemWriter::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut encoded);
+            encoder.set_icc_profile(profile.clone());
+            encoder.encode(&[0, 255, 255, 0], 2, 2, color::ColorType::Gray(8)).unwrap();
+        }
+
+        let mut decoder = PNGDecoder::new(MemReader::new(encoded.into_inner()));
+        assert_eq!(decoder.icc_profile().unwrap(), Some(profile));
+        assert!(decoder.read_image().is_ok());
+    }
+
     //#[test]
     //fn render_all() {
     //    let images = get_testimages("f", "", true)
@@ -7,23 +7,36 @@
 //! are interpreted as signed numbers and summed is chosen as the filter.
 
 use std::slice;
+use std::old_io;
 use std::old_io:: {
     IoResult,
     MemWriter
 };
+use std::cmp;
 use std::num::FromPrimitive;
 use std::iter::repeat;
 
 use color;
+use animation::Frame;
 use super::hash::Crc32;
 
 use super::filter::filter;
 use super::decoder::PNGSIGNATURE;
+use super::text::{self, TextEntry};
+use super::colorspace::{self, RenderingIntent};
+use super::apng::{self, FrameControl, DisposeOp, BlendOp};
 
 /// The representation of a PNG encoder
 pub struct PNGEncoder<'a, W: 'a> {
     w: &'a mut W,
-    crc: Crc32
+    crc: Crc32,
+    icc_profile: Option<Vec<u8>>,
+    gamma: Option<f32>,
+    srgb: Option<RenderingIntent>,
+    palette: Option<Vec<(u8, u8, u8)>>,
+    transparency: Option<Vec<u8>>,
+    text: Vec<TextEntry>,
+    crop_frames: bool
 }
 
 impl<'a, W: Writer> PNGEncoder<'a, W> {
@@ -31,10 +44,58 @@ impl<'a, W: Writer> PNGEncoder<'a, W> {
     pub fn new(w: &mut W) -> PNGEncoder<W> {
         PNGEncoder {
             w: w,
-            crc: Crc32::new()
+            crc: Crc32::new(),
+            icc_profile: None,
+            gamma: None,
+            srgb: None,
+            palette: None,
+            transparency: None,
+            text: Vec::new(),
+            crop_frames: false
         }
     }
 
+    /// Embeds the ICC profile ```profile``` in an ```iCCP``` chunk
+    pub fn set_icc_profile(&mut self, profile: Vec<u8>) {
+        self.icc_profile = Some(profile);
+    }
+
+    /// Writes a ```gAMA``` chunk with the exponent ```gamma``` that relates the samples
+    /// to the light intensity, e.g. ```0.45455``` for images encoded for a display gamma of 2.2
+    pub fn set_gamma(&mut self, gamma: f32) {
+        self.gamma = Some(gamma);
+    }
+
+    /// Writes an ```sRGB``` chunk, which tells decoders that the image is in the sRGB color space.
+    /// Unless a gamma is set, a ```gAMA``` chunk approximating sRGB is written for older decoders.
+    pub fn set_srgb(&mut self, intent: RenderingIntent) {
+        self.srgb = Some(intent);
+    }
+
+    /// Sets the palette of images with the ```ColorType``` ```Palette```,
+    /// their pixels are indices into ```palette```
+    pub fn set_palette(&mut self, palette: Vec<(u8, u8, u8)>) {
+        self.palette = Some(palette);
+    }
+
+    /// Writes the alpha values of the palette entries in a ```tRNS``` chunk.
+    /// Entries without an alpha value are opaque.
+    pub fn set_transparency(&mut self, alpha: Vec<u8>) {
+        self.transparency = Some(alpha);
+    }
+
+    /// Writes ```entry``` in a text chunk, ```iTXt``` chunks are only used
+    /// for text that can not be stored as Latin-1
+    pub fn add_text(&mut self, entry: TextEntry) {
+        self.text.push(entry);
+    }
+
+    /// Stores only the region of an animation frame that changed from the previous frame
+    /// instead of the whole frame, which makes animations with small changes smaller
+    pub fn set_crop_frames(&mut self, crop: bool) {
+        self.crop_frames = crop;
+    }
+
     /// Encodes the image ```image```
     /// that has dimensions ```width``` and ```height```
     /// and ```ColorType``` ```c```
@@ -44,11 +105,8 @@ impl<'a, W: Writer> PNGEncoder<'a, W> {
                   height: u32,
                   c: color::ColorType) -> IoResult<()> {
 
-        let _ = try!(self.write_signature());
-        let (bytes, bpp) = build_ihdr(width, height, c);
-
-        let _ = try!(self.write_chunk("IHDR", &bytes[]));
-        let compressed_bytes = build_idat(image, bpp, width, height);
+        let bits = try!(self.write_header(width, height, c));
+        let compressed_bytes = build_idat(image, bits, width, height);
 
         for chunk in compressed_bytes[].chunks(1024 * 256) {
             let _ = try!(self.write_chunk("IDAT", chunk));
@@ -57,6 +115,204 @@ impl<'a, W: Writer> PNGEncoder<'a, W> {
         self.write_chunk("IEND", &[])
     }
 
+    /// Encodes ```frames``` as an animated image with 8 bit RGBA pixels that is played
+    /// ```num_plays``` times, 0 repeats it infinitely. Every frame replaces the area it
+    /// covers, the canvas is large enough to hold all frames and the first frame is
+    /// shown by decoders without animation support.
+    pub fn encode_animation(&mut self, frames: &[Frame], num_plays: u32) -> IoResult<()> {
+        if frames.len() == 0 || frames.iter().any(|f| f.buffer().width() == 0 || f.buffer().height() == 0) {
+            return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Invalid animation.",
+                detail: Some("An animation needs at least one frame and frames can not be empty.".to_string())
+            })
+        }
+
+        let (width, height) = frames.iter().fold((0, 0), |(w, h), f| {
+            (cmp::max(w, f.left() + f.buffer().width()), cmp::max(h, f.top() + f.buffer().height()))
+        });
+
+        let _ = try!(self.write_header(width, height, color::ColorType::RGBA(8)));
+
+        let mut actl = apng::write_u32(frames.len() as u32).to_vec();
+        actl.push_all(&apng::write_u32(num_plays));
+        let _ = try!(self.write_chunk("acTL", &actl[]));
+
+        let mut canvas: Vec<u8> = repeat(0u8).take(4 * width as usize * height as usize).collect();
+        let mut sequence_number = 0;
+
+        for (i, frame) in frames.iter().enumerate() {
+            let previous = canvas.clone();
+            let (left, top) = (frame.left(), frame.top());
+            let (frame_width, frame_height) = frame.buffer().dimensions();
+
+            for (y, row) in frame.buffer().as_slice().chunks(4 * frame_width as usize).enumerate() {
+                let start = (((top + y as u32) * width + left) * 4) as usize;
+                slice::bytes::copy_memory(&mut canvas[start..start + row.len()], row);
+            }
+
+            // The image data holds the first frame, which has to cover the canvas
+            let (x, y, w, h) = if i == 0 {
+                (0, 0, width, height)
+            } else if self.crop_frames {
+                apng::changed_region(&previous[], &canvas[], width, height)
+            } else {
+                (left, top, frame_width, frame_height)
+            };
+
+            let control = FrameControl {
+                sequence_number: sequence_number,
+                width: w,
+                height: h,
+                x_offset: x,
+                y_offset: y,
+                delay_num: *frame.delay().numer(),
+                delay_den: *frame.delay().denom(),
+                dispose_op: DisposeOp::None,
+                blend_op: BlendOp::Source,
+            };
+
+            let _ = try!(self.write_chunk("fcTL", &apng::encode_fctl(&control)[]));
+            sequence_number += 1;
+
+            let pixels = apng::crop_canvas(&canvas[], width, x, y, w, h);
+            let compressed_bytes = build_idat(&pixels[], 32, w, h);
+
+            for chunk in compressed_bytes[].chunks(1024 * 256) {
+                if i == 0 {
+                    let _ = try!(self.write_chunk("IDAT", chunk));
+                } else {
+                    let mut fdat = apng::write_u32(sequence_number).to_vec();
+                    fdat.push_all(chunk);
+
+                    let _ = try!(self.write_chunk("fdAT", &fdat[]));
+                    sequence_number += 1;
+                }
+            }
+        }
+
+        self.write_chunk("IEND", &[])
+    }
+
+    // Writes the chunks before the image data and returns the number of bits per pixel
+    fn write_header(&mut self, width: u32, height: u32, c: color::ColorType) -> IoResult<usize> {
+        let _ = try!(self.check_palette(c));
+        let _ = try!(self.check_color_space());
+
+        if let Some(entry) = self.text.iter().find(|e| !e.has_valid_keyword()) {
+            return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Invalid text keyword.",
+                detail: Some(format!(
+                    "The keyword {:?} is not 1 to 79 Latin-1 characters long.",
+                    entry.keyword
+                ))
+            })
+        }
+
+        let _ = try!(self.write_signature());
+        let (bytes, bits) = build_ihdr(width, height, c);
+
+        let _ = try!(self.write_chunk("IHDR", &bytes[]));
+
+        if let Some(gamma) = self.gamma.or(self.srgb.map(|_| 0.45455)) {
+            let mut gama = Vec::with_capacity(4);
+            colorspace::write_value(&mut gama, gamma);
+
+            let _ = try!(self.write_chunk("gAMA", &gama[]));
+        }
+
+        if let Some(intent) = self.srgb {
+            let _ = try!(self.write_chunk("sRGB", &[intent as u8]));
+        }
+
+        if let Some(iccp) = self.icc_profile.as_ref().map(|p| build_iccp(&p[])) {
+            let _ = try!(self.write_chunk("iCCP", &iccp[]));
+        }
+
+        if let color::ColorType::Palette(_) = c {
+            let plte = self.palette.as_ref().unwrap().iter().flat_map(|&(r, g, b)| {
+                vec![r, g, b].into_iter()
+            }).collect::<Vec<u8>>();
+            let _ = try!(self.write_chunk("PLTE", &plte[]));
+
+            if let Some(trns) = self.transparency.clone() {
+                let _ = try!(self.write_chunk("tRNS", &trns[]));
+            }
+        }
+
+        let chunks = self.text.iter().map(|e| text::encode_text(e)).collect::<Vec<(&str, Vec<u8>)>>();
+
+        for &(name, ref data) in chunks.iter() {
+            let _ = try!(self.write_chunk(name, &data[]));
+        }
+
+        Ok(bits)
+    }
+
+    // Palette images need a palette with an entry for every index,
+    // transparency is only written for palette images
+    fn check_palette(&self, c: color::ColorType) -> IoResult<()> {
+        let entries = match (c, &self.palette) {
+            (color::ColorType::Palette(n), &Some(ref palette)) => {
+                if palette.len() == 0 || palette.len() > 1 << n as usize {
+                    return Err(old_io::IoError {
+                        kind: old_io::InvalidInput,
+                        desc: "Invalid palette.",
+                        detail: Some(format!(
+                            "A palette of {} entries can not be stored with {} bits per index.",
+                            palette.len(), n
+                        ))
+                    })
+                }
+
+                palette.len()
+            }
+
+            (color::ColorType::Palette(_), &None) => return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Missing palette.",
+                detail: Some("Palette images need a palette set with set_palette.".to_string())
+            }),
+
+            _ => 0
+        };
+
+        match self.transparency {
+            Some(ref alpha) if alpha.len() > entries => Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Invalid transparency.",
+                detail: Some(format!(
+                    "{} alpha values do not fit a palette of {} entries.",
+                    alpha.len(), entries
+                ))
+            }),
+
+            _ => Ok(())
+        }
+    }
+
+    // An image is either in the sRGB color space or described by an ICC profile
+    fn check_color_space(&self) -> IoResult<()> {
+        if self.srgb.is_some() && self.icc_profile.is_some() {
+            return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Conflicting color spaces.",
+                detail: Some("An image can not have both an sRGB chunk and an ICC profile.".to_string())
+            })
+        }
+
+        match self.gamma {
+            Some(gamma) if !(gamma > 0.0 && gamma * 100000.0 < 4294967295.0) => Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Invalid gamma.",
+                detail: Some(format!("The gamma {} can not be stored.", gamma))
+            }),
+
+            _ => Ok(())
+        }
+    }
+
     fn write_signature(&mut self) -> IoResult<()> {
         self.w.write_all(&PNGSIGNATURE)
     }
@@ -77,6 +333,7 @@ impl<'a, W: Writer> PNGEncoder<'a, W> {
     }
 }
 
+// Returns the chunk and the number of bits per pixel
 fn build_ihdr(width: u32, height: u32, c: color::ColorType) -> (Vec<u8>, usize) {
     let mut m = MemWriter::with_capacity(13);
 
@@ -113,15 +370,24 @@ fn build_ihdr(width: u32, height: u32, c: color::ColorType) -> (Vec<u8>, usize)
     let channels = match colortype {
         0 => 1,
         2 => 3,
-        3 => 3,
+        3 => 1,
         4 => 2,
         6 => 4,
         _ => panic!("unknown colour type")
     };
 
-    let bpp = ((channels * bit_depth + 7) / 8) as usize;
+    (m.into_inner(), (channels * bit_depth) as usize)
+}
+
+// The profile name is followed by a NUL, the compression method and the compressed profile
+fn build_iccp(profile: &[u8]) -> Vec<u8> {
+    use flate::deflate_bytes_zlib;
+
+    let mut buf = b"ICC profile\0".to_vec();
+    buf.push(0);
+    buf.push_all(&deflate_bytes_zlib(profile).unwrap()[]);
 
-    (m.into_inner(), bpp)
+    buf
 }
 
 fn sum_abs_difference(buf: &[u8]) -> i32 {
@@ -146,10 +412,12 @@ fn select_filter(rowlength: usize, bpp: usize, previous: &[u8], current_s: &mut
     method
 }
 
-fn build_idat(image: &[u8], bpp: usize, width: u32, height: u32) -> Vec<u8> {
+// Pixels of less than 8 bits are packed into the bytes of a row
+fn build_idat(image: &[u8], bits: usize, width: u32, height: u32) -> Vec<u8> {
     use flate::deflate_bytes_zlib;
 
-    let rowlen = bpp * width as usize;
+    let bpp = (bits + 7) / 8;
+    let rowlen = (bits * width as usize + 7) / 8;
 
     let mut p: Vec<u8> = repeat(0u8).take(rowlen).collect();
     let mut c: Vec<u8> = repeat(0u8).take(4 * rowlen).collect();
@@ -8,10 +8,16 @@
 
 pub use self::decoder::PNGDecoder;
 pub use self::encoder::PNGEncoder;
+pub use self::text::TextEntry;
+pub use self::colorspace::{ColorInfo, Chromaticities, RenderingIntent};
+pub use self::apng::{AnimationControl, FrameControl, DisposeOp, BlendOp};
 
 mod filter;
 mod decoder;
 mod encoder;
+mod text;
+mod colorspace;
+mod apng;
 pub mod zlib;
 pub mod deflate;
 pub mod hash;
\ No newline at end of file
@@ -0,0 +1,181 @@
+//! Text chunks of PNG images
+//!
+//! ```tEXt``` chunks hold Latin-1 text and ```zTXt``` chunks zlib compressed Latin-1 text.
+//! ```iTXt``` chunks hold UTF-8 text that may be compressed, along with its language
+//! and a translation of the keyword.
+//!
+//! # Related Links
+//! * http://www.w3.org/TR/PNG/#11textinfo - Textual information
+
+use std::old_io::MemReader;
+
+use flate::deflate_bytes_zlib;
+
+use super::zlib::ZlibDecoder;
+
+// Returns ```None``` from the enclosing function if the option is empty
+macro_rules! try_opt(
+    ($e:expr) => (match $e { Some(v) => v, None => return None })
+);
+
+// The length of keywords is limited to 79 bytes
+const MAX_KEYWORD_LENGTH: usize = 79;
+
+/// A keyword and its text
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextEntry {
+    /// The keyword, e.g. ```Title```, ```Author``` or ```Software```
+    pub keyword: String,
+
+    /// The text
+    pub text: String,
+
+    /// The language of ```iTXt``` text, e.g. ```en```, empty if it is unknown
+    pub language: String,
+
+    /// The keyword translated to ```language```, empty if there is no translation
+    pub translated_keyword: String,
+
+    /// Whether the text is compressed
+    pub compressed: bool,
+}
+
+impl TextEntry {
+    /// Create an uncompressed entry without a language
+    pub fn new(keyword: &str, text: &str) -> TextEntry {
+        TextEntry {
+            keyword: keyword.to_string(),
+            text: text.to_string(),
+            language: String::new(),
+            translated_keyword: String::new(),
+            compressed: false,
+        }
+    }
+
+    /// Whether the keyword can be stored, it has to be 1 to 79 Latin-1 characters long
+    /// and must not contain NUL characters
+    pub fn has_valid_keyword(&self) -> bool {
+        match to_latin1(&self.keyword[]) {
+            Some(k) => k.len() > 0 && k.len() <= MAX_KEYWORD_LENGTH && !k.contains(&0),
+            None => false
+        }
+    }
+}
+
+// Splits ```data``` at the first NUL
+fn split_at_nul(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    data.iter().position(|&b| b == 0).map(|i| (&data[..i], &data[i + 1..]))
+}
+
+fn from_latin1(data: &[u8]) -> String {
+    data.iter().map(|&b| b as char).collect()
+}
+
+fn to_latin1(text: &str) -> Option<Vec<u8>> {
+    if text.chars().all(|c| (c as u32) < 256) {
+        Some(text.chars().map(|c| c as u8).collect())
+    } else {
+        None
+    }
+}
+
+fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    ZlibDecoder::new(MemReader::new(data.to_vec())).read_to_end().ok()
+}
+
+/// Decodes the data of a ```tEXt```, ```zTXt``` or ```iTXt``` chunk.
+/// Malformed chunks and unknown compression methods yield ```None```.
+pub fn decode_text(chunk_type: &[u8], data: &[u8]) -> Option<TextEntry> {
+    let (keyword, rest) = match split_at_nul(data) {
+        Some((keyword, rest)) if keyword.len() > 0 && keyword.len() <= MAX_KEYWORD_LENGTH => (keyword, rest),
+        _ => return None
+    };
+
+    let mut entry = TextEntry::new(&from_latin1(keyword)[], "");
+
+    match chunk_type {
+        b"tEXt" => {
+            entry.text = from_latin1(rest);
+        }
+
+        // The keyword is followed by the compression method
+        b"zTXt" => {
+            if rest.len() < 1 || rest[0] != 0 {
+                return None
+            }
+
+            entry.text = from_latin1(&try_opt!(decompress(&rest[1..]))[]);
+            entry.compressed = true;
+        }
+
+        // The keyword is followed by the compression flag, the compression method,
+        // the language and the translated keyword
+        b"iTXt" => {
+            if rest.len() < 2 || rest[0] > 1 || (rest[0] == 1 && rest[1] != 0) {
+                return None
+            }
+
+            let compressed = rest[0] == 1;
+            let (language, rest) = try_opt!(split_at_nul(&rest[2..]));
+            let (translated, text) = try_opt!(split_at_nul(rest));
+
+            let text = if compressed {
+                try_opt!(decompress(text))
+            } else {
+                text.to_vec()
+            };
+
+            entry.language = try_opt!(String::from_utf8(language.to_vec()).ok());
+            entry.translated_keyword = try_opt!(String::from_utf8(translated.to_vec()).ok());
+            entry.text = try_opt!(String::from_utf8(text).ok());
+            entry.compressed = compressed;
+        }
+
+        _ => return None
+    }
+
+    Some(entry)
+}
+
+/// Returns the chunk type and data of ```entry```, whose keyword has to be valid.
+/// Latin-1 text without a language or translated keyword is stored in a ```tEXt```
+/// or ```zTXt``` chunk, other text in an ```iTXt``` chunk.
+pub fn encode_text(entry: &TextEntry) -> (&'static str, Vec<u8>) {
+    let mut data = to_latin1(&entry.keyword[]).unwrap();
+    data.push(0);
+
+    let latin1 = if entry.language.is_empty() && entry.translated_keyword.is_empty() {
+        to_latin1(&entry.text[])
+    } else {
+        None
+    };
+
+    match latin1 {
+        Some(text) => if entry.compressed {
+            data.push(0);
+            data.push_all(&deflate_bytes_zlib(&text[]).unwrap()[]);
+
+            ("zTXt", data)
+        } else {
+            data.push_all(&text[]);
+
+            ("tEXt", data)
+        },
+
+        None => {
+            data.push_all(&[if entry.compressed { 1 } else { 0 }, 0]);
+            data.push_all(entry.language.as_bytes());
+            data.push(0);
+            data.push_all(entry.translated_keyword.as_bytes());
+            data.push(0);
+
+            if entry.compressed {
+                data.push_all(&deflate_bytes_zlib(entry.text.as_bytes()).unwrap()[]);
+            } else {
+                data.push_all(entry.text.as_bytes());
+            }
+
+            ("iTXt", data)
+        }
+    }
+}
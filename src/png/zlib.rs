@@ -45,13 +45,13 @@ impl<R: Reader> ZlibDecoder<R> {
         let _cinfo = cmf >> 4;
 
         let flg = try!(self.inner().read_u8());
+
+        // Preset dictionaries are not used by PNG
         let fdict  = (flg & 0b100000) != 0;
-        if fdict {
-            let _dictid = try!(self.inner().read_be_u32());
-            panic!("invalid png: zlib detected fdict true")
-        }
 
-        assert!((cmf as u16 * 256 + flg as u16) % 31 == 0);
+        if fdict || (cmf as u16 * 256 + flg as u16) % 31 != 0 {
+            return Err(old_io::standard_error(old_io::InvalidInput))
+        }
 
         Ok(())
     }
@@ -60,7 +60,10 @@ impl<R: Reader> ZlibDecoder<R> {
         let stream_adler32 = try!(self.inner().read_be_u32());
         let adler32 = self.adler.checksum();
 
-        assert!(adler32 == stream_adler32);
+        if adler32 != stream_adler32 {
+            return Err(old_io::standard_error(old_io::InvalidInput))
+        }
+
         self.adler.reset();
 
         Ok(())
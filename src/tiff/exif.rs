@@ -0,0 +1,245 @@
+//! Parsing of EXIF metadata
+//!
+//! EXIF metadata is stored as a TIFF structure, e.g. inside the APP1 segment of JPEG images.
+//!
+//! # Related Links
+//! * http://www.cipa.jp/std/documents/e/DC-008-2012_E.pdf - The EXIF specification
+
+use std::old_io;
+use std::old_io::{MemReader, Seek};
+use std::num::FromPrimitive;
+use std::collections::HashMap;
+
+use image;
+use image::ImageResult;
+
+use super::ifd::{Tag, Type};
+use super::stream::{ByteOrder, EndianReader, SmartReader};
+
+/// The value of an EXIF tag
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExifValue {
+    /// BYTE and UNDEFINED values
+    Byte(Vec<u8>),
+    /// An ASCII string without its terminating NUL
+    Ascii(String),
+    /// SHORT and LONG values
+    Unsigned(Vec<u32>),
+    /// SBYTE, SSHORT and SLONG values
+    Signed(Vec<i32>),
+    /// RATIONAL values as pairs of numerator and denominator
+    Rational(Vec<(u32, u32)>),
+    /// SRATIONAL values as pairs of numerator and denominator
+    SignedRational(Vec<(i32, i32)>),
+}
+
+/// EXIF metadata of an image
+#[derive(Debug)]
+pub struct Exif {
+    /// The byte order of the underlying TIFF structure
+    pub byte_order: ByteOrder,
+    /// The tags describing the primary image, e.g. ```Make```, ```Model``` and ```Orientation```
+    pub image: HashMap<Tag, ExifValue>,
+    /// The tags of the EXIF IFD, e.g. the exposure settings
+    pub exif: HashMap<Tag, ExifValue>,
+    /// The tags of the GPS IFD
+    pub gps: HashMap<Tag, ExifValue>,
+}
+
+impl Exif {
+    /// Parses EXIF metadata from ```data```, which has to start with the TIFF header
+    pub fn from_bytes(data: &[u8]) -> ImageResult<Exif> {
+        if data.len() < 8 {
+            return Err(image::ImageError::FormatError("EXIF data too short".to_string()))
+        }
+
+        let byte_order = match (data[0], data[1]) {
+            (b'I', b'I') => ByteOrder::LittleEndian,
+            (b'M', b'M') => ByteOrder::BigEndian,
+            _ => return Err(image::ImageError::FormatError(
+                "EXIF byte order mark not found".to_string()
+            ))
+        };
+
+        let mut r = SmartReader::wrap(MemReader::new(data.to_vec()), byte_order);
+        try!(r.seek(2, old_io::SeekSet));
+
+        if try!(r.read_u16()) != 42 {
+            return Err(image::ImageError::FormatError("EXIF signature not found".to_string()))
+        }
+
+        let offset = try!(r.read_u32());
+        let image = try!(read_directory(&mut r, offset, data.len()));
+
+        let exif = match image.get(&Tag::ExifIfd) {
+            Some(&ExifValue::Unsigned(ref v)) if v.len() == 1 => {
+                try!(read_directory(&mut r, v[0], data.len()))
+            }
+            _ => HashMap::new()
+        };
+
+        let gps = match image.get(&Tag::GpsIfd) {
+            Some(&ExifValue::Unsigned(ref v)) if v.len() == 1 => {
+                try!(read_directory(&mut r, v[0], data.len()))
+            }
+            _ => HashMap::new()
+        };
+
+        Ok(Exif {
+            byte_order: byte_order,
+            image: image,
+            exif: exif,
+            gps: gps,
+        })
+    }
+
+    /// Returns the value of the ```Orientation``` tag, if it is present and valid.
+    ///
+    /// The values ```1``` to ```8``` describe how the stored image has to be
+    /// rotated and flipped to be displayed upright.
+    pub fn orientation(&self) -> Option<u16> {
+        match self.image.get(&Tag::Orientation) {
+            Some(&ExifValue::Unsigned(ref v)) if v.len() == 1 && v[0] >= 1 && v[0] <= 8 => {
+                Some(v[0] as u16)
+            }
+            _ => None
+        }
+    }
+}
+
+fn type_size(type_: Type) -> usize {
+    match type_ {
+        Type::BYTE | Type::ASCII | Type::SBYTE | Type::UNDEFINED => 1,
+        Type::SHORT | Type::SSHORT => 2,
+        Type::LONG | Type::SLONG => 4,
+        Type::RATIONAL | Type::SRATIONAL => 8,
+    }
+}
+
+fn read_directory(r: &mut SmartReader<MemReader>, offset: u32, len: usize)
+-> ImageResult<HashMap<Tag, ExifValue>> {
+    try!(r.seek(offset as i64, old_io::SeekSet));
+
+    let count = try!(r.read_u16());
+    let mut directory = HashMap::new();
+
+    for i in range(0, count as i64) {
+        // Every entry is 12 bytes long, the value follows the tag, type and count
+        try!(r.seek(offset as i64 + 2 + 12 * i, old_io::SeekSet));
+
+        let tag = Tag::from_u16(try!(r.read_u16()));
+        let type_: Type = match FromPrimitive::from_u16(try!(r.read_u16())) {
+            Some(t) => t,
+            // Unknown types have to be skipped
+            None => {
+                let _ = try!(r.read_u32());
+                continue
+            }
+        };
+        let count = try!(r.read_u32()) as usize;
+
+        let size = type_size(type_) * count;
+        if size > len {
+            return Err(image::ImageError::FormatError(format!(
+                "EXIF value of tag {:?} exceeds the data", tag
+            )))
+        }
+
+        if size > 4 {
+            let value_offset = try!(r.read_u32());
+            try!(r.seek(value_offset as i64, old_io::SeekSet));
+        }
+
+        let value = try!(read_value(r, type_, count));
+        directory.insert(tag, value);
+    }
+
+    Ok(directory)
+}
+
+fn read_value<R: EndianReader>(r: &mut R, type_: Type, count: usize) -> ImageResult<ExifValue> {
+    let value = match type_ {
+        Type::BYTE | Type::UNDEFINED => ExifValue::Byte(try!(r.read_exact(count))),
+        Type::ASCII => {
+            let bytes = try!(r.read_exact(count));
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+
+            ExifValue::Ascii(String::from_utf8_lossy(&bytes[..end]).into_owned())
+        }
+        Type::SHORT => {
+            let mut v = Vec::with_capacity(count);
+            for _ in range(0, count) {
+                v.push(try!(r.read_u16()) as u32);
+            }
+            ExifValue::Unsigned(v)
+        }
+        Type::LONG => {
+            let mut v = Vec::with_capacity(count);
+            for _ in range(0, count) {
+                v.push(try!(r.read_u32()));
+            }
+            ExifValue::Unsigned(v)
+        }
+        Type::RATIONAL => {
+            let mut v = Vec::with_capacity(count);
+            for _ in range(0, count) {
+                let n = try!(r.read_u32());
+                let d = try!(r.read_u32());
+                v.push((n, d));
+            }
+            ExifValue::Rational(v)
+        }
+        Type::SBYTE => {
+            let bytes = try!(r.read_exact(count));
+            ExifValue::Signed(bytes.iter().map(|&b| b as i8 as i32).collect())
+        }
+        Type::SSHORT => {
+            let mut v = Vec::with_capacity(count);
+            for _ in range(0, count) {
+                v.push(try!(r.read_u16()) as i16 as i32);
+            }
+            ExifValue::Signed(v)
+        }
+        Type::SLONG => {
+            let mut v = Vec::with_capacity(count);
+            for _ in range(0, count) {
+                v.push(try!(r.read_u32()) as i32);
+            }
+            ExifValue::Signed(v)
+        }
+        Type::SRATIONAL => {
+            let mut v = Vec::with_capacity(count);
+            for _ in range(0, count) {
+                let n = try!(r.read_u32()) as i32;
+                let d = try!(r.read_u32()) as i32;
+                v.push((n, d));
+            }
+            ExifValue::SignedRational(v)
+        }
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Exif, ExifValue};
+    use super::super::ifd::Tag;
+
+    #[test]
+    fn test_orientation() {
+        // Little endian TIFF header with one IFD holding Orientation = 6 and Make = "Cam"
+        let data = [
+            b'I', b'I', 42, 0, 8, 0, 0, 0,
+            2, 0,
+            0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0,
+            0x0F, 0x01, 2, 0, 4, 0, 0, 0, b'C', b'a', b'm', 0,
+            0, 0, 0, 0
+        ];
+        let exif = Exif::from_bytes(&data).unwrap();
+
+        assert_eq!(exif.orientation(), Some(6));
+        assert_eq!(exif.image.get(&Tag::Make), Some(&ExifValue::Ascii("Cam".to_string())));
+        assert!(exif.exif.is_empty());
+    }
+}
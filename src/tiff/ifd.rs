@@ -72,6 +72,9 @@ tags!{
     YResolution 283;
     // Advanced tags
     Predictor 317;
+    // Private IFD pointers used by EXIF
+    ExifIfd 34665;
+    GpsIfd 34853;
 }
 
 #[derive(Copy, Debug, FromPrimitive)]
@@ -81,6 +84,11 @@ pub enum Type {
     SHORT = 3,
     LONG = 4,
     RATIONAL = 5,
+    SBYTE = 6,
+    UNDEFINED = 7,
+    SSHORT = 8,
+    SLONG = 9,
+    SRATIONAL = 10,
 }
 
 
@@ -9,7 +9,10 @@
 
 pub use self::decoder::TIFFDecoder;
 pub use self::stream::ByteOrder;
+pub use self::ifd::Tag;
+pub use self::exif::{Exif, ExifValue};
 
 mod decoder;
+mod exif;
 mod ifd;
 mod stream;
\ No newline at end of file
@@ -142,6 +142,13 @@ impl<W> $name<W> where W: Writer {
             acc: 0,
         }
     }
+
+    /// Returns the underlying writer, bits that do not
+    /// fill a whole byte yet are discarded
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> W {
+        self.w
+    }
 }
 
 impl<W> Writer for $name<W> where W: Writer {
@@ -158,7 +165,7 @@ impl<W> Writer for $name<W> where W: Writer {
     }
 
     fn flush(&mut self) -> IoResult<()> {
-        let missing = 8 - self.bits;
+        let missing = (8 - self.bits) % 8;
         if missing > 0 {
             try!(self.write_bits(0, missing));
         }
@@ -1,37 +1,83 @@
+use std::cmp;
 use std::slice;
-use std::old_io::MemReader;
+use std::old_io::{MemReader, Seek};
+use std::old_io::util::LimitReader;
+use std::iter::repeat;
 use std::default::Default;
 
+use num::rational::Ratio;
+
 use image;
 use image::ImageResult;
 use image::ImageDecoder;
 
 use color;
+use buffer::ImageBuffer;
+use animation::{Frame, Frames};
+use dynimage::decoder_to_image;
 
-use super::vp8::Frame;
 use super::vp8::VP8Decoder;
+use super::lossless::LosslessDecoder;
+use super::extended::{self, ExtendedHeader, WebpMetadata, AnimationInfo, AnimationFrameHeader};
+
 
 
+// A decoded still image or animation frame
+struct DecodedImage {
+    width: u32,
+    height: u32,
+    color: color::ColorType,
+    buf: Vec<u8>,
+}
+
+impl DecodedImage {
+    fn into_rgba(self) -> Vec<u8> {
+        match self.color {
+            color::ColorType::RGB(8) => {
+                let alpha: Vec<u8> = repeat(255u8).take(self.width as usize
+                                                        * self.height as usize).collect();
+                extended::rgb_to_rgba(&self.buf[], &alpha[])
+            }
+            _ => self.buf
+        }
+    }
+}
 
 /// A Representation of a Webp Image format decoder.
 pub struct WebpDecoder<R> {
     r: R,
-    frame: Frame,
+    width: u32,
+    height: u32,
+    color: color::ColorType,
+    image: Vec<u8>,
     have_frame: bool,
     decoded_rows: u32,
+
+    extended: Option<ExtendedHeader>,
+    metadata: WebpMetadata,
+
+    animation: Option<AnimationInfo>,
+    frames: Vec<(AnimationFrameHeader, Vec<u8>)>,
 }
 
 impl<R: Reader> WebpDecoder<R> {
     /// Create a new WebpDecoder from the Reader ```r```.
     /// This function takes ownership of the Reader.
     pub fn new(r: R) -> WebpDecoder<R> {
-        let f: Frame = Default::default();
-
         WebpDecoder {
             r: r,
+            width: 0,
+            height: 0,
+            color: color::ColorType::RGB(8),
+            image: Vec::new(),
             have_frame: false,
-            frame: f,
-            decoded_rows: 0
+            decoded_rows: 0,
+
+            extended: None,
+            metadata: Default::default(),
+
+            animation: None,
+            frames: Vec::new(),
         }
     }
 
@@ -48,72 +94,288 @@ impl<R: Reader> WebpDecoder<R> {
             return Err(image::ImageError::FormatError("Invalid WEBP signature.".to_string()))
         }
 
+        // The RIFF size includes the WEBP signature
+        if size < 4 {
+            return Err(image::ImageError::FormatError("Invalid RIFF size.".to_string()))
+        }
+
         Ok(size)
     }
 
-    fn read_vp8_header(&mut self) -> ImageResult<()> {
-        let vp8 = try!(self.r.read_exact(4));
+    fn read_metadata(&mut self) -> ImageResult<()> {
+        if !self.have_frame {
+            let size = try!(self.read_riff_header());
+
+            // The data is read as it arrives instead of allocating
+            // whatever size a corrupt header claims up front
+            let len = size as usize - 4;
+            let data = try!(LimitReader::new(self.r.by_ref(), len).read_to_end());
+
+            if data.len() < len {
+                return Err(image::ImageError::NotEnoughData)
+            }
+            let mut chunks = MemReader::new(data);
+
+            let (fourcc, data) = try!(read_chunk(&mut chunks));
+
+            let image = match &fourcc[] {
+                b"VP8 " => try!(decode_vp8(data, None)),
+                b"VP8L" => try!(decode_vp8l(data)),
+                b"VP8X" => {
+                    self.extended = Some(try!(ExtendedHeader::from_bytes(&data[])));
+                    try!(self.read_extended(&mut chunks))
+                }
+                _ => return Err(image::ImageError::FormatError("Invalid VP8 signature.".to_string()))
+            };
+
+            self.width = image.width;
+            self.height = image.height;
+            self.color = image.color;
+            self.image = image.buf;
 
-        if &vp8[] != "VP8 ".as_bytes() {
-            return Err(image::ImageError::FormatError("Invalid VP8 signature.".to_string()))
+            self.have_frame = true;
         }
 
-        let _len = try!(self.r.read_le_u32());
-
         Ok(())
     }
 
-    fn read_frame(&mut self) -> ImageResult<()> {
-        let framedata = try!(self.r.read_to_end());
-        let m = MemReader::new(framedata);
+    fn read_extended(&mut self, chunks: &mut MemReader) -> ImageResult<DecodedImage> {
+        let mut image = None;
+        let mut alpha_chunk = None;
+
+        while !chunks.eof() {
+            let (fourcc, data) = try!(read_chunk(chunks));
+
+            match &fourcc[] {
+                b"VP8 " => image = Some(try!(decode_vp8(data, alpha_chunk.take()))),
+                b"VP8L" => image = Some(try!(decode_vp8l(data))),
+                b"ALPH" => alpha_chunk = Some(data),
+                b"ANIM" => self.animation = Some(try!(AnimationInfo::from_bytes(&data[]))),
+                b"ANMF" => {
+                    let header = try!(AnimationFrameHeader::from_bytes(&data[]));
+                    let frame = try!(decode_animation_frame(data));
+
+                    if frame.width != header.width || frame.height != header.height {
+                        return Err(image::ImageError::FormatError(
+                            "Animation frame has not the expected size.".to_string()
+                        ))
+                    }
+
+                    self.frames.push((header, frame.into_rgba()));
+                }
+                b"ICCP" => self.metadata.icc_profile = Some(data),
+                b"EXIF" => self.metadata.exif = Some(data),
+                b"XMP " => self.metadata.xmp = Some(data),
+
+                // Unknown chunks are ignored
+                _ => ()
+            }
+        }
+
+        let header = self.extended.unwrap();
 
-        let mut v = VP8Decoder::new(m);
-        let frame = try!(v.decode_frame());
+        match image {
+            Some(image) => Ok(image),
 
-        self.frame = frame.clone();
+            // A still image of an animation is its first frame
+            None if !self.frames.is_empty() => {
+                let (width, height) = (header.canvas_width, header.canvas_height);
+                let mut canvas: Vec<u8> = repeat(0u8).take(4 * width as usize
+                                                            * height as usize).collect();
 
-        Ok(())
+                let (ref frame_header, ref frame) = self.frames[0];
+                extended::composite_frame(&mut canvas[], width, height, &frame[], frame_header);
+
+                Ok(DecodedImage {
+                    width: width,
+                    height: height,
+                    color: color::ColorType::RGBA(8),
+                    buf: canvas,
+                })
+            }
+
+            None => Err(image::ImageError::FormatError("No image data found.".to_string()))
+        }
     }
 
-    fn read_metadata(&mut self) -> ImageResult<()> {
-        if !self.have_frame {
-            let _ = try!(self.read_riff_header());
-            let _ = try!(self.read_vp8_header());
-            let _ = try!(self.read_frame());
+    /// Returns the ```VP8X``` header of images in the extended format
+    pub fn extended_header(&mut self) -> ImageResult<Option<ExtendedHeader>> {
+        let _ = try!(self.read_metadata());
 
-            self.have_frame = true;
+        Ok(self.extended)
+    }
+
+    /// Returns the ICC profile, EXIF and XMP metadata of the image
+    pub fn metadata(&mut self) -> ImageResult<&WebpMetadata> {
+        let _ = try!(self.read_metadata());
+
+        Ok(&self.metadata)
+    }
+
+    /// Returns the ICC profile of the image, if it contains an ```ICCP``` chunk
+    pub fn icc_profile(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        let _ = try!(self.read_metadata());
+
+        Ok(self.metadata.icc_profile.clone())
+    }
+
+    /// Returns the background color and loop count of animated images
+    pub fn animation_info(&mut self) -> ImageResult<Option<AnimationInfo>> {
+        let _ = try!(self.read_metadata());
+
+        Ok(self.animation)
+    }
+}
+
+// Reads a chunk including its padding to an even size
+fn read_chunk(r: &mut MemReader) -> ImageResult<(Vec<u8>, Vec<u8>)> {
+    let fourcc = try!(r.read_exact(4));
+    let len = try!(r.read_le_u32());
+
+    // Don't reserve more than is left for a corrupt length
+    let left = r.get_ref().len() - try!(r.tell()) as usize;
+    if len as usize > left {
+        return Err(image::ImageError::NotEnoughData)
+    }
+    let data = try!(r.read_exact(len as usize));
+
+    if len % 2 == 1 && !r.eof() {
+        let _ = try!(r.read_u8());
+    }
+
+    Ok((fourcc, data))
+}
+
+fn decode_vp8(data: Vec<u8>, alpha_chunk: Option<Vec<u8>>) -> ImageResult<DecodedImage> {
+    let m = MemReader::new(data);
+
+    let mut v = VP8Decoder::new(m);
+    let frame = try!(v.decode_frame());
+
+    let mut rgb: Vec<u8> = repeat(0u8).take(3 * frame.width as usize
+                                            * frame.height as usize).collect();
+    frame.fill_rgb(&mut rgb[]);
+
+    // Lossy frames keep their alpha channel in a separate chunk
+    let (color, buf) = match alpha_chunk {
+        Some(data) => {
+            let alpha = try!(extended::decode_alpha(&data[], frame.width, frame.height));
+            (color::ColorType::RGBA(8), extended::rgb_to_rgba(&rgb[], &alpha[]))
         }
+        None => (color::ColorType::RGB(8), rgb)
+    };
+
+    Ok(DecodedImage {
+        width: frame.width as u32,
+        height: frame.height as u32,
+        color: color,
+        buf: buf,
+    })
+}
 
-        Ok(())
+fn decode_vp8l(data: Vec<u8>) -> ImageResult<DecodedImage> {
+    let m = MemReader::new(data);
+
+    let mut v = LosslessDecoder::new(m);
+    let frame = try!(v.decode_frame());
+
+    let mut rgba: Vec<u8> = repeat(0u8).take(4 * frame.width as usize
+                                             * frame.height as usize).collect();
+    frame.fill_rgba(&mut rgba[]);
+
+    Ok(DecodedImage {
+        width: frame.width as u32,
+        height: frame.height as u32,
+        color: color::ColorType::RGBA(8),
+        buf: rgba,
+    })
+}
+
+// The frame data follows the 16 byte header of an ANMF chunk
+fn decode_animation_frame(data: Vec<u8>) -> ImageResult<DecodedImage> {
+    let mut chunks = MemReader::new(data);
+    let _ = try!(chunks.read_exact(16));
+
+    let mut alpha_chunk = None;
+
+    while !chunks.eof() {
+        let (fourcc, data) = try!(read_chunk(&mut chunks));
+
+        match &fourcc[] {
+            b"ALPH" => alpha_chunk = Some(data),
+            b"VP8 " => return decode_vp8(data, alpha_chunk),
+            b"VP8L" => return decode_vp8l(data),
+            _ => ()
+        }
     }
+
+    Err(image::ImageError::FormatError("Animation frame without image data.".to_string()))
 }
 
 impl<R: Reader> ImageDecoder for WebpDecoder<R> {
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         let _ = try!(self.read_metadata());
 
-        Ok((self.frame.width as u32, self.frame.height as u32))
+        Ok((self.width, self.height))
     }
 
     fn colortype(&mut self) -> ImageResult<color::ColorType> {
-        Ok(color::ColorType::Gray(8))
+        let _ = try!(self.read_metadata());
+
+        Ok(self.color)
     }
 
     fn row_len(&mut self) -> ImageResult<usize> {
         let _ = try!(self.read_metadata());
 
-        Ok(self.frame.width as usize)
+        Ok(color::bits_per_pixel(self.color) / 8 * self.width as usize)
+    }
+
+    fn is_animated(&mut self) -> ImageResult<bool> {
+        let _ = try!(self.read_metadata());
+
+        Ok(!self.frames.is_empty())
+    }
+
+    fn into_frames(self) -> ImageResult<Frames> {
+        let mut decoder = self;
+
+        if !try!(decoder.is_animated()) {
+            return Ok(Frames::new(vec![
+                Frame::new(try!(decoder_to_image(decoder)).to_rgba())
+            ]))
+        }
+
+        let (width, height) = (decoder.width, decoder.height);
+        let mut canvas: Vec<u8> = repeat(0u8).take(4 * width as usize
+                                                    * height as usize).collect();
+        let mut frames = Vec::with_capacity(decoder.frames.len());
+
+        for &(ref header, ref frame) in decoder.frames.iter() {
+            extended::composite_frame(&mut canvas[], width, height, &frame[], header);
+
+            let buffer = ImageBuffer::from_raw(width, height, canvas.clone()).unwrap();
+            let delay = Ratio::new(cmp::min(header.duration, 0xffff) as u16, 1000);
+
+            frames.push(Frame::from_parts(buffer, 0, 0, delay));
+
+            if header.dispose {
+                extended::dispose_frame(&mut canvas[], width, height, header);
+            }
+        }
+
+        Ok(Frames::new(frames))
     }
 
     fn read_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
         let _ = try!(self.read_metadata());
 
-        if self.decoded_rows > self.frame.height as u32 {
+        if self.decoded_rows > self.height {
             return Err(image::ImageError::ImageEnd)
         }
 
         let rlen  = buf.len();
-        let slice = &self.frame.ybuf[
+        let slice = &self.image[
             self.decoded_rows as usize * rlen..
             self.decoded_rows as usize * rlen + rlen
         ];
@@ -127,6 +389,155 @@ impl<R: Reader> ImageDecoder for WebpDecoder<R> {
     fn read_image(&mut self) -> ImageResult<image::DecodingResult> {
         let _ = try!(self.read_metadata());
 
-        Ok(image::DecodingResult::U8(self.frame.ybuf.clone()))
+        Ok(image::DecodingResult::U8(self.image.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{File, MemReader};
+
+    use num::rational::Ratio;
+
+    use color;
+    use image;
+    use image::{ImageDecoder, DecodingResult};
+    use super::WebpDecoder;
+
+    fn read_testdata(name: &str) -> Vec<u8> {
+        let path = Path::new(".").join_many(&["src", "webp", "testdata", name]);
+
+        File::open(&path).read_to_end().unwrap()
+    }
+
+    // Returns the samples at the end of a binary PPM or PAM file
+    fn read_samples(name: &str, len: usize) -> Vec<u8> {
+        let data = read_testdata(name);
+
+        data[data.len() - len..].to_vec()
+    }
+
+    #[test]
+    fn test_lossy_rgb() {
+        // The reference was decoded with dwebp -nodither
+        let mut decoder = WebpDecoder::new(MemReader::new(read_testdata("lossy-nofilter.webp")));
+
+        assert_eq!(decoder.dimensions().unwrap(), (37, 29));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGB(8));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, read_samples("lossy-nofilter.ppm", 3 * 37 * 29)),
+            _ => panic!("expected 8 bit pixels")
+        }
+    }
+
+    #[test]
+    fn test_lossless() {
+        // Encoded with cwebp -lossless -q 100 -exact, the first image (-m 6) with the predictor
+        // and cross-color transforms and an entropy image of prefix code groups,
+        // the second (-m 4) with a palette of 6 colors bundling two pixels per byte.
+        // The references were decoded with dwebp -pam.
+        for &(name, reference, width, height) in [("lossless-transforms.webp", "lossless-transforms.pam", 60, 40),
+                                                  ("lossless-palette.webp", "lossless-palette.pam", 45, 31)].iter() {
+            let mut decoder = WebpDecoder::new(MemReader::new(read_testdata(name)));
+
+            assert_eq!(decoder.dimensions().unwrap(), (width, height));
+            assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+
+            match decoder.read_image().unwrap() {
+                DecodingResult::U8(pixels) => {
+                    assert!(pixels == read_samples(reference, (4 * width * height) as usize))
+                }
+                _ => panic!("expected 8 bit pixels")
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_riff_size() {
+        for &size in [0u8, 3].iter() {
+            let data = vec![b'R', b'I', b'F', b'F', size, 0, 0, 0, b'W', b'E', b'B', b'P'];
+            assert!(WebpDecoder::new(MemReader::new(data)).dimensions().is_err());
+        }
+
+        // A header claiming almost 4 GiB of data
+        let data = b"RIFF\xf0\xff\xff\xffWEBPVP8 ".to_vec();
+        assert!(WebpDecoder::new(MemReader::new(data)).dimensions().is_err());
+
+        // Chunks claiming almost 4 GiB of data inside a valid RIFF size
+        for fourcc in [b"VP8 ", b"VP8X", b"VP8L"].iter() {
+            let mut data = b"RIFF\x10\x00\x00\x00WEBP".to_vec();
+            data.push_all(&fourcc[]);
+            data.push_all(b"\xf0\xff\xff\xff\x00\x00\x00\x00");
+            match WebpDecoder::new(MemReader::new(data)).dimensions() {
+                Err(image::ImageError::NotEnoughData) => (),
+                _ => panic!("expected NotEnoughData")
+            }
+        }
+    }
+
+    #[test]
+    fn test_extended_alpha_metadata() {
+        // A VP8X image whose ALPH chunk is losslessly compressed with the
+        // gradient filter, followed by EXIF and XMP chunks
+        let mut decoder = WebpDecoder::new(MemReader::new(read_testdata("lossy-alpha-metadata.webp")));
+
+        let header = decoder.extended_header().unwrap().unwrap();
+        assert!(header.alpha && header.exif_metadata && header.xmp_metadata);
+        assert!(!header.icc_profile && !header.animation);
+        assert_eq!((header.canvas_width, header.canvas_height), (29, 23));
+
+        {
+            let metadata = decoder.metadata().unwrap();
+            assert_eq!(metadata.exif, Some(vec![0x4D, 0x4D, 0, 0x2A, 0, 0, 0, 8, 0, 0]));
+            assert_eq!(metadata.xmp, Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()));
+            assert_eq!(metadata.icc_profile, None);
+        }
+
+        assert_eq!(decoder.dimensions().unwrap(), (29, 23));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+
+        // The reference was decoded with dwebp -nodither -pam
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, read_samples("lossy-alpha-metadata.pam", 4 * 29 * 23)),
+            _ => panic!("expected 8 bit pixels")
+        }
+    }
+
+    #[test]
+    fn test_animation() {
+        // Lossless frames muxed with webpmux: a 16x12 frame without blending, an 8x6 frame
+        // at (4, 2) blended and disposed, a 6x4 frame at (2, 6) without blending and
+        // a 6x6 frame at (8, 4) blended over the disposed area.
+        // The references were composited with anim_dump -pam.
+        let mut decoder = WebpDecoder::new(MemReader::new(read_testdata("animated.webp")));
+
+        let header = decoder.extended_header().unwrap().unwrap();
+        assert!(header.animation && header.alpha);
+
+        let info = decoder.animation_info().unwrap().unwrap();
+        assert_eq!(info.background_color, [255, 255, 255, 255]);
+        assert_eq!(info.loop_count, 0);
+
+        assert!(decoder.is_animated().unwrap());
+        assert_eq!(decoder.dimensions().unwrap(), (16, 12));
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+
+        // The still image is the first frame
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert!(pixels == read_samples("animated-0.pam", 4 * 16 * 12)),
+            _ => panic!("expected 8 bit pixels")
+        }
+
+        let frames: Vec<_> = decoder.into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 4);
+
+        for (i, (frame, &duration)) in frames.into_iter().zip([70u16, 50, 120, 30].iter()).enumerate() {
+            assert_eq!(frame.delay(), Ratio::new(duration, 1000));
+            assert_eq!((frame.left(), frame.top()), (0, 0));
+
+            let reference = read_samples(&format!("animated-{}.pam", i)[], 4 * 16 * 12);
+            assert!(frame.into_buffer().into_raw() == reference);
+        }
     }
 }
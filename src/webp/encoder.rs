@@ -0,0 +1,470 @@
+//! A WebP Encoder
+//!
+//! Lossy images are encoded as a single VP8 keyframe.
+//! The alpha channel of lossy images with transparency is stored
+//! uncompressed in an ```ALPH``` chunk of the extended file format.
+//! Lossless images are stored in a single ```VP8L``` chunk.
+//! An ICC profile is stored in an ```ICCP``` chunk of the extended file format.
+
+use std::old_io;
+use std::iter::repeat;
+use std::old_io::{
+    IoResult,
+    MemWriter
+};
+
+use color;
+use color::Rgba;
+use buffer::{ImageBuffer, RgbaImage};
+
+use super::vp8::{Frame, VP8Encoder};
+use super::lossless::LosslessEncoder;
+
+/// The quality used by ```WebpEncoder::new```
+pub const DEFAULT_QUALITY: u8 = 75;
+
+/// How the pixels of a WebP image are compressed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WebpCompression {
+    /// Lossy VP8 compression with a quality from 0 (smallest file)
+    /// to 100 (best quality)
+    Lossy(u8),
+
+    /// Lossless VP8L compression
+    Lossless,
+}
+
+// The dimensions are stored with 14 bits
+const MAX_DIMENSION: u32 = (1 << 14) - 1;
+
+/// The representation of a WebP encoder
+pub struct WebpEncoder<'a, W: 'a> {
+    w: &'a mut W,
+    compression: WebpCompression,
+    icc_profile: Option<Vec<u8>>,
+}
+
+impl<'a, W: Writer> WebpEncoder<'a, W> {
+    /// Create a new encoder that writes its output to ```w```
+    pub fn new(w: &mut W) -> WebpEncoder<W> {
+        WebpEncoder::new_with_compression(w, WebpCompression::Lossy(DEFAULT_QUALITY))
+    }
+
+    /// Create a new encoder that writes its output to ```w```.
+    /// ```quality``` ranges from 0 (smallest file) to 100 (best quality).
+    pub fn new_with_quality(w: &mut W, quality: u8) -> WebpEncoder<W> {
+        WebpEncoder::new_with_compression(w, WebpCompression::Lossy(quality))
+    }
+
+    /// Create a new encoder that writes its output to ```w```
+    /// and compresses the image as specified by ```compression```
+    pub fn new_with_compression(w: &mut W, compression: WebpCompression) -> WebpEncoder<W> {
+        WebpEncoder {
+            w: w,
+            compression: compression,
+            icc_profile: None,
+        }
+    }
+
+    /// Embeds the ICC profile ```profile``` in the image
+    pub fn set_icc_profile(&mut self, profile: Vec<u8>) {
+        self.icc_profile = Some(profile);
+    }
+
+    /// Encodes ```image``` losslessly, regardless of the
+    /// compression the encoder was created with
+    pub fn encode_lossless(&mut self, image: &RgbaImage) -> IoResult<()> {
+        let (width, height) = image.dimensions();
+        let _ = try!(check_dimensions(width, height));
+
+        let mut m = MemWriter::new();
+        let _ = try!(LosslessEncoder::new(&mut m).encode_frame(image));
+        let vp8l = m.into_inner();
+
+        let alpha = image.pixels().any(|&Rgba([_, _, _, a])| a != 255);
+        self.write_image(width, height, alpha, &[("VP8L", &vp8l[])])
+    }
+
+    /// Encodes the image ```image```
+    /// that has dimensions ```width``` and ```height```
+    /// and ```ColorType``` ```c```
+    pub fn encode(&mut self,
+                  image: &[u8],
+                  width: u32,
+                  height: u32,
+                  c: color::ColorType) -> IoResult<()> {
+
+        let _ = try!(check_dimensions(width, height));
+
+        let (rgb, alpha) = match c {
+            color::ColorType::RGB(8)   => (image.to_vec(), None),
+            color::ColorType::RGBA(8)  => split_alpha(image, 4),
+            color::ColorType::Gray(8)  => split_alpha(image, 1),
+            color::ColorType::GrayA(8) => split_alpha(image, 2),
+            _  => return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Unsupported color type. Use 8 bit per channel RGB(A) or Gray(A) instead.",
+                detail: Some(format!(
+                    "Color type {:?} is not suppored by this WebP encoder.",
+                    c
+                ))
+            })
+        };
+
+        let quality = match self.compression {
+            WebpCompression::Lossy(quality) => quality,
+            WebpCompression::Lossless => {
+                let alpha = alpha.unwrap_or_else(|| repeat(255u8).take(rgb.len() / 3).collect());
+                let image = ImageBuffer::from_raw(width, height, add_alpha(&rgb[], &alpha[])).unwrap();
+
+                return self.encode_lossless(&image)
+            }
+        };
+
+        let frame = Frame::from_rgb(&rgb[], width as u16, height as u16);
+
+        let mut m = MemWriter::new();
+        let _ = try!(VP8Encoder::new(&mut m, quality).encode_frame(&frame));
+        let vp8 = m.into_inner();
+
+        match alpha {
+            Some(alpha) => {
+                // Uncompressed and unfiltered alpha values
+                let mut alph = Vec::with_capacity(1 + alpha.len());
+                alph.push(0);
+                alph.push_all(&alpha[]);
+
+                self.write_image(width, height, true, &[("ALPH", &alph[]), ("VP8 ", &vp8[])])
+            }
+
+            None => self.write_image(width, height, false, &[("VP8 ", &vp8[])])
+        }
+    }
+
+    // Writes the chunks of a still image, in the extended file format
+    // if the image has an ALPH chunk or an ICC profile.
+    // VP8L chunks carry their own alpha flag.
+    fn write_image(&mut self, width: u32, height: u32, alpha: bool, chunks: &[(&str, &[u8])]) -> IoResult<()> {
+        let size = chunks.iter().fold(4, |size, &(_, data)| size + chunk_size(data));
+        let profile = self.icc_profile.clone();
+
+        if chunks.iter().any(|&(name, _)| name == "ALPH") || profile.is_some() {
+            let header = build_extended_header(width, height, alpha, profile.is_some());
+            let profile_size = profile.as_ref().map_or(0, |p| chunk_size(&p[]));
+
+            let _ = try!(self.write_riff_header(size + chunk_size(&header[]) + profile_size));
+            let _ = try!(self.write_chunk("VP8X", &header[]));
+
+            if let Some(ref profile) = profile {
+                let _ = try!(self.write_chunk("ICCP", &profile[]));
+            }
+        } else {
+            let _ = try!(self.write_riff_header(size));
+        }
+
+        for &(name, data) in chunks.iter() {
+            let _ = try!(self.write_chunk(name, data));
+        }
+
+        Ok(())
+    }
+
+    fn write_riff_header(&mut self, size: u32) -> IoResult<()> {
+        let _ = try!(self.w.write_str("RIFF"));
+        let _ = try!(self.w.write_le_u32(size));
+        self.w.write_str("WEBP")
+    }
+
+    fn write_chunk(&mut self, name: &str, buf: &[u8]) -> IoResult<()> {
+        let _ = try!(self.w.write_str(name));
+        let _ = try!(self.w.write_le_u32(buf.len() as u32));
+        let _ = try!(self.w.write_all(buf));
+
+        // Chunks are padded to an even size
+        if buf.len() % 2 == 1 {
+            let _ = try!(self.w.write_u8(0));
+        }
+
+        Ok(())
+    }
+}
+
+fn check_dimensions(width: u32, height: u32) -> IoResult<()> {
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(old_io::IoError {
+            kind: old_io::InvalidInput,
+            desc: "Invalid image dimensions.",
+            detail: Some(format!(
+                "The dimensions {}x{} can not be stored in a WebP image.",
+                width, height
+            ))
+        })
+    }
+
+    Ok(())
+}
+
+// The size of a chunk including its header and padding
+fn chunk_size(buf: &[u8]) -> u32 {
+    8 + buf.len() as u32 + (buf.len() as u32 & 1)
+}
+
+// Converts the pixels to RGB and returns the alpha channel
+// if any pixel is not opaque
+fn split_alpha(image: &[u8], bpp: usize) -> (Vec<u8>, Option<Vec<u8>>) {
+    let mut rgb = Vec::with_capacity(image.len() / bpp * 3);
+    let mut alpha = Vec::with_capacity(image.len() / bpp);
+
+    for p in image.chunks(bpp) {
+        if bpp < 3 {
+            rgb.push_all(&[p[0], p[0], p[0]]);
+        } else {
+            rgb.push_all(&p[..3]);
+        }
+
+        alpha.push(if bpp % 2 == 0 { p[bpp - 1] } else { 255 });
+    }
+
+    if alpha.iter().all(|&a| a == 255) {
+        (rgb, None)
+    } else {
+        (rgb, Some(alpha))
+    }
+}
+
+fn add_alpha(rgb: &[u8], alpha: &[u8]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(alpha.len() * 4);
+
+    for (p, &a) in rgb.chunks(3).zip(alpha.iter()) {
+        rgba.push_all(p);
+        rgba.push(a);
+    }
+
+    rgba
+}
+
+// A VP8X chunk announcing an alpha channel and an ICC profile
+fn build_extended_header(width: u32, height: u32, alpha: bool, icc_profile: bool) -> Vec<u8> {
+    let flags = (if alpha { 0x10 } else { 0 }) | (if icc_profile { 0x20 } else { 0 });
+    let mut header = vec![flags, 0, 0, 0];
+
+    for &v in [width - 1, height - 1].iter() {
+        header.push_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8]);
+    }
+
+    header
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::Float;
+    use std::old_io::{MemReader, MemWriter};
+
+    use color;
+    use buffer::{ImageBuffer, RgbaImage};
+    use image::{ImageDecoder, DecodingResult};
+    use super::super::decoder::WebpDecoder;
+    use super::{WebpEncoder, WebpCompression};
+
+    // A smooth pattern with some edges, the dimensions are not multiples of 16
+    fn test_image(width: u32, height: u32, bpp: u32) -> Vec<u8> {
+        let mut image = Vec::new();
+
+        for y in (0..height) {
+            for x in (0..width) {
+                let edge = if (x / 8 + y / 8) % 2 == 0 { 0 } else { 64 };
+                let p = [(x * 4 + edge) as u8, (y * 6 + edge) as u8, (128 + x + y) as u8, (x * y) as u8];
+
+                image.push_all(&p[..bpp as usize]);
+            }
+        }
+
+        image
+    }
+
+    fn round_trip(image: &[u8], width: u32, height: u32, c: color::ColorType, quality: u8) -> (color::ColorType, Vec<u8>) {
+        let mut m = MemWriter::new();
+        WebpEncoder::new_with_quality(&mut m, quality).encode(image, width, height, c).unwrap();
+
+        let mut decoder = WebpDecoder::new(MemReader::new(m.into_inner()));
+        assert_eq!(decoder.dimensions().unwrap(), (width, height));
+
+        let c = decoder.colortype().unwrap();
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => (c, pixels),
+            _ => panic!("expected 8 bit pixels")
+        }
+    }
+
+    // The peak signal to noise ratio of the channels selected by ```channels```
+    fn psnr(a: &[u8], b: &[u8], bpp: usize, channels: &[usize]) -> f64 {
+        let mut sum = 0.0;
+        let mut n = 0.0;
+
+        for (p, q) in a.chunks(bpp).zip(b.chunks(bpp)) {
+            for &c in channels.iter() {
+                let d = p[c] as f64 - q[c] as f64;
+                sum += d * d;
+                n += 1.0;
+            }
+        }
+
+        10.0 * (255.0 * 255.0 * n / sum).log10()
+    }
+
+    #[test]
+    fn test_lossy_round_trip() {
+        let image = test_image(45, 31, 3);
+
+        let (c, pixels) = round_trip(&image[], 45, 31, color::ColorType::RGB(8), 75);
+        assert_eq!(c, color::ColorType::RGB(8));
+        let low = psnr(&image[], &pixels[], 3, &[0, 1, 2]);
+        assert!(low > 30.0);
+
+        // The chroma subsampling of the edges limits the quality
+        let (_, pixels) = round_trip(&image[], 45, 31, color::ColorType::RGB(8), 95);
+        let high = psnr(&image[], &pixels[], 3, &[0, 1, 2]);
+        assert!(high > 32.0 && high > low);
+    }
+
+    #[test]
+    fn test_lossy_alpha_round_trip() {
+        let image = test_image(45, 31, 4);
+
+        let (c, pixels) = round_trip(&image[], 45, 31, color::ColorType::RGBA(8), 75);
+        assert_eq!(c, color::ColorType::RGBA(8));
+        assert!(psnr(&image[], &pixels[], 4, &[0, 1, 2]) > 30.0);
+
+        // The alpha channel is stored uncompressed
+        for (p, q) in image.chunks(4).zip(pixels.chunks(4)) {
+            assert_eq!(p[3], q[3]);
+        }
+    }
+
+    // Encodes ```image``` losslessly and checks that it decodes to the same pixels
+    fn lossless_round_trip(image: &RgbaImage) {
+        let mut m = MemWriter::new();
+        WebpEncoder::new(&mut m).encode_lossless(image).unwrap();
+
+        let mut decoder = WebpDecoder::new(MemReader::new(m.into_inner()));
+        assert_eq!(decoder.dimensions().unwrap(), image.dimensions());
+        assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert!(&pixels[] == image.as_slice()),
+            _ => panic!("expected 8 bit pixels")
+        }
+    }
+
+    // A reproducible xorshift sequence
+    fn random_bytes(n: usize) -> Vec<u8> {
+        let mut state = 0x2545f491u32;
+
+        (0..n).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_lossless_random() {
+        let image = ImageBuffer::from_raw(23, 17, random_bytes(4 * 23 * 17)).unwrap();
+        lossless_round_trip(&image);
+    }
+
+    #[test]
+    fn test_lossless_single_color() {
+        // All but the green and distance codes have a single symbol
+        lossless_round_trip(&ImageBuffer::from_pixel(13, 7, color::Rgba([200, 100, 50, 255])));
+        lossless_round_trip(&ImageBuffer::from_pixel(1, 1, color::Rgba([0, 0, 0, 0])));
+    }
+
+    #[test]
+    fn test_lossless_wide() {
+        // More distinct colors per row than the color cache holds,
+        // the rows repeat with a few random changes
+        let width = 1100;
+        let row = random_bytes(4 * width);
+        let noise = random_bytes(5 * width);
+
+        let mut data = Vec::new();
+        for y in (0..5) {
+            for x in (0..width) {
+                let p = &row[4 * x..4 * x + 4];
+
+                if noise[y * width + x] < 16 {
+                    data.push_all(&[p[1], p[2], p[0], p[3]]);
+                } else {
+                    data.push_all(p);
+                }
+            }
+        }
+
+        lossless_round_trip(&ImageBuffer::from_raw(width as u32, 5, data).unwrap());
+    }
+
+    #[test]
+    fn test_lossless_transparent_format() {
+        // The alpha flag of the VP8L header suffices without an ICC profile
+        let image = ImageBuffer::from_pixel(5, 3, color::Rgba([10, 20, 30, 40]));
+
+        let mut m = MemWriter::new();
+        WebpEncoder::new(&mut m).encode_lossless(&image).unwrap();
+        let data = m.into_inner();
+
+        assert_eq!(&data[12..16], b"VP8L");
+        lossless_round_trip(&image);
+    }
+
+    // The names of the chunks following the RIFF header
+    fn chunk_names(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut names = Vec::new();
+        let mut i = 12;
+
+        while i + 8 <= data.len() {
+            let len = data[i + 4] as usize | (data[i + 5] as usize) << 8
+                    | (data[i + 6] as usize) << 16 | (data[i + 7] as usize) << 24;
+
+            names.push(data[i..i + 4].to_vec());
+            i += 8 + len + len % 2;
+        }
+
+        names
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        // An odd length needs padding
+        let profile = (0..1001us).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let image = test_image(9, 7, 4);
+
+        for &lossless in [false, true].iter() {
+            let mut m = MemWriter::new();
+            {
+                let compression = if lossless { WebpCompression::Lossless } else { WebpCompression::Lossy(75) };
+                let mut encoder = WebpEncoder::new_with_compression(&mut m, compression);
+                encoder.set_icc_profile(profile.clone());
+                encoder.encode(&image[], 9, 7, color::ColorType::RGBA(8)).unwrap();
+            }
+            let data = m.into_inner();
+
+            // The ICC profile and alpha flags of the VP8X chunk
+            assert_eq!(data[20] & 0x30, 0x30);
+
+            // The profile precedes the image data
+            let expected = if lossless {
+                vec![b"VP8X".to_vec(), b"ICCP".to_vec(), b"VP8L".to_vec()]
+            } else {
+                vec![b"VP8X".to_vec(), b"ICCP".to_vec(), b"ALPH".to_vec(), b"VP8 ".to_vec()]
+            };
+            assert_eq!(chunk_names(&data[]), expected);
+
+            let mut decoder = WebpDecoder::new(MemReader::new(data));
+            assert_eq!(decoder.icc_profile().unwrap(), Some(profile.clone()));
+            assert_eq!(decoder.dimensions().unwrap(), (9, 7));
+            assert_eq!(decoder.colortype().unwrap(), color::ColorType::RGBA(8));
+        }
+    }
+}
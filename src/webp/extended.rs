@@ -0,0 +1,343 @@
+//! The extended WebP file format
+//!
+//! Images with alpha, metadata or animation start with a ```VP8X``` chunk
+//! that describes the canvas and announces the optional chunks.
+//!
+//! # Related Links
+//! * [Container spec](https://developers.google.com/speed/webp/docs/riff_container)
+//!
+
+use std::iter::repeat;
+use std::old_io::MemReader;
+
+use image::{ImageError, ImageResult};
+use utils::bitstream::LsbReader;
+
+use super::lossless::decode_image_stream;
+
+/// The contents of a ```VP8X``` chunk
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ExtendedHeader {
+    /// An ICC profile chunk is present
+    pub icc_profile: bool,
+
+    /// Some frame of the image has an alpha channel
+    pub alpha: bool,
+
+    /// An EXIF metadata chunk is present
+    pub exif_metadata: bool,
+
+    /// An XMP metadata chunk is present
+    pub xmp_metadata: bool,
+
+    /// The image is an animation
+    pub animation: bool,
+
+    /// The width of the canvas
+    pub canvas_width: u32,
+
+    /// The height of the canvas
+    pub canvas_height: u32,
+}
+
+impl ExtendedHeader {
+    /// Parses the 10 bytes of a ```VP8X``` chunk
+    pub fn from_bytes(data: &[u8]) -> ImageResult<ExtendedHeader> {
+        if data.len() < 10 {
+            return Err(ImageError::FormatError("VP8X chunk is too small.".to_string()))
+        }
+
+        let flags = data[0];
+
+        Ok(ExtendedHeader {
+            icc_profile:   flags & 0x20 != 0,
+            alpha:         flags & 0x10 != 0,
+            exif_metadata: flags & 0x08 != 0,
+            xmp_metadata:  flags & 0x04 != 0,
+            animation:     flags & 0x02 != 0,
+
+            canvas_width:  read_le_u24(&data[4..7]) + 1,
+            canvas_height: read_le_u24(&data[7..10]) + 1,
+        })
+    }
+}
+
+/// Reads a 24 bit little endian integer
+pub fn read_le_u24(data: &[u8]) -> u32 {
+    data[0] as u32 | ((data[1] as u32) << 8) | ((data[2] as u32) << 16)
+}
+
+/// The metadata chunks of an extended WebP image
+#[derive(Clone, Debug, Default)]
+pub struct WebpMetadata {
+    /// The raw ICC color profile
+    pub icc_profile: Option<Vec<u8>>,
+
+    /// The raw EXIF metadata
+    pub exif: Option<Vec<u8>>,
+
+    /// The XMP metadata, usually an XML document
+    pub xmp: Option<Vec<u8>>,
+}
+
+#[derive(Copy, PartialEq, Eq, Debug)]
+enum AlphaFilter {
+    None,
+    Horizontal,
+    Vertical,
+    Gradient,
+}
+
+/// Decodes the contents of an ```ALPH``` chunk into a plane of
+/// ```width``` by ```height``` alpha values.
+pub fn decode_alpha(data: &[u8], width: u16, height: u16) -> ImageResult<Vec<u8>> {
+    if data.len() < 1 {
+        return Err(ImageError::FormatError("ALPH chunk is empty.".to_string()))
+    }
+
+    let w = width as usize;
+    let h = height as usize;
+
+    let compression = data[0] & 0x03;
+    let filter = match (data[0] >> 2) & 0x03 {
+        0 => AlphaFilter::None,
+        1 => AlphaFilter::Horizontal,
+        2 => AlphaFilter::Vertical,
+        _ => AlphaFilter::Gradient,
+    };
+
+    let mut alpha = match compression {
+        0 => {
+            if data.len() < 1 + w * h {
+                return Err(ImageError::NotEnoughData)
+            }
+
+            data[1..1 + w * h].to_vec()
+        }
+
+        1 => {
+            // A VP8L image stream without header,
+            // the alpha values are stored in the green channel
+            let mut bits = LsbReader::new(MemReader::new(data[1..].to_vec()));
+            let pixels = try!(decode_image_stream(&mut bits, width, height, true));
+
+            pixels.iter().map(|&p| (p >> 8) as u8).collect()
+        }
+
+        _ => return Err(ImageError::FormatError(
+            "Unknown alpha compression method.".to_string()
+        ))
+    };
+
+    unfilter_alpha(&mut alpha[], w, h, filter);
+
+    Ok(alpha)
+}
+
+// Undoes the spatial prediction of the alpha plane. The first pixel is
+// predicted from zero, the remaining pixels of the first row from their left
+// neighbour and the remaining pixels of the first column from above.
+fn unfilter_alpha(alpha: &mut [u8], w: usize, h: usize, filter: AlphaFilter) {
+    if filter == AlphaFilter::None {
+        return
+    }
+
+    for y in (0..h) {
+        for x in (0..w) {
+            let i = y * w + x;
+
+            let predictor = if x == 0 && y == 0 {
+                0
+            } else if y == 0 {
+                alpha[i - 1]
+            } else if x == 0 {
+                alpha[i - w]
+            } else {
+                let left = alpha[i - 1] as i32;
+                let top = alpha[i - w] as i32;
+                let top_left = alpha[i - w - 1] as i32;
+
+                match filter {
+                    AlphaFilter::Horizontal => left as u8,
+                    AlphaFilter::Vertical   => top as u8,
+                    _ => {
+                        let gradient = left + top - top_left;
+                        if gradient < 0 { 0 } else if gradient > 255 { 255 } else { gradient as u8 }
+                    }
+                }
+            };
+
+            alpha[i] = (alpha[i] as u32 + predictor as u32) as u8;
+        }
+    }
+}
+
+/// Adds an alpha plane to an RGB image
+pub fn rgb_to_rgba(rgb: &[u8], alpha: &[u8]) -> Vec<u8> {
+    let mut rgba: Vec<u8> = repeat(0u8).take(alpha.len() * 4).collect();
+
+    for ((to, from), &a) in rgba.chunks_mut(4).zip(rgb.chunks(3)).zip(alpha.iter()) {
+        to[0] = from[0];
+        to[1] = from[1];
+        to[2] = from[2];
+        to[3] = a;
+    }
+
+    rgba
+}
+
+/// The contents of an ```ANIM``` chunk
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationInfo {
+    /// The suggested background color as RGBA
+    pub background_color: [u8; 4],
+
+    /// The number of times to loop the animation, zero means infinitely
+    pub loop_count: u16,
+}
+
+impl AnimationInfo {
+    /// Parses the 6 bytes of an ```ANIM``` chunk
+    pub fn from_bytes(data: &[u8]) -> ImageResult<AnimationInfo> {
+        if data.len() < 6 {
+            return Err(ImageError::FormatError("ANIM chunk is too small.".to_string()))
+        }
+
+        Ok(AnimationInfo {
+            // The color is stored in BGRA order
+            background_color: [data[2], data[1], data[0], data[3]],
+            loop_count: data[4] as u16 | ((data[5] as u16) << 8),
+        })
+    }
+}
+
+/// The header of an ```ANMF``` chunk
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationFrameHeader {
+    /// The x offset of the frame on the canvas
+    pub left: u32,
+
+    /// The y offset of the frame on the canvas
+    pub top: u32,
+
+    /// The width of the frame
+    pub width: u32,
+
+    /// The height of the frame
+    pub height: u32,
+
+    /// How long the frame is shown in milliseconds
+    pub duration: u32,
+
+    /// Whether the frame is alpha-blended with the canvas
+    /// instead of replacing it
+    pub blend: bool,
+
+    /// Whether the area of the frame is cleared to transparent
+    /// before the next frame is rendered
+    pub dispose: bool,
+}
+
+impl AnimationFrameHeader {
+    /// Parses the first 16 bytes of an ```ANMF``` chunk
+    pub fn from_bytes(data: &[u8]) -> ImageResult<AnimationFrameHeader> {
+        if data.len() < 16 {
+            return Err(ImageError::FormatError("ANMF chunk is too small.".to_string()))
+        }
+
+        Ok(AnimationFrameHeader {
+            left:     read_le_u24(&data[0..3]) * 2,
+            top:      read_le_u24(&data[3..6]) * 2,
+            width:    read_le_u24(&data[6..9]) + 1,
+            height:   read_le_u24(&data[9..12]) + 1,
+            duration: read_le_u24(&data[12..15]),
+            blend:    data[15] & 0x02 == 0,
+            dispose:  data[15] & 0x01 != 0,
+        })
+    }
+}
+
+/// Renders the RGBA ```frame``` described by ```header``` onto ```canvas```
+pub fn composite_frame(canvas: &mut [u8],
+                       canvas_width: u32,
+                       canvas_height: u32,
+                       frame: &[u8],
+                       header: &AnimationFrameHeader) {
+    for y in (0..header.height) {
+        if header.top + y >= canvas_height {
+            break
+        }
+
+        for x in (0..header.width) {
+            if header.left + x >= canvas_width {
+                break
+            }
+
+            let src = ((y * header.width + x) * 4) as usize;
+            let dst = (((header.top + y) * canvas_width + header.left + x) * 4) as usize;
+
+            let pixel = if header.blend {
+                blend_pixel(&canvas[dst..dst + 4], &frame[src..src + 4])
+            } else {
+                [frame[src], frame[src + 1], frame[src + 2], frame[src + 3]]
+            };
+
+            for c in (0us..4) {
+                canvas[dst + c] = pixel[c];
+            }
+        }
+    }
+}
+
+/// Clears the area covered by the frame to transparent black
+pub fn dispose_frame(canvas: &mut [u8],
+                     canvas_width: u32,
+                     canvas_height: u32,
+                     header: &AnimationFrameHeader) {
+    for y in (header.top..header.top + header.height) {
+        if y >= canvas_height {
+            break
+        }
+
+        for x in (header.left..header.left + header.width) {
+            if x >= canvas_width {
+                break
+            }
+
+            let i = ((y * canvas_width + x) * 4) as usize;
+
+            for c in (0us..4) {
+                canvas[i + c] = 0;
+            }
+        }
+    }
+}
+
+// Non-premultiplied src-over compositing as given by the container spec,
+// with the integer approximation libwebp uses so the frames match its output
+fn blend_pixel(dst: &[u8], src: &[u8]) -> [u8; 4] {
+    let src_a = src[3] as u32;
+    let dst_a = dst[3] as u32;
+
+    if src_a == 0 {
+        return [dst[0], dst[1], dst[2], dst[3]]
+    } else if src_a == 255 {
+        return [src[0], src[1], src[2], src[3]]
+    }
+
+    // dst.A * (1 - src.A / 255)
+    let dst_factor = (dst_a * (256 - src_a)) >> 8;
+    let blend_a = src_a + dst_factor;
+    let scale = (1u32 << 24) / blend_a;
+
+    let mut pixel = [0u8; 4];
+
+    for c in (0us..3) {
+        let value = (src[c] as u32 * src_a + dst[c] as u32 * dst_factor) * scale;
+        pixel[c] = (value >> 24) as u8;
+    }
+
+    pixel[3] = blend_a as u8;
+
+    pixel
+}
@@ -0,0 +1,299 @@
+//! Canonical prefix codes as used by the VP8L bitstream
+//!
+//! The codes are stored as a binary tree that is walked one bit at a time.
+//! Bits are consumed in the order they appear in the stream, starting
+//! with the most significant bit of a code.
+
+use std::cmp;
+use std::old_io;
+use std::old_io::IoResult;
+use std::iter::repeat;
+
+use image::{ImageError, ImageResult};
+use utils::bitstream::{BitReader, BitWriter};
+
+const MAX_CODE_LENGTH: usize = 15;
+
+#[derive(Clone, Copy, Debug)]
+enum HuffmanNode {
+    Empty,
+    Leaf(u16),
+    Branch(usize),
+}
+
+/// A prefix code built from the code length of every symbol
+#[derive(Clone, Debug)]
+pub struct HuffmanTree {
+    // A branch points to the first of its two consecutive children
+    nodes: Vec<HuffmanNode>,
+}
+
+impl HuffmanTree {
+    /// Builds the canonical prefix code for ```code_lengths```.
+    /// A length of zero means the symbol does not occur, at least one symbol has to occur.
+    pub fn from_code_lengths(code_lengths: &[u8]) -> ImageResult<HuffmanTree> {
+        let used = code_lengths.iter().filter(|&&l| l != 0).count();
+
+        if used == 0 {
+            return Err(ImageError::FormatError("Prefix code without symbols.".to_string()))
+        }
+
+        // A code with a single symbol needs no bits at all
+        if used == 1 {
+            let symbol = code_lengths.iter().position(|&l| l != 0).unwrap();
+
+            return Ok(HuffmanTree {
+                nodes: vec![HuffmanNode::Leaf(symbol as u16)]
+            })
+        }
+
+        let codes = try!(canonical_codes(code_lengths));
+        let mut nodes = vec![HuffmanNode::Empty];
+
+        for (symbol, &len) in code_lengths.iter().enumerate() {
+            if len == 0 {
+                continue
+            }
+
+            let code = codes[symbol];
+            let mut index = 0;
+
+            for i in (0..len).rev() {
+                let bit = ((code >> i as usize) & 1) as usize;
+
+                index = match nodes[index] {
+                    HuffmanNode::Branch(children) => children + bit,
+                    HuffmanNode::Empty => {
+                        let children = nodes.len();
+                        nodes.push(HuffmanNode::Empty);
+                        nodes.push(HuffmanNode::Empty);
+                        nodes[index] = HuffmanNode::Branch(children);
+
+                        children + bit
+                    }
+                    HuffmanNode::Leaf(_) => return Err(ImageError::FormatError(
+                        "Invalid prefix code.".to_string()
+                    ))
+                };
+            }
+
+            nodes[index] = HuffmanNode::Leaf(symbol as u16);
+        }
+
+        Ok(HuffmanTree {
+            nodes: nodes
+        })
+    }
+
+    /// Reads the next symbol from the bit reader ```r```
+    pub fn read_symbol<R: BitReader>(&self, r: &mut R) -> ImageResult<u16> {
+        let mut index = 0;
+
+        loop {
+            match self.nodes[index] {
+                HuffmanNode::Leaf(symbol) => return Ok(symbol),
+                HuffmanNode::Branch(children) => {
+                    index = children + try!(r.read_bits(1)) as usize;
+                }
+                HuffmanNode::Empty => return Err(ImageError::FormatError(
+                    "Invalid prefix code in bitstream.".to_string()
+                ))
+            }
+        }
+    }
+}
+
+/// Assigns the canonical codes for the given code lengths (RFC 1951, 3.2.2)
+pub fn canonical_codes(code_lengths: &[u8]) -> IoResult<Vec<u16>> {
+    let mut bl_count = [0u16; MAX_CODE_LENGTH + 1];
+
+    for &len in code_lengths.iter() {
+        if len as usize > MAX_CODE_LENGTH {
+            return Err(old_io::IoError {
+                kind: old_io::InvalidInput,
+                desc: "Prefix code length out of range",
+                detail: None
+            })
+        }
+
+        bl_count[len as usize] += 1;
+    }
+
+    bl_count[0] = 0;
+
+    let mut next_code = [0u16; MAX_CODE_LENGTH + 1];
+    let mut code = 0u16;
+
+    for bits in (1us..MAX_CODE_LENGTH + 1) {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    let mut codes = Vec::with_capacity(code_lengths.len());
+
+    for &len in code_lengths.iter() {
+        if len == 0 {
+            codes.push(0);
+        } else {
+            codes.push(next_code[len as usize]);
+            next_code[len as usize] += 1;
+        }
+    }
+
+    Ok(codes)
+}
+
+/// A prefix code used to write symbols
+#[derive(Clone, Debug)]
+pub struct HuffmanCode {
+    lengths: Vec<u8>,
+    codes: Vec<u16>,
+
+    // A code with a single symbol is written with zero bits
+    single_symbol: bool,
+}
+
+impl HuffmanCode {
+    /// Builds a prefix code for the symbol frequencies ```counts```
+    /// with codes of at most ```max_length``` bits.
+    pub fn from_histogram(counts: &[u32], max_length: u8) -> HuffmanCode {
+        let lengths = build_code_lengths(counts, max_length);
+        let used = lengths.iter().filter(|&&l| l != 0).count();
+
+        // The lengths are limited, they are always in range
+        let codes = canonical_codes(&lengths[]).unwrap();
+
+        HuffmanCode {
+            lengths: lengths,
+            codes: codes,
+            single_symbol: used <= 1,
+        }
+    }
+
+    /// The code length of every symbol
+    pub fn lengths(&self) -> &[u8] {
+        &self.lengths[]
+    }
+
+    /// Writes ```symbol``` so that ```HuffmanTree::read_symbol```
+    /// reads the most significant bit of its code first
+    pub fn write_symbol<W: BitWriter>(&self, w: &mut W, symbol: usize) -> IoResult<()> {
+        if self.single_symbol {
+            return Ok(())
+        }
+
+        let len = self.lengths[symbol];
+        let code = self.codes[symbol];
+
+        let mut reversed = 0u16;
+        for i in (0..len) {
+            reversed |= ((code >> i as usize) & 1) << (len - 1 - i) as usize;
+        }
+
+        w.write_bits(reversed, len)
+    }
+}
+
+// Builds a Huffman tree from the symbol frequencies and returns the depth
+// of every symbol. While the tree is too deep the frequencies are halved,
+// which flattens it.
+fn build_code_lengths(counts: &[u32], max_length: u8) -> Vec<u8> {
+    let mut lengths: Vec<u8> = repeat(0u8).take(counts.len()).collect();
+    let mut counts = counts.to_vec();
+
+    loop {
+        let mut leaves: Vec<(u32, usize)> = counts.iter()
+                                                  .enumerate()
+                                                  .filter(|&(_, &c)| c > 0)
+                                                  .map(|(i, &c)| (c, i))
+                                                  .collect();
+
+        if leaves.len() <= 1 {
+            for &(_, symbol) in leaves.iter() {
+                lengths[symbol] = 1;
+            }
+
+            return lengths
+        }
+
+        leaves.sort();
+
+        // The leaves and the internal nodes are both taken in increasing
+        // weight order, internal nodes are created in that order
+        let n = leaves.len();
+        let mut weights: Vec<u32> = leaves.iter().map(|&(c, _)| c).collect();
+        let mut parent: Vec<usize> = repeat(0us).take(2 * n - 1).collect();
+
+        let mut next_leaf = 0;
+        let mut next_internal = n;
+
+        for node in (n..2 * n - 1) {
+            let mut weight = 0;
+
+            for _ in (0us..2) {
+                let take_leaf = next_leaf < n && (next_internal >= node
+                                                  || weights[next_leaf] <= weights[next_internal]);
+
+                let child = if take_leaf {
+                    next_leaf += 1;
+                    next_leaf - 1
+                } else {
+                    next_internal += 1;
+                    next_internal - 1
+                };
+
+                parent[child] = node;
+                weight += weights[child];
+            }
+
+            weights.push(weight);
+        }
+
+        // Parents always come after their children
+        let mut depth: Vec<u8> = repeat(0u8).take(2 * n - 1).collect();
+        for node in (0..2 * n - 2).rev() {
+            depth[node] = depth[parent[node]] + 1;
+        }
+
+        if depth[..n].iter().any(|&d| d > max_length) {
+            for c in counts.iter_mut() {
+                if *c > 0 {
+                    *c = cmp::max(*c / 2, 1);
+                }
+            }
+
+            continue
+        }
+
+        for (i, &(_, symbol)) in leaves.iter().enumerate() {
+            lengths[symbol] = depth[i];
+        }
+
+        return lengths
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use utils::bitstream::LsbReader;
+    use super::HuffmanTree;
+
+    #[test]
+    fn test_empty_code() {
+        assert!(HuffmanTree::from_code_lengths(&[0, 0, 0, 0]).is_err());
+        assert!(HuffmanTree::from_code_lengths(&[]).is_err());
+    }
+
+    #[test]
+    fn test_single_symbol() {
+        let tree = HuffmanTree::from_code_lengths(&[0, 0, 3, 0]).unwrap();
+
+        // The symbol is read without consuming any bits
+        let data: &[u8] = &[];
+        let mut reader = LsbReader::new(data);
+
+        for _ in (0us..3) {
+            assert_eq!(tree.read_symbol(&mut reader).unwrap(), 2);
+        }
+    }
+}
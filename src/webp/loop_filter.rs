@@ -0,0 +1,175 @@
+//! Does loop filtering on webp lossy images
+//!
+//! The filters operate in place on a plane of pixels. ```point``` is the
+//! index of the first pixel after the edge (q0) and ```stride``` is the
+//! distance between two pixels perpendicular to the edge, i.e. 1 when
+//! filtering across a vertical edge and the row length when filtering
+//! across a horizontal edge.
+
+use math::utils::clamp;
+
+// Section 15.2 the clamp is to the range of a signed byte
+fn c(val: i32) -> i32 {
+    clamp(val, -128, 127)
+}
+
+// Unsigned to signed
+fn u2s(val: u8) -> i32 {
+    val as i32 - 128
+}
+
+// Signed to unsigned
+fn s2u(val: i32) -> u8 {
+    (c(val) + 128) as u8
+}
+
+fn diff(val1: u8, val2: u8) -> u8 {
+    if val1 > val2 {
+        val1 - val2
+    } else {
+        val2 - val1
+    }
+}
+
+// 15.2
+fn common_adjust(use_outer_taps: bool, pixels: &mut [u8], point: usize, stride: usize) -> i32 {
+    let p1 = u2s(pixels[point - 2 * stride]);
+    let p0 = u2s(pixels[point - stride]);
+    let q0 = u2s(pixels[point]);
+    let q1 = u2s(pixels[point + stride]);
+
+    // Disregarding clamping, when "use_outer_taps" is false,
+    // "a" is 3*(q0-p0). Since we are about to divide "a" by
+    // 8, in this case we end up multiplying the edge
+    // difference by 5/8.
+    //
+    // When "use_outer_taps" is true (as for the simple filter),
+    // "a" is p1 - 3*p0 + 3*q0 - q1, which can be thought of as
+    // a refinement of 2*(q0 - p0) and the adjustment is
+    // something like (q0 - p0)/4.
+    let outer = if use_outer_taps { c(p1 - q1) } else { 0 };
+    let a = c(outer + 3 * (q0 - p0));
+
+    // b is used to balance the rounding of a/8 in the case where
+    // the "fractional" part "f" of a/8 is exactly 1/2.
+    let b = (c(a + 3)) >> 3;
+
+    // Divide a by 8, rounding up when f >= 1/2.
+    // Although not strictly part of the C language,
+    // the right shift is assumed to propagate the sign bit.
+    let a = (c(a + 4)) >> 3;
+
+    // Subtract "a" from q0, "bringing it closer" to p0.
+    pixels[point] = s2u(q0 - a);
+
+    // Add "a" (with adjustment "b") to p0, "bringing it closer" to q0.
+    //
+    // The clamp of "a+b", while present in the reference decoder,
+    // is superfluous; we have -16 <= a <= 15 at this point.
+    pixels[point - stride] = s2u(p0 + b);
+
+    a
+}
+
+fn simple_threshold(filter_limit: i32, pixels: &[u8], point: usize, stride: usize) -> bool {
+    diff(pixels[point - stride], pixels[point]) as i32 * 2
+        + (diff(pixels[point - 2 * stride], pixels[point + stride]) >> 1) as i32
+        <= filter_limit
+}
+
+fn should_filter(interior_limit: u8,
+                 edge_limit: u8,
+                 pixels: &[u8],
+                 point: usize,
+                 stride: usize) -> bool {
+
+    simple_threshold(edge_limit as i32, pixels, point, stride)
+        && diff(pixels[point - 4 * stride], pixels[point - 3 * stride]) <= interior_limit
+        && diff(pixels[point - 3 * stride], pixels[point - 2 * stride]) <= interior_limit
+        && diff(pixels[point - 2 * stride], pixels[point - stride]) <= interior_limit
+        && diff(pixels[point + 3 * stride], pixels[point + 2 * stride]) <= interior_limit
+        && diff(pixels[point + 2 * stride], pixels[point + stride]) <= interior_limit
+        && diff(pixels[point + stride], pixels[point]) <= interior_limit
+}
+
+fn high_edge_variance(threshold: u8, pixels: &[u8], point: usize, stride: usize) -> bool {
+    diff(pixels[point - 2 * stride], pixels[point - stride]) > threshold
+        || diff(pixels[point + stride], pixels[point]) > threshold
+}
+
+/// Part of the simple filter described in 15.2 in the specification
+///
+/// Affects 4 pixels on an edge(2 each side)
+pub fn simple_segment(edge_limit: u8, pixels: &mut [u8], point: usize, stride: usize) {
+    if simple_threshold(edge_limit as i32, pixels, point, stride) {
+        common_adjust(true, pixels, point, stride);
+    }
+}
+
+/// Filters the edges between subblocks within a macroblock
+/// as described in 15.3 of the specification
+///
+/// Affects 4 pixels on an edge(2 each side)
+pub fn subblock_filter(hev_threshold: u8,
+                       interior_limit: u8,
+                       edge_limit: u8,
+                       pixels: &mut [u8],
+                       point: usize,
+                       stride: usize) {
+
+    if should_filter(interior_limit, edge_limit, pixels, point, stride) {
+        let hv = high_edge_variance(hev_threshold, pixels, point, stride);
+
+        let a = (common_adjust(hv, pixels, point, stride) + 1) >> 1;
+
+        if !hv {
+            pixels[point + stride] = s2u(u2s(pixels[point + stride]) - a);
+            pixels[point - 2 * stride] = s2u(u2s(pixels[point - 2 * stride]) + a);
+        }
+    }
+}
+
+/// Filters the edges between macroblocks
+/// as described in 15.3 of the specification
+///
+/// Affects 6 pixels on an edge(3 each side)
+pub fn macroblock_filter(hev_threshold: u8,
+                         interior_limit: u8,
+                         edge_limit: u8,
+                         pixels: &mut [u8],
+                         point: usize,
+                         stride: usize) {
+
+    if should_filter(interior_limit, edge_limit, pixels, point, stride) {
+        if !high_edge_variance(hev_threshold, pixels, point, stride) {
+            let p2 = u2s(pixels[point - 3 * stride]);
+            let p1 = u2s(pixels[point - 2 * stride]);
+            let p0 = u2s(pixels[point - stride]);
+            let q0 = u2s(pixels[point]);
+            let q1 = u2s(pixels[point + stride]);
+            let q2 = u2s(pixels[point + 2 * stride]);
+
+            let w = c(c(p1 - q1) + 3 * (q0 - p0));
+
+            // Roughly 3/7th difference across boundary
+            let a = c((27 * w + 63) >> 7);
+
+            pixels[point] = s2u(q0 - a);
+            pixels[point - stride] = s2u(p0 + a);
+
+            // Roughly 2/7th difference across boundary
+            let a = c((18 * w + 63) >> 7);
+
+            pixels[point + stride] = s2u(q1 - a);
+            pixels[point - 2 * stride] = s2u(p1 + a);
+
+            // Roughly 1/7th difference across boundary
+            let a = c((9 * w + 63) >> 7);
+
+            pixels[point + 2 * stride] = s2u(q2 - a);
+            pixels[point - 3 * stride] = s2u(p2 + a);
+        } else {
+            common_adjust(true, pixels, point, stride);
+        }
+    }
+}
@@ -0,0 +1,945 @@
+//! Decoding and Encoding of lossless WebP images
+//!
+//! The encoder applies the subtract green and predictor transforms
+//! and codes the residuals with backward references, a color cache
+//! and a single group of prefix codes.
+//!
+//! # Related Links
+//! * [Lossless spec](https://developers.google.com/speed/webp/docs/webp_lossless_bitstream_specification)
+//!
+
+use std::cmp;
+use std::iter::repeat;
+use std::old_io::{IoResult, MemReader, MemWriter};
+
+use image::{ImageError, ImageResult};
+use buffer::RgbaImage;
+use utils::bitstream::{BitReader, BitWriter, LsbReader, LsbWriter};
+
+use super::huffman::{HuffmanTree, HuffmanCode};
+use super::lossless_transform::{TransformType, add_pixels, subsample_size, color_index_width_bits};
+use super::lossless_transform::choose_predictors;
+
+const VP8L_SIGNATURE: u8 = 0x2f;
+
+const CODE_LENGTH_CODES: usize = 19;
+static CODE_LENGTH_CODE_ORDER: [usize; CODE_LENGTH_CODES] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+const NUM_LITERAL_CODES: u16 = 256;
+const NUM_LENGTH_CODES: u16 = 24;
+const NUM_DISTANCE_CODES: u16 = 40;
+
+// The green, red, blue, alpha and distance codes of a prefix code group
+const HUFFMAN_CODES_PER_META_CODE: usize = 5;
+
+static DISTANCE_MAP: [(i8, i8); 120] = [
+    (0, 1),  (1, 0),  (1, 1),  (-1, 1), (0, 2),  (2, 0),  (1, 2),  (-1, 2),
+    (2, 1),  (-2, 1), (2, 2),  (-2, 2), (0, 3),  (3, 0),  (1, 3),  (-1, 3),
+    (3, 1),  (-3, 1), (2, 3),  (-2, 3), (3, 2),  (-3, 2), (0, 4),  (4, 0),
+    (1, 4),  (-1, 4), (4, 1),  (-4, 1), (3, 3),  (-3, 3), (2, 4),  (-2, 4),
+    (4, 2),  (-4, 2), (0, 5),  (3, 4),  (-3, 4), (4, 3),  (-4, 3), (5, 0),
+    (1, 5),  (-1, 5), (5, 1),  (-5, 1), (2, 5),  (-2, 5), (5, 2),  (-5, 2),
+    (4, 4),  (-4, 4), (3, 5),  (-3, 5), (5, 3),  (-5, 3), (0, 6),  (6, 0),
+    (1, 6),  (-1, 6), (6, 1),  (-6, 1), (2, 6),  (-2, 6), (6, 2),  (-6, 2),
+    (4, 5),  (-4, 5), (5, 4),  (-5, 4), (3, 6),  (-3, 6), (6, 3),  (-6, 3),
+    (0, 7),  (7, 0),  (1, 7),  (-1, 7), (5, 5),  (-5, 5), (7, 1),  (-7, 1),
+    (4, 6),  (-4, 6), (6, 4),  (-6, 4), (2, 7),  (-2, 7), (7, 2),  (-7, 2),
+    (3, 7),  (-3, 7), (7, 3),  (-7, 3), (5, 6),  (-5, 6), (6, 5),  (-6, 5),
+    (8, 0),  (4, 7),  (-4, 7), (7, 4),  (-7, 4), (8, 1),  (8, 2),  (6, 6),
+    (-6, 6), (8, 3),  (5, 7),  (-5, 7), (7, 5),  (-7, 5), (8, 4),  (6, 7),
+    (-6, 7), (7, 6),  (-7, 6), (8, 5),  (7, 7),  (-7, 7), (8, 6),  (8, 7)
+];
+
+/// A decoded lossless image
+#[derive(Default, Debug, Clone)]
+pub struct LosslessFrame {
+    /// The width of the image
+    pub width: u16,
+
+    /// The height of the image
+    pub height: u16,
+
+    /// Whether the encoder marked the alpha channel as used
+    pub alpha_is_used: bool,
+
+    /// The pixels of the image as ARGB
+    pub buf: Vec<u32>,
+}
+
+impl LosslessFrame {
+    /// Converts the frame to RGBA and writes it to ```buf```.
+    pub fn fill_rgba(&self, buf: &mut [u8]) {
+        for (&argb, rgba) in self.buf.iter().zip(buf.chunks_mut(4)) {
+            rgba[0] = (argb >> 16) as u8;
+            rgba[1] = (argb >> 8) as u8;
+            rgba[2] = argb as u8;
+            rgba[3] = (argb >> 24) as u8;
+        }
+    }
+}
+
+// The five prefix codes used to decode one region of the image
+#[derive(Clone, Debug)]
+struct HuffmanCodeGroup {
+    trees: Vec<HuffmanTree>,
+}
+
+struct HuffmanInfo {
+    // Bits of the subsampling of the entropy image, if there is one
+    bits: u8,
+    xsize: u16,
+    image: Vec<u32>,
+    groups: Vec<HuffmanCodeGroup>,
+    color_cache_bits: u8,
+}
+
+impl HuffmanInfo {
+    fn group_index(&self, x: usize, y: usize) -> usize {
+        if self.image.is_empty() {
+            0
+        } else {
+            let index = (y >> self.bits as usize) * self.xsize as usize + (x >> self.bits as usize);
+            ((self.image[index] >> 8) & 0xffff) as usize
+        }
+    }
+}
+
+struct ColorCache {
+    bits: u8,
+    colors: Vec<u32>,
+}
+
+impl ColorCache {
+    fn new(bits: u8) -> ColorCache {
+        ColorCache {
+            bits: bits,
+            colors: repeat(0u32).take(1us << bits as usize).collect(),
+        }
+    }
+
+    fn index(&self, color: u32) -> usize {
+        ((0x1e35a7bdu64 * color as u64) as u32 >> (32 - self.bits as usize)) as usize
+    }
+
+    fn insert(&mut self, color: u32) {
+        let index = self.index(color);
+        self.colors[index] = color;
+    }
+
+    fn lookup(&self, index: usize) -> ImageResult<u32> {
+        match self.colors.get(index) {
+            Some(&color) => Ok(color),
+            None => Err(ImageError::FormatError("Invalid color cache index.".to_string()))
+        }
+    }
+}
+
+/// A VP8L decoder
+pub struct LosslessDecoder<R> {
+    r: R,
+    frame: LosslessFrame,
+}
+
+impl<R: Reader> LosslessDecoder<R> {
+    /// Create a new decoder.
+    /// The reader must present the contents of a VP8L chunk to the decoder.
+    pub fn new(r: R) -> LosslessDecoder<R> {
+        LosslessDecoder {
+            r: r,
+            frame: Default::default(),
+        }
+    }
+
+    /// Decodes the image and returns a reference to it
+    pub fn decode_frame(&mut self) -> ImageResult<&LosslessFrame> {
+        let data = try!(self.r.read_to_end());
+        let mut bits = LsbReader::new(MemReader::new(data));
+
+        let signature = try!(bits.read_bits(8)) as u8;
+        if signature != VP8L_SIGNATURE {
+            return Err(ImageError::FormatError("Invalid VP8L signature.".to_string()))
+        }
+
+        self.frame.width = try!(bits.read_bits(14)) + 1;
+        self.frame.height = try!(bits.read_bits(14)) + 1;
+        self.frame.alpha_is_used = try!(bits.read_bits(1)) == 1;
+
+        let version = try!(bits.read_bits(3));
+        if version != 0 {
+            return Err(ImageError::UnsupportedError(
+                format!("VP8L version {} is not supported.", version)
+            ))
+        }
+
+        let (width, height) = (self.frame.width, self.frame.height);
+        self.frame.buf = try!(decode_image_stream(&mut bits, width, height, true));
+
+        Ok(&self.frame)
+    }
+}
+
+/// Decodes an image stream without the VP8L header, as also used for the
+/// compressed alpha plane of lossy images. ```is_main``` must be set for
+/// the argb image, the only one that may have transforms and meta codes.
+pub fn decode_image_stream<B: BitReader>(bits: &mut B,
+                                         width: u16,
+                                         height: u16,
+                                         is_main: bool) -> ImageResult<Vec<u32>> {
+    let mut xsize = width;
+    let mut transforms = Vec::new();
+
+    if is_main {
+        let mut seen = [false; 4];
+
+        while try!(bits.read_bits(1)) == 1 {
+            let transform_type = try!(bits.read_bits(2)) as usize;
+
+            if seen[transform_type] {
+                return Err(ImageError::FormatError(
+                    "A VP8L transform may only be used once.".to_string()
+                ))
+            }
+
+            seen[transform_type] = true;
+
+            let transform = try!(read_transform(bits, transform_type, xsize, height));
+
+            let new_xsize = match transform {
+                TransformType::ColorIndexing { table_size, .. } => {
+                    subsample_size(xsize, color_index_width_bits(table_size))
+                }
+                _ => xsize
+            };
+
+            transforms.push((transform, xsize));
+            xsize = new_xsize;
+        }
+    }
+
+    let color_cache_bits = if try!(bits.read_bits(1)) == 1 {
+        let cache_bits = try!(bits.read_bits(4)) as u8;
+
+        if cache_bits < 1 || cache_bits > 11 {
+            return Err(ImageError::FormatError("Invalid color cache size.".to_string()))
+        }
+
+        cache_bits
+    } else {
+        0
+    };
+
+    let huffman_info = try!(read_huffman_codes(bits, xsize, height, color_cache_bits, is_main));
+
+    let mut pixels = try!(decode_pixels(bits, xsize, height, &huffman_info));
+
+    for &(ref transform, transform_xsize) in transforms.iter().rev() {
+        transform.apply_inverse(&mut pixels, transform_xsize, height);
+    }
+
+    Ok(pixels)
+}
+
+fn read_transform<B: BitReader>(bits: &mut B,
+                                transform_type: usize,
+                                xsize: u16,
+                                ysize: u16) -> ImageResult<TransformType> {
+    match transform_type {
+        0 | 1 => {
+            let size_bits = try!(bits.read_bits(3)) as u8 + 2;
+            let block_xsize = subsample_size(xsize, size_bits);
+            let block_ysize = subsample_size(ysize, size_bits);
+
+            let data = try!(decode_image_stream(bits, block_xsize, block_ysize, false));
+
+            Ok(if transform_type == 0 {
+                TransformType::Predictor { size_bits: size_bits, data: data }
+            } else {
+                TransformType::CrossColor { size_bits: size_bits, data: data }
+            })
+        }
+
+        2 => Ok(TransformType::SubtractGreen),
+
+        _ => {
+            let table_size = try!(bits.read_bits(8)) + 1;
+            let mut table = try!(decode_image_stream(bits, table_size, 1, false));
+
+            // The table is coded as differences to the previous entry
+            for i in (1..table.len()) {
+                table[i] = add_pixels(table[i], table[i - 1]);
+            }
+
+            Ok(TransformType::ColorIndexing { table_size: table_size, table: table })
+        }
+    }
+}
+
+fn read_huffman_codes<B: BitReader>(bits: &mut B,
+                                    xsize: u16,
+                                    ysize: u16,
+                                    color_cache_bits: u8,
+                                    is_main: bool) -> ImageResult<HuffmanInfo> {
+    let mut num_groups = 1;
+    let mut huffman_bits = 0;
+    let mut huffman_xsize = 1;
+    let mut entropy_image = Vec::new();
+
+    if is_main && try!(bits.read_bits(1)) == 1 {
+        huffman_bits = try!(bits.read_bits(3)) as u8 + 2;
+        huffman_xsize = subsample_size(xsize, huffman_bits);
+        let huffman_ysize = subsample_size(ysize, huffman_bits);
+
+        entropy_image = try!(decode_image_stream(bits, huffman_xsize, huffman_ysize, false));
+
+        for &p in entropy_image.iter() {
+            let meta_code = ((p >> 8) & 0xffff) as usize;
+
+            if meta_code >= num_groups {
+                num_groups = meta_code + 1;
+            }
+        }
+    }
+
+    let color_cache_size = if color_cache_bits > 0 { 1u16 << color_cache_bits as usize }
+                           else { 0 };
+
+    let alphabet_sizes = [
+        NUM_LITERAL_CODES + NUM_LENGTH_CODES + color_cache_size,
+        NUM_LITERAL_CODES,
+        NUM_LITERAL_CODES,
+        NUM_LITERAL_CODES,
+        NUM_DISTANCE_CODES,
+    ];
+
+    let mut groups = Vec::with_capacity(num_groups);
+
+    for _ in (0..num_groups) {
+        let mut trees = Vec::with_capacity(HUFFMAN_CODES_PER_META_CODE);
+
+        for &alphabet_size in alphabet_sizes.iter() {
+            trees.push(try!(read_huffman_code(bits, alphabet_size)));
+        }
+
+        groups.push(HuffmanCodeGroup { trees: trees });
+    }
+
+    Ok(HuffmanInfo {
+        bits: huffman_bits,
+        xsize: huffman_xsize,
+        image: entropy_image,
+        groups: groups,
+        color_cache_bits: color_cache_bits,
+    })
+}
+
+fn read_huffman_code<B: BitReader>(bits: &mut B, alphabet_size: u16) -> ImageResult<HuffmanTree> {
+    let mut code_lengths: Vec<u8> = repeat(0u8).take(alphabet_size as usize).collect();
+    let simple = try!(bits.read_bits(1)) == 1;
+
+    if simple {
+        let num_symbols = try!(bits.read_bits(1)) + 1;
+        let is_first_8bits = try!(bits.read_bits(1));
+
+        let first = try!(bits.read_bits(1 + 7 * is_first_8bits as u8)) as usize;
+        if first >= code_lengths.len() {
+            return Err(ImageError::FormatError("Invalid prefix code symbol.".to_string()))
+        }
+        code_lengths[first] = 1;
+
+        if num_symbols == 2 {
+            let second = try!(bits.read_bits(8)) as usize;
+            if second >= code_lengths.len() {
+                return Err(ImageError::FormatError("Invalid prefix code symbol.".to_string()))
+            }
+            code_lengths[second] = 1;
+        }
+    } else {
+        let num_code_lengths = 4 + try!(bits.read_bits(4)) as usize;
+        let mut code_length_code_lengths = [0u8; CODE_LENGTH_CODES];
+
+        for i in (0..num_code_lengths) {
+            code_length_code_lengths[CODE_LENGTH_CODE_ORDER[i]] = try!(bits.read_bits(3)) as u8;
+        }
+
+        let code_length_code = try!(HuffmanTree::from_code_lengths(&code_length_code_lengths[]));
+
+        try!(read_code_lengths(bits, &code_length_code, &mut code_lengths[]));
+    }
+
+    HuffmanTree::from_code_lengths(&code_lengths[])
+}
+
+fn read_code_lengths<B: BitReader>(bits: &mut B,
+                                   code_length_code: &HuffmanTree,
+                                   code_lengths: &mut [u8]) -> ImageResult<()> {
+    let mut max_symbol = if try!(bits.read_bits(1)) == 1 {
+        let length_nbits = 2 + 2 * try!(bits.read_bits(3)) as u8;
+        let max_symbol = 2 + try!(bits.read_bits(length_nbits)) as usize;
+
+        if max_symbol > code_lengths.len() {
+            return Err(ImageError::FormatError("Invalid prefix code.".to_string()))
+        }
+
+        max_symbol
+    } else {
+        code_lengths.len()
+    };
+
+    let mut prev_code_len = 8u8;
+    let mut symbol = 0;
+
+    while symbol < code_lengths.len() {
+        if max_symbol == 0 {
+            break
+        }
+        max_symbol -= 1;
+
+        let code_len = try!(code_length_code.read_symbol(bits));
+
+        if code_len < 16 {
+            code_lengths[symbol] = code_len as u8;
+            symbol += 1;
+
+            if code_len != 0 {
+                prev_code_len = code_len as u8;
+            }
+        } else {
+            let (extra_bits, offset, value) = match code_len {
+                16 => (2, 3, prev_code_len),
+                17 => (3, 3, 0),
+                _  => (7, 11, 0),
+            };
+
+            let repeat_count = offset + try!(bits.read_bits(extra_bits)) as usize;
+
+            if symbol + repeat_count > code_lengths.len() {
+                return Err(ImageError::FormatError("Invalid prefix code.".to_string()))
+            }
+
+            for _ in (0..repeat_count) {
+                code_lengths[symbol] = value;
+                symbol += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_pixels<B: BitReader>(bits: &mut B,
+                               xsize: u16,
+                               ysize: u16,
+                               huffman_info: &HuffmanInfo) -> ImageResult<Vec<u32>> {
+    let width = xsize as usize;
+    let num_pixels = width * ysize as usize;
+
+    let mut pixels = Vec::with_capacity(num_pixels);
+    let mut cache = if huffman_info.color_cache_bits > 0 {
+        Some(ColorCache::new(huffman_info.color_cache_bits))
+    } else {
+        None
+    };
+
+    while pixels.len() < num_pixels {
+        let index = pixels.len();
+        let group = match huffman_info.groups.get(huffman_info.group_index(index % width,
+                                                                          index / width)) {
+            Some(group) => group,
+            None => return Err(ImageError::FormatError("Invalid prefix code group.".to_string()))
+        };
+
+        let code = try!(group.trees[0].read_symbol(bits));
+
+        if code < NUM_LITERAL_CODES {
+            let green = code as u32;
+            let red   = try!(group.trees[1].read_symbol(bits)) as u32;
+            let blue  = try!(group.trees[2].read_symbol(bits)) as u32;
+            let alpha = try!(group.trees[3].read_symbol(bits)) as u32;
+
+            let argb = (alpha << 24) | (red << 16) | (green << 8) | blue;
+
+            pixels.push(argb);
+
+            if let Some(ref mut cache) = cache {
+                cache.insert(argb);
+            }
+        } else if code < NUM_LITERAL_CODES + NUM_LENGTH_CODES {
+            let length = try!(lz77_prefix_value(bits, code - NUM_LITERAL_CODES));
+
+            let distance_symbol = try!(group.trees[4].read_symbol(bits));
+            let distance_code = try!(lz77_prefix_value(bits, distance_symbol));
+            let distance = plane_code_to_distance(width, distance_code);
+
+            if distance > index || index + length > num_pixels {
+                return Err(ImageError::FormatError("Invalid backward reference.".to_string()))
+            }
+
+            for i in (0..length) {
+                let argb = pixels[index + i - distance];
+
+                pixels.push(argb);
+
+                if let Some(ref mut cache) = cache {
+                    cache.insert(argb);
+                }
+            }
+        } else {
+            let argb = match cache {
+                Some(ref cache) => try!(cache.lookup((code - NUM_LITERAL_CODES - NUM_LENGTH_CODES) as usize)),
+                None => return Err(ImageError::FormatError("Invalid color cache index.".to_string()))
+            };
+
+            pixels.push(argb);
+
+            if let Some(ref mut cache) = cache {
+                cache.insert(argb);
+            }
+        }
+    }
+
+    Ok(pixels)
+}
+
+// Length and distance prefix codes share the same mapping to values
+fn lz77_prefix_value<B: BitReader>(bits: &mut B, prefix: u16) -> ImageResult<usize> {
+    if prefix < 4 {
+        return Ok(prefix as usize + 1)
+    }
+
+    let extra_bits = (prefix - 2) >> 1;
+    let offset = (2 + (prefix as usize & 1)) << extra_bits as usize;
+
+    let extra = if extra_bits > 16 {
+        let low = try!(bits.read_bits(16)) as usize;
+        let high = try!(bits.read_bits(extra_bits as u8 - 16)) as usize;
+        low | (high << 16)
+    } else {
+        try!(bits.read_bits(extra_bits as u8)) as usize
+    };
+
+    Ok(offset + extra + 1)
+}
+
+// The first 120 distance codes address a neighbourhood of the pixel
+fn plane_code_to_distance(xsize: usize, plane_code: usize) -> usize {
+    if plane_code > 120 {
+        plane_code - 120
+    } else {
+        let (xoffset, yoffset) = DISTANCE_MAP[plane_code - 1];
+        let distance = xoffset as isize + yoffset as isize * xsize as isize;
+
+        if distance < 1 { 1 } else { distance as usize }
+    }
+}
+
+// The color cache of the encoder, chosen like libwebp's default
+const ENCODER_CACHE_BITS: u8 = 10;
+
+// The predictor modes are chosen for blocks of 16x16 pixels
+const ENCODER_PREDICTOR_BITS: u8 = 4;
+
+// Backward references are searched in the last 2^16 pixels,
+// following at most this many candidates of the hash chain
+const WINDOW_SIZE: usize = 1 << 16;
+const MAX_CHAIN_LENGTH: usize = 32;
+
+const MIN_LENGTH: usize = 3;
+const MAX_LENGTH: usize = 4096;
+
+#[derive(Copy, Clone, Debug)]
+enum PixelToken {
+    Literal(u32),
+    CacheIndex(u16),
+    BackwardReference(usize, usize),
+}
+
+/// A VP8L encoder
+pub struct LosslessEncoder<'a, W: 'a> {
+    w: &'a mut W,
+}
+
+impl<'a, W: Writer> LosslessEncoder<'a, W> {
+    /// Create a new encoder that writes the contents of a VP8L chunk to ```w```
+    pub fn new(w: &mut W) -> LosslessEncoder<W> {
+        LosslessEncoder {
+            w: w,
+        }
+    }
+
+    /// Encodes ```image``` with the subtract green and predictor transforms
+    pub fn encode_frame(&mut self, image: &RgbaImage) -> IoResult<()> {
+        let (width, height) = image.dimensions();
+
+        let mut pixels: Vec<u32> = image.pixels().map(|p| {
+            ((p[3] as u32) << 24) | ((p[0] as u32) << 16) | ((p[1] as u32) << 8) | p[2] as u32
+        }).collect();
+
+        let alpha_is_used = pixels.iter().any(|&p| p >> 24 != 0xff);
+
+        let mut bits = LsbWriter::new(MemWriter::new());
+
+        let _ = try!(bits.write_bits(VP8L_SIGNATURE as u16, 8));
+        let _ = try!(bits.write_bits(width as u16 - 1, 14));
+        let _ = try!(bits.write_bits(height as u16 - 1, 14));
+        let _ = try!(bits.write_bits(if alpha_is_used { 1 } else { 0 }, 1));
+        let _ = try!(bits.write_bits(0, 3));
+
+        let (width, height) = (width as u16, height as u16);
+
+        // Subtract green
+        TransformType::SubtractGreen.apply_forward(&mut pixels, width, height);
+
+        let _ = try!(bits.write_bits(1, 1));
+        let _ = try!(bits.write_bits(2, 2));
+
+        // Predictor
+        let size_bits = ENCODER_PREDICTOR_BITS;
+        let modes = choose_predictors(&pixels[], width, height, size_bits);
+        let predictor = TransformType::Predictor { size_bits: size_bits, data: modes };
+        predictor.apply_forward(&mut pixels, width, height);
+
+        let _ = try!(bits.write_bits(1, 1));
+        let _ = try!(bits.write_bits(0, 2));
+        let _ = try!(bits.write_bits(size_bits as u16 - 2, 3));
+
+        if let TransformType::Predictor { ref data, .. } = predictor {
+            let block_width = subsample_size(width, size_bits);
+            let _ = try!(encode_image_stream(&mut bits, &data[], block_width, false));
+        }
+
+        // No more transforms
+        let _ = try!(bits.write_bits(0, 1));
+
+        let _ = try!(encode_image_stream(&mut bits, &pixels[], width, true));
+        let _ = try!(bits.flush());
+
+        self.w.write_all(&bits.into_inner().into_inner()[])
+    }
+}
+
+/// Encodes an image stream without the VP8L header. The argb image
+/// (```is_main```) uses a color cache but never meta prefix codes.
+pub fn encode_image_stream<B: BitWriter>(bits: &mut B,
+                                         pixels: &[u32],
+                                         width: u16,
+                                         is_main: bool) -> IoResult<()> {
+    let cache_bits = if is_main { ENCODER_CACHE_BITS } else { 0 };
+
+    if cache_bits > 0 {
+        let _ = try!(bits.write_bits(1, 1));
+        let _ = try!(bits.write_bits(cache_bits as u16, 4));
+    } else {
+        let _ = try!(bits.write_bits(0, 1));
+    }
+
+    if is_main {
+        // A single prefix code group for the whole image
+        let _ = try!(bits.write_bits(0, 1));
+    }
+
+    let tokens = tokenize(pixels, width as usize, cache_bits);
+
+    let color_cache_size = if cache_bits > 0 { 1us << cache_bits as usize } else { 0 };
+    let alphabet_sizes = [
+        (NUM_LITERAL_CODES + NUM_LENGTH_CODES) as usize + color_cache_size,
+        NUM_LITERAL_CODES as usize,
+        NUM_LITERAL_CODES as usize,
+        NUM_LITERAL_CODES as usize,
+        NUM_DISTANCE_CODES as usize,
+    ];
+
+    let mut histograms: Vec<Vec<u32>> = alphabet_sizes.iter().map(|&size| {
+        repeat(0u32).take(size).collect()
+    }).collect();
+
+    for token in tokens.iter() {
+        match *token {
+            PixelToken::Literal(argb) => {
+                histograms[0][((argb >> 8) & 0xff) as usize] += 1;
+                histograms[1][((argb >> 16) & 0xff) as usize] += 1;
+                histograms[2][(argb & 0xff) as usize] += 1;
+                histograms[3][(argb >> 24) as usize] += 1;
+            }
+
+            PixelToken::CacheIndex(index) => {
+                histograms[0][(NUM_LITERAL_CODES + NUM_LENGTH_CODES + index) as usize] += 1;
+            }
+
+            PixelToken::BackwardReference(length, distance_code) => {
+                let (length_prefix, _, _) = prefix_encode(length);
+                let (distance_prefix, _, _) = prefix_encode(distance_code);
+
+                histograms[0][NUM_LITERAL_CODES as usize + length_prefix] += 1;
+                histograms[4][distance_prefix] += 1;
+            }
+        }
+    }
+
+    let mut codes = Vec::with_capacity(HUFFMAN_CODES_PER_META_CODE);
+
+    for histogram in histograms.iter() {
+        let code = HuffmanCode::from_histogram(&histogram[], 15);
+        let _ = try!(write_huffman_code(bits, &code));
+
+        codes.push(code);
+    }
+
+    for token in tokens.iter() {
+        match *token {
+            PixelToken::Literal(argb) => {
+                let _ = try!(codes[0].write_symbol(bits, ((argb >> 8) & 0xff) as usize));
+                let _ = try!(codes[1].write_symbol(bits, ((argb >> 16) & 0xff) as usize));
+                let _ = try!(codes[2].write_symbol(bits, (argb & 0xff) as usize));
+                let _ = try!(codes[3].write_symbol(bits, (argb >> 24) as usize));
+            }
+
+            PixelToken::CacheIndex(index) => {
+                let symbol = (NUM_LITERAL_CODES + NUM_LENGTH_CODES + index) as usize;
+                let _ = try!(codes[0].write_symbol(bits, symbol));
+            }
+
+            PixelToken::BackwardReference(length, distance_code) => {
+                let (prefix, extra_bits, extra) = prefix_encode(length);
+                let _ = try!(codes[0].write_symbol(bits, NUM_LITERAL_CODES as usize + prefix));
+                let _ = try!(write_extra_bits(bits, extra, extra_bits));
+
+                let (prefix, extra_bits, extra) = prefix_encode(distance_code);
+                let _ = try!(codes[4].write_symbol(bits, prefix));
+                let _ = try!(write_extra_bits(bits, extra, extra_bits));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Splits the pixels into literals, color cache hits and backward
+// references, found greedily through hash chains of pixel pairs
+fn tokenize(pixels: &[u32], width: usize, cache_bits: u8) -> Vec<PixelToken> {
+    let num_pixels = pixels.len();
+    let hash_size = 1us << 16;
+
+    let mut head: Vec<Option<usize>> = repeat(None).take(hash_size).collect();
+    let mut chain: Vec<Option<usize>> = repeat(None).take(num_pixels).collect();
+
+    let mut cache = if cache_bits > 0 { Some(ColorCache::new(cache_bits)) } else { None };
+    let mut tokens = Vec::new();
+
+    let hash = |i: usize| -> usize {
+        let pair = pixels[i] as u64 ^ ((pixels[i + 1] as u64) << 7);
+        ((pair * 0x1e35a7bd) >> 16) as usize & (hash_size - 1)
+    };
+
+    let mut i = 0;
+
+    while i < num_pixels {
+        let mut best_length = 0;
+        let mut best_distance = 0;
+
+        if i + MIN_LENGTH <= num_pixels {
+            let mut candidate = head[hash(i)];
+            let mut chain_length = 0;
+
+            while let Some(c) = candidate {
+                if i - c > WINDOW_SIZE || chain_length == MAX_CHAIN_LENGTH {
+                    break
+                }
+
+                let max_length = cmp::min(MAX_LENGTH, num_pixels - i);
+                let mut length = 0;
+
+                while length < max_length && pixels[c + length] == pixels[i + length] {
+                    length += 1;
+                }
+
+                if length > best_length {
+                    best_length = length;
+                    best_distance = i - c;
+                }
+
+                candidate = chain[c];
+                chain_length += 1;
+            }
+        }
+
+        let length = if best_length >= MIN_LENGTH {
+            tokens.push(PixelToken::BackwardReference(best_length,
+                                                      distance_to_plane_code(width, best_distance)));
+            best_length
+        } else {
+            let argb = pixels[i];
+
+            let token = match cache {
+                Some(ref cache) if cache.colors[cache.index(argb)] == argb => {
+                    PixelToken::CacheIndex(cache.index(argb) as u16)
+                }
+                _ => PixelToken::Literal(argb)
+            };
+
+            tokens.push(token);
+            1
+        };
+
+        for j in (i..i + length) {
+            if let Some(ref mut cache) = cache {
+                cache.insert(pixels[j]);
+            }
+
+            if j + 1 < num_pixels {
+                let h = hash(j);
+                chain[j] = head[h];
+                head[h] = Some(j);
+            }
+        }
+
+        i += length;
+    }
+
+    tokens
+}
+
+// The inverse of plane_code_to_distance, nearby pixels have short codes
+fn distance_to_plane_code(xsize: usize, distance: usize) -> usize {
+    for (i, &(xoffset, yoffset)) in DISTANCE_MAP.iter().enumerate() {
+        if xoffset as isize + yoffset as isize * xsize as isize == distance as isize {
+            return i + 1
+        }
+    }
+
+    distance + 120
+}
+
+// The inverse of lz77_prefix_value, returns the prefix symbol,
+// the number of extra bits and their value
+fn prefix_encode(value: usize) -> (usize, u8, usize) {
+    let value = value - 1;
+
+    if value < 4 {
+        return (value, 0, 0)
+    }
+
+    let mut highest_bit = 0;
+    while value >> (highest_bit + 1) != 0 {
+        highest_bit += 1;
+    }
+
+    let second_highest_bit = (value >> (highest_bit - 1)) & 1;
+    let extra_bits = highest_bit - 1;
+
+    (2 * highest_bit + second_highest_bit, extra_bits as u8, value & ((1 << extra_bits) - 1))
+}
+
+fn write_extra_bits<B: BitWriter>(bits: &mut B, value: usize, n: u8) -> IoResult<()> {
+    if n > 16 {
+        let _ = try!(bits.write_bits(value as u16, 16));
+        bits.write_bits((value >> 16) as u16, n - 16)
+    } else {
+        bits.write_bits(value as u16, n)
+    }
+}
+
+// Writes a prefix code as read by read_huffman_code
+fn write_huffman_code<B: BitWriter>(bits: &mut B, code: &HuffmanCode) -> IoResult<()> {
+    let lengths = code.lengths();
+    let symbols: Vec<usize> = (0..lengths.len()).filter(|&i| lengths[i] != 0).collect();
+
+    // Up to two symbols below 256 are stored directly
+    if symbols.len() <= 2 && symbols.iter().all(|&s| s < 256) {
+        let first = if symbols.is_empty() { 0 } else { symbols[0] };
+
+        let _ = try!(bits.write_bits(1, 1));
+        let _ = try!(bits.write_bits(if symbols.len() == 2 { 1 } else { 0 }, 1));
+
+        if first < 2 {
+            let _ = try!(bits.write_bits(0, 1));
+            let _ = try!(bits.write_bits(first as u16, 1));
+        } else {
+            let _ = try!(bits.write_bits(1, 1));
+            let _ = try!(bits.write_bits(first as u16, 8));
+        }
+
+        if symbols.len() == 2 {
+            let _ = try!(bits.write_bits(symbols[1] as u16, 8));
+        }
+
+        return Ok(())
+    }
+
+    let _ = try!(bits.write_bits(0, 1));
+
+    // Run length coding of the code lengths with the symbols 16, 17 and 18,
+    // each as (symbol, number of extra bits, extra bits)
+    let mut rle = Vec::new();
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let value = lengths[i];
+
+        let mut run = 1;
+        while i + run < lengths.len() && lengths[i + run] == value {
+            run += 1;
+        }
+
+        i += run;
+
+        if value == 0 {
+            while run >= 11 {
+                let n = cmp::min(run, 138);
+                rle.push((18u8, 7u8, (n - 11) as u16));
+                run -= n;
+            }
+
+            if run >= 3 {
+                rle.push((17, 3, (run - 3) as u16));
+                run = 0;
+            }
+        } else {
+            rle.push((value, 0, 0));
+            run -= 1;
+
+            while run >= 3 {
+                let n = cmp::min(run, 6);
+                rle.push((16, 2, (n - 3) as u16));
+                run -= n;
+            }
+        }
+
+        for _ in (0..run) {
+            rle.push((value, 0, 0));
+        }
+    }
+
+    let mut histogram = [0u32; CODE_LENGTH_CODES];
+    for &(symbol, _, _) in rle.iter() {
+        histogram[symbol as usize] += 1;
+    }
+
+    let code_length_code = HuffmanCode::from_histogram(&histogram, 7);
+    let code_length_code_lengths = code_length_code.lengths();
+
+    let mut num_code_lengths = CODE_LENGTH_CODES;
+    while num_code_lengths > 4 && code_length_code_lengths[CODE_LENGTH_CODE_ORDER[num_code_lengths - 1]] == 0 {
+        num_code_lengths -= 1;
+    }
+
+    let _ = try!(bits.write_bits(num_code_lengths as u16 - 4, 4));
+
+    for i in (0..num_code_lengths) {
+        let _ = try!(bits.write_bits(code_length_code_lengths[CODE_LENGTH_CODE_ORDER[i]] as u16, 3));
+    }
+
+    // All code lengths are written, max_symbol is not used
+    let _ = try!(bits.write_bits(0, 1));
+
+    for &(symbol, extra_bits, extra) in rle.iter() {
+        let _ = try!(code_length_code.write_symbol(bits, symbol as usize));
+
+        if extra_bits > 0 {
+            let _ = try!(bits.write_bits(extra, extra_bits));
+        }
+    }
+
+    Ok(())
+}
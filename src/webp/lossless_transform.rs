@@ -0,0 +1,358 @@
+//! The image transforms of the VP8L bitstream
+//!
+//! Pixels are stored as ARGB in a ```u32```, with alpha in the most
+//! significant byte, exactly like the bitstream decodes them.
+
+use std::cmp;
+use std::num::SignedInt;
+
+/// The transforms a VP8L image can be coded with
+#[derive(Clone, Debug)]
+pub enum TransformType {
+    /// Spatial prediction of each pixel from its already decoded neighbours.
+    /// The predictor mode of every block is stored in the green channel
+    /// of the subsampled image.
+    Predictor {
+        size_bits: u8,
+        data: Vec<u32>,
+    },
+
+    /// Decorrelation of the color channels per block
+    CrossColor {
+        size_bits: u8,
+        data: Vec<u32>,
+    },
+
+    /// The green channel has been subtracted from red and blue
+    SubtractGreen,
+
+    /// The image is an index into a palette of at most 256 colors,
+    /// possibly packing several indices into one pixel
+    ColorIndexing {
+        table_size: u16,
+        table: Vec<u32>,
+    },
+}
+
+/// Divides ```size``` by ```2^bits```, rounding up
+pub fn subsample_size(size: u16, bits: u8) -> u16 {
+    ((size as u32 + (1u32 << bits as usize) - 1) >> bits as usize) as u16
+}
+
+fn alpha(p: u32) -> u8 { (p >> 24) as u8 }
+fn red(p: u32) -> u8 { (p >> 16) as u8 }
+fn green(p: u32) -> u8 { (p >> 8) as u8 }
+fn blue(p: u32) -> u8 { p as u8 }
+
+fn argb(a: u8, r: u8, g: u8, b: u8) -> u32 {
+    ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+}
+
+fn channel(p: u32, shift: usize) -> i32 {
+    ((p >> shift) & 0xff) as i32
+}
+
+/// Adds two pixels channel by channel, modulo 256
+pub fn add_pixels(a: u32, b: u32) -> u32 {
+    let mut p = 0u32;
+
+    for &shift in [0us, 8, 16, 24].iter() {
+        p |= ((channel(a, shift) + channel(b, shift)) as u32 & 0xff) << shift;
+    }
+
+    p
+}
+
+/// Subtracts two pixels channel by channel, modulo 256
+pub fn sub_pixels(a: u32, b: u32) -> u32 {
+    let mut p = 0u32;
+
+    for &shift in [0us, 8, 16, 24].iter() {
+        p |= ((channel(a, shift) - channel(b, shift)) as u32 & 0xff) << shift;
+    }
+
+    p
+}
+
+fn average2(a: u32, b: u32) -> u32 {
+    (((a ^ b) & 0xfefefefe) >> 1) + (a & b)
+}
+
+fn clamp_channel(v: i32) -> u32 {
+    if v < 0 { 0 } else if v > 255 { 255 } else { v as u32 }
+}
+
+fn select(l: u32, t: u32, tl: u32) -> u32 {
+    // Manhattan distances of the gradient estimate to the left and top pixel
+    let mut pl = 0;
+    let mut pt = 0;
+
+    for &shift in [0us, 8, 16, 24].iter() {
+        let estimate = channel(l, shift) + channel(t, shift) - channel(tl, shift);
+
+        pl += (estimate - channel(l, shift)).abs();
+        pt += (estimate - channel(t, shift)).abs();
+    }
+
+    if pl < pt {
+        l
+    } else {
+        t
+    }
+}
+
+fn clamp_add_subtract_full(a: u32, b: u32, c: u32) -> u32 {
+    let mut p = 0u32;
+
+    for &shift in [0us, 8, 16, 24].iter() {
+        let v = channel(a, shift) + channel(b, shift) - channel(c, shift);
+        p |= clamp_channel(v) << shift;
+    }
+
+    p
+}
+
+fn clamp_add_subtract_half(a: u32, b: u32) -> u32 {
+    let mut p = 0u32;
+
+    for &shift in [0us, 8, 16, 24].iter() {
+        let v = channel(a, shift) + (channel(a, shift) - channel(b, shift)) / 2;
+        p |= clamp_channel(v) << shift;
+    }
+
+    p
+}
+
+/// Computes the prediction of ```mode``` for the pixel at ```index```.
+/// ```index``` must not be in the first row or column of the image.
+pub fn predict(pixels: &[u32], index: usize, width: usize, mode: u8) -> u32 {
+    let l  = pixels[index - 1];
+    let t  = pixels[index - width];
+    let tl = pixels[index - width - 1];
+
+    // The top right pixel of the last column is the
+    // first pixel of the current row
+    let tr = pixels[index - width + 1];
+
+    match mode {
+        0  => 0xff000000,
+        1  => l,
+        2  => t,
+        3  => tr,
+        4  => tl,
+        5  => average2(average2(l, tr), t),
+        6  => average2(l, tl),
+        7  => average2(l, t),
+        8  => average2(tl, t),
+        9  => average2(t, tr),
+        10 => average2(average2(l, tl), average2(t, tr)),
+        11 => select(l, t, tl),
+        12 => clamp_add_subtract_full(l, t, tl),
+        13 => clamp_add_subtract_half(average2(l, t), tl),
+
+        // Modes 14 and 15 are not used by encoders,
+        // libwebp treats them like mode 0
+        _  => 0xff000000,
+    }
+}
+
+fn color_transform_delta(t: u8, c: u8) -> i32 {
+    (t as i8 as i32 * c as i8 as i32) >> 5
+}
+
+impl TransformType {
+    /// Undoes the transform on ```pixels```, an image of ```width```
+    /// by ```height``` pixels. ```width``` is the width of the image
+    /// after this transform has been undone.
+    pub fn apply_inverse(&self, pixels: &mut Vec<u32>, width: u16, height: u16) {
+        let w = width as usize;
+        let h = height as usize;
+
+        match *self {
+            TransformType::Predictor { size_bits, ref data } => {
+                let block_w = subsample_size(width, size_bits) as usize;
+
+                // The top left pixel is predicted as black,
+                // the rest of the first row from the left
+                pixels[0] = add_pixels(pixels[0], 0xff000000);
+
+                for x in (1..w) {
+                    pixels[x] = add_pixels(pixels[x], pixels[x - 1]);
+                }
+
+                for y in (1..h) {
+                    // The first column is predicted from the top
+                    pixels[y * w] = add_pixels(pixels[y * w], pixels[(y - 1) * w]);
+
+                    for x in (1..w) {
+                        let block = (y >> size_bits as usize) * block_w + (x >> size_bits as usize);
+                        let mode = green(data[block]) & 0xf;
+
+                        let index = y * w + x;
+                        let prediction = predict(&pixels[], index, w, mode);
+
+                        pixels[index] = add_pixels(pixels[index], prediction);
+                    }
+                }
+            }
+
+            TransformType::CrossColor { size_bits, ref data } => {
+                let block_w = subsample_size(width, size_bits) as usize;
+
+                for y in (0..h) {
+                    for x in (0..w) {
+                        let block = (y >> size_bits as usize) * block_w + (x >> size_bits as usize);
+                        let element = data[block];
+
+                        let green_to_red  = blue(element);
+                        let green_to_blue = green(element);
+                        let red_to_blue   = red(element);
+
+                        let p = pixels[y * w + x];
+                        let r = (red(p) as i32
+                                 + color_transform_delta(green_to_red, green(p))) as u8;
+                        let b = (blue(p) as i32
+                                 + color_transform_delta(green_to_blue, green(p))
+                                 + color_transform_delta(red_to_blue, r)) as u8;
+
+                        pixels[y * w + x] = argb(alpha(p), r, green(p), b);
+                    }
+                }
+            }
+
+            TransformType::SubtractGreen => {
+                for p in pixels.iter_mut() {
+                    let g = green(*p) as u32;
+                    *p = argb(alpha(*p), (red(*p) as u32 + g) as u8, g as u8, (blue(*p) as u32 + g) as u8);
+                }
+            }
+
+            TransformType::ColorIndexing { table_size, ref table } => {
+                let width_bits = color_index_width_bits(table_size);
+                let packed_w = subsample_size(width, width_bits) as usize;
+
+                let bits_per_pixel = 8 >> width_bits as usize;
+                let mask = (1u32 << bits_per_pixel) - 1;
+
+                let mut image = Vec::with_capacity(w * h);
+
+                for y in (0..h) {
+                    for x in (0..w) {
+                        let packed = green(pixels[y * packed_w + (x >> width_bits as usize)]) as u32;
+                        let shift = (x & ((1 << width_bits as usize) - 1)) * bits_per_pixel;
+                        let index = ((packed >> shift) & mask) as usize;
+
+                        // Indices beyond the table are transparent black
+                        image.push(if index < table.len() { table[index] } else { 0 });
+                    }
+                }
+
+                *pixels = image;
+            }
+        }
+    }
+}
+
+/// The number of bits the width of an image coded with a color table
+/// of ```table_size``` entries is reduced by
+pub fn color_index_width_bits(table_size: u16) -> u8 {
+    if table_size <= 2 { 3 }
+    else if table_size <= 4 { 2 }
+    else if table_size <= 16 { 1 }
+    else { 0 }
+}
+
+/// Chooses the predictor mode of every block of ```2^size_bits``` pixels
+/// that leaves the smallest residuals. The modes are returned as the
+/// subsampled image of the predictor transform.
+pub fn choose_predictors(pixels: &[u32], width: u16, height: u16, size_bits: u8) -> Vec<u32> {
+    let w = width as usize;
+    let h = height as usize;
+
+    let block_w = subsample_size(width, size_bits) as usize;
+    let block_h = subsample_size(height, size_bits) as usize;
+    let block_size = 1us << size_bits as usize;
+
+    let mut modes = Vec::with_capacity(block_w * block_h);
+
+    for by in (0..block_h) {
+        for bx in (0..block_w) {
+            let mut best_mode = 0;
+            let mut best_cost = -1;
+
+            for mode in (0u8..14) {
+                let mut cost = 0i32;
+
+                // The first row and column use fixed predictions
+                for y in (cmp::max(by * block_size, 1)..cmp::min((by + 1) * block_size, h)) {
+                    for x in (cmp::max(bx * block_size, 1)..cmp::min((bx + 1) * block_size, w)) {
+                        let index = y * w + x;
+                        let residual = sub_pixels(pixels[index], predict(pixels, index, w, mode));
+
+                        for &shift in [0us, 8, 16, 24].iter() {
+                            cost += (((residual >> shift) & 0xff) as u8 as i8 as i32).abs();
+                        }
+                    }
+                }
+
+                if best_cost < 0 || cost < best_cost {
+                    best_mode = mode;
+                    best_cost = cost;
+                }
+            }
+
+            modes.push(0xff000000 | ((best_mode as u32) << 8));
+        }
+    }
+
+    modes
+}
+
+impl TransformType {
+    /// Applies the transform to ```pixels```, the inverse of
+    /// ```apply_inverse```. Only the predictor and subtract green
+    /// transforms can be applied.
+    pub fn apply_forward(&self, pixels: &mut Vec<u32>, width: u16, height: u16) {
+        let w = width as usize;
+        let h = height as usize;
+
+        match *self {
+            TransformType::Predictor { size_bits, ref data } => {
+                let block_w = subsample_size(width, size_bits) as usize;
+
+                // The residuals are predicted from the original pixels,
+                // which the decoder has reconstructed at that point
+                let original = pixels.clone();
+
+                pixels[0] = sub_pixels(original[0], 0xff000000);
+
+                for x in (1..w) {
+                    pixels[x] = sub_pixels(original[x], original[x - 1]);
+                }
+
+                for y in (1..h) {
+                    pixels[y * w] = sub_pixels(original[y * w], original[(y - 1) * w]);
+
+                    for x in (1..w) {
+                        let block = (y >> size_bits as usize) * block_w + (x >> size_bits as usize);
+                        let mode = green(data[block]) & 0xf;
+
+                        let index = y * w + x;
+                        let prediction = predict(&original[], index, w, mode);
+
+                        pixels[index] = sub_pixels(original[index], prediction);
+                    }
+                }
+            }
+
+            TransformType::SubtractGreen => {
+                for p in pixels.iter_mut() {
+                    let g = green(*p) as i32;
+                    *p = argb(alpha(*p), (red(*p) as i32 - g) as u8, g as u8, (blue(*p) as i32 - g) as u8);
+                }
+            }
+
+            _ => panic!("the transform can not be applied by the encoder")
+        }
+    }
+}
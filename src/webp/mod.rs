@@ -1,8 +1,17 @@
-//! Decoding of Webp Images
+//! Decoding and Encoding of Webp Images
 
 pub use self::decoder::WebpDecoder as WebpDecoder;
+pub use self::encoder::WebpEncoder as WebpEncoder;
+pub use self::encoder::{WebpCompression, DEFAULT_QUALITY};
+pub use self::extended::{ExtendedHeader, WebpMetadata, AnimationInfo, AnimationFrameHeader};
 
 mod decoder;
+mod encoder;
 mod transform;
+mod loop_filter;
+mod huffman;
+mod lossless_transform;
+mod extended;
 
-pub mod vp8;
\ No newline at end of file
+pub mod vp8;
+pub mod lossless;
\ No newline at end of file
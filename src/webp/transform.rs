@@ -72,3 +72,61 @@ pub fn iwht4x4(block: &mut [i32]) {
         block[4 * i + 3] = (d2 + 3) >> 3;
     }
 }
+
+// The forward transforms of libwebp's encoder, they are inverted
+// (up to rounding) by idct4x4 and iwht4x4
+pub fn fdct4x4(block: &mut [i32]) {
+    let mut tmp = [0i32; 16];
+
+    for i in (0us..4) {
+        let a0 = block[4 * i + 0] + block[4 * i + 3];
+        let a1 = block[4 * i + 1] + block[4 * i + 2];
+        let a2 = block[4 * i + 1] - block[4 * i + 2];
+        let a3 = block[4 * i + 0] - block[4 * i + 3];
+
+        tmp[4 * i + 0] = (a0 + a1) * 8;
+        tmp[4 * i + 1] = (a2 * 2217 + a3 * 5352 + 1812) >> 9;
+        tmp[4 * i + 2] = (a0 - a1) * 8;
+        tmp[4 * i + 3] = (a3 * 2217 - a2 * 5352 + 937) >> 9;
+    }
+
+    for i in (0us..4) {
+        let a0 = tmp[0 + i] + tmp[12 + i];
+        let a1 = tmp[4 + i] + tmp[8  + i];
+        let a2 = tmp[4 + i] - tmp[8  + i];
+        let a3 = tmp[0 + i] - tmp[12 + i];
+
+        block[0  + i] = (a0 + a1 + 7) >> 4;
+        block[4  + i] = ((a2 * 2217 + a3 * 5352 + 12000) >> 16) + if a3 != 0 { 1 } else { 0 };
+        block[8  + i] = (a0 - a1 + 7) >> 4;
+        block[12 + i] = (a3 * 2217 - a2 * 5352 + 51000) >> 16;
+    }
+}
+
+pub fn fwht4x4(block: &mut [i32]) {
+    let mut tmp = [0i32; 16];
+
+    for i in (0us..4) {
+        let a0 = block[4 * i + 0] + block[4 * i + 2];
+        let a1 = block[4 * i + 1] + block[4 * i + 3];
+        let a2 = block[4 * i + 1] - block[4 * i + 3];
+        let a3 = block[4 * i + 0] - block[4 * i + 2];
+
+        tmp[4 * i + 0] = a0 + a1;
+        tmp[4 * i + 1] = a3 + a2;
+        tmp[4 * i + 2] = a3 - a2;
+        tmp[4 * i + 3] = a0 - a1;
+    }
+
+    for i in (0us..4) {
+        let a0 = tmp[0 + i] + tmp[8  + i];
+        let a1 = tmp[4 + i] + tmp[12 + i];
+        let a2 = tmp[4 + i] - tmp[12 + i];
+        let a3 = tmp[0 + i] - tmp[8  + i];
+
+        block[0  + i] = (a0 + a1) >> 1;
+        block[4  + i] = (a3 + a2) >> 1;
+        block[8  + i] = (a3 - a2) >> 1;
+        block[12 + i] = (a0 - a1) >> 1;
+    }
+}
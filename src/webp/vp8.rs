@@ -3,7 +3,9 @@
 //! This module contains a partial implementation of the
 //! VP8 video format as defined in RFC-6386.
 //!
-//! It decodes Keyframes only sans Loop Filtering.
+//! It decodes and encodes Keyframes only.
+//! The luma and chroma planes of the frame are kept separately,
+//! ```Frame::fill_rgb``` converts them to RGB.
 //! VP8 is the underpinning of the Webp image format
 //!
 //! # Related Links
@@ -12,11 +14,14 @@
 //! of the VP8 format
 //!
 
+use std::cmp;
+use std::num::SignedInt;
 use std::old_io::IoResult;
 use std::default::Default;
 use std::iter::repeat;
 
 use super::transform;
+use super::loop_filter;
 
 use math::utils::clamp;
 
@@ -726,6 +731,136 @@ impl BoolReader {
     }
 }
 
+// Section 7.3
+// The boolean entropy encoder, every bool written with a probability
+// is read back by BoolReader::read_bool with the same probability
+struct BoolWriter {
+    buf: Vec<u8>,
+
+    range: u32,
+    bottom: u32,
+    bit_count: u8,
+}
+
+impl BoolWriter {
+    pub fn new() -> BoolWriter {
+        BoolWriter {buf: Vec::new(), range: 255, bottom: 0, bit_count: 24}
+    }
+
+    // Propagates a carry into the bytes already written
+    fn add_one_to_output(&mut self) {
+        let mut i = self.buf.len();
+
+        loop {
+            i -= 1;
+
+            if self.buf[i] == 255 {
+                self.buf[i] = 0;
+            } else {
+                self.buf[i] += 1;
+                break
+            }
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool, probability: u8) {
+        let split = 1 + (((self.range - 1) * probability as u32) >> 8);
+
+        if value {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+
+        while self.range < 128 {
+            self.range <<= 1;
+
+            if self.bottom & (1 << 31) != 0 {
+                self.add_one_to_output();
+            }
+
+            self.bottom <<= 1;
+            self.bit_count -= 1;
+
+            if self.bit_count == 0 {
+                self.buf.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    pub fn write_literal(&mut self, n: u8, value: u8) {
+        for i in (0..n).rev() {
+            self.write_bool((value >> i as usize) & 1 == 1, 128u8);
+        }
+    }
+
+    pub fn write_with_tree(&mut self, tree: &[i8], probs: &[Prob], value: i8, start: isize) {
+        let path = match tree_path(tree, value, start as usize) {
+            Some(path) => path,
+            None => panic!("value is not a leaf of the tree")
+        };
+
+        for &(index, bit) in path.iter() {
+            self.write_bool(bit == 1, probs[index >> 1]);
+        }
+    }
+
+    pub fn write_flag(&mut self, flag: bool) {
+        self.write_literal(1, if flag { 1 } else { 0 });
+    }
+
+    // Writes out the remaining bits of ```bottom```
+    pub fn flush(mut self) -> Vec<u8> {
+        let mut c = self.bit_count as usize;
+        let mut v = self.bottom;
+
+        if v & (1 << (32 - c)) != 0 {
+            self.add_one_to_output();
+        }
+
+        v <<= c & 7;
+        c >>= 3;
+
+        while c > 0 {
+            v <<= 8;
+            c -= 1;
+        }
+
+        for _ in (0us..4) {
+            self.buf.push((v >> 24) as u8);
+            v <<= 8;
+        }
+
+        self.buf
+    }
+}
+
+// The branches taken from ```index``` to reach the leaf ```value``` of ```tree```
+fn tree_path(tree: &[i8], value: i8, index: usize) -> Option<Vec<(usize, u8)>> {
+    for bit in (0u8..2) {
+        let next = tree[index + bit as usize];
+
+        if next <= 0 {
+            if -next == value {
+                return Some(vec![(index, bit)])
+            }
+        } else {
+            match tree_path(tree, value, next as usize) {
+                Some(mut path) => {
+                    path.insert(0, (index, bit));
+                    return Some(path)
+                }
+                None => ()
+            }
+        }
+    }
+
+    None
+}
+
 #[derive(Copy)]
 struct MacroBlock {
     bpred:       [i8; 16],
@@ -733,6 +868,7 @@ struct MacroBlock {
     luma_mode:   i8,
     chroma_mode: i8,
     segmentid:   u8,
+    coeffs_skipped: bool,
 }
 
 impl MacroBlock {
@@ -743,6 +879,7 @@ impl MacroBlock {
             luma_mode:    0,
             chroma_mode:  0,
             segmentid:    0,
+            coeffs_skipped: false,
         }
     }
 }
@@ -759,6 +896,14 @@ pub struct Frame {
     /// The luma plane of the frame
     pub ybuf: Vec<u8>,
 
+    /// The blue difference chroma plane of the frame, subsampled by
+    /// a factor of two in both directions
+    pub ubuf: Vec<u8>,
+
+    /// The red difference chroma plane of the frame, subsampled by
+    /// a factor of two in both directions
+    pub vbuf: Vec<u8>,
+
     /// Indicates whether this frame is a keyframe
     pub keyframe: bool,
 
@@ -778,6 +923,86 @@ pub struct Frame {
     sharpness_level: u8,
 }
 
+impl Frame {
+    /// The width of the chroma planes
+    pub fn chroma_width(&self) -> u16 {
+        (self.width + 1) / 2
+    }
+
+    /// The height of the chroma planes
+    pub fn chroma_height(&self) -> u16 {
+        (self.height + 1) / 2
+    }
+
+    /// Converts the frame to RGB and writes it to ```buf```.
+    /// The chroma planes are upsampled with the same bilinear
+    /// (9-3-3-1) filter as libwebp's "fancy upsampling".
+    pub fn fill_rgb(&self, buf: &mut [u8]) {
+        let w  = self.width as usize;
+        let h  = self.height as usize;
+        let cw = self.chroma_width() as usize;
+        let ch = self.chroma_height() as usize;
+
+        for y in (0..h) {
+            for x in (0..w) {
+                let luma = self.ybuf[y * w + x];
+                let u = upsample_chroma(&self.ubuf[], cw, ch, x, y);
+                let v = upsample_chroma(&self.vbuf[], cw, ch, x, y);
+
+                let (r, g, b) = yuv_to_rgb(luma, u, v);
+                let i = (y * w + x) * 3;
+
+                buf[i] = r;
+                buf[i + 1] = g;
+                buf[i + 2] = b;
+            }
+        }
+    }
+
+    /// Creates a frame of ```width``` by ```height``` pixels from the
+    /// RGB image ```buf```. The chroma planes are the average of
+    /// each 2x2 block of pixels.
+    pub fn from_rgb(buf: &[u8], width: u16, height: u16) -> Frame {
+        let mut frame = Frame {
+            width: width,
+            height: height,
+            keyframe: true,
+            for_display: true,
+            ..Default::default()
+        };
+
+        let w  = width as usize;
+        let h  = height as usize;
+        let cw = frame.chroma_width() as usize;
+        let ch = frame.chroma_height() as usize;
+
+        frame.ybuf = buf.chunks(3).map(|p| rgb_to_y(p[0], p[1], p[2])).collect();
+
+        for cy in (0..ch) {
+            for cx in (0..cw) {
+                let (mut r, mut g, mut b) = (0i32, 0i32, 0i32);
+
+                // Odd sizes repeat the last row or column
+                for &(x, y) in [(2 * cx, 2 * cy), (2 * cx + 1, 2 * cy),
+                                (2 * cx, 2 * cy + 1), (2 * cx + 1, 2 * cy + 1)].iter() {
+                    let i = (cmp::min(y, h - 1) * w + cmp::min(x, w - 1)) * 3;
+
+                    r += buf[i] as i32;
+                    g += buf[i + 1] as i32;
+                    b += buf[i + 2] as i32;
+                }
+
+                let (u, v) = rgb_sum_to_uv(r, g, b);
+
+                frame.ubuf.push(u);
+                frame.vbuf.push(v);
+            }
+        }
+
+        frame
+    }
+}
+
 #[derive(Copy, Default)]
 struct Segment {
     ydc: i16,
@@ -827,6 +1052,20 @@ pub struct VP8Decoder<R> {
 
     top_border: Vec<u8>,
     left_border: Vec<u8>,
+
+    top_border_u: Vec<u8>,
+    left_border_u: Vec<u8>,
+
+    top_border_v: Vec<u8>,
+    left_border_v: Vec<u8>,
+
+    // Section 9.6
+    loop_filter_adjustments: bool,
+    ref_delta: [i32; 4],
+    mode_delta: [i32; 4],
+
+    // The macroblocks of the current frame, kept for loop filtering
+    macroblocks: Vec<MacroBlock>,
 }
 
 impl<R: Reader> VP8Decoder<R> {
@@ -871,6 +1110,18 @@ impl<R: Reader> VP8Decoder<R> {
 
             top_border: Vec::new(),
             left_border: Vec::new(),
+
+            top_border_u: Vec::new(),
+            left_border_u: Vec::new(),
+
+            top_border_v: Vec::new(),
+            left_border_v: Vec::new(),
+
+            loop_filter_adjustments: false,
+            ref_delta: [0; 4],
+            mode_delta: [0; 4],
+
+            macroblocks: Vec::new(),
         }
 }
 
@@ -895,7 +1146,7 @@ impl<R: Reader> VP8Decoder<R> {
             let sizes = try!(self.r.read_exact(3 * n - 3));
 
             for (i, s) in sizes[].chunks(3).enumerate() {
-                let size = s[0] as u32 + ((s[1] as u32) << 8) + ((s[2] as u32) << 8);
+                let size = s[0] as u32 + ((s[1] as u32) << 8) + ((s[2] as u32) << 16);
                 let buf  = try!(self.r.read_exact(size as usize));
 
                 self.partitions[i].init(buf);
@@ -927,8 +1178,9 @@ impl<R: Reader> VP8Decoder<R> {
 
         let n = if self.segments_enabled { MAX_SEGMENTS } else { 1 };
         for i in (0us..n) {
-            let base = if !self.segment[i].delta_values { self.segment[i].quantizer_level as i16 }
-                    else { self.segment[i].quantizer_level as i16 + yac_abs as i16} as i32;
+            let base = if !self.segments_enabled { yac_abs as i16 }
+                       else if !self.segment[i].delta_values { self.segment[i].quantizer_level as i16 }
+                       else { self.segment[i].quantizer_level as i16 + yac_abs as i16} as i32;
 
             self.segment[i].ydc  = DC_QUANT[clamp(base + ydc_delta, 0, 127) as usize];
             self.segment[i].yac  = AC_QUANT[clamp(base, 0, 127) as usize];
@@ -951,24 +1203,20 @@ impl<R: Reader> VP8Decoder<R> {
 
     fn read_loop_filter_adjustments(&mut self) {
         if self.b.read_flag() {
-            for _i in (0us..4) {
+            for i in (0us..4) {
                 let ref_frame_delta_update_flag = self.b.read_flag();
 
-                let _delta = if ref_frame_delta_update_flag {
-                    self.b.read_magnitude_and_sign(6)
-                } else {
-                    0i32
-                };
+                if ref_frame_delta_update_flag {
+                    self.ref_delta[i] = self.b.read_magnitude_and_sign(6);
+                }
             }
 
-            for _i in (0us..4) {
+            for i in (0us..4) {
                 let mb_mode_delta_update_flag = self.b.read_flag();
 
-                let _delta = if mb_mode_delta_update_flag {
-                    self.b.read_magnitude_and_sign(6)
-                } else {
-                    0i32
-                };
+                if mb_mode_delta_update_flag {
+                    self.mode_delta[i] = self.b.read_magnitude_and_sign(6);
+                }
             }
         }
     }
@@ -1046,11 +1294,28 @@ impl<R: Reader> VP8Decoder<R> {
             self.mbwidth  = (self.frame.width + 15) / 16;
             self.mbheight = (self.frame.height + 15) / 16;
 
-            self.frame.ybuf = repeat(0u8).take(self.frame.width as usize
-                * self.frame.height as usize).collect();
+            self.macroblocks = Vec::with_capacity(self.mbwidth as usize * self.mbheight as usize);
+
+            // Keyframes reset the loop filter deltas
+            self.ref_delta = [0; 4];
+            self.mode_delta = [0; 4];
+
+            // The planes are decoded with the size of whole macroblocks
+            // and cropped once the frame has been loop filtered
+            let luma_len = self.mbwidth as usize * 16 * self.mbheight as usize * 16;
+
+            self.frame.ybuf = repeat(0u8).take(luma_len).collect();
+            self.frame.ubuf = repeat(0u8).take(luma_len / 4).collect();
+            self.frame.vbuf = repeat(0u8).take(luma_len / 4).collect();
 
             self.top_border = repeat(127u8).take(self.frame.width as usize + 4 + 16).collect();
             self.left_border = repeat(129u8).take(1 + 16).collect();
+
+            self.top_border_u = repeat(127u8).take(self.mbwidth as usize * 8).collect();
+            self.left_border_u = repeat(129u8).take(1 + 8).collect();
+
+            self.top_border_v = repeat(127u8).take(self.mbwidth as usize * 8).collect();
+            self.left_border_v = repeat(129u8).take(1 + 8).collect();
         }
 
         let buf = try!(self.r.read_exact(first_partition_size as usize));
@@ -1073,6 +1338,7 @@ impl<R: Reader> VP8Decoder<R> {
         self.frame.sharpness_level = self.b.read_literal(3);
 
         let lf_adjust_enable = self.b.read_flag();
+        self.loop_filter_adjustments = lf_adjust_enable;
         if lf_adjust_enable {
             self.read_loop_filter_adjustments();
         }
@@ -1181,10 +1447,10 @@ impl<R: Reader> VP8Decoder<R> {
         (skip_coeff, mb)
     }
 
-    fn intra_predict(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, resdata: &[i32]) {
+    fn intra_predict_luma(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, resdata: &[i32]) {
         let stride = 1us + 16 + 4;
-        let w  = self.frame.width as usize;
         let mw = self.mbwidth as usize;
+        let w  = mw * 16;
         let mut ws = create_border(
             mbx, mby, mw, &self.top_border[], &self.left_border[]);
 
@@ -1217,22 +1483,48 @@ impl<R: Reader> VP8Decoder<R> {
             self.left_border[i + 1] = ws[(i + 1) * stride + 16];
         }
 
-        let ylength = if mby < self.mbheight as usize - 1 { 16us }
-                      else if self.frame.height % 16 == 0 { 16us }
-                      else { (16 - (self.frame.height as usize & 15)) % 16 };
-
-        let xlength = if mbx < self.mbwidth as usize - 1 { 16us }
-                      else if self.frame.width % 16 == 0 { 16us }
-                      else { (16 - (self.frame.width as usize & 15)) % 16 };
-
-        for y in (0us..ylength) {
-            for x in (0us..xlength) {
+        for y in (0us..16) {
+            for x in (0us..16) {
                 self.frame.ybuf[(mby * 16 + y) * w + mbx * 16 + x] =
                     ws[(1 + y) * stride + 1 + x];
             }
         }
     }
 
+    fn intra_predict_chroma(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, resdata: &[i32]) {
+        let stride = 1us + 8;
+        let w = self.mbwidth as usize * 8;
+
+        // Section 12.2
+        // The U and V planes share the chroma mode of the macroblock,
+        // their residue lives in blocks 16..20 and 20..24 respectively
+        let mut uws = create_border_chroma(mbx, mby, &self.top_border_u[], &self.left_border_u[]);
+        let mut vws = create_border_chroma(mbx, mby, &self.top_border_v[], &self.left_border_v[]);
+
+        predict_chroma(&mut uws, mb.chroma_mode, mbx, mby, &resdata[16 * 16..20 * 16]);
+        predict_chroma(&mut vws, mb.chroma_mode, mbx, mby, &resdata[20 * 16..24 * 16]);
+
+        self.left_border_u[0] = uws[8];
+        self.left_border_v[0] = vws[8];
+
+        for i in (0us..8) {
+            self.top_border_u[mbx * 8 + i] = uws[8 * stride + 1 + i];
+            self.left_border_u[i + 1] = uws[(i + 1) * stride + 8];
+
+            self.top_border_v[mbx * 8 + i] = vws[8 * stride + 1 + i];
+            self.left_border_v[i + 1] = vws[(i + 1) * stride + 8];
+        }
+
+        for y in (0us..8) {
+            for x in (0us..8) {
+                let i = (mby * 8 + y) * w + mbx * 8 + x;
+
+                self.frame.ubuf[i] = uws[(1 + y) * stride + 1 + x];
+                self.frame.vbuf[i] = vws[(1 + y) * stride + 1 + x];
+            }
+        }
+    }
+
     fn read_coefficients(&mut self,
                          block: &mut [i32],
                          p: usize,
@@ -1306,9 +1598,10 @@ impl<R: Reader> VP8Decoder<R> {
         has_coefficients
     }
 
-    fn read_residual_data(&mut self, mb: &MacroBlock, mbx: usize, p: usize) -> [i32; 384] {
+    fn read_residual_data(&mut self, mb: &mut MacroBlock, mbx: usize, p: usize) -> [i32; 384] {
         let sindex     = mb.segmentid as usize;
         let mut blocks = [0i32; 384];
+        let mut non_zero = false;
         let mut plane  = if mb.luma_mode == B_PRED { 3 }
                          else { 1 };
 
@@ -1344,6 +1637,7 @@ impl<R: Reader> VP8Decoder<R> {
                 let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq);
 
                 if block[0] != 0 || n {
+                    non_zero = true;
                     transform::idct4x4(block);
                 }
 
@@ -1370,6 +1664,7 @@ impl<R: Reader> VP8Decoder<R> {
 
                     let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq);
                     if block[0] != 0 || n {
+                        non_zero = true;
                         transform::idct4x4(block);
                     }
 
@@ -1381,6 +1676,8 @@ impl<R: Reader> VP8Decoder<R> {
             }
         }
 
+        mb.coeffs_skipped = !non_zero;
+
         blocks
     }
 
@@ -1393,12 +1690,14 @@ impl<R: Reader> VP8Decoder<R> {
             self.left = MacroBlock::new();
 
             for mbx in (0..self.mbwidth as usize) {
-                let (skip, mb) = self.read_macroblock_header(mbx);
+                let (skip, mut mb) = self.read_macroblock_header(mbx);
                 let mut blocks = [0i32; 384];
 
                 if !skip {
-                    blocks = self.read_residual_data(&mb, mbx, p);
+                    blocks = self.read_residual_data(&mut mb, mbx, p);
                 } else {
+                    mb.coeffs_skipped = true;
+
                     if mb.luma_mode != B_PRED {
                         self.left.complexity[0] = 0;
                         self.top[mbx].complexity[0] = 0;
@@ -1410,110 +1709,977 @@ impl<R: Reader> VP8Decoder<R> {
                     }
                 }
 
-                self.intra_predict(mbx, mby, &mb, &blocks);
+                self.intra_predict_luma(mbx, mby, &mb, &blocks);
+                self.intra_predict_chroma(mbx, mby, &mb, &blocks);
+
+                self.macroblocks.push(mb);
             }
 
             self.left_border = repeat(129u8).take(1 + 16).collect();
+            self.left_border_u = repeat(129u8).take(1 + 8).collect();
+            self.left_border_v = repeat(129u8).take(1 + 8).collect();
+        }
+
+        // Section 15
+        // Intra prediction uses the unfiltered pixels, so the whole frame
+        // is filtered once all macroblocks have been reconstructed.
+        if self.frame.filter_level != 0 {
+            for mby in (0..self.mbheight as usize) {
+                for mbx in (0..self.mbwidth as usize) {
+                    let mb = self.macroblocks[mby * self.mbwidth as usize + mbx];
+                    self.loop_filter(mbx, mby, &mb);
+                }
+            }
         }
 
+        self.crop_planes();
+
         Ok(&self.frame)
     }
-}
 
-fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
-    let mb_width = (width + 15) / 16;
+    // Section 15.1 and 9.6
+    fn filter_level(&self, mb: &MacroBlock) -> u8 {
+        let segment = self.segment[mb.segmentid as usize];
 
-    let mb = MacroBlock {
-        // Section 11.3 #3
-        bpred: [B_DC_PRED; 16],
-        luma_mode: DC_PRED,
-        ..MacroBlock::new()
-    };
+        let mut level = if !self.segments_enabled {
+            self.frame.filter_level as i32
+        } else if segment.delta_values {
+            self.frame.filter_level as i32 + segment.loopfilter_level as i32
+        } else {
+            segment.loopfilter_level as i32
+        };
 
-    (0..mb_width).map(|_| mb).collect()
-}
+        if self.loop_filter_adjustments {
+            // Keyframes only use the intra frame delta
+            level += self.ref_delta[0];
 
-fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) -> [u8; 357] {
-    let stride = 1us + 16 + 4;
-    let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
+            if mb.luma_mode == B_PRED {
+                level += self.mode_delta[0];
+            }
+        }
 
-    // A
-    {
-        let above = &mut ws[1..stride];
-        if mby == 0 {
-            for i in (0us..above.len()) {
-                above[i] = 127;
+        clamp(level, 0, 63) as u8
+    }
+
+    fn loop_filter(&mut self, mbx: usize, mby: usize, mb: &MacroBlock) {
+        let luma_w = self.mbwidth as usize * 16;
+        let chroma_w = self.mbwidth as usize * 8;
+
+        let filter_level = self.filter_level(mb);
+        if filter_level == 0 {
+            return
+        }
+
+        let sharpness = self.frame.sharpness_level;
+
+        let mut interior_limit = filter_level;
+        if sharpness != 0 {
+            interior_limit >>= if sharpness > 4 { 2 } else { 1 };
+
+            if interior_limit > 9 - sharpness {
+                interior_limit = 9 - sharpness;
             }
-        } else  {
-            for i in (0us..16) {
-                above[i] = top[mbx * 16 + i];
+        }
+
+        if interior_limit == 0 {
+            interior_limit = 1;
+        }
+
+        let hev_threshold = if filter_level >= 40 { 2 }
+                            else if filter_level >= 15 { 1 }
+                            else { 0 };
+
+        let mbedge_limit = (filter_level + 2) * 2 + interior_limit;
+        let sub_bedge_limit = filter_level * 2 + interior_limit;
+
+        // Subblock edges are skipped for macroblocks without coefficients,
+        // unless they are predicted per subblock
+        let do_subblocks = mb.luma_mode == B_PRED || !mb.coeffs_skipped;
+
+        let ybuf = &mut self.frame.ybuf[];
+        let ubuf = &mut self.frame.ubuf[];
+        let vbuf = &mut self.frame.vbuf[];
+
+        // The simple filter only filters the luma plane
+        if self.frame.filter == 1 {
+            if mbx > 0 {
+                for y in (0us..16) {
+                    let point = (mby * 16 + y) * luma_w + mbx * 16;
+                    loop_filter::simple_segment(mbedge_limit, ybuf, point, 1);
+                }
             }
 
-            if mbx == mbw - 1 {
-                for i in (16us..above.len()) {
-                    above[i] = top[mbx * 16 + 15];
+            if do_subblocks {
+                for x in [4us, 8, 12].iter() {
+                    for y in (0us..16) {
+                        let point = (mby * 16 + y) * luma_w + mbx * 16 + *x;
+                        loop_filter::simple_segment(sub_bedge_limit, ybuf, point, 1);
+                    }
                 }
-            } else {
-                for i in (16us..above.len()) {
-                    above[i] = top[mbx * 16 + i];
+            }
+
+            if mby > 0 {
+                for x in (0us..16) {
+                    let point = mby * 16 * luma_w + mbx * 16 + x;
+                    loop_filter::simple_segment(mbedge_limit, ybuf, point, luma_w);
                 }
             }
-        }
-    }
 
-    for i in (17us..stride) {
-        ws[4  * stride + i] = ws[i];
-        ws[8  * stride + i] = ws[i];
-        ws[12 * stride + i] = ws[i];
-    }
+            if do_subblocks {
+                for y in [4us, 8, 12].iter() {
+                    for x in (0us..16) {
+                        let point = (mby * 16 + *y) * luma_w + mbx * 16 + x;
+                        loop_filter::simple_segment(sub_bedge_limit, ybuf, point, luma_w);
+                    }
+                }
+            }
+        } else {
+            if mbx > 0 {
+                for y in (0us..16) {
+                    let point = (mby * 16 + y) * luma_w + mbx * 16;
+                    loop_filter::macroblock_filter(hev_threshold, interior_limit, mbedge_limit,
+                                                   ybuf, point, 1);
+                }
 
-    // L
-    if mbx == 0 {
-        for i in (0us..16) {
-            ws[(i + 1) * stride] = 129;
-        }
-    } else {
-        for i in (0us..16) {
-            ws[(i + 1) * stride] = left[i + 1];
-        }
-    }
+                for y in (0us..8) {
+                    let point = (mby * 8 + y) * chroma_w + mbx * 8;
+                    loop_filter::macroblock_filter(hev_threshold, interior_limit, mbedge_limit,
+                                                   ubuf, point, 1);
+                    loop_filter::macroblock_filter(hev_threshold, interior_limit, mbedge_limit,
+                                                   vbuf, point, 1);
+                }
+            }
 
-    // P
-    ws[0] = if mby == 0 && mbx == 0 {
-        127
-    } else if mby == 0 && mbx != 0 {
-        127
-    } else if mbx == 0 && mby != 0 {
-        129
-    } else {
-        left[0]
-    };
+            if do_subblocks {
+                for x in [4us, 8, 12].iter() {
+                    for y in (0us..16) {
+                        let point = (mby * 16 + y) * luma_w + mbx * 16 + *x;
+                        loop_filter::subblock_filter(hev_threshold, interior_limit,
+                                                     sub_bedge_limit, ybuf, point, 1);
+                    }
+                }
 
-    ws
-}
+                for y in (0us..8) {
+                    let point = (mby * 8 + y) * chroma_w + mbx * 8 + 4;
+                    loop_filter::subblock_filter(hev_threshold, interior_limit,
+                                                 sub_bedge_limit, ubuf, point, 1);
+                    loop_filter::subblock_filter(hev_threshold, interior_limit,
+                                                 sub_bedge_limit, vbuf, point, 1);
+                }
+            }
 
-fn avg3(left: u8, this: u8, right: u8) -> u8 {
-    let avg = (left as u16 + 2 * this as u16 + right as u16 + 2) >> 2;
-    avg as u8
-}
+            if mby > 0 {
+                for x in (0us..16) {
+                    let point = mby * 16 * luma_w + mbx * 16 + x;
+                    loop_filter::macroblock_filter(hev_threshold, interior_limit, mbedge_limit,
+                                                   ybuf, point, luma_w);
+                }
 
-fn avg2(this: u8, right: u8) -> u8 {
-    let avg = (this as u16 + right as u16 + 1) >> 1;
-    avg as u8
-}
+                for x in (0us..8) {
+                    let point = mby * 8 * chroma_w + mbx * 8 + x;
+                    loop_filter::macroblock_filter(hev_threshold, interior_limit, mbedge_limit,
+                                                   ubuf, point, chroma_w);
+                    loop_filter::macroblock_filter(hev_threshold, interior_limit, mbedge_limit,
+                                                   vbuf, point, chroma_w);
+                }
+            }
 
-fn add_residue(pblock: &mut [u8], rblock: &[i32], y0: usize, x0: usize, stride: usize) {
-    for y in (0us..4) {
-        for x in (0us..4) {
-            let a = rblock[x + y * 4];
-            let b = pblock[(y0 + y) * stride + x0 + x];
-            let c = clamp(a + b as i32, 0, 255) as u8;
-            pblock[(y0 + y) * stride + x0 + x] = c;
+            if do_subblocks {
+                for y in [4us, 8, 12].iter() {
+                    for x in (0us..16) {
+                        let point = (mby * 16 + *y) * luma_w + mbx * 16 + x;
+                        loop_filter::subblock_filter(hev_threshold, interior_limit,
+                                                     sub_bedge_limit, ybuf, point, luma_w);
+                    }
+                }
+
+                for x in (0us..8) {
+                    let point = (mby * 8 + 4) * chroma_w + mbx * 8 + x;
+                    loop_filter::subblock_filter(hev_threshold, interior_limit,
+                                                 sub_bedge_limit, ubuf, point, chroma_w);
+                    loop_filter::subblock_filter(hev_threshold, interior_limit,
+                                                 sub_bedge_limit, vbuf, point, chroma_w);
+                }
+            }
         }
     }
+
+    // Removes the padding of the planes to whole macroblocks
+    fn crop_planes(&mut self) {
+        let luma_w = self.mbwidth as usize * 16;
+        let chroma_w = self.mbwidth as usize * 8;
+
+        let w = self.frame.width as usize;
+        let h = self.frame.height as usize;
+        let cw = self.frame.chroma_width() as usize;
+        let ch = self.frame.chroma_height() as usize;
+
+        self.frame.ybuf = crop_plane(&self.frame.ybuf[], luma_w, w, h);
+        self.frame.ubuf = crop_plane(&self.frame.ubuf[], chroma_w, cw, ch);
+        self.frame.vbuf = crop_plane(&self.frame.vbuf[], chroma_w, cw, ch);
+    }
 }
 
-fn predict_4x4(ws: &mut [u8], stride: usize, modes: &[i8], resdata: &[i32]) {
+// The largest magnitude a DCT_CAT6 token can code
+const MAX_LEVEL: i32 = 2048 + 66;
+
+/// VP8 Encoder
+/// Only encodes keyframes, every macroblock is predicted
+/// with one of the 16x16 luma modes.
+pub struct VP8Encoder<'a, W: 'a> {
+    w: &'a mut W,
+    quantizer: u8,
+
+    mbwidth: u16,
+    mbheight: u16,
+
+    segment: Segment,
+
+    top: Vec<MacroBlock>,
+    left: MacroBlock,
+
+    top_border: Vec<u8>,
+    left_border: Vec<u8>,
+
+    top_border_u: Vec<u8>,
+    left_border_u: Vec<u8>,
+
+    top_border_v: Vec<u8>,
+    left_border_v: Vec<u8>,
+}
+
+impl<'a, W: Writer> VP8Encoder<'a, W> {
+    /// Create a new encoder that writes a raw vp8 bitstream to ```w```.
+    /// ```quality``` ranges from 0 (smallest) to 100 (best).
+    pub fn new(w: &mut W, quality: u8) -> VP8Encoder<W> {
+        let quality = cmp::min(quality, 100) as u32;
+        let s: Segment = Default::default();
+
+        VP8Encoder {
+            w: w,
+            quantizer: (((100 - quality) * 127 + 50) / 100) as u8,
+
+            mbwidth: 0,
+            mbheight: 0,
+
+            segment: s,
+
+            top: Vec::new(),
+            left: MacroBlock::new(),
+
+            top_border: Vec::new(),
+            left_border: Vec::new(),
+
+            top_border_u: Vec::new(),
+            left_border_u: Vec::new(),
+
+            top_border_v: Vec::new(),
+            left_border_v: Vec::new(),
+        }
+    }
+
+    // The quantizers the decoder derives from the quantizer index
+    // when segments and deltas are disabled
+    fn init_quantizers(&mut self) {
+        let q = self.quantizer as usize;
+
+        self.segment.ydc  = DC_QUANT[q];
+        self.segment.yac  = AC_QUANT[q];
+
+        self.segment.y2dc = DC_QUANT[q] * 2;
+        self.segment.y2ac = cmp::max(AC_QUANT[q] * 155 / 100, 8);
+
+        self.segment.uvdc = cmp::min(DC_QUANT[q], 132);
+        self.segment.uvac = AC_QUANT[q];
+    }
+
+    fn write_frame_header(&self, b: &mut BoolWriter, prob_skip_false: Prob) {
+        // Color space and clamping type
+        b.write_literal(1, 0);
+        b.write_literal(1, 0);
+
+        // No segmentation
+        b.write_flag(false);
+
+        // Stronger quantization leaves stronger blocking artifacts
+        b.write_literal(1, 0);
+        b.write_literal(6, self.quantizer / 2);
+        b.write_literal(3, 0);
+
+        // No loop filter adjustments
+        b.write_flag(false);
+
+        // A single token partition
+        b.write_literal(2, 0);
+
+        // The quantizer index without deltas
+        b.write_literal(7, self.quantizer);
+        for _ in (0us..5) {
+            b.write_flag(false);
+        }
+
+        // Refresh entropy probs
+        b.write_literal(1, 0);
+
+        // The default token probabilities are kept
+        for i in (0us..4) {
+            for j in (0us..8) {
+                for k in (0us..3) {
+                    for t in (0us..NUM_DCT_TOKENS - 1) {
+                        b.write_bool(false, COEFF_UPDATE_PROBS[i][j][k][t]);
+                    }
+                }
+            }
+        }
+
+        b.write_literal(1, 1);
+        b.write_literal(8, prob_skip_false);
+    }
+
+    fn write_macroblock_header(&self, b: &mut BoolWriter, mb: &MacroBlock, prob_skip_false: Prob) {
+        b.write_bool(mb.coeffs_skipped, prob_skip_false);
+
+        b.write_with_tree(&KEYFRAME_YMODE_TREE, &KEYFRAME_YMODE_PROBS, mb.luma_mode, 0);
+        b.write_with_tree(&KEYFRAME_UV_MODE_TREE, &KEYFRAME_UV_MODE_PROBS, mb.chroma_mode, 0);
+    }
+
+    // Chooses the luma prediction with the smallest sum of absolute
+    // differences, transforms and quantizes the residue and
+    // reconstructs the macroblock like the decoder does.
+    fn encode_luma(&mut self, src: &[u8], mbx: usize, mby: usize, levels: &mut [i32]) -> i8 {
+        let stride = 1us + 16 + 4;
+        let mw = self.mbwidth as usize;
+        let w  = mw * 16;
+
+        let mut best_mode = DC_PRED;
+        let mut best_sad = -1;
+        let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
+
+        for &mode in [DC_PRED, V_PRED, H_PRED, TM_PRED].iter() {
+            let mut pred = create_border(
+                mbx, mby, mw, &self.top_border[], &self.left_border[]);
+
+            match mode {
+                V_PRED  => predict_vpred(&mut pred, 16, 1, 1, stride),
+                H_PRED  => predict_hpred(&mut pred, 16, 1, 1, stride),
+                TM_PRED => predict_tmpred(&mut pred, 16, 1, 1, stride),
+                _       => predict_dcpred(&mut pred, 16, stride, mby != 0, mbx != 0),
+            }
+
+            let mut sad = 0i32;
+            for y in (0us..16) {
+                for x in (0us..16) {
+                    let s = src[(mby * 16 + y) * w + mbx * 16 + x] as i32;
+                    sad += (s - pred[(1 + y) * stride + 1 + x] as i32).abs();
+                }
+            }
+
+            if best_sad < 0 || sad < best_sad {
+                best_mode = mode;
+                best_sad = sad;
+                ws = pred;
+            }
+        }
+
+        let mut coeffs = [0i32; 256];
+        for i in (0us..16) {
+            let block = &mut coeffs[i * 16..i * 16 + 16];
+
+            for y in (0us..4) {
+                for x in (0us..4) {
+                    let py = (i / 4) * 4 + y;
+                    let px = (i % 4) * 4 + x;
+
+                    block[y * 4 + x] = src[(mby * 16 + py) * w + mbx * 16 + px] as i32
+                                     - ws[(1 + py) * stride + 1 + px] as i32;
+                }
+            }
+
+            transform::fdct4x4(block);
+        }
+
+        // The DC coefficients of the luma blocks are coded in the Y2 block
+        let mut y2 = [0i32; 16];
+        for i in (0us..16) {
+            y2[i] = coeffs[i * 16];
+        }
+
+        transform::fwht4x4(&mut y2);
+
+        for k in (0us..16) {
+            let q = if k == 0 { self.segment.y2dc } else { self.segment.y2ac };
+
+            levels[384 + k] = quantize(y2[k], q, k == 0);
+            y2[k] = levels[384 + k] * q as i32;
+        }
+
+        transform::iwht4x4(&mut y2);
+
+        let mut resdata = [0i32; 256];
+        for i in (0us..16) {
+            let rb = &mut resdata[i * 16..i * 16 + 16];
+
+            rb[0] = y2[i];
+            for k in (1us..16) {
+                levels[i * 16 + k] = quantize(coeffs[i * 16 + k], self.segment.yac, false);
+                rb[k] = levels[i * 16 + k] * self.segment.yac as i32;
+            }
+
+            transform::idct4x4(rb);
+        }
+
+        for y in (0us..4) {
+            for x in (0us..4) {
+                let i = x + y * 4;
+                add_residue(&mut ws, &resdata[i * 16..i * 16 + 16], 1 + y * 4, 1 + x * 4, stride);
+            }
+        }
+
+        self.left_border[0] = ws[16];
+
+        for i in (0us..16) {
+            self.top_border[mbx * 16 + i] = ws[16 * stride + 1 + i];
+            self.left_border[i + 1] = ws[(i + 1) * stride + 16];
+        }
+
+        best_mode
+    }
+
+    fn encode_chroma(&mut self, usrc: &[u8], vsrc: &[u8], mbx: usize, mby: usize, levels: &mut [i32]) -> i8 {
+        let stride = 1us + 8;
+        let w = self.mbwidth as usize * 8;
+        let zero = [0i32; 64];
+
+        let mut best_mode = DC_PRED;
+        let mut best_sad = -1;
+        let mut uws = [0u8; (1 + 8) * (1 + 8)];
+        let mut vws = [0u8; (1 + 8) * (1 + 8)];
+
+        for &mode in [DC_PRED, V_PRED, H_PRED, TM_PRED].iter() {
+            let mut upred = create_border_chroma(mbx, mby, &self.top_border_u[], &self.left_border_u[]);
+            let mut vpred = create_border_chroma(mbx, mby, &self.top_border_v[], &self.left_border_v[]);
+
+            predict_chroma(&mut upred, mode, mbx, mby, &zero);
+            predict_chroma(&mut vpred, mode, mbx, mby, &zero);
+
+            let mut sad = 0i32;
+            for y in (0us..8) {
+                for x in (0us..8) {
+                    let i = (mby * 8 + y) * w + mbx * 8 + x;
+                    let p = (1 + y) * stride + 1 + x;
+
+                    sad += (usrc[i] as i32 - upred[p] as i32).abs();
+                    sad += (vsrc[i] as i32 - vpred[p] as i32).abs();
+                }
+            }
+
+            if best_sad < 0 || sad < best_sad {
+                best_mode = mode;
+                best_sad = sad;
+                uws = upred;
+                vws = vpred;
+            }
+        }
+
+        // The residue of U lives in blocks 16..20 and of V in 20..24
+        encode_chroma_plane(&mut uws, usrc, &self.segment, mbx, mby, w, &mut levels[16 * 16..20 * 16]);
+        encode_chroma_plane(&mut vws, vsrc, &self.segment, mbx, mby, w, &mut levels[20 * 16..24 * 16]);
+
+        self.left_border_u[0] = uws[8];
+        self.left_border_v[0] = vws[8];
+
+        for i in (0us..8) {
+            self.top_border_u[mbx * 8 + i] = uws[8 * stride + 1 + i];
+            self.left_border_u[i + 1] = uws[(i + 1) * stride + 8];
+
+            self.top_border_v[mbx * 8 + i] = vws[8 * stride + 1 + i];
+            self.left_border_v[i + 1] = vws[(i + 1) * stride + 8];
+        }
+
+        best_mode
+    }
+
+    // Mirrors read_coefficients, returns whether the block has
+    // non zero coefficients
+    fn write_coefficients(&self, b: &mut BoolWriter, block: &[i32], plane: usize, complexity: usize) -> bool {
+        let first = if plane == 0 { 1us } else { 0us };
+        let probs = &COEFF_PROBS[plane];
+        let tree  = &DCT_TOKEN_TREE[];
+
+        let last = match (first..16us).rev().find(|&i| block[ZIGZAG[i] as usize] != 0) {
+            Some(last) => last,
+            None => {
+                let table = &probs[COEFF_BANDS[first] as usize][complexity][];
+                b.write_with_tree(tree, table, DCT_EOB, 0);
+
+                return false
+            }
+        };
+
+        let mut complexity = complexity;
+        let mut skip = false;
+
+        for i in (first..last + 1) {
+            let table = &probs[COEFF_BANDS[i] as usize][complexity][];
+            let value = block[ZIGZAG[i] as usize];
+            let abs_value = value.abs();
+
+            let token = token_for_value(abs_value);
+            b.write_with_tree(tree, table, token, if skip { 2 } else { 0 });
+
+            if token == DCT_0 {
+                skip = true;
+                complexity = 0;
+                continue
+            }
+
+            if token >= DCT_CAT1 {
+                let t = PROB_DCT_CAT[(token - DCT_CAT1) as usize];
+                let extra = abs_value - DCT_CAT_BASE[(token - DCT_CAT1) as usize] as i32;
+                let n = t.iter().take_while(|&&p| p > 0).count();
+
+                for j in (0..n) {
+                    b.write_bool((extra >> (n - 1 - j)) & 1 == 1, t[j]);
+                }
+            }
+
+            b.write_bool(value < 0, 128);
+
+            skip = false;
+            complexity = if abs_value == 1 { 1 } else { 2 };
+        }
+
+        if last < 15 {
+            let table = &probs[COEFF_BANDS[last + 1] as usize][complexity][];
+            b.write_with_tree(tree, table, DCT_EOB, 0);
+        }
+
+        true
+    }
+
+    // Mirrors read_residual_data
+    fn write_residual_data(&mut self, b: &mut BoolWriter, levels: &[i32], mbx: usize) {
+        let complexity = self.top[mbx].complexity[0] + self.left.complexity[0];
+        let n = self.write_coefficients(b, &levels[384..400], 1, complexity as usize);
+
+        self.left.complexity[0] = if n { 1 } else { 0 };
+        self.top[mbx].complexity[0] = if n { 1 } else { 0 };
+
+        for y in (0us..4) {
+            let mut left = self.left.complexity[y + 1];
+
+            for x in (0us..4) {
+                let i = x + y * 4;
+                let complexity = self.top[mbx].complexity[x + 1] + left;
+                let n = self.write_coefficients(b, &levels[i * 16..i * 16 + 16], 0, complexity as usize);
+
+                left = if n { 1 } else { 0 };
+                self.top[mbx].complexity[x + 1] = if n { 1 } else { 0 };
+            }
+
+            self.left.complexity[y + 1] = left;
+        }
+
+        for &j in [5us, 7us].iter() {
+            for y in (0us..2) {
+                let mut left = self.left.complexity[y + j];
+
+                for x in (0us..2) {
+                    let i = x + y * 2 + if j == 5 { 16 } else { 20 };
+                    let complexity = self.top[mbx].complexity[x + j] + left;
+                    let n = self.write_coefficients(b, &levels[i * 16..i * 16 + 16], 2, complexity as usize);
+
+                    left = if n { 1 } else { 0 };
+                    self.top[mbx].complexity[x + j] = if n { 1 } else { 0 };
+                }
+
+                self.left.complexity[y + j] = left;
+            }
+        }
+    }
+
+    /// Encodes ```frame``` as a keyframe
+    pub fn encode_frame(&mut self, frame: &Frame) -> IoResult<()> {
+        self.mbwidth  = (frame.width + 15) / 16;
+        self.mbheight = (frame.height + 15) / 16;
+
+        let mw = self.mbwidth as usize;
+        let mh = self.mbheight as usize;
+
+        self.init_quantizers();
+
+        self.top = init_top_macroblocks(frame.width as usize);
+        self.top_border = repeat(127u8).take(frame.width as usize + 4 + 16).collect();
+        self.top_border_u = repeat(127u8).take(mw * 8).collect();
+        self.top_border_v = repeat(127u8).take(mw * 8).collect();
+
+        let ysrc = pad_plane(&frame.ybuf[], frame.width as usize, frame.height as usize, mw * 16, mh * 16);
+        let usrc = pad_plane(&frame.ubuf[], frame.chroma_width() as usize,
+                             frame.chroma_height() as usize, mw * 8, mh * 8);
+        let vsrc = pad_plane(&frame.vbuf[], frame.chroma_width() as usize,
+                             frame.chroma_height() as usize, mw * 8, mh * 8);
+
+        // The macroblocks are predicted and quantized first,
+        // the probability of skipped macroblocks is needed for the header
+        let mut macroblocks = Vec::with_capacity(mw * mh);
+
+        for mby in (0..mh) {
+            self.left_border = repeat(129u8).take(1 + 16).collect();
+            self.left_border_u = repeat(129u8).take(1 + 8).collect();
+            self.left_border_v = repeat(129u8).take(1 + 8).collect();
+
+            for mbx in (0..mw) {
+                let mut mb = MacroBlock::new();
+                let mut levels = [0i32; 400];
+
+                mb.luma_mode = self.encode_luma(&ysrc[], mbx, mby, &mut levels);
+                mb.chroma_mode = self.encode_chroma(&usrc[], &vsrc[], mbx, mby, &mut levels);
+                mb.coeffs_skipped = levels.iter().all(|&l| l == 0);
+
+                macroblocks.push((mb, levels));
+            }
+        }
+
+        let skipped = macroblocks.iter().filter(|&&(ref mb, _)| mb.coeffs_skipped).count();
+        let prob_skip_false = clamp(255 * (macroblocks.len() - skipped) / macroblocks.len(), 1, 254) as Prob;
+
+        let mut header = BoolWriter::new();
+        let mut tokens = BoolWriter::new();
+
+        self.write_frame_header(&mut header, prob_skip_false);
+
+        for (i, &(ref mb, ref levels)) in macroblocks.iter().enumerate() {
+            let mbx = i % mw;
+            if mbx == 0 {
+                self.left = MacroBlock::new();
+            }
+
+            self.write_macroblock_header(&mut header, mb, prob_skip_false);
+
+            if !mb.coeffs_skipped {
+                self.write_residual_data(&mut tokens, levels, mbx);
+            } else {
+                for i in (0us..9) {
+                    self.left.complexity[i] = 0;
+                    self.top[mbx].complexity[i] = 0;
+                }
+            }
+        }
+
+        let first_partition = header.flush();
+        let tokens = tokens.flush();
+
+        // Section 9.1
+        // A shown keyframe of version 0
+        let tag = ((first_partition.len() as u32) << 5) | (1 << 4);
+
+        let _ = try!(self.w.write_u8(tag as u8));
+        let _ = try!(self.w.write_u8((tag >> 8) as u8));
+        let _ = try!(self.w.write_u8((tag >> 16) as u8));
+
+        let _ = try!(self.w.write_all(&[0x9d, 0x01, 0x2a]));
+        let _ = try!(self.w.write_le_u16(frame.width));
+        let _ = try!(self.w.write_le_u16(frame.height));
+
+        let _ = try!(self.w.write_all(&first_partition[]));
+        self.w.write_all(&tokens[])
+    }
+}
+
+// Transforms and quantizes the four blocks of the residue of
+// one chroma plane and adds the reconstructed residue to ```ws```
+fn encode_chroma_plane(ws: &mut [u8],
+                       src: &[u8],
+                       segment: &Segment,
+                       mbx: usize,
+                       mby: usize,
+                       w: usize,
+                       levels: &mut [i32]) {
+    let stride = 1us + 8;
+
+    for y in (0us..2) {
+        for x in (0us..2) {
+            let i = x + y * 2;
+            let mut block = [0i32; 16];
+
+            for by in (0us..4) {
+                for bx in (0us..4) {
+                    let py = y * 4 + by;
+                    let px = x * 4 + bx;
+
+                    block[by * 4 + bx] = src[(mby * 8 + py) * w + mbx * 8 + px] as i32
+                                       - ws[(1 + py) * stride + 1 + px] as i32;
+                }
+            }
+
+            transform::fdct4x4(&mut block);
+
+            for k in (0us..16) {
+                let q = if k == 0 { segment.uvdc } else { segment.uvac };
+
+                levels[i * 16 + k] = quantize(block[k], q, k == 0);
+                block[k] = levels[i * 16 + k] * q as i32;
+            }
+
+            transform::idct4x4(&mut block);
+            add_residue(ws, &block, 1 + y * 4, 1 + x * 4, stride);
+        }
+    }
+}
+
+// Extends a plane to whole macroblocks by repeating its last row and column
+fn pad_plane(plane: &[u8], width: usize, height: usize, padded_width: usize, padded_height: usize) -> Vec<u8> {
+    let mut padded = Vec::with_capacity(padded_width * padded_height);
+
+    for y in (0..padded_height) {
+        let row = &plane[cmp::min(y, height - 1) * width..];
+
+        for x in (0..padded_width) {
+            padded.push(row[cmp::min(x, width - 1)]);
+        }
+    }
+
+    padded
+}
+
+// Quantizes with rounding for DC and a dead zone for AC coefficients
+fn quantize(coeff: i32, q: i16, dc: bool) -> i32 {
+    let q = q as i32;
+    let bias = if dc { q / 2 } else { q * 3 / 8 };
+    let level = cmp::min((coeff.abs() + bias) / q, MAX_LEVEL);
+
+    if coeff < 0 { -level } else { level }
+}
+
+fn token_for_value(abs_value: i32) -> i8 {
+    match abs_value {
+        0 => DCT_0,
+        1 => DCT_1,
+        2 => DCT_2,
+        3 => DCT_3,
+        4 => DCT_4,
+        5 ... 6 => DCT_CAT1,
+        7 ... 10 => DCT_CAT2,
+        11 ... 18 => DCT_CAT3,
+        19 ... 34 => DCT_CAT4,
+        35 ... 66 => DCT_CAT5,
+        _ => DCT_CAT6,
+    }
+}
+
+fn crop_plane(plane: &[u8], stride: usize, width: usize, height: usize) -> Vec<u8> {
+    let mut cropped = Vec::with_capacity(width * height);
+
+    for row in plane.chunks(stride).take(height) {
+        cropped.push_all(&row[..width]);
+    }
+
+    cropped
+}
+
+fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
+    let mb_width = (width + 15) / 16;
+
+    let mb = MacroBlock {
+        // Section 11.3 #3
+        bpred: [B_DC_PRED; 16],
+        luma_mode: DC_PRED,
+        ..MacroBlock::new()
+    };
+
+    (0..mb_width).map(|_| mb).collect()
+}
+
+fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) -> [u8; 357] {
+    let stride = 1us + 16 + 4;
+    let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
+
+    // A
+    {
+        let above = &mut ws[1..stride];
+        if mby == 0 {
+            for i in (0us..above.len()) {
+                above[i] = 127;
+            }
+        } else  {
+            for i in (0us..16) {
+                above[i] = top[mbx * 16 + i];
+            }
+
+            if mbx == mbw - 1 {
+                for i in (16us..above.len()) {
+                    above[i] = top[mbx * 16 + 15];
+                }
+            } else {
+                for i in (16us..above.len()) {
+                    above[i] = top[mbx * 16 + i];
+                }
+            }
+        }
+    }
+
+    for i in (17us..stride) {
+        ws[4  * stride + i] = ws[i];
+        ws[8  * stride + i] = ws[i];
+        ws[12 * stride + i] = ws[i];
+    }
+
+    // L
+    if mbx == 0 {
+        for i in (0us..16) {
+            ws[(i + 1) * stride] = 129;
+        }
+    } else {
+        for i in (0us..16) {
+            ws[(i + 1) * stride] = left[i + 1];
+        }
+    }
+
+    // P
+    ws[0] = if mby == 0 && mbx == 0 {
+        127
+    } else if mby == 0 && mbx != 0 {
+        127
+    } else if mbx == 0 && mby != 0 {
+        129
+    } else {
+        left[0]
+    };
+
+    ws
+}
+
+fn create_border_chroma(mbx: usize, mby: usize, top: &[u8], left: &[u8]) -> [u8; 81] {
+    let stride = 1us + 8;
+    let mut ws = [0u8; (1 + 8) * (1 + 8)];
+
+    // A
+    for i in (0us..8) {
+        ws[1 + i] = if mby == 0 { 127 } else { top[mbx * 8 + i] };
+    }
+
+    // L
+    for i in (0us..8) {
+        ws[(i + 1) * stride] = if mbx == 0 { 129 } else { left[i + 1] };
+    }
+
+    // P
+    ws[0] = if mby == 0 {
+        127
+    } else if mbx == 0 {
+        129
+    } else {
+        left[0]
+    };
+
+    ws
+}
+
+fn predict_chroma(ws: &mut [u8], mode: i8, mbx: usize, mby: usize, resdata: &[i32]) {
+    let stride = 1us + 8;
+
+    match mode {
+        V_PRED  => predict_vpred(ws, 8, 1, 1, stride),
+        H_PRED  => predict_hpred(ws, 8, 1, 1, stride),
+        TM_PRED => predict_tmpred(ws, 8, 1, 1, stride),
+        DC_PRED => predict_dcpred(ws, 8, stride, mby != 0, mbx != 0),
+        _       => panic!("unknown chroma intra prediction mode")
+    }
+
+    for y in (0us..2) {
+        for x in (0us..2) {
+            let i  = x + y * 2;
+            let rb = &resdata[i * 16..i * 16 + 16];
+
+            add_residue(ws, rb, 1 + y * 4, 1 + x * 4, stride);
+        }
+    }
+}
+
+// Chroma samples are centered between two luma samples, each luma position
+// is interpolated from its nearest chroma sample and the three neighbours
+// towards the luma position with weights 9/16, 3/16, 3/16 and 1/16.
+fn upsample_chroma(plane: &[u8], cw: usize, ch: usize, x: usize, y: usize) -> u8 {
+    let cx = x / 2;
+    let cy = y / 2;
+
+    let fx = if x & 1 == 0 { if cx > 0 { cx - 1 } else { 0 } }
+             else if cx + 1 < cw { cx + 1 }
+             else { cx };
+
+    let fy = if y & 1 == 0 { if cy > 0 { cy - 1 } else { 0 } }
+             else if cy + 1 < ch { cy + 1 }
+             else { cy };
+
+    let near   = plane[cy * cw + cx] as u32;
+    let horiz  = plane[cy * cw + fx] as u32;
+    let vert   = plane[fy * cw + cx] as u32;
+    let diag   = plane[fy * cw + fx] as u32;
+
+    ((9 * near + 3 * horiz + 3 * vert + diag + 8) >> 4) as u8
+}
+
+// ITU-R BT.601 conversion using libwebp's 14 bit fixed point constants
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> (u8, u8, u8) {
+    fn mult_hi(v: i32, coeff: i32) -> i32 {
+        (v * coeff) >> 8
+    }
+
+    fn clip(v: i32) -> u8 {
+        clamp(v >> 6, 0, 255) as u8
+    }
+
+    let (y, u, v) = (y as i32, u as i32, v as i32);
+
+    let r = clip(mult_hi(y, 19077) + mult_hi(v, 26149) - 14234);
+    let g = clip(mult_hi(y, 19077) - mult_hi(u, 6419) - mult_hi(v, 13320) + 8708);
+    let b = clip(mult_hi(y, 19077) + mult_hi(u, 33050) - 17685);
+
+    (r, g, b)
+}
+
+// The inverse of yuv_to_rgb with libwebp's 16 bit fixed point constants
+fn rgb_to_y(r: u8, g: u8, b: u8) -> u8 {
+    let luma = 16839 * r as i32 + 33059 * g as i32 + 6420 * b as i32;
+
+    ((luma + (1 << 15) + (16 << 16)) >> 16) as u8
+}
+
+// Converts the sums of four RGB pixels to chroma
+fn rgb_sum_to_uv(r: i32, g: i32, b: i32) -> (u8, u8) {
+    let rounding = (1 << 15) << 2;
+
+    let u = -9719 * r - 19081 * g + 28800 * b;
+    let v = 28800 * r - 24116 * g - 4684 * b;
+
+    let u = (u + rounding + (128 << 18)) >> 18;
+    let v = (v + rounding + (128 << 18)) >> 18;
+
+    (clamp(u, 0, 255) as u8, clamp(v, 0, 255) as u8)
+}
+
+fn avg3(left: u8, this: u8, right: u8) -> u8 {
+    let avg = (left as u16 + 2 * this as u16 + right as u16 + 2) >> 2;
+    avg as u8
+}
+
+fn avg2(this: u8, right: u8) -> u8 {
+    let avg = (this as u16 + right as u16 + 1) >> 1;
+    avg as u8
+}
+
+fn add_residue(pblock: &mut [u8], rblock: &[i32], y0: usize, x0: usize, stride: usize) {
+    for y in (0us..4) {
+        for x in (0us..4) {
+            let a = rblock[x + y * 4];
+            let b = pblock[(y0 + y) * stride + x0 + x];
+            let c = clamp(a + b as i32, 0, 255) as u8;
+            pblock[(y0 + y) * stride + x0 + x] = c;
+        }
+    }
+}
+
+fn predict_4x4(ws: &mut [u8], stride: usize, modes: &[i8], resdata: &[i32]) {
     for sby in (0us..4) {
         for sbx in (0us..4) {
             let i  = sbx + sby * 4;
@@ -1835,3 +3001,63 @@ fn predict_bhupred(a: &mut [u8], x0: usize, y0: usize, stride: usize) {
     a[(y0 + 3) * stride + x0 + 2] = l3;
     a[(y0 + 3) * stride + x0 + 3] = l3;
 }
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{File, MemReader};
+
+    use super::{BoolReader, BoolWriter, VP8Decoder, DCT_TOKEN_TREE};
+
+    fn read_testdata(name: &str) -> Vec<u8> {
+        let path = Path::new(".").join_many(&["src", "webp", "testdata", name]);
+
+        File::open(&path).read_to_end().unwrap()
+    }
+
+    #[test]
+    fn test_loop_filter() {
+        // The references are the planes written by dwebp -nodither -yuv,
+        // the images use the normal and the simple filter
+        for name in ["lossy-normal-filter", "lossy-simple-filter"].iter() {
+            let data = read_testdata(&format!("{}.webp", name)[]);
+            let reference = read_testdata(&format!("{}.yuv", name)[]);
+
+            // The VP8 chunk follows the RIFF header
+            let len = data[16] as usize | (data[17] as usize) << 8 | (data[18] as usize) << 16;
+            let mut decoder = VP8Decoder::new(MemReader::new(data[20..20 + len].to_vec()));
+            let frame = decoder.decode_frame().unwrap();
+
+            assert_eq!((frame.width, frame.height), (37, 29));
+
+            let (y, rest) = reference.split_at(37 * 29);
+            let (u, v) = rest.split_at(19 * 15);
+
+            assert_eq!(&frame.ybuf[], y);
+            assert_eq!(&frame.ubuf[], u);
+            assert_eq!(&frame.vbuf[], v);
+        }
+    }
+
+    #[test]
+    fn bool_writer_reader() {
+        let probs = [1u8, 20, 128, 200, 255];
+        let mut writer = BoolWriter::new();
+
+        for i in (0us..1000) {
+            writer.write_bool(i % 3 == 0, probs[i % probs.len()]);
+        }
+
+        writer.write_literal(7, 99);
+        writer.write_with_tree(&DCT_TOKEN_TREE, &[128u8; 11], 9, 0);
+
+        let mut reader = BoolReader::new();
+        reader.init(writer.flush());
+
+        for i in (0us..1000) {
+            assert_eq!(reader.read_bool(probs[i % probs.len()]), if i % 3 == 0 { 1 } else { 0 });
+        }
+
+        assert_eq!(reader.read_literal(7), 99);
+        assert_eq!(reader.read_with_tree(&DCT_TOKEN_TREE, &[128u8; 11], 0), 9);
+    }
+}